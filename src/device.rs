@@ -1,15 +1,318 @@
 use crate::error::{Error, Result};
+use crate::partition::VolumeLocation;
+use crate::qcow2::{self, Qcow2Image};
+use crate::resize::relocator::MAX_RUN_BYTES;
+use crate::system;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom};
-use std::os::unix::fs::FileExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Second handle on a block device, opened with `O_DIRECT`
+///
+/// Transfers aligned to the logical block size bypass the page cache through
+/// it, each using a bounce buffer of its own aligned to the block size; all
+/// others go through the buffered handle.
+struct DirectFile {
+    file: File,
+    block_size: u64,
+    /// Direct transfers in progress, and the most that were at once
+    #[cfg(test)]
+    in_flight: AtomicU64,
+    #[cfg(test)]
+    peak_in_flight: AtomicU64,
+}
+
+impl DirectFile {
+    fn new(file: File, block_size: u64) -> Self {
+        Self {
+            file,
+            block_size,
+            #[cfg(test)]
+            in_flight: AtomicU64::new(0),
+            #[cfg(test)]
+            peak_in_flight: AtomicU64::new(0),
+        }
+    }
+
+    /// Open `path` for direct I/O, if the device's block size is known
+    #[cfg(target_os = "linux")]
+    fn open(path: &Path, writable: bool, buffered: &File) -> Result<Option<Self>> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let Some(block_size) = system::get_logical_block_size(buffered) else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(writable)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Ok(Some(Self::new(file, block_size)))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open(_path: &Path, _writable: bool, _buffered: &File) -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Check whether a transfer can bypass the page cache
+    fn is_aligned(&self, offset: u64, len: usize) -> bool {
+        offset.is_multiple_of(self.block_size) && (len as u64).is_multiple_of(self.block_size)
+    }
+
+    /// A bounce buffer for a transfer of `len` bytes, and where its
+    /// block-aligned part starts
+    ///
+    /// Holds at most one run of the largest size a data shift moves; longer
+    /// transfers go through it in pieces. Every transfer allocates its own,
+    /// so the reader thread of a shift and its writer run at the same time.
+    fn bounce_buffer(&self, len: usize) -> (Vec<u8>, usize) {
+        let len = len.min(MAX_RUN_BYTES as usize) + self.block_size as usize;
+        let bounce = vec![0u8; len];
+        let start = bounce.as_ptr().align_offset(self.block_size as usize);
+        (bounce, start)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        #[cfg(test)]
+        let _transfer = self.track_transfer();
+        let (mut bounce, start) = self.bounce_buffer(buf.len());
+        let mut offset = offset;
+        for chunk in buf.chunks_mut(MAX_RUN_BYTES as usize) {
+            let aligned = &mut bounce[start..start + chunk.len()];
+            self.file.read_exact_at(aligned, offset)?;
+            chunk.copy_from_slice(aligned);
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        #[cfg(test)]
+        let _transfer = self.track_transfer();
+        let (mut bounce, start) = self.bounce_buffer(data.len());
+        let mut offset = offset;
+        for chunk in data.chunks(MAX_RUN_BYTES as usize) {
+            let aligned = &mut bounce[start..start + chunk.len()];
+            aligned.copy_from_slice(chunk);
+            self.file.write_all_at(aligned, offset)?;
+            offset += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Count a transfer as in progress until the guard drops
+    ///
+    /// Holds the transfer open for a moment, so that transfers which can
+    /// overlap do.
+    #[cfg(test)]
+    fn track_transfer(&self) -> TransferGuard<'_> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(5));
+        TransferGuard(&self.in_flight)
+    }
+}
+
+/// A direct transfer in progress (see [`DirectFile::track_transfer`])
+#[cfg(test)]
+struct TransferGuard<'a>(&'a AtomicU64);
+
+#[cfg(test)]
+impl Drop for TransferGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Where the bytes of a device are stored
+enum Backend {
+    /// Block device or raw image file (`regular`: a file, whose holes can
+    /// be found and punched; `direct`: set by [`Device::enable_direct_io`])
+    Raw {
+        file: File,
+        regular: bool,
+        direct: Option<DirectFile>,
+    },
+    /// Virtual disk inside a qcow2 image
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl Backend {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        match self {
+            Self::Raw {
+                direct: Some(direct),
+                ..
+            } if direct.is_aligned(offset, buf.len()) => direct.read_exact_at(buf, offset),
+            Self::Raw { file, .. } => Ok(file.read_exact_at(buf, offset)?),
+            Self::Qcow2(image) => image.read_exact_at(buf, offset),
+        }
+    }
+
+    fn write_all_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        match self {
+            Self::Raw {
+                direct: Some(direct),
+                ..
+            } if direct.is_aligned(offset, data.len()) => direct.write_all_at(data, offset),
+            Self::Raw { file, .. } => Ok(file.write_all_at(data, offset)?),
+            Self::Qcow2(image) => image.write_all_at(data, offset),
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        match self {
+            Self::Raw { file, regular, .. } => {
+                if *regular {
+                    Ok(file.metadata()?.len())
+                } else {
+                    // Block device - use seek to end to get size
+                    let mut f = file.try_clone()?;
+                    Ok(f.seek(SeekFrom::End(0))?)
+                }
+            }
+            Self::Qcow2(image) => Ok(image.size()),
+        }
+    }
+
+    fn sync(&self) -> Result<()> {
+        match self {
+            Self::Raw { file, .. } => Ok(file.sync_all()?),
+            Self::Qcow2(image) => image.sync(),
+        }
+    }
+
+    fn is_hole(&self, offset: u64, len: u64) -> Result<bool> {
+        match self {
+            Self::Raw { file, regular, .. } => {
+                Ok(*regular && system::is_file_hole(file, offset, len))
+            }
+            Self::Qcow2(image) => image.is_unallocated(offset, len),
+        }
+    }
+
+    fn punch_hole(&self, offset: u64, len: u64) -> Result<bool> {
+        match self {
+            Self::Raw { file, regular, .. } => {
+                if *regular {
+                    system::punch_file_hole(file, offset, len)
+                } else {
+                    Ok(false)
+                }
+            }
+            Self::Qcow2(image) => image.discard(offset, len),
+        }
+    }
+
+    fn discard(&self, offset: u64, len: u64) -> Result<bool> {
+        match self {
+            Self::Raw {
+                file,
+                regular: false,
+                ..
+            } => system::discard_block_range(file, offset, len),
+            _ => self.punch_hole(offset, len),
+        }
+    }
+
+    fn copy_range(&self, from: u64, to: u64, len: u64) -> Result<bool> {
+        match self {
+            Self::Raw {
+                file,
+                regular: true,
+                ..
+            } => {
+                let block_size = file.metadata()?.blksize().max(1);
+                system::copy_file_range_within(file, from, to, len, block_size)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Space an operation kept from being allocated in a sparse image file or
+/// qcow2 image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SparseStats {
+    /// Bytes that were holes and stayed holes instead of being written
+    pub skipped_bytes: u64,
+    /// Bytes turned back into holes after their data moved away
+    pub punched_bytes: u64,
+}
+
+impl SparseStats {
+    /// Total bytes not allocated thanks to holes
+    pub fn saved_bytes(&self) -> u64 {
+        self.skipped_bytes + self.punched_bytes
+    }
+}
+
+/// I/O an operation issued to a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoStats {
+    /// Read calls
+    pub read_ops: u64,
+    /// Write, hole punching, discard and kernel copy calls
+    pub write_ops: u64,
+    pub bytes_read: u64,
+    /// Bytes written, including those copied by the host filesystem
+    pub bytes_written: u64,
+    /// Time spent in data shifts and FAT copies, the transfers that make up
+    /// nearly all of the I/O
+    pub elapsed: Duration,
+}
+
+impl IoStats {
+    /// Bytes written per second
+    pub fn throughput(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.elapsed.as_secs_f64()
+    }
+}
 
 /// Wrapper around a block device or image file for sector-based I/O
+///
+/// All I/O is relative to the start of the filesystem, which is the start of
+/// the device unless it was opened at a partition or offset. qcow2 images
+/// are recognized by their magic, and I/O goes to their virtual disk.
 pub struct Device {
-    file: File,
+    backend: Backend,
     path: PathBuf,
+    writable: bool,
     sector_size: u32,
     total_sectors: u64,
+    /// Byte offset of the filesystem on the device
+    start: u64,
+    /// Bytes after `start` that I/O is confined to (`None`: up to the end
+    /// of the device)
+    limit: Option<u64>,
+    /// Counters behind [`SparseStats`]
+    skipped_bytes: AtomicU64,
+    punched_bytes: AtomicU64,
+    /// Bytes moved by [`Device::copy_range`] without passing through userspace
+    offloaded_bytes: AtomicU64,
+    /// Counters behind [`IoStats`]
+    read_ops: AtomicU64,
+    write_ops: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Nanoseconds spent in [`Device::timed`] transfers
+    transfer_nanos: AtomicU64,
+    /// Writes left before every further write fails (simulated crash)
+    #[cfg(test)]
+    write_budget: std::sync::atomic::AtomicU64,
+    /// Reads left before every further read fails (simulated read error)
+    #[cfg(test)]
+    read_budget: std::sync::atomic::AtomicU64,
+    /// Whether [`Device::copy_range`] acts as on a filesystem without
+    /// `copy_file_range`
+    #[cfg(test)]
+    refuse_copy_range: std::sync::atomic::AtomicBool,
 }
 
 impl std::fmt::Debug for Device {
@@ -18,6 +321,8 @@ impl std::fmt::Debug for Device {
             .field("path", &self.path)
             .field("sector_size", &self.sector_size)
             .field("total_sectors", &self.total_sectors)
+            .field("start", &self.start)
+            .field("limit", &self.limit)
             .finish_non_exhaustive()
     }
 }
@@ -34,16 +339,17 @@ impl Device {
             .open(&path_buf)
             .map_err(|_| Error::DeviceNotFound(path_display))?;
 
-        // Get device size
-        let metadata = file.metadata()?;
-        let size = if metadata.is_file() {
-            // Regular file (image)
-            metadata.len()
+        let backend = if qcow2::is_qcow2(&file)? {
+            Backend::Qcow2(Box::new(Qcow2Image::open(file, writable)?))
         } else {
-            // Block device - use seek to end to get size
-            let mut f = file.try_clone()?;
-            f.seek(SeekFrom::End(0))?
+            let regular = file.metadata()?.is_file();
+            Backend::Raw {
+                file,
+                regular,
+                direct: None,
+            }
         };
+        let size = backend.size()?;
 
         // Default to 512-byte sectors (most common)
         // We'll update this after reading the boot sector
@@ -51,10 +357,27 @@ impl Device {
         let total_sectors = size / sector_size as u64;
 
         Ok(Self {
-            file,
+            backend,
             path: path_buf,
+            writable,
             sector_size,
             total_sectors,
+            start: 0,
+            limit: None,
+            skipped_bytes: AtomicU64::new(0),
+            punched_bytes: AtomicU64::new(0),
+            offloaded_bytes: AtomicU64::new(0),
+            read_ops: AtomicU64::new(0),
+            write_ops: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            transfer_nanos: AtomicU64::new(0),
+            #[cfg(test)]
+            write_budget: std::sync::atomic::AtomicU64::new(u64::MAX),
+            #[cfg(test)]
+            read_budget: std::sync::atomic::AtomicU64::new(u64::MAX),
+            #[cfg(test)]
+            refuse_copy_range: std::sync::atomic::AtomicBool::new(false),
         })
     }
 
@@ -68,11 +391,52 @@ impl Device {
         Self::open_impl(path, false)
     }
 
+    /// Open a device or image for read/write access to the filesystem at `location`
+    pub fn open_at<P: AsRef<Path>>(path: P, location: VolumeLocation) -> Result<Self> {
+        Self::open_impl(path, true)?.select(location)
+    }
+
+    /// Open a device in read-only mode at `location`
+    pub fn open_readonly_at<P: AsRef<Path>>(path: P, location: VolumeLocation) -> Result<Self> {
+        Self::open_impl(path, false)?.select(location)
+    }
+
+    /// Confine all further I/O to the partition or offset at `location`
+    fn select(mut self, location: VolumeLocation) -> Result<Self> {
+        let device_size = self.raw_size()?;
+        match location {
+            VolumeLocation::Whole => {}
+            VolumeLocation::Offset(offset) => {
+                if offset >= device_size {
+                    return Err(Error::OffsetOutOfRange(offset));
+                }
+                self.start = offset;
+            }
+            VolumeLocation::Partition(number) => {
+                let table = crate::partition::read_partition_table(&self)?
+                    .ok_or_else(|| Error::NoPartitionTable(self.path.display().to_string()))?;
+                let partition = table
+                    .partition(number)
+                    .ok_or(Error::PartitionNotFound(number))?;
+                self.start = partition.start_bytes;
+                self.limit = Some(partition.size_bytes);
+            }
+        }
+        self.total_sectors = self.file_size()? / self.sector_size as u64;
+        Ok(self)
+    }
+
     /// Get the device path
     pub fn path(&self) -> &Path {
         &self.path
     }
 
+    /// Byte offset of the filesystem on the device (0 unless opened at a
+    /// partition or offset)
+    pub fn start_offset(&self) -> u64 {
+        self.start
+    }
+
     /// Get the sector size in bytes
     pub fn sector_size(&self) -> u32 {
         self.sector_size
@@ -92,24 +456,47 @@ impl Device {
         self.total_sectors
     }
 
-    /// Get total device size in bytes
+    /// Get the size of the partition, or of the device after the start offset
     fn file_size(&self) -> Result<u64> {
-        let metadata = self.file.metadata()?;
-        if metadata.is_file() {
-            Ok(metadata.len())
-        } else {
-            let mut f = self.file.try_clone()?;
-            Ok(f.seek(SeekFrom::End(0))?)
+        match self.limit {
+            Some(limit) => Ok(limit),
+            None => Ok(self.raw_size()?.saturating_sub(self.start)),
         }
     }
 
+    /// Get total device size in bytes, ignoring any partition
+    fn raw_size(&self) -> Result<u64> {
+        self.backend.size()
+    }
+
+    /// Translate an offset into the filesystem to one on the device, refusing
+    /// access past the end of the partition
+    fn device_offset(&self, offset: u64, len: usize) -> Result<u64> {
+        if let Some(limit) = self.limit {
+            if offset.saturating_add(len as u64) > limit {
+                return Err(Error::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Access to {} bytes at offset {} is past the end of the partition ({} bytes)",
+                        len, offset, limit
+                    ),
+                )));
+            }
+        }
+        Ok(self.start + offset)
+    }
+
     /// Read sectors starting at the given sector number
     pub fn read_sectors(&self, start_sector: u64, count: u32) -> Result<Vec<u8>> {
         let offset = start_sector * self.sector_size as u64;
         let size = count as usize * self.sector_size as usize;
         let mut buffer = vec![0u8; size];
 
-        self.file.read_exact_at(&mut buffer, offset)?;
+        #[cfg(test)]
+        Self::consume_budget(&self.read_budget, "simulated read error")?;
+        self.backend
+            .read_exact_at(&mut buffer, self.device_offset(offset, size)?)?;
+        self.count_read(size);
         Ok(buffer)
     }
 
@@ -120,8 +507,12 @@ impl Device {
 
     /// Write sectors starting at the given sector number
     pub fn write_sectors(&self, start_sector: u64, data: &[u8]) -> Result<()> {
+        #[cfg(test)]
+        self.consume_write_budget()?;
         let offset = start_sector * self.sector_size as u64;
-        self.file.write_all_at(data, offset)?;
+        self.backend
+            .write_all_at(data, self.device_offset(offset, data.len())?)?;
+        self.count_write(data.len() as u64);
         Ok(())
     }
 
@@ -142,27 +533,286 @@ impl Device {
 
     /// Flush all writes to disk
     pub fn sync(&self) -> Result<()> {
-        self.file.sync_all()?;
-        Ok(())
+        self.backend.sync()
     }
 
     /// Read raw bytes from a byte offset (used for bootstrapping before sector size is known)
     pub fn read_bytes_at(&self, offset: u64, size: usize) -> Result<Vec<u8>> {
         let mut buffer = vec![0u8; size];
-        self.file.read_exact_at(&mut buffer, offset)?;
+        #[cfg(test)]
+        Self::consume_budget(&self.read_budget, "simulated read error")?;
+        self.backend
+            .read_exact_at(&mut buffer, self.device_offset(offset, size)?)?;
+        self.count_read(size);
         Ok(buffer)
     }
 
     /// Write raw bytes at a byte offset
     pub fn write_bytes_at(&self, offset: u64, data: &[u8]) -> Result<()> {
-        self.file.write_all_at(data, offset)?;
+        #[cfg(test)]
+        self.consume_write_budget()?;
+        self.backend
+            .write_all_at(data, self.device_offset(offset, data.len())?)?;
+        self.count_write(data.len() as u64);
         Ok(())
     }
 
-    /// Get total device size in bytes (public version)
+    /// Get the size in bytes of the partition, or of the device after the
+    /// start offset
     pub fn size_bytes(&self) -> Result<u64> {
         self.file_size()
     }
+
+    /// Check whether a byte range reads as zeros without taking space: a
+    /// hole of a sparse image file, or unallocated clusters of a qcow2 image
+    ///
+    /// Always `false` for block devices.
+    pub fn is_hole(&self, offset: u64, len: u64) -> Result<bool> {
+        let device_offset = self.device_offset(offset, len as usize)?;
+        self.backend.is_hole(device_offset, len)
+    }
+
+    /// Make a byte range read as zeros and give its space back, if the
+    /// device is a sparse image file or qcow2 image
+    ///
+    /// Returns `false`, leaving the range as it is, for block devices and
+    /// filesystems that cannot punch holes.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> Result<bool> {
+        #[cfg(test)]
+        self.consume_write_budget()?;
+        let device_offset = self.device_offset(offset, len as usize)?;
+        let punched = self.backend.punch_hole(device_offset, len)?;
+        if punched {
+            self.punched_bytes.fetch_add(len, Ordering::Relaxed);
+            self.count_write(0);
+        }
+        Ok(punched)
+    }
+
+    /// Give the space of a byte range back to the storage below: `BLKDISCARD`
+    /// (TRIM) on block devices, a hole in sparse image files and qcow2
+    /// images
+    ///
+    /// The range must hold nothing worth keeping, as block devices may
+    /// return anything for it afterwards. Returns `false`, leaving the range
+    /// as it is, if the device cannot discard.
+    pub fn discard(&self, offset: u64, len: u64) -> Result<bool> {
+        #[cfg(test)]
+        self.consume_write_budget()?;
+        let device_offset = self.device_offset(offset, len as usize)?;
+        let discarded = self.backend.discard(device_offset, len)?;
+        if discarded {
+            self.count_write(0);
+        }
+        Ok(discarded)
+    }
+
+    /// Fill a byte range with zeros, leaving holes as they are and punching
+    /// new ones where possible instead of writing
+    pub fn zero_range(&self, offset: u64, len: u64) -> Result<()> {
+        if self.is_hole(offset, len)? {
+            self.skipped_bytes.fetch_add(len, Ordering::Relaxed);
+            return Ok(());
+        }
+        if !self.punch_hole(offset, len)? {
+            self.write_bytes_at(offset, &vec![0u8; len as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Copy `len` bytes from `from` to `to` inside the kernel, sharing the
+    /// blocks (reflink) where the host filesystem can
+    ///
+    /// The ranges must not overlap. Returns `false`, copying nothing, for
+    /// block devices, qcow2 images and filesystems without
+    /// `copy_file_range`; the caller then copies through a buffer.
+    pub fn copy_range(&self, from: u64, to: u64, len: u64) -> Result<bool> {
+        #[cfg(test)]
+        self.consume_write_budget()?;
+        #[cfg(test)]
+        if self.refuse_copy_range.load(Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let from = self.device_offset(from, len as usize)?;
+        let to = self.device_offset(to, len as usize)?;
+        let copied = self.backend.copy_range(from, to, len)?;
+        if copied {
+            self.offloaded_bytes.fetch_add(len, Ordering::Relaxed);
+            self.count_write(len);
+        }
+        Ok(copied)
+    }
+
+    /// Bytes moved by [`Device::copy_range`] since the device was opened
+    pub fn offloaded_bytes(&self) -> u64 {
+        self.offloaded_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Space kept from being allocated since the device was opened
+    pub fn sparse_stats(&self) -> SparseStats {
+        SparseStats {
+            skipped_bytes: self.skipped_bytes.load(Ordering::Relaxed),
+            punched_bytes: self.punched_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// I/O issued since the device was opened
+    pub fn io_stats(&self) -> IoStats {
+        IoStats {
+            read_ops: self.read_ops.load(Ordering::Relaxed),
+            write_ops: self.write_ops.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            elapsed: Duration::from_nanos(self.transfer_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Run a bulk transfer (a data shift or FAT copy), counting its time in
+    /// [`IoStats::elapsed`]
+    pub(crate) fn timed<T>(&self, transfer: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let result = transfer();
+        self.transfer_nanos
+            .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        result
+    }
+
+    fn count_read(&self, len: usize) {
+        self.read_ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn count_write(&self, len: u64) {
+        self.write_ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Bypass the page cache for reads and writes aligned to the device's
+    /// logical block size
+    ///
+    /// Keeps a large shift from evicting everything else cached on the host
+    /// and from leaving gigabytes for `sync` to flush. Unaligned transfers
+    /// still go through the page cache. Returns `false`, changing nothing,
+    /// for image files (regular and qcow2) and devices whose block size is
+    /// unknown, and the error of the open for devices that refuse
+    /// `O_DIRECT`; I/O stays buffered either way.
+    pub fn enable_direct_io(&mut self) -> Result<bool> {
+        let Backend::Raw {
+            file,
+            regular: false,
+            direct,
+        } = &mut self.backend
+        else {
+            return Ok(false);
+        };
+        if direct.is_none() {
+            *direct = DirectFile::open(&self.path, self.writable, file)?;
+        }
+        Ok(direct.is_some())
+    }
+
+    /// Logical block size direct I/O is aligned to (`None` without direct I/O)
+    pub fn direct_io_block_size(&self) -> Option<u64> {
+        match &self.backend {
+            Backend::Raw {
+                direct: Some(direct),
+                ..
+            } => Some(direct.block_size),
+            _ => None,
+        }
+    }
+
+    /// Check whether the device is the virtual disk of a qcow2 image
+    pub fn is_qcow2(&self) -> bool {
+        matches!(self.backend, Backend::Qcow2(_))
+    }
+
+    /// Grow a raw image file, or the virtual disk of a qcow2 image, to
+    /// `size_bytes` (ignoring any partition)
+    ///
+    /// Raw images are extended with `set_len`, so the new space takes no
+    /// room on disk until written; in qcow2 images it is unallocated.
+    pub fn grow_image_to(&self, size_bytes: u64) -> Result<()> {
+        match &self.backend {
+            Backend::Raw { file, .. } => file.set_len(size_bytes)?,
+            Backend::Qcow2(image) => image.set_virtual_size(size_bytes)?,
+        }
+        self.backend.sync()
+    }
+
+    /// Let the next `writes` writes succeed and fail all later ones
+    ///
+    /// Simulates a crash at an arbitrary point of an operation, so tests can
+    /// check that it resumes correctly.
+    #[cfg(test)]
+    pub(crate) fn crash_after_writes(&self, writes: u64) {
+        self.write_budget
+            .store(writes, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Let [`Device::copy_range`] copy nothing, as where the host
+    /// filesystem cannot copy in the kernel
+    #[cfg(test)]
+    pub(crate) fn refuse_copy_range(&self) {
+        self.refuse_copy_range.store(true, Ordering::Relaxed);
+    }
+
+    /// Let the next `reads` reads succeed and fail all later ones
+    #[cfg(test)]
+    pub(crate) fn fail_reads_after(&self, reads: u64) {
+        self.read_budget
+            .store(reads, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    /// Bypass the page cache on an image file as on a block device with
+    /// `block_size` blocks; `false` where its filesystem refuses `O_DIRECT`
+    #[cfg(all(test, target_os = "linux"))]
+    pub(crate) fn attach_direct_io(&mut self, block_size: u64) -> bool {
+        use std::os::unix::fs::OpenOptionsExt;
+        let Ok(file) = OpenOptions::new()
+            .read(true)
+            .write(self.writable)
+            .custom_flags(libc::O_DIRECT)
+            .open(&self.path)
+        else {
+            return false;
+        };
+        match &mut self.backend {
+            Backend::Raw { direct, .. } => *direct = Some(DirectFile::new(file, block_size)),
+            Backend::Qcow2(_) => return false,
+        }
+        true
+    }
+
+    /// Most direct transfers that were in progress at once
+    #[cfg(test)]
+    pub(crate) fn peak_direct_transfers(&self) -> u64 {
+        match &self.backend {
+            Backend::Raw {
+                direct: Some(direct),
+                ..
+            } => direct.peak_in_flight.load(Ordering::SeqCst),
+            _ => 0,
+        }
+    }
+
+    #[cfg(test)]
+    fn consume_write_budget(&self) -> Result<()> {
+        Self::consume_budget(&self.write_budget, "simulated crash")
+    }
+
+    #[cfg(test)]
+    fn consume_budget(budget: &std::sync::atomic::AtomicU64, failure: &str) -> Result<()> {
+        use std::sync::atomic::Ordering;
+        let remaining = budget.load(Ordering::SeqCst);
+        if remaining == 0 {
+            return Err(Error::Io(std::io::Error::other(failure.to_string())));
+        }
+        if remaining != u64::MAX {
+            budget.store(remaining - 1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -202,4 +852,135 @@ mod tests {
         let sector0 = device.read_sector(0).unwrap();
         assert_eq!(sector0, vec![0u8; 512]);
     }
+
+    #[test]
+    fn test_device_simulated_crash() {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), vec![0u8; 4096]).unwrap();
+
+        let device = Device::open(file.path()).unwrap();
+        device.crash_after_writes(1);
+
+        device.write_sector(1, &[0xAB; 512]).unwrap();
+        assert!(device.write_sector(2, &[0xCD; 512]).is_err());
+        assert_eq!(device.read_sector(2).unwrap(), vec![0u8; 512]);
+    }
+
+    #[test]
+    fn test_device_io_stats_time_transfers() {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let device = Device::open(file.path()).unwrap();
+
+        // Only timed transfers count towards the throughput
+        device.write_sector(1, &[0xAB; 512]).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(device.io_stats().elapsed, Duration::ZERO);
+        assert_eq!(device.io_stats().throughput(), 0.0);
+
+        device
+            .timed(|| {
+                std::thread::sleep(Duration::from_millis(5));
+                device.write_bytes_at(4096, &[0xCD; 4096])
+            })
+            .unwrap();
+        let stats = device.io_stats();
+        assert!(stats.elapsed >= Duration::from_millis(5));
+        assert_eq!(stats.write_ops, 2);
+        assert_eq!(stats.bytes_written, 512 + 4096);
+    }
+
+    #[test]
+    fn test_device_holes() {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let device = Device::open(file.path()).unwrap();
+        device.write_bytes_at(65536, &[0xAB; 8192]).unwrap();
+
+        assert!(device.is_hole(0, 65536).unwrap());
+        assert!(!device.is_hole(65536, 512).unwrap());
+        assert!(!device.is_hole(0, 65536 + 512).unwrap());
+
+        device.zero_range(4096, 4096).unwrap();
+        device.zero_range(65536, 4096).unwrap();
+        assert_eq!(device.read_bytes_at(65536, 4096).unwrap(), vec![0u8; 4096]);
+        assert_eq!(device.read_bytes_at(69632, 4096).unwrap(), vec![0xAB; 4096]);
+        assert!(device.is_hole(65536, 4096).unwrap());
+        assert_eq!(
+            device.sparse_stats(),
+            SparseStats {
+                skipped_bytes: 4096,
+                punched_bytes: 4096
+            }
+        );
+
+        // Discards punch holes in image files; callers count them themselves
+        assert!(device.discard(69632, 4096).unwrap());
+        assert!(device.is_hole(69632, 4096).unwrap());
+        assert_eq!(device.read_bytes_at(69632, 4096).unwrap(), vec![0u8; 4096]);
+        assert_eq!(device.sparse_stats().punched_bytes, 4096);
+    }
+
+    #[test]
+    fn test_device_copy_range() {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let device = Device::open(file.path()).unwrap();
+        device.write_bytes_at(8192, &[0xCD; 8192]).unwrap();
+
+        // Block-aligned ranges may be cloned, others are copied
+        for (from, to, len) in [(8192, 65536, 8192), (8704, 131_584, 3584)] {
+            if device.copy_range(from, to, len).unwrap() {
+                assert_eq!(
+                    device.read_bytes_at(to, len as usize).unwrap(),
+                    vec![0xCD; len as usize]
+                );
+            }
+        }
+        assert_eq!(device.read_bytes_at(8192, 8192).unwrap(), vec![0xCD; 8192]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_device_direct_io() {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(1 << 20).unwrap();
+        let mut device = Device::open(file.path()).unwrap();
+
+        // Image files keep buffered I/O
+        assert!(!device.enable_direct_io().unwrap());
+        assert_eq!(device.direct_io_block_size(), None);
+
+        // Attach a direct handle as for a block device with 4 KiB blocks
+        if !device.attach_direct_io(4096) {
+            return; // The temporary directory does not support O_DIRECT
+        }
+        assert_eq!(device.direct_io_block_size(), Some(4096));
+
+        // Aligned transfers go direct, others through the page cache
+        device.write_bytes_at(8192, &[0xAB; 8192]).unwrap();
+        device.write_bytes_at(8192 + 100, &[0xCD; 50]).unwrap();
+        let data = device.read_bytes_at(8192, 8192).unwrap();
+        assert_eq!(&data[..100], &[0xAB; 100]);
+        assert_eq!(&data[100..150], &[0xCD; 50]);
+        assert_eq!(&data[150..], &[0xAB; 8192 - 150]);
+        assert_eq!(device.read_bytes_at(8292, 50).unwrap(), vec![0xCD; 50]);
+
+        // An aligned start with a misaligned tail goes through the page
+        // cache as a whole, and direct reads see it
+        device.write_bytes_at(65536, &[0xEF; 8192 + 512]).unwrap();
+        assert_eq!(device.read_bytes_at(65536, 8192).unwrap(), vec![0xEF; 8192]);
+        assert_eq!(
+            device.read_bytes_at(65536, 8192 + 512).unwrap(),
+            vec![0xEF; 8192 + 512]
+        );
+        assert_eq!(device.read_bytes_at(73728, 4096).unwrap()[512..], [0; 3584]);
+
+        // Transfers longer than a bounce buffer go through it in pieces
+        let len = MAX_RUN_BYTES as usize + 4096;
+        file.as_file().set_len(16 << 20).unwrap();
+        let data: Vec<u8> = (0..len).map(|i| (i / 4096) as u8).collect();
+        device.write_bytes_at(1 << 20, &data).unwrap();
+        assert_eq!(device.read_bytes_at(1 << 20, len).unwrap(), data);
+    }
 }
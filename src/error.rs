@@ -9,12 +9,48 @@ pub enum Error {
     #[error("Device '{0}' is currently mounted at '{1}'")]
     DeviceMounted(String, String),
 
+    #[error("Device '{0}' has no MBR or GPT partition table")]
+    NoPartitionTable(String),
+
+    #[error("Partition {0} not found in the partition table")]
+    PartitionNotFound(u32),
+
+    #[error("Invalid partition table: {0}")]
+    InvalidPartitionTable(String),
+
+    #[error("Device '{0}' does not have a GPT partition table")]
+    NotGpt(String),
+
+    #[error("Cannot grow partition {0}: {1}")]
+    CannotGrowPartition(u32, String),
+
+    #[error("Cannot grow image '{0}': {1}")]
+    CannotGrowImage(String, String),
+
+    #[error("Offset {0} is past the end of the device")]
+    OffsetOutOfRange(u64),
+
     #[error("Not a valid FAT32 filesystem: {0}")]
     InvalidFAT32(String),
 
+    #[error("Not a valid exFAT filesystem: {0}")]
+    InvalidExfat(String),
+
+    #[error("Not a valid qcow2 image: {0}")]
+    InvalidQcow2(String),
+
+    #[error("Unsupported qcow2 image: {0}")]
+    UnsupportedQcow2(String),
+
+    #[error("Filesystem is marked dirty (not cleanly unmounted); check it with fsck first")]
+    DirtyVolume,
+
     #[error("Boot sector validation failed: {0}")]
     BootSectorValidation(String),
 
+    #[error("Boot sector is invalidated (signature 0x0000), as by an interrupted resize")]
+    InvalidatedBootSector,
+
     #[error("FSInfo sector validation failed: {0}")]
     FSInfoValidation(String),
 
@@ -29,9 +65,36 @@ pub enum Error {
     #[error("Filesystem is already at maximum size for this device")]
     AlreadyMaxSize,
 
-    #[error("Cannot shrink filesystem (not supported)")]
+    #[error("Target size of {target} sectors exceeds the device size of {available} sectors")]
+    TargetTooLarge { target: u64, available: u64 },
+
+    #[error("Invalid size '{0}' (expected e.g. 512M, 8G or +2G)")]
+    InvalidSize(String),
+
+    #[error("Invalid strategy '{0}' (expected shift, renumber or auto)")]
+    InvalidStrategy(String),
+
+    #[error("Cannot align the data area to {0} bytes: the cluster layout does not allow it")]
+    CannotAlign(u64),
+
+    #[error("Invalid FAT reserve '{0}' (expected a size such as 64G or a percentage such as 50%)")]
+    InvalidFatReserve(String),
+
+    #[error("Invalid number of FATs: {0} (must be 1 or 2)")]
+    InvalidFatCount(u8),
+
+    #[error("Invalid cluster size of {0} bytes (must be a power of two larger than the current cluster size, at most 64 KiB)")]
+    InvalidClusterSize(u64),
+
+    #[error("{0} filesystems are not supported by this operation (only FAT32)")]
+    UnsupportedFatType(crate::fat32::FatType),
+
+    #[error("Target size is smaller than the filesystem; use shrink to reduce it")]
     ShrinkNotSupported,
 
+    #[error("{0} needs the whole FAT in memory, so it cannot run within a memory limit")]
+    MemoryLimitUnsupported(&'static str),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
@@ -59,13 +122,22 @@ pub enum Error {
     #[error("Resize checkpoint is corrupted (CRC mismatch)")]
     CheckpointCorrupted,
 
-    #[error("Filesystem has been invalidated by an interrupted resize operation. Checkpoint not found or corrupted - cannot recover automatically.")]
+    #[error("Resize journal is corrupted (CRC mismatch)")]
+    JournalCorrupted,
+
+    #[error("Not enough free space to shrink: {needed} clusters must move, but only {free} are free inside the new size")]
+    NotEnoughFreeSpace { needed: u32, free: u32 },
+
+    #[error("Filesystem has been invalidated by an interrupted resize operation. Checkpoint not found or corrupted - cannot recover automatically. If the resize was started with a target size, run it again with the same size.")]
     InvalidatedFilesystem,
 
     #[error(
         "Incomplete resize detected at phase {0}, but device size changed. Cannot safely resume."
     )]
     ResizeSizeMismatch(u8),
+
+    #[error("An interrupted resize was found; run the resize again to complete it first")]
+    IncompleteResize,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,22 @@
+pub mod operations;
+pub mod structs;
+pub mod validation;
+
+// Re-export types from structs
+pub use structs::{
+    boot_checksum, entry_type, fat_entry, BitmapEntry, ExfatBootSector, BACKUP_BOOT_REGION,
+    BOOT_REGION_SECTORS, CHECKSUM_SECTOR, ENTRY_SIZE, FILE_SYSTEM_NAME, MAX_CLUSTERS,
+};
+
+// Re-export operations
+pub use operations::{
+    boot_regions_match, cluster_chain, count_free_clusters, is_cluster_used, is_exfat, is_exfat_at,
+    read_bitmap, read_bitmap_entry, read_boot_region, read_boot_region_for_recovery,
+    read_fat_entry, set_cluster_used, write_bitmap_entry, write_boot_region, write_boot_sector,
+    write_fat_entries,
+};
+
+// Re-export validation
+pub use validation::{
+    validate_boot_checksum, validate_boot_sector, validate_boot_sector_for_recovery,
+};
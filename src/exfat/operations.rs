@@ -0,0 +1,256 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::exfat::structs::{
+    boot_checksum, entry_type, fat_entry, BitmapEntry, ExfatBootSector, BACKUP_BOOT_REGION,
+    BOOT_REGION_SECTORS, CHECKSUM_SECTOR, ENTRY_SIZE,
+};
+use crate::exfat::validation::{
+    validate_boot_checksum, validate_boot_sector, validate_boot_sector_for_recovery,
+};
+use crate::partition::VolumeLocation;
+use std::path::Path;
+
+/// Check whether the device or image holds an exFAT filesystem
+pub fn is_exfat(device_path: impl AsRef<Path>) -> Result<bool> {
+    is_exfat_at(device_path, VolumeLocation::Whole)
+}
+
+/// Check whether the partition or offset of the device holds an exFAT filesystem
+pub fn is_exfat_at(device_path: impl AsRef<Path>, location: VolumeLocation) -> Result<bool> {
+    let device = Device::open_readonly_at(device_path, location)?;
+    if device.size_bytes()? < 512 {
+        return Ok(false);
+    }
+    Ok(ExfatBootSector::is_exfat(&device.read_bytes_at(0, 512)?))
+}
+
+/// Read the main boot sector, switch the device to its sector size and
+/// check the checksum of the main boot region
+pub fn read_boot_region(device: &mut Device) -> Result<ExfatBootSector> {
+    let boot = read_boot_sector_impl(device)?;
+    validate_boot_sector(&boot)?;
+    validate_boot_checksum(
+        &device.read_sectors(0, BOOT_REGION_SECTORS as u32)?,
+        boot.bytes_per_sector() as usize,
+    )?;
+    Ok(boot)
+}
+
+/// Read the main boot sector, allowing the invalidated signature of an
+/// interrupted resize
+///
+/// The checksum is only checked while the signature is valid: invalidating
+/// the signature breaks it.
+pub fn read_boot_region_for_recovery(device: &mut Device) -> Result<ExfatBootSector> {
+    let boot = read_boot_sector_impl(device)?;
+    validate_boot_sector_for_recovery(&boot)?;
+    if boot.is_signature_valid() {
+        validate_boot_checksum(
+            &device.read_sectors(0, BOOT_REGION_SECTORS as u32)?,
+            boot.bytes_per_sector() as usize,
+        )?;
+    }
+    Ok(boot)
+}
+
+fn read_boot_sector_impl(device: &mut Device) -> Result<ExfatBootSector> {
+    let data = device.read_bytes_at(0, 512)?;
+    let shift = ExfatBootSector::from_bytes(&data)?.bytes_per_sector_shift();
+    if !(9..=12).contains(&shift) {
+        return Err(Error::UnsupportedSectorSize(
+            1u32.checked_shl(shift as u32).unwrap_or(0),
+        ));
+    }
+
+    // Update device to use the actual sector size and re-read the full sector
+    device.set_sector_size(1 << shift);
+    ExfatBootSector::from_bytes(&device.read_sector(0)?)
+}
+
+/// Check that the backup boot region matches the main one
+///
+/// Volume flags and the percentage in use may differ: they are not covered
+/// by the checksum and only kept current in the main boot sector.
+pub fn boot_regions_match(device: &Device) -> Result<bool> {
+    let main = device.read_sectors(0, BOOT_REGION_SECTORS as u32)?;
+    let backup = device.read_sectors(BACKUP_BOOT_REGION, BOOT_REGION_SECTORS as u32)?;
+    let sector_size = device.sector_size() as usize;
+    let checksum_start = CHECKSUM_SECTOR as usize * sector_size;
+    Ok(main
+        .iter()
+        .zip(&backup)
+        .enumerate()
+        .all(|(i, (a, b))| a == b || matches!(i, 106 | 107 | 112))
+        && boot_checksum(&main[..checksum_start]) == boot_checksum(&backup[..checksum_start]))
+}
+
+/// Write only the main boot sector (used to invalidate it)
+pub fn write_boot_sector(device: &Device, boot: &ExfatBootSector) -> Result<()> {
+    device.write_sector(0, boot.as_bytes())
+}
+
+/// Write a boot sector to the boot region starting at `region_start` (0 for
+/// the main, [`BACKUP_BOOT_REGION`] for the backup region) and recompute the
+/// region's checksum
+///
+/// The checksum sector is written first, so the boot sector's signature
+/// decides when the region becomes valid.
+pub fn write_boot_region(device: &Device, boot: &ExfatBootSector, region_start: u64) -> Result<()> {
+    let sector_size = device.sector_size() as usize;
+    let mut region = device.read_sectors(region_start, CHECKSUM_SECTOR as u32)?;
+    region[..sector_size].copy_from_slice(boot.as_bytes());
+
+    let checksum = boot_checksum(&region);
+    let checksum_sector: Vec<u8> = std::iter::repeat_n(checksum.to_le_bytes(), sector_size / 4)
+        .flatten()
+        .collect();
+    device.write_sector(region_start + CHECKSUM_SECTOR, &checksum_sector)?;
+    device.sync()?;
+    device.write_sector(region_start, boot.as_bytes())
+}
+
+/// Sector of the first FAT holding a cluster's entry, and the entry's offset in it
+fn fat_entry_position(boot: &ExfatBootSector, cluster: u32) -> (u64, usize) {
+    let byte = cluster as u64 * 4;
+    let bps = boot.bytes_per_sector() as u64;
+    (boot.fat_offset() as u64 + byte / bps, (byte % bps) as usize)
+}
+
+/// Read a single FAT entry from the first FAT
+pub fn read_fat_entry(device: &Device, boot: &ExfatBootSector, cluster: u32) -> Result<u32> {
+    let (sector, offset) = fat_entry_position(boot, cluster);
+    let data = device.read_sector(sector)?;
+    Ok(u32::from_le_bytes(
+        data[offset..offset + 4].try_into().unwrap(),
+    ))
+}
+
+/// Write FAT entries, reading and writing each FAT sector once
+pub fn write_fat_entries(
+    device: &Device,
+    boot: &ExfatBootSector,
+    entries: &[(u32, u32)],
+) -> Result<()> {
+    let mut by_sector: std::collections::BTreeMap<u64, Vec<(usize, u32)>> = Default::default();
+    for &(cluster, value) in entries {
+        let (sector, offset) = fat_entry_position(boot, cluster);
+        by_sector.entry(sector).or_default().push((offset, value));
+    }
+    for (sector, updates) in by_sector {
+        let mut data = device.read_sector(sector)?;
+        for (offset, value) in updates {
+            data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        device.write_sector(sector, &data)?;
+    }
+    Ok(())
+}
+
+/// Follow a FAT chain from its first cluster
+///
+/// Fails on loops and clusters outside the cluster heap.
+pub fn cluster_chain(device: &Device, boot: &ExfatBootSector, first: u32) -> Result<Vec<u32>> {
+    let max_cluster = boot.cluster_count() + 2;
+    let mut chain = Vec::new();
+    let mut cluster = Some(first);
+    while let Some(current) = cluster {
+        if !(2..max_cluster).contains(&current) || chain.len() >= boot.cluster_count() as usize {
+            return Err(Error::CorruptedFAT(current));
+        }
+        chain.push(current);
+        cluster = fat_entry::next_cluster(read_fat_entry(device, boot, current)?);
+    }
+    Ok(chain)
+}
+
+/// Find the allocation bitmap entry in the root directory
+///
+/// `cluster_sector` gives the first sector of a cluster, which differs from
+/// the boot sector's layout while the cluster heap moves. TexFAT volumes with
+/// two FATs also have two bitmaps; only the first is returned.
+pub fn read_bitmap_entry(
+    device: &Device,
+    boot: &ExfatBootSector,
+    cluster_sector: impl Fn(u32) -> u64,
+) -> Result<BitmapEntry> {
+    let spc = boot.sectors_per_cluster();
+    let bps = boot.bytes_per_sector() as usize;
+    for cluster in cluster_chain(device, boot, boot.root_cluster())? {
+        let first_sector = cluster_sector(cluster);
+        let data = device.read_sectors(first_sector, spc)?;
+        for (i, entry) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+            match entry[0] {
+                entry_type::END => break,
+                entry_type::ALLOCATION_BITMAP if entry[1] & 1 == 0 => {
+                    let byte = i * ENTRY_SIZE;
+                    return Ok(BitmapEntry::from_entry(
+                        entry,
+                        first_sector + (byte / bps) as u64,
+                        byte % bps,
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+    Err(Error::InvalidExfat(
+        "root directory has no allocation bitmap".to_string(),
+    ))
+}
+
+/// Write the cluster and length of an allocation bitmap entry
+pub fn write_bitmap_entry(device: &Device, entry: &BitmapEntry) -> Result<()> {
+    let mut data = device.read_sector(entry.sector)?;
+    entry.write_to(&mut data[entry.offset..entry.offset + ENTRY_SIZE]);
+    device.write_sector(entry.sector, &data)
+}
+
+/// Read the allocation bitmap, which exFAT stores in contiguous clusters
+///
+/// `cluster_sector` maps clusters to sectors as for [`read_bitmap_entry`].
+pub fn read_bitmap(
+    device: &Device,
+    boot: &ExfatBootSector,
+    entry: &BitmapEntry,
+    cluster_sector: impl Fn(u32) -> u64,
+) -> Result<Vec<u8>> {
+    let clusters = entry.clusters(boot.bytes_per_cluster());
+    let mut bitmap = Vec::with_capacity((clusters as u64 * boot.bytes_per_cluster()) as usize);
+    for cluster in entry.first_cluster..entry.first_cluster + clusters {
+        bitmap.extend(device.read_sectors(cluster_sector(cluster), boot.sectors_per_cluster())?);
+    }
+    bitmap.truncate(entry.data_length as usize);
+    Ok(bitmap)
+}
+
+/// Check whether the bitmap marks a cluster as in use
+pub fn is_cluster_used(bitmap: &[u8], cluster: u32) -> bool {
+    let index = (cluster - 2) as usize;
+    bitmap
+        .get(index / 8)
+        .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+}
+
+/// Mark a cluster as used or free in the bitmap
+pub fn set_cluster_used(bitmap: &mut [u8], cluster: u32, used: bool) {
+    let index = (cluster - 2) as usize;
+    if used {
+        bitmap[index / 8] |= 1 << (index % 8);
+    } else {
+        bitmap[index / 8] &= !(1 << (index % 8));
+    }
+}
+
+/// Count the clusters the bitmap marks as free
+pub fn count_free_clusters(bitmap: &[u8], cluster_count: u32) -> u32 {
+    let full_bytes = (cluster_count / 8) as usize;
+    let mut used: u32 = bitmap[..full_bytes]
+        .iter()
+        .map(|byte| byte.count_ones())
+        .sum();
+    let rest = cluster_count % 8;
+    if rest > 0 {
+        used += (bitmap[full_bytes] & ((1 << rest) - 1)).count_ones();
+    }
+    cluster_count - used
+}
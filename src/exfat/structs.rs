@@ -0,0 +1,418 @@
+use crate::error::{Error, Result};
+
+// ===== Boot Region Layout =====
+
+/// Sectors in a boot region: boot sector, 8 extended boot sectors, OEM
+/// parameters, a reserved sector and the checksum sector
+pub const BOOT_REGION_SECTORS: u64 = 12;
+
+/// First sector of the backup boot region
+pub const BACKUP_BOOT_REGION: u64 = BOOT_REGION_SECTORS;
+
+/// Sector of a boot region holding its checksum
+pub const CHECKSUM_SECTOR: u64 = 11;
+
+/// File system name of an exFAT boot sector (offset 3)
+pub const FILE_SYSTEM_NAME: &[u8; 8] = b"EXFAT   ";
+
+/// Largest cluster count exFAT can address
+pub const MAX_CLUSTERS: u32 = 0xFFFFFFF5;
+
+/// Checksum of a boot region, over all sectors but the checksum sector
+///
+/// `VolumeFlags` (offsets 106-107) and `PercentInUse` (offset 112) of the boot
+/// sector are skipped, so they can change without rewriting the checksum.
+pub fn boot_checksum(region: &[u8]) -> u32 {
+    region
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(i, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, &byte)| {
+            sum.rotate_right(1).wrapping_add(byte as u32)
+        })
+}
+
+// ===== exFAT Boot Sector =====
+
+/// exFAT boot sector (first sector of the main and backup boot regions)
+///
+/// All multi-byte values are stored in little-endian format. Unlike the FAT32
+/// BPB, sizes are given as powers of two and the volume length is 64-bit.
+#[derive(Clone)]
+pub struct ExfatBootSector {
+    /// Full sector data (512 to 4096 bytes depending on sector size)
+    raw: Vec<u8>,
+}
+
+impl ExfatBootSector {
+    /// Check whether a boot sector carries the exFAT file system name
+    pub fn is_exfat(bytes: &[u8]) -> bool {
+        bytes.len() >= 11 && &bytes[3..11] == FILE_SYSTEM_NAME
+    }
+
+    /// Parse a boot sector from raw bytes
+    /// The input must be at least 512 bytes and carry the exFAT name
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 512 {
+            return Err(Error::BootSectorValidation(format!(
+                "Boot sector too small: {} bytes",
+                bytes.len()
+            )));
+        }
+        if !Self::is_exfat(bytes) {
+            return Err(Error::InvalidExfat(
+                "file system name is not EXFAT".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            raw: bytes.to_vec(),
+        })
+    }
+
+    /// Get the raw bytes (full sector)
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.raw[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        self.raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // ===== Boot Sector Fields =====
+
+    /// Sectors before this volume on the media (offset 64, 8 bytes)
+    pub fn partition_offset(&self) -> u64 {
+        u64::from_le_bytes(self.raw[64..72].try_into().unwrap())
+    }
+
+    /// Size of the volume in sectors (offset 72, 8 bytes)
+    pub fn volume_length(&self) -> u64 {
+        u64::from_le_bytes(self.raw[72..80].try_into().unwrap())
+    }
+
+    /// Set the size of the volume in sectors
+    pub fn set_volume_length(&mut self, sectors: u64) {
+        self.raw[72..80].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    /// First sector of the first FAT (offset 80, 4 bytes)
+    pub fn fat_offset(&self) -> u32 {
+        self.u32_at(80)
+    }
+
+    /// Sectors per FAT (offset 84, 4 bytes)
+    pub fn fat_length(&self) -> u32 {
+        self.u32_at(84)
+    }
+
+    /// Set sectors per FAT
+    pub fn set_fat_length(&mut self, sectors: u32) {
+        self.set_u32_at(84, sectors);
+    }
+
+    /// First sector of the cluster heap (offset 88, 4 bytes)
+    pub fn cluster_heap_offset(&self) -> u32 {
+        self.u32_at(88)
+    }
+
+    /// Set the first sector of the cluster heap
+    pub fn set_cluster_heap_offset(&mut self, sector: u32) {
+        self.set_u32_at(88, sector);
+    }
+
+    /// Number of clusters in the cluster heap (offset 92, 4 bytes)
+    pub fn cluster_count(&self) -> u32 {
+        self.u32_at(92)
+    }
+
+    /// Set the number of clusters in the cluster heap
+    pub fn set_cluster_count(&mut self, clusters: u32) {
+        self.set_u32_at(92, clusters);
+    }
+
+    /// First cluster of the root directory (offset 96, 4 bytes)
+    pub fn root_cluster(&self) -> u32 {
+        self.u32_at(96)
+    }
+
+    /// Volume serial number (offset 100, 4 bytes)
+    pub fn volume_serial(&self) -> u32 {
+        self.u32_at(100)
+    }
+
+    /// File system revision (offset 104, 2 bytes) - major in the high byte
+    pub fn fs_revision(&self) -> u16 {
+        u16::from_le_bytes([self.raw[104], self.raw[105]])
+    }
+
+    /// Volume flags (offset 106, 2 bytes)
+    pub fn volume_flags(&self) -> u16 {
+        u16::from_le_bytes([self.raw[106], self.raw[107]])
+    }
+
+    /// Whether the volume was not cleanly unmounted (volume flags bit 1)
+    pub fn is_dirty(&self) -> bool {
+        self.volume_flags() & 0x0002 != 0
+    }
+
+    /// Bytes per sector as a power of two (offset 108, 1 byte)
+    pub fn bytes_per_sector_shift(&self) -> u8 {
+        self.raw[108]
+    }
+
+    /// Sectors per cluster as a power of two (offset 109, 1 byte)
+    pub fn sectors_per_cluster_shift(&self) -> u8 {
+        self.raw[109]
+    }
+
+    /// Number of FATs and allocation bitmaps (offset 110, 1 byte) - 2 for TexFAT
+    pub fn number_of_fats(&self) -> u8 {
+        self.raw[110]
+    }
+
+    /// Percentage of clusters in use (offset 112, 1 byte) - 0xFF if unknown
+    pub fn percent_in_use(&self) -> u8 {
+        self.raw[112]
+    }
+
+    /// Set the percentage of clusters in use
+    pub fn set_percent_in_use(&mut self, percent: u8) {
+        self.raw[112] = percent;
+    }
+
+    /// Boot signature at end of sector (offset 510, 2 bytes) - must be 0xAA55
+    pub fn boot_signature(&self) -> u16 {
+        u16::from_le_bytes([self.raw[510], self.raw[511]])
+    }
+
+    /// Check if the boot sector signature is valid
+    pub fn is_signature_valid(&self) -> bool {
+        self.boot_signature() == 0xAA55
+    }
+
+    /// Invalidate the boot sector signature (for crash safety during resize)
+    pub fn invalidate_signature(&mut self) {
+        self.raw[510] = 0x00;
+        self.raw[511] = 0x00;
+    }
+
+    /// Restore the boot sector signature to the valid value
+    pub fn restore_signature(&mut self) {
+        self.raw[510] = 0x55;
+        self.raw[511] = 0xAA;
+    }
+
+    // ===== Calculated Values =====
+
+    /// Bytes per sector
+    pub fn bytes_per_sector(&self) -> u32 {
+        1 << self.bytes_per_sector_shift()
+    }
+
+    /// Sectors per cluster
+    pub fn sectors_per_cluster(&self) -> u32 {
+        1 << self.sectors_per_cluster_shift()
+    }
+
+    /// Bytes per cluster
+    pub fn bytes_per_cluster(&self) -> u64 {
+        self.bytes_per_sector() as u64 * self.sectors_per_cluster() as u64
+    }
+
+    /// First sector of a cluster
+    pub fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.cluster_heap_offset() as u64 + (cluster as u64 - 2) * self.sectors_per_cluster() as u64
+    }
+
+    /// Bytes the allocation bitmap needs for the cluster count
+    pub fn bitmap_bytes(&self) -> u64 {
+        (self.cluster_count() as u64).div_ceil(8)
+    }
+}
+
+impl std::fmt::Debug for ExfatBootSector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExfatBootSector")
+            .field("volume_length", &self.volume_length())
+            .field("fat_offset", &self.fat_offset())
+            .field("fat_length", &self.fat_length())
+            .field("cluster_heap_offset", &self.cluster_heap_offset())
+            .field("cluster_count", &self.cluster_count())
+            .field("root_cluster", &self.root_cluster())
+            .field("bytes_per_sector", &self.bytes_per_sector())
+            .field("sectors_per_cluster", &self.sectors_per_cluster())
+            .finish()
+    }
+}
+
+// ===== FAT Entries =====
+
+/// exFAT FAT entry values
+///
+/// Unlike FAT32, all 32 bits are used. Files marked `NoFatChain` are
+/// contiguous and have undefined FAT entries, so the allocation bitmap, not
+/// the FAT, tells which clusters are in use.
+pub mod fat_entry {
+    /// Free (or undefined) entry
+    pub const FREE: u32 = 0x00000000;
+    /// Bad cluster marker
+    pub const BAD_CLUSTER: u32 = 0xFFFFFFF7;
+    /// Entry 0: media descriptor
+    pub const MEDIA: u32 = 0xFFFFFFF8;
+    /// End of chain marker
+    pub const END_OF_CHAIN: u32 = 0xFFFFFFFF;
+
+    /// Next cluster of a chain, `None` at its end
+    pub fn next_cluster(entry: u32) -> Option<u32> {
+        if (2..BAD_CLUSTER).contains(&entry) {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+}
+
+// ===== Directory Entries =====
+
+/// Size of a directory entry in bytes
+pub const ENTRY_SIZE: usize = 32;
+
+/// Directory entry types (the first byte of an entry)
+pub mod entry_type {
+    /// End of the directory
+    pub const END: u8 = 0x00;
+    /// Allocation bitmap
+    pub const ALLOCATION_BITMAP: u8 = 0x81;
+    /// Up-case table
+    pub const UPCASE_TABLE: u8 = 0x82;
+    /// Volume label
+    pub const VOLUME_LABEL: u8 = 0x83;
+    /// File or directory, followed by its secondary entries
+    pub const FILE: u8 = 0x85;
+    /// Stream extension of a file
+    pub const STREAM_EXTENSION: u8 = 0xC0;
+    /// Part of a file name
+    pub const FILE_NAME: u8 = 0xC1;
+}
+
+/// Allocation bitmap directory entry (type 0x81) and where it is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitmapEntry {
+    /// Device sector holding the entry
+    pub sector: u64,
+    /// Byte offset of the entry in that sector
+    pub offset: usize,
+    /// First cluster of the bitmap
+    pub first_cluster: u32,
+    /// Length of the bitmap in bytes
+    pub data_length: u64,
+}
+
+impl BitmapEntry {
+    /// Parse the cluster and length of an allocation bitmap entry
+    pub fn from_entry(entry: &[u8], sector: u64, offset: usize) -> Self {
+        Self {
+            sector,
+            offset,
+            first_cluster: u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+            data_length: u64::from_le_bytes(entry[24..32].try_into().unwrap()),
+        }
+    }
+
+    /// Write the cluster and length into a raw entry
+    pub fn write_to(&self, entry: &mut [u8]) {
+        entry[20..24].copy_from_slice(&self.first_cluster.to_le_bytes());
+        entry[24..32].copy_from_slice(&self.data_length.to_le_bytes());
+    }
+
+    /// Clusters the bitmap occupies
+    pub fn clusters(&self, bytes_per_cluster: u64) -> u32 {
+        self.data_length.div_ceil(bytes_per_cluster) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_boot_sector() -> Vec<u8> {
+        let mut data = vec![0u8; 512];
+        data[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        data[3..11].copy_from_slice(FILE_SYSTEM_NAME);
+        data[72..80].copy_from_slice(&1_000_000u64.to_le_bytes());
+        data[80..84].copy_from_slice(&2048u32.to_le_bytes());
+        data[84..88].copy_from_slice(&64u32.to_le_bytes());
+        data[88..92].copy_from_slice(&4096u32.to_le_bytes());
+        data[92..96].copy_from_slice(&124_488u32.to_le_bytes());
+        data[96..100].copy_from_slice(&4u32.to_le_bytes());
+        data[108] = 9;
+        data[109] = 3;
+        data[110] = 1;
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        data
+    }
+
+    #[test]
+    fn test_boot_sector_fields() {
+        let mut boot = ExfatBootSector::from_bytes(&sample_boot_sector()).unwrap();
+        assert_eq!(boot.volume_length(), 1_000_000);
+        assert_eq!(boot.fat_offset(), 2048);
+        assert_eq!(boot.fat_length(), 64);
+        assert_eq!(boot.cluster_heap_offset(), 4096);
+        assert_eq!(boot.cluster_count(), 124_488);
+        assert_eq!(boot.root_cluster(), 4);
+        assert_eq!(boot.bytes_per_sector(), 512);
+        assert_eq!(boot.sectors_per_cluster(), 8);
+        assert_eq!(boot.bytes_per_cluster(), 4096);
+        assert_eq!(boot.cluster_sector(2), 4096);
+        assert_eq!(boot.cluster_sector(3), 4104);
+        assert_eq!(boot.bitmap_bytes(), 15_561);
+
+        boot.set_volume_length(2_000_000);
+        boot.set_fat_length(128);
+        boot.set_cluster_heap_offset(8192);
+        boot.set_cluster_count(249_000);
+        assert_eq!(boot.volume_length(), 2_000_000);
+        assert_eq!(boot.fat_length(), 128);
+        assert_eq!(boot.cluster_heap_offset(), 8192);
+        assert_eq!(boot.cluster_count(), 249_000);
+
+        boot.invalidate_signature();
+        assert!(!boot.is_signature_valid());
+        boot.restore_signature();
+        assert!(boot.is_signature_valid());
+
+        let mut fat32 = sample_boot_sector();
+        fat32[3..11].copy_from_slice(b"MSDOS5.0");
+        assert!(matches!(
+            ExfatBootSector::from_bytes(&fat32),
+            Err(Error::InvalidExfat(_))
+        ));
+    }
+
+    #[test]
+    fn test_boot_checksum_skips_flags() {
+        let mut region = vec![0u8; 11 * 512];
+        region[..512].copy_from_slice(&sample_boot_sector());
+        let checksum = boot_checksum(&region);
+
+        // Volume flags and percent in use do not count
+        region[106] = 0x02;
+        region[112] = 42;
+        assert_eq!(boot_checksum(&region), checksum);
+
+        region[72] ^= 1;
+        assert_ne!(boot_checksum(&region), checksum);
+
+        // Rotate right, then add: a lone 1 in the first byte ends up rotated
+        let mut single = vec![0u8; 3];
+        single[0] = 1;
+        assert_eq!(boot_checksum(&single), 0x4000_0000);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::{Error, Result};
+use crate::exfat::structs::{ExfatBootSector, MAX_CLUSTERS};
+
+/// Validate an exFAT boot sector
+pub fn validate_boot_sector(boot: &ExfatBootSector) -> Result<()> {
+    validate_boot_sector_impl(boot, false)
+}
+
+/// Validate a boot sector, allowing the invalidated signature of an
+/// interrupted resize (for recovery)
+pub fn validate_boot_sector_for_recovery(boot: &ExfatBootSector) -> Result<()> {
+    validate_boot_sector_impl(boot, true)
+}
+
+fn validate_boot_sector_impl(boot: &ExfatBootSector, allow_invalidated: bool) -> Result<()> {
+    // Same error as for FAT, so callers can recognize an interrupted resize
+    let sig = boot.boot_signature();
+    if sig == 0x0000 && !allow_invalidated {
+        return Err(Error::InvalidatedBootSector);
+    }
+    if sig != 0xAA55 && sig != 0x0000 {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid boot signature: {:#06X} (expected 0xAA55)",
+            sig
+        )));
+    }
+
+    // 512 to 4096 bytes per sector, clusters of at most 32 MiB
+    let bps_shift = boot.bytes_per_sector_shift();
+    if !(9..=12).contains(&bps_shift) {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid bytes per sector shift: {} (must be 9 to 12)",
+            bps_shift
+        )));
+    }
+    let spc_shift = boot.sectors_per_cluster_shift();
+    if spc_shift > 25 - bps_shift {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid sectors per cluster shift: {} (clusters larger than 32 MiB)",
+            spc_shift
+        )));
+    }
+
+    if boot.number_of_fats() == 0 || boot.number_of_fats() > 2 {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid number of FATs: {} (must be 1 or 2)",
+            boot.number_of_fats()
+        )));
+    }
+
+    // Boot regions, then the FATs, then the cluster heap
+    let fat_length = boot.fat_length() as u64;
+    let clusters = boot.cluster_count() as u64;
+    if boot.fat_offset() < 24 {
+        return Err(Error::BootSectorValidation(format!(
+            "FAT offset {} overlaps the boot regions",
+            boot.fat_offset()
+        )));
+    }
+    if fat_length * boot.bytes_per_sector() as u64 / 4 < clusters + 2 {
+        return Err(Error::BootSectorValidation(format!(
+            "FAT of {} sectors is too small for {} clusters",
+            fat_length, clusters
+        )));
+    }
+    if (boot.cluster_heap_offset() as u64)
+        < boot.fat_offset() as u64 + boot.number_of_fats() as u64 * fat_length
+    {
+        return Err(Error::BootSectorValidation(
+            "Cluster heap overlaps the FAT".to_string(),
+        ));
+    }
+    if clusters == 0 || clusters > MAX_CLUSTERS as u64 {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid cluster count: {}",
+            clusters
+        )));
+    }
+    if boot.cluster_heap_offset() as u64 + clusters * boot.sectors_per_cluster() as u64
+        > boot.volume_length()
+    {
+        return Err(Error::BootSectorValidation(
+            "Cluster heap extends past the end of the volume".to_string(),
+        ));
+    }
+    if !(2..clusters as u32 + 2).contains(&boot.root_cluster()) {
+        return Err(Error::BootSectorValidation(format!(
+            "Root directory cluster {} is outside the cluster heap",
+            boot.root_cluster()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Check that a boot region's checksum sector matches its other sectors
+///
+/// `region` holds all 12 sectors of a boot region.
+pub fn validate_boot_checksum(region: &[u8], bytes_per_sector: usize) -> Result<()> {
+    let checksum_start = 11 * bytes_per_sector;
+    let expected = crate::exfat::structs::boot_checksum(&region[..checksum_start]);
+    let stored = &region[checksum_start..checksum_start + bytes_per_sector];
+    let matches = stored
+        .chunks_exact(4)
+        .all(|word| u32::from_le_bytes(word.try_into().unwrap()) == expected);
+    if !matches {
+        return Err(Error::InvalidExfat(format!(
+            "boot region checksum mismatch (expected {:#010X})",
+            expected
+        )));
+    }
+    Ok(())
+}
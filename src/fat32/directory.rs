@@ -0,0 +1,202 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::operations::read_cluster;
+use crate::fat32::structs::{fat_entry, BootSector};
+use std::collections::HashSet;
+
+/// Size of a directory entry in bytes
+pub const ENTRY_SIZE: usize = 32;
+
+/// Directory entry attribute bits
+pub mod attr {
+    /// Volume label entry
+    pub const VOLUME_ID: u8 = 0x08;
+    /// Subdirectory entry
+    pub const DIRECTORY: u8 = 0x10;
+    /// Long filename entry (all of read-only, hidden, system and volume set)
+    pub const LONG_NAME: u8 = 0x0F;
+}
+
+/// First name byte of a deleted entry
+const DELETED: u8 = 0xE5;
+
+/// Kind of a 32-byte directory entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Marks the end of the directory; later entries are unused
+    End,
+    /// Deleted entry
+    Deleted,
+    /// Part of a long filename
+    LongName,
+    /// Volume label
+    VolumeLabel,
+    /// The `.` or `..` entry of a subdirectory
+    DotEntry,
+    /// Subdirectory
+    Directory,
+    /// Regular file
+    File,
+}
+
+/// Classify a raw directory entry
+pub fn entry_kind(entry: &[u8]) -> EntryKind {
+    let attributes = entry[11];
+    match entry[0] {
+        0x00 => EntryKind::End,
+        DELETED => EntryKind::Deleted,
+        _ if attributes & 0x3F == attr::LONG_NAME => EntryKind::LongName,
+        _ if attributes & attr::VOLUME_ID != 0 => EntryKind::VolumeLabel,
+        b'.' => EntryKind::DotEntry,
+        _ if attributes & attr::DIRECTORY != 0 => EntryKind::Directory,
+        _ => EntryKind::File,
+    }
+}
+
+/// First cluster stored in a directory entry (0 for empty files and the root)
+pub fn entry_first_cluster(entry: &[u8]) -> u32 {
+    let hi = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let lo = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+    (hi << 16) | lo
+}
+
+/// Store a new first cluster in a directory entry
+pub fn set_entry_first_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// Follow a cluster chain in the FAT
+///
+/// Fails with `CorruptedFAT` if the chain leaves the FAT, hits a free or bad
+/// cluster, or loops.
+pub fn cluster_chain(fat: &[u32], first: u32) -> Result<Vec<u32>> {
+    let mut chain = Vec::new();
+    let mut seen = HashSet::new();
+    let mut cluster = first;
+
+    loop {
+        let Some(&entry) = fat.get(cluster as usize) else {
+            return Err(Error::CorruptedFAT(cluster));
+        };
+        if cluster < 2 || !seen.insert(cluster) {
+            return Err(Error::CorruptedFAT(cluster));
+        }
+        if fat_entry::is_free(entry) || fat_entry::is_bad(entry) {
+            return Err(Error::CorruptedFAT(cluster));
+        }
+        chain.push(cluster);
+
+        match fat_entry::next_cluster(entry) {
+            Some(next) => cluster = next,
+            None => return Ok(chain),
+        }
+    }
+}
+
+/// Find the cluster chains of all directories, starting with the root
+///
+/// Walks the directory tree breadth-first. Directories reachable through more
+/// than one entry are only listed once.
+pub fn directory_chains(device: &Device, boot: &BootSector, fat: &[u32]) -> Result<Vec<Vec<u32>>> {
+    let mut chains = Vec::new();
+    let mut visited = HashSet::new();
+    let mut pending = vec![boot.root_cluster()];
+    visited.insert(boot.root_cluster());
+
+    while let Some(first) = pending.pop() {
+        let chain = cluster_chain(fat, first)?;
+
+        'clusters: for &cluster in &chain {
+            let data = read_cluster(device, boot, cluster)?;
+            for entry in data.chunks_exact(ENTRY_SIZE) {
+                match entry_kind(entry) {
+                    EntryKind::End => break 'clusters,
+                    EntryKind::Directory => {
+                        let child = entry_first_cluster(entry);
+                        if child >= 2 && visited.insert(child) {
+                            pending.push(child);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        chains.push(chain);
+    }
+
+    Ok(chains)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &[u8; 11], attributes: u8, cluster: u32) -> [u8; 32] {
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(name);
+        entry[11] = attributes;
+        set_entry_first_cluster(&mut entry, cluster);
+        entry
+    }
+
+    #[test]
+    fn test_entry_kind() {
+        assert_eq!(entry_kind(&[0u8; 32]), EntryKind::End);
+        assert_eq!(entry_kind(&entry(b"FILE    TXT", 0x20, 5)), EntryKind::File);
+        assert_eq!(
+            entry_kind(&entry(b"SUBDIR     ", attr::DIRECTORY, 6)),
+            EntryKind::Directory
+        );
+        assert_eq!(
+            entry_kind(&entry(b"..         ", attr::DIRECTORY, 0)),
+            EntryKind::DotEntry
+        );
+        assert_eq!(
+            entry_kind(&entry(b"LABEL      ", attr::VOLUME_ID, 0)),
+            EntryKind::VolumeLabel
+        );
+        assert_eq!(
+            entry_kind(&entry(b"Ab\0c\0d\0e\0f\0", attr::LONG_NAME, 0)),
+            EntryKind::LongName
+        );
+
+        let mut deleted = entry(b"FILE    TXT", 0x20, 5);
+        deleted[0] = DELETED;
+        assert_eq!(entry_kind(&deleted), EntryKind::Deleted);
+    }
+
+    #[test]
+    fn test_entry_first_cluster_roundtrip() {
+        let mut entry = entry(b"FILE    TXT", 0x20, 0);
+        set_entry_first_cluster(&mut entry, 0x0123_4567);
+        assert_eq!(entry_first_cluster(&entry), 0x0123_4567);
+        assert_eq!(&entry[20..22], &[0x23, 0x01]);
+        assert_eq!(&entry[26..28], &[0x67, 0x45]);
+    }
+
+    #[test]
+    fn test_cluster_chain() {
+        let fat = vec![
+            0x0FFFFFF8, 0x0FFFFFFF, 0x00000004, // 2 -> 4
+            0x00000000, 0x00000005, // 4 -> 5
+            0x0FFFFFFF, // 5: end of chain
+            0x00000006, // 6 -> 6: loop
+        ];
+
+        assert_eq!(cluster_chain(&fat, 2).unwrap(), vec![2, 4, 5]);
+        assert!(matches!(
+            cluster_chain(&fat, 3),
+            Err(Error::CorruptedFAT(3))
+        ));
+        assert!(matches!(
+            cluster_chain(&fat, 6),
+            Err(Error::CorruptedFAT(6))
+        ));
+        assert!(matches!(
+            cluster_chain(&fat, 9),
+            Err(Error::CorruptedFAT(9))
+        ));
+    }
+}
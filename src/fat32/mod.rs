@@ -1,18 +1,28 @@
+pub mod directory;
 pub mod operations;
+pub mod reader;
 pub mod structs;
 pub mod validation;
 
 // Re-export types from structs
-pub use structs::{fat_entry, BootSector, ClusterId, FSInfo, SectorNum};
+pub use structs::{fat_entry, BootSector, ClusterId, FSInfo, FatType, SectorNum};
+
+// Re-export directory helpers
+pub use directory::{
+    cluster_chain, directory_chains, entry_first_cluster, entry_kind, set_entry_first_cluster,
+    EntryKind, ENTRY_SIZE,
+};
 
 // Re-export operations
 pub use operations::{
-    count_free_clusters, find_free_cluster, read_backup_boot_sector, read_boot_sector,
-    read_boot_sector_for_recovery, read_cluster, read_fat_entry, read_fat_table, read_fsinfo,
-    write_backup_boot_sector, write_boot_sector, write_cluster, write_fat_entries, write_fat_entry,
-    write_fat_entry_with_size, write_fsinfo,
+    count_free_clusters, decode_fat_table, find_free_cluster, read_backup_boot_sector,
+    read_boot_sector, read_boot_sector_for_recovery, read_cluster, read_fat_entry, read_fat_table,
+    read_fsinfo, write_backup_boot_sector, write_boot_sector, write_cluster, write_fat_entries,
+    write_fat_entry, write_fat_entry_with_size, write_fsinfo,
 };
 
+pub use reader::FatReader;
+
 // Re-export validation
 pub use validation::{
     boot_sectors_match, validate_boot_sector, validate_boot_sector_for_recovery, validate_fsinfo,
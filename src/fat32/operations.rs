@@ -1,6 +1,6 @@
 use crate::device::Device;
 use crate::error::{Error, Result};
-use crate::fat32::structs::{fat_entry, BootSector, FSInfo};
+use crate::fat32::structs::{fat_entry, BootSector, FSInfo, FatType};
 use crate::fat32::validation::{
     validate_boot_sector, validate_boot_sector_for_recovery, validate_fsinfo,
 };
@@ -11,6 +11,9 @@ const MAX_SECTOR_SIZE: usize = 4096;
 /// Valid FAT32 sector sizes
 const VALID_SECTOR_SIZES: &[u16] = &[512, 1024, 2048, 4096];
 
+/// Largest transfer when reading, writing or copying a FAT region
+pub(crate) const FAT_IO_CHUNK_BYTES: usize = 1024 * 1024;
+
 /// Read and parse the boot sector from a device, bootstrapping the sector size
 ///
 /// This reads enough bytes to cover the maximum sector size (4096), then
@@ -20,6 +23,11 @@ pub fn read_boot_sector(device: &mut Device) -> Result<BootSector> {
     // Read max sector size bytes to ensure we have the complete boot sector
     let data = device.read_bytes_at(0, MAX_SECTOR_SIZE)?;
 
+    // exFAT shares the jump instruction and signature, but not the layout
+    if crate::exfat::ExfatBootSector::is_exfat(&data) {
+        return Err(Error::UnsupportedFatType(FatType::ExFat));
+    }
+
     // Parse the boot sector (validates minimum 512 bytes)
     let boot = BootSector::from_bytes(&data)?;
 
@@ -46,6 +54,11 @@ pub fn read_boot_sector_for_recovery(device: &mut Device) -> Result<BootSector>
     // Read max sector size bytes to ensure we have the complete boot sector
     let data = device.read_bytes_at(0, MAX_SECTOR_SIZE)?;
 
+    // exFAT shares the jump instruction and signature, but not the layout
+    if crate::exfat::ExfatBootSector::is_exfat(&data) {
+        return Err(Error::UnsupportedFatType(FatType::ExFat));
+    }
+
     // Parse the boot sector (validates minimum 512 bytes)
     let boot = BootSector::from_bytes(&data)?;
 
@@ -94,6 +107,9 @@ pub fn write_fsinfo(device: &Device, fsinfo: &FSInfo, sector: u16) -> Result<()>
 }
 
 /// Read the entire FAT table (one copy)
+///
+/// FAT12/16 entries are widened to FAT32 values (see `normalize_entry`), so
+/// callers can treat every FAT the same way.
 pub fn read_fat_table(device: &Device, boot: &BootSector, fat_number: u8) -> Result<Vec<u32>> {
     let fat_start = boot.first_fat_sector() + (fat_number as u64 * boot.fat_size() as u64);
     let fat_sectors = boot.fat_size();
@@ -103,30 +119,147 @@ pub fn read_fat_table(device: &Device, boot: &BootSector, fat_number: u8) -> Res
     let mut fat_data = Vec::with_capacity(total_bytes);
 
     // Read FAT in chunks
-    const CHUNK_SECTORS: u32 = 256; // Read 128KB at a time
+    let chunk_sectors = (FAT_IO_CHUNK_BYTES / bytes_per_sector) as u32;
     let mut sector = 0u32;
     while sector < fat_sectors {
-        let count = std::cmp::min(CHUNK_SECTORS, fat_sectors - sector);
+        let count = std::cmp::min(chunk_sectors, fat_sectors - sector);
         let data = device.read_sectors(fat_start + sector as u64, count)?;
         fat_data.extend_from_slice(&data);
         sector += count;
     }
 
+    Ok(decode_fat_table(boot, &fat_data))
+}
+
+/// Decode the raw bytes of one FAT copy into entries
+///
+/// FAT12/16 entries are widened to FAT32 values as in [`read_fat_table`].
+pub fn decode_fat_table(boot: &BootSector, fat_data: &[u8]) -> Vec<u32> {
+    decode_fat_entries(boot.fat_type(), fat_data)
+}
+
+/// Decode FAT entries of `fat_type` from raw bytes that start on an entry
+/// (for FAT12, an even one)
+pub(crate) fn decode_fat_entries(fat_type: FatType, fat_data: &[u8]) -> Vec<u32> {
+    if fat_type != FatType::Fat32 {
+        let entry_count = fat_data.len() * 8 / fat_type.entry_bits() as usize;
+        return (0..entry_count as u32)
+            .map(|cluster| normalize_entry(fat_type, decode_entry(fat_type, fat_data, cluster)))
+            .collect();
+    }
+
     // Convert bytes to u32 entries (little-endian)
-    let entry_count = total_bytes / 4;
-    let mut entries = Vec::with_capacity(entry_count);
-    for i in 0..entry_count {
-        let offset = i * 4;
-        let entry = u32::from_le_bytes([
-            fat_data[offset],
-            fat_data[offset + 1],
-            fat_data[offset + 2],
-            fat_data[offset + 3],
-        ]);
-        entries.push(entry);
+    fat_data
+        .chunks_exact(4)
+        .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]))
+        .collect()
+}
+
+// ===== FAT12/16 Entry Encoding =====
+//
+// In memory every FAT is handled as FAT32 values, so the chain helpers in
+// `fat_entry` work unchanged. FAT12/16 entries are widened on read and
+// narrowed again on write; end-of-chain and bad-cluster markers map onto
+// their FAT32 counterparts.
+
+/// Byte offset of a FAT entry within one FAT copy
+fn entry_byte_offset(fat_type: FatType, cluster: u32) -> usize {
+    match fat_type {
+        FatType::Fat12 => cluster as usize * 3 / 2,
+        FatType::Fat16 => cluster as usize * 2,
+        FatType::Fat32 | FatType::ExFat => cluster as usize * 4,
+    }
+}
+
+/// Number of bytes an entry touches (FAT12 entries share a byte)
+fn entry_byte_len(fat_type: FatType) -> usize {
+    match fat_type {
+        FatType::Fat12 | FatType::Fat16 => 2,
+        FatType::Fat32 | FatType::ExFat => 4,
+    }
+}
+
+/// Bad-cluster marker and first end-of-chain value for a narrow FAT
+fn narrow_markers(fat_type: FatType) -> (u32, u32) {
+    match fat_type {
+        FatType::Fat12 => (0xFF7, 0xFF8),
+        FatType::Fat16 => (0xFFF7, 0xFFF8),
+        FatType::Fat32 | FatType::ExFat => (fat_entry::BAD_CLUSTER, fat_entry::END_OF_CHAIN_MIN),
+    }
+}
+
+/// Widen a raw FAT12/16 entry to its FAT32 equivalent
+fn normalize_entry(fat_type: FatType, raw: u32) -> u32 {
+    let (bad, eoc_min) = narrow_markers(fat_type);
+    if raw >= eoc_min {
+        fat_entry::END_OF_CHAIN_MIN + (raw - eoc_min)
+    } else if raw == bad {
+        fat_entry::BAD_CLUSTER
+    } else {
+        raw
+    }
+}
+
+/// Narrow a FAT32 value to the raw FAT12/16 encoding
+fn denormalize_entry(fat_type: FatType, value: u32) -> u32 {
+    let (bad, eoc_min) = narrow_markers(fat_type);
+    let value = value & fat_entry::CLUSTER_MASK;
+    if value >= fat_entry::END_OF_CHAIN_MIN {
+        eoc_min + (value - fat_entry::END_OF_CHAIN_MIN)
+    } else if value == fat_entry::BAD_CLUSTER {
+        bad
+    } else {
+        value
+    }
+}
+
+/// Decode a raw FAT12/16 entry from a buffer starting at the given offset
+fn decode_narrow(fat_type: FatType, data: &[u8], offset: usize, cluster: u32) -> u32 {
+    let word = u16::from_le_bytes([data[offset], data[offset + 1]]) as u32;
+    match fat_type {
+        FatType::Fat12 if cluster & 1 == 1 => word >> 4,
+        FatType::Fat12 => word & 0x0FFF,
+        _ => word,
     }
+}
+
+/// Encode a raw FAT12/16 entry into a buffer starting at the given offset
+fn encode_narrow(fat_type: FatType, data: &mut [u8], offset: usize, cluster: u32, raw: u32) {
+    let word = u16::from_le_bytes([data[offset], data[offset + 1]]);
+    let word = match fat_type {
+        FatType::Fat12 if cluster & 1 == 1 => (word & 0x000F) | ((raw as u16 & 0x0FFF) << 4),
+        FatType::Fat12 => (word & 0xF000) | (raw as u16 & 0x0FFF),
+        _ => raw as u16,
+    };
+    data[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+}
 
-    Ok(entries)
+/// Decode a raw FAT12/16 entry from a whole FAT copy
+fn decode_entry(fat_type: FatType, fat_data: &[u8], cluster: u32) -> u32 {
+    decode_narrow(
+        fat_type,
+        fat_data,
+        entry_byte_offset(fat_type, cluster),
+        cluster,
+    )
+}
+
+/// Read the sectors holding one FAT12/16 entry, returning the data and the
+/// entry's offset within it
+fn read_narrow_sectors(
+    device: &Device,
+    boot: &BootSector,
+    fat_start: u64,
+    cluster: u32,
+) -> Result<(u64, Vec<u8>, usize)> {
+    let fat_type = boot.fat_type();
+    let bytes_per_sector = boot.bytes_per_sector() as usize;
+    let offset = entry_byte_offset(fat_type, cluster);
+    let sector = fat_start + (offset / bytes_per_sector) as u64;
+    let within = offset % bytes_per_sector;
+    // A FAT12 entry may straddle two sectors
+    let count = (within + entry_byte_len(fat_type)).div_ceil(bytes_per_sector) as u32;
+    Ok((sector, device.read_sectors(sector, count)?, within))
 }
 
 /// Write FAT entries starting at a specific index (for one FAT copy)
@@ -137,37 +270,49 @@ pub fn write_fat_entries(
     start_entry: u32,
     entries: &[u32],
 ) -> Result<()> {
+    let fat_start = boot.first_fat_sector() + (fat_number as u64 * boot.fat_size() as u64);
+    let fat_type = boot.fat_type();
+    if fat_type != FatType::Fat32 {
+        for (i, &value) in entries.iter().enumerate() {
+            let cluster = start_entry + i as u32;
+            let (sector, mut data, within) = read_narrow_sectors(device, boot, fat_start, cluster)?;
+            encode_narrow(
+                fat_type,
+                &mut data,
+                within,
+                cluster,
+                denormalize_entry(fat_type, value),
+            );
+            device.write_sectors(sector, &data)?;
+        }
+        return Ok(());
+    }
+
     let bytes_per_sector = boot.bytes_per_sector() as usize;
     let entries_per_sector = bytes_per_sector / 4;
 
-    let fat_start = boot.first_fat_sector() + (fat_number as u64 * boot.fat_size() as u64);
-
     // Calculate which sectors we need to write
     let start_sector = start_entry as usize / entries_per_sector;
     let end_entry = start_entry as usize + entries.len();
     let end_sector = end_entry.div_ceil(entries_per_sector);
 
-    // Process sector by sector
-    for sector_idx in start_sector..end_sector {
-        let sector = fat_start + sector_idx as u64;
-
-        // Read existing sector
-        let mut sector_data = device.read_sector(sector)?;
-
-        // Calculate which entries in this sector to update
-        let sector_start_entry = sector_idx * entries_per_sector;
-        let sector_end_entry = sector_start_entry + entries_per_sector;
-
-        for entry_idx in sector_start_entry..sector_end_entry {
-            if entry_idx >= start_entry as usize && entry_idx < end_entry {
-                let entry_value = entries[entry_idx - start_entry as usize];
-                let offset = (entry_idx - sector_start_entry) * 4;
-                sector_data[offset..offset + 4].copy_from_slice(&entry_value.to_le_bytes());
-            }
+    // Read, update and write back the covered sectors in large chunks
+    let chunk_sectors = FAT_IO_CHUNK_BYTES / bytes_per_sector;
+    for chunk_start in (start_sector..end_sector).step_by(chunk_sectors) {
+        let count = chunk_sectors.min(end_sector - chunk_start);
+        let mut data = device.read_sectors(fat_start + chunk_start as u64, count as u32)?;
+
+        // Entries of this chunk to update
+        let chunk_start_entry = chunk_start * entries_per_sector;
+        let first = (start_entry as usize).max(chunk_start_entry);
+        let last = end_entry.min(chunk_start_entry + count * entries_per_sector);
+        for entry_idx in first..last {
+            let entry_value = entries[entry_idx - start_entry as usize];
+            let offset = (entry_idx - chunk_start_entry) * 4;
+            data[offset..offset + 4].copy_from_slice(&entry_value.to_le_bytes());
         }
 
-        // Write back
-        device.write_sector(sector, &sector_data)?;
+        device.write_sectors(fat_start + chunk_start as u64, &data)?;
     }
 
     Ok(())
@@ -175,6 +320,16 @@ pub fn write_fat_entries(
 
 /// Read a single FAT entry
 pub fn read_fat_entry(device: &Device, boot: &BootSector, cluster: u32) -> Result<u32> {
+    let fat_type = boot.fat_type();
+    if fat_type != FatType::Fat32 {
+        let (_, data, within) =
+            read_narrow_sectors(device, boot, boot.first_fat_sector(), cluster)?;
+        return Ok(normalize_entry(
+            fat_type,
+            decode_narrow(fat_type, &data, within, cluster),
+        ));
+    }
+
     let bytes_per_sector = boot.bytes_per_sector() as usize;
     let entries_per_sector = bytes_per_sector / 4;
 
@@ -209,6 +364,18 @@ pub fn write_fat_entry_with_size(
     value: u32,
     fat_size: u32,
 ) -> Result<()> {
+    let fat_type = boot.fat_type();
+    if fat_type != FatType::Fat32 {
+        let raw = denormalize_entry(fat_type, value);
+        for fat_num in 0..boot.num_fats() {
+            let fat_start = boot.first_fat_sector() + (fat_num as u64 * fat_size as u64);
+            let (sector, mut data, within) = read_narrow_sectors(device, boot, fat_start, cluster)?;
+            encode_narrow(fat_type, &mut data, within, cluster, raw);
+            device.write_sectors(sector, &data)?;
+        }
+        return Ok(());
+    }
+
     let bytes_per_sector = boot.bytes_per_sector() as usize;
     let entries_per_sector = bytes_per_sector / 4;
 
@@ -317,6 +484,35 @@ mod tests {
         assert_eq!(count_free_clusters(&fat, 5), 2); // Entries 4 and 5 are free
     }
 
+    #[test]
+    fn test_narrow_entry_roundtrip() {
+        // Clusters 2 and 3 of a FAT12 share the middle byte
+        let mut data = vec![0u8; 8];
+        encode_narrow(FatType::Fat12, &mut data, 3, 2, 0xABC);
+        encode_narrow(FatType::Fat12, &mut data, 4, 3, 0x123);
+        assert_eq!(&data[3..6], &[0xBC, 0x3A, 0x12]);
+        assert_eq!(decode_entry(FatType::Fat12, &data, 2), 0xABC);
+        assert_eq!(decode_entry(FatType::Fat12, &data, 3), 0x123);
+
+        assert_eq!(
+            normalize_entry(FatType::Fat12, 0xFFF),
+            fat_entry::END_OF_CHAIN
+        );
+        assert_eq!(
+            normalize_entry(FatType::Fat16, 0xFFF7),
+            fat_entry::BAD_CLUSTER
+        );
+        assert_eq!(normalize_entry(FatType::Fat16, 0x1234), 0x1234);
+        assert_eq!(
+            denormalize_entry(FatType::Fat16, fat_entry::END_OF_CHAIN),
+            0xFFFF
+        );
+        assert_eq!(
+            denormalize_entry(FatType::Fat12, fat_entry::BAD_CLUSTER),
+            0xFF7
+        );
+    }
+
     #[test]
     fn test_find_free_cluster() {
         let fat = [
@@ -0,0 +1,231 @@
+//! Reading a FAT a window at a time
+//!
+//! [`read_fat_table`](super::read_fat_table) holds a whole FAT copy in
+//! memory, four bytes per cluster: 2 GiB for a 2 TiB volume with 4 KiB
+//! clusters. A [`FatReader`] keeps only the windows of entries it was last
+//! asked about, so counting and planning run in a bounded amount of memory.
+
+use crate::device::Device;
+use crate::error::Result;
+use crate::fat32::operations::{decode_fat_entries, FAT_IO_CHUNK_BYTES};
+use crate::fat32::structs::{fat_entry, BootSector, FatType};
+use std::ops::Range;
+
+/// Windows hold a multiple of this many entries, so FAT12 windows start on
+/// a whole byte
+const WINDOW_ALIGN_ENTRIES: u64 = 1024;
+
+/// Windows kept at once
+const MAX_WINDOWS: usize = 2;
+
+/// Decoded entries of one part of the FAT
+struct Window {
+    first: u32,
+    entries: Vec<u32>,
+}
+
+/// Reads one FAT copy a window of entries at a time
+///
+/// Entries are widened to FAT32 values as by `read_fat_table`. The two
+/// windows used last are kept, so two cursors moving through the FAT
+/// together (a cluster, and the one a shift copies onto its old position)
+/// read each window once. Without a memory limit, the whole FAT is a single
+/// window.
+pub struct FatReader<'a> {
+    device: &'a Device,
+    fat_type: FatType,
+    /// Byte offset of the FAT copy on the device
+    fat_offset: u64,
+    entry_count: u32,
+    window_entries: u32,
+    /// Most recently used first
+    windows: Vec<Window>,
+}
+
+impl<'a> FatReader<'a> {
+    /// Read FAT copy `fat_number`, keeping at most about `memory_limit`
+    /// bytes of decoded entries (`None` for no limit)
+    pub fn new(
+        device: &'a Device,
+        boot: &BootSector,
+        fat_number: u8,
+        memory_limit: Option<u64>,
+    ) -> Self {
+        let fat_type = boot.fat_type();
+        let bytes_per_sector = boot.bytes_per_sector() as u64;
+        let fat_sector = boot.first_fat_sector() + fat_number as u64 * boot.fat_size() as u64;
+        let fat_bytes = boot.fat_size() as u64 * bytes_per_sector;
+        let entry_count = (fat_bytes * 8 / fat_type.entry_bits() as u64).min(u32::MAX as u64);
+
+        let window_entries = match memory_limit {
+            Some(limit) => {
+                let entries = limit / (MAX_WINDOWS as u64 * 4);
+                (entries / WINDOW_ALIGN_ENTRIES * WINDOW_ALIGN_ENTRIES).max(WINDOW_ALIGN_ENTRIES)
+            }
+            None => entry_count,
+        };
+
+        Self {
+            device,
+            fat_type,
+            fat_offset: fat_sector * bytes_per_sector,
+            entry_count: entry_count as u32,
+            window_entries: window_entries.min(entry_count).max(1) as u32,
+            windows: Vec::new(),
+        }
+    }
+
+    /// Number of entries in the FAT copy
+    pub fn entry_count(&self) -> u32 {
+        self.entry_count
+    }
+
+    /// Number of entries read at a time
+    pub fn window_entries(&self) -> u32 {
+        self.window_entries
+    }
+
+    /// Check whether the FAT is larger than one window
+    pub fn is_windowed(&self) -> bool {
+        self.window_entries < self.entry_count
+    }
+
+    /// The whole FAT, if it fits into one window
+    pub fn table(&mut self) -> Result<Option<&[u32]>> {
+        if self.is_windowed() {
+            return Ok(None);
+        }
+        Ok(Some(self.window(0)?))
+    }
+
+    /// Read the entry of a cluster; clusters past the end of the FAT read as
+    /// free
+    pub fn entry(&mut self, cluster: u32) -> Result<u32> {
+        if cluster >= self.entry_count {
+            return Ok(0);
+        }
+        let first = cluster - cluster % self.window_entries;
+        Ok(self.window(first)?[(cluster - first) as usize])
+    }
+
+    /// Count the clusters in `clusters` that are in use (allocated, bad or
+    /// end of chain)
+    pub fn count_used(&mut self, clusters: Range<u32>) -> Result<u32> {
+        let end = clusters.end.min(self.entry_count);
+        let mut cluster = clusters.start;
+        let mut used = 0;
+        while cluster < end {
+            let first = cluster - cluster % self.window_entries;
+            let stop = end.min(first.saturating_add(self.window_entries));
+            let entries = self.window(first)?;
+            used += entries[(cluster - first) as usize..(stop - first) as usize]
+                .iter()
+                .filter(|&&entry| !fat_entry::is_free(entry))
+                .count() as u32;
+            cluster = stop;
+        }
+        Ok(used)
+    }
+
+    /// Count free clusters, as `count_free_clusters` does on a whole table
+    pub fn count_free(&mut self, max_cluster: u32) -> Result<u32> {
+        let end = max_cluster.saturating_add(2).min(self.entry_count).max(2);
+        Ok(end - 2 - self.count_used(2..end)?)
+    }
+
+    /// Entries of the window starting at `first`, reading it if it is not
+    /// one of the windows kept
+    fn window(&mut self, first: u32) -> Result<&[u32]> {
+        match self.windows.iter().position(|window| window.first == first) {
+            Some(index) => self.windows[..=index].rotate_right(1),
+            None => {
+                // Drop the oldest window before reading, so the limit holds
+                self.windows.truncate(MAX_WINDOWS - 1);
+                let entries = self.read_window(first)?;
+                self.windows.insert(0, Window { first, entries });
+            }
+        }
+        Ok(&self.windows[0].entries)
+    }
+
+    /// Read and decode the window starting at `first`, in chunks of
+    /// `FAT_IO_CHUNK_BYTES`
+    fn read_window(&self, first: u32) -> Result<Vec<u32>> {
+        let end = first
+            .saturating_add(self.window_entries)
+            .min(self.entry_count);
+        let bits = self.fat_type.entry_bits() as u64;
+        // An odd FAT12 entry ends halfway through its last byte
+        let byte_at = |entry: u32| (entry as u64 * bits).div_ceil(8);
+        let chunk_entries = (FAT_IO_CHUNK_BYTES as u64 * 8 / bits) as u32 & !1;
+
+        let mut entries = Vec::with_capacity((end - first) as usize);
+        let mut start = first;
+        while start < end {
+            let stop = end.min(start.saturating_add(chunk_entries));
+            let data = self.device.read_bytes_at(
+                self.fat_offset + byte_at(start),
+                (byte_at(stop) - byte_at(start)) as usize,
+            )?;
+            entries.extend(decode_fat_entries(self.fat_type, &data));
+            start = stop;
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::{count_free_clusters, read_boot_sector, read_fat_table};
+    use crate::test_image::{pattern, ImageSpec, TestImage};
+
+    #[test]
+    fn test_windows_match_whole_table() {
+        let fat12 = ImageSpec {
+            fat_type: FatType::Fat12,
+            total_sectors: 2_880,
+            reserved_sectors: 1,
+            root_entries: 224,
+            ..Default::default()
+        };
+        for spec in [ImageSpec::default(), fat12] {
+            let mut image = TestImage::create(spec);
+            image.add_file(b"FIRST   BIN", &pattern(1, 40 * 512), 3);
+            image.skip_to(1500);
+            image.add_file(b"SECOND  BIN", &pattern(2, 700 * 512), 1);
+
+            let mut device = Device::open_readonly(image.path()).unwrap();
+            let boot = read_boot_sector(&mut device).unwrap();
+            let table = read_fat_table(&device, &boot, 0).unwrap();
+
+            let mut whole = FatReader::new(&device, &boot, 0, None);
+            assert!(!whole.is_windowed());
+            assert_eq!(whole.table().unwrap().unwrap(), &table[..]);
+
+            // The smallest windows, 1024 entries each
+            let mut reader = FatReader::new(&device, &boot, 0, Some(0));
+            assert!(reader.is_windowed());
+            assert!(reader.table().unwrap().is_none());
+            assert_eq!(reader.entry_count() as usize, table.len());
+            let descending: Vec<u32> = (0..table.len() as u32)
+                .rev()
+                .map(|cluster| reader.entry(cluster).unwrap())
+                .rev()
+                .collect();
+            assert_eq!(descending, table);
+            assert_eq!(reader.entry(table.len() as u32).unwrap(), 0);
+
+            let max_cluster = boot.data_clusters();
+            assert_eq!(
+                reader.count_free(max_cluster).unwrap(),
+                count_free_clusters(&table, max_cluster)
+            );
+            let used = table[1000..2100]
+                .iter()
+                .filter(|&&e| !fat_entry::is_free(e))
+                .count() as u32;
+            assert_eq!(reader.count_used(1000..2100).unwrap(), used);
+        }
+    }
+}
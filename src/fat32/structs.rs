@@ -118,6 +118,75 @@ impl std::fmt::Display for SectorNum {
     }
 }
 
+// ===== FAT Type =====
+
+/// FAT variant of a filesystem
+///
+/// See [`BootSector::fat_type`] for how it is decided; the "FAT12"/"FAT16"
+/// labels in the boot sector are informational only. exFAT volumes have their
+/// own boot sector (see [`crate::exfat`]) and are never classified by cluster
+/// count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+}
+
+impl FatType {
+    /// Classify a filesystem by its data cluster count
+    pub fn from_cluster_count(clusters: u32) -> Self {
+        if clusters < 4085 {
+            Self::Fat12
+        } else if clusters < 65525 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    /// Smallest cluster count that still classifies as this type
+    pub fn min_clusters(self) -> u32 {
+        match self {
+            Self::Fat12 => 1,
+            Self::Fat16 => 4085,
+            Self::Fat32 => 65525,
+            Self::ExFat => 1,
+        }
+    }
+
+    /// Largest cluster count this type can address
+    pub fn max_clusters(self) -> u32 {
+        match self {
+            Self::Fat12 => 4084,
+            Self::Fat16 => 65524,
+            Self::Fat32 => 0x0FFFFFF5,
+            Self::ExFat => crate::exfat::MAX_CLUSTERS,
+        }
+    }
+
+    /// Width of one FAT entry in bits
+    pub fn entry_bits(self) -> u32 {
+        match self {
+            Self::Fat12 => 12,
+            Self::Fat16 => 16,
+            Self::Fat32 | Self::ExFat => 32,
+        }
+    }
+}
+
+impl std::fmt::Display for FatType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fat12 => write!(f, "FAT12"),
+            Self::Fat16 => write!(f, "FAT16"),
+            Self::Fat32 => write!(f, "FAT32"),
+            Self::ExFat => write!(f, "exFAT"),
+        }
+    }
+}
+
 // ===== FAT32 Structures =====
 
 /// FAT32 Boot Sector / BIOS Parameter Block
@@ -186,6 +255,11 @@ impl BootSector {
         self.raw[13]
     }
 
+    /// Set sectors per cluster
+    pub fn set_sectors_per_cluster(&mut self, sectors: u8) {
+        self.raw[13] = sectors;
+    }
+
     /// Reserved sector count (offset 14, 2 bytes) - includes boot sector
     pub fn reserved_sectors(&self) -> u16 {
         u16::from_le_bytes([self.raw[14], self.raw[15]])
@@ -196,6 +270,11 @@ impl BootSector {
         self.raw[16]
     }
 
+    /// Set number of FAT copies
+    pub fn set_num_fats(&mut self, num_fats: u8) {
+        self.raw[16] = num_fats;
+    }
+
     /// Root directory entries for FAT12/16 (offset 17, 2 bytes) - 0 for FAT32
     pub fn root_entry_count(&self) -> u16 {
         u16::from_le_bytes([self.raw[17], self.raw[18]])
@@ -206,6 +285,11 @@ impl BootSector {
         u16::from_le_bytes([self.raw[19], self.raw[20]])
     }
 
+    /// Set total sectors 16-bit
+    pub fn set_total_sectors_16(&mut self, sectors: u16) {
+        self.raw[19..21].copy_from_slice(&sectors.to_le_bytes());
+    }
+
     /// Media type (offset 21, 1 byte) - 0xF8 for hard disks
     pub fn media_type(&self) -> u8 {
         self.raw[21]
@@ -216,6 +300,11 @@ impl BootSector {
         u16::from_le_bytes([self.raw[22], self.raw[23]])
     }
 
+    /// Set sectors per FAT for FAT12/16
+    pub fn set_fat_size_16(&mut self, size: u16) {
+        self.raw[22..24].copy_from_slice(&size.to_le_bytes());
+    }
+
     /// Sectors per track (offset 24, 2 bytes)
     pub fn sectors_per_track(&self) -> u16 {
         u16::from_le_bytes([self.raw[24], self.raw[25]])
@@ -231,6 +320,11 @@ impl BootSector {
         u32::from_le_bytes([self.raw[28], self.raw[29], self.raw[30], self.raw[31]])
     }
 
+    /// Set hidden sectors
+    pub fn set_hidden_sectors(&mut self, sectors: u32) {
+        self.raw[28..32].copy_from_slice(&sectors.to_le_bytes());
+    }
+
     /// Total sectors 32-bit (offset 32, 4 bytes)
     pub fn total_sectors_32(&self) -> u32 {
         u32::from_le_bytes([self.raw[32], self.raw[33], self.raw[34], self.raw[35]])
@@ -293,6 +387,17 @@ impl BootSector {
         &self.raw[52..64]
     }
 
+    /// Cluster holding the checkpoint of an interrupted shrink (first 4
+    /// reserved bytes), set only while the signature is invalidated; 0 if none
+    pub fn checkpoint_cluster(&self) -> u32 {
+        u32::from_le_bytes([self.raw[52], self.raw[53], self.raw[54], self.raw[55]])
+    }
+
+    /// Set the checkpoint cluster (0 clears it)
+    pub fn set_checkpoint_cluster(&mut self, cluster: u32) {
+        self.raw[52..56].copy_from_slice(&cluster.to_le_bytes());
+    }
+
     /// Drive number (offset 64, 1 byte)
     pub fn drive_number(&self) -> u8 {
         self.raw[64]
@@ -372,30 +477,72 @@ impl BootSector {
         }
     }
 
+    /// Whether the BPB uses the FAT12/16 layout (a 16-bit FAT size)
+    pub fn has_fat16_layout(&self) -> bool {
+        self.fat_size_16() != 0
+    }
+
+    /// Set total sectors, using the 16-bit field where FAT12/16 allows it
+    ///
+    /// FAT32 always stores the count in the 32-bit field. FAT12/16 use the
+    /// 16-bit field while the count fits, and switch to the 32-bit field
+    /// (zeroing the 16-bit one) once it no longer does.
+    pub fn set_total_sectors(&mut self, sectors: u32) {
+        if self.has_fat16_layout() && sectors <= u16::MAX as u32 {
+            self.set_total_sectors_16(sectors as u16);
+            self.set_total_sectors_32(0);
+        } else {
+            self.set_total_sectors_16(0);
+            self.set_total_sectors_32(sectors);
+        }
+    }
+
+    /// Set the FAT size in the field used by this BPB layout
+    pub fn set_fat_size(&mut self, size: u32) {
+        if self.has_fat16_layout() {
+            self.set_fat_size_16(size as u16);
+        } else {
+            self.set_fat_size_32(size);
+        }
+    }
+
+    /// FAT type of the filesystem
+    ///
+    /// As in the Linux driver, a BPB without a 16-bit FAT size is FAT32; any
+    /// other is FAT12 or FAT16 depending on its data cluster count.
+    pub fn fat_type(&self) -> FatType {
+        if !self.has_fat16_layout() {
+            FatType::Fat32
+        } else if self.data_clusters() < FatType::Fat16.min_clusters() {
+            FatType::Fat12
+        } else {
+            FatType::Fat16
+        }
+    }
+
+    /// Sectors taken by the fixed FAT12/16 root directory (0 for FAT32)
+    pub fn root_dir_sectors(&self) -> u64 {
+        (self.root_entry_count() as u64 * 32).div_ceil(self.bytes_per_sector() as u64)
+    }
+
     /// First sector of the FAT area
     pub fn first_fat_sector(&self) -> u64 {
         self.reserved_sectors() as u64
     }
 
+    /// First sector of the fixed FAT12/16 root directory region
+    pub fn first_root_dir_sector(&self) -> u64 {
+        self.reserved_sectors() as u64 + (self.num_fats() as u64 * self.fat_size() as u64)
+    }
+
     /// First sector of the data area
     pub fn first_data_sector(&self) -> u64 {
-        let root_dir_sectors =
-            (self.root_entry_count() as u64 * 32).div_ceil(self.bytes_per_sector() as u64);
-
-        self.reserved_sectors() as u64
-            + (self.num_fats() as u64 * self.fat_size() as u64)
-            + root_dir_sectors
+        self.first_root_dir_sector() + self.root_dir_sectors()
     }
 
     /// Total data sectors
     pub fn data_sectors(&self) -> u64 {
-        let root_dir_sectors =
-            (self.root_entry_count() as u64 * 32).div_ceil(self.bytes_per_sector() as u64);
-
-        self.total_sectors() as u64
-            - self.reserved_sectors() as u64
-            - (self.num_fats() as u64 * self.fat_size() as u64)
-            - root_dir_sectors
+        (self.total_sectors() as u64).saturating_sub(self.first_data_sector())
     }
 
     /// Total number of data clusters
@@ -412,6 +559,66 @@ impl BootSector {
     pub fn bytes_per_cluster(&self) -> u32 {
         self.bytes_per_sector() as u32 * self.sectors_per_cluster() as u32
     }
+
+    // ===== Conversion =====
+
+    /// Build the FAT32 boot sector for this FAT12/16 filesystem after conversion
+    ///
+    /// The geometry, media type, volume serial number and label carry over;
+    /// the BPB switches to the FAT32 layout with FSInfo in sector 1 and the
+    /// backup boot sector in sector 6. The old boot code does not fit behind
+    /// the larger BPB, so the volume is left non-bootable: the new code asks
+    /// the BIOS to try the next boot device. The signature is kept as is.
+    pub fn converted_to_fat32(
+        &self,
+        reserved_sectors: u16,
+        num_fats: u8,
+        total_sectors: u32,
+        fat_size: u32,
+        root_cluster: u32,
+    ) -> Self {
+        let old = &self.raw;
+        let mut boot = Self {
+            raw: vec![0u8; old.len()],
+        };
+        let raw = &mut boot.raw;
+
+        // Jump over the FAT32 BPB to the boot code at offset 90
+        raw[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        // OEM name and the BPB fields FAT12/16 and FAT32 share
+        raw[3..36].copy_from_slice(&old[3..36]);
+        raw[14..16].copy_from_slice(&reserved_sectors.to_le_bytes());
+        raw[16] = num_fats;
+        // No fixed root directory, 16-bit total or 16-bit FAT size
+        raw[17..19].fill(0);
+        raw[19..21].fill(0);
+        raw[22..24].fill(0);
+        raw[32..36].copy_from_slice(&total_sectors.to_le_bytes());
+
+        raw[36..40].copy_from_slice(&fat_size.to_le_bytes());
+        raw[44..48].copy_from_slice(&root_cluster.to_le_bytes());
+        raw[48..50].copy_from_slice(&1u16.to_le_bytes());
+        raw[50..52].copy_from_slice(&6u16.to_le_bytes());
+
+        // The FAT12/16 extended BPB at offset 36 moves to offset 64
+        raw[64] = old[36];
+        raw[66] = 0x29;
+        if matches!(old[38], 0x28 | 0x29) {
+            raw[67..71].copy_from_slice(&old[39..43]);
+        }
+        if old[38] == 0x29 {
+            raw[71..82].copy_from_slice(&old[43..54]);
+        } else {
+            raw[71..82].copy_from_slice(b"NO NAME    ");
+        }
+        raw[82..90].copy_from_slice(b"FAT32   ");
+
+        // int 18h, then halt if the BIOS returns
+        raw[90..94].copy_from_slice(&[0xCD, 0x18, 0xEB, 0xFE]);
+
+        raw[510..512].copy_from_slice(&old[510..512]);
+        boot
+    }
 }
 
 impl std::fmt::Debug for BootSector {
@@ -423,6 +630,7 @@ impl std::fmt::Debug for BootSector {
             .field("num_fats", &self.num_fats())
             .field("total_sectors", &self.total_sectors())
             .field("fat_size", &self.fat_size())
+            .field("fat_type", &self.fat_type())
             .field("root_cluster", &self.root_cluster())
             .field("fs_info_sector", &self.fs_info_sector())
             .field("backup_boot_sector", &self.backup_boot_sector())
@@ -452,6 +660,17 @@ impl FSInfo {
     /// Unknown free count value
     pub const UNKNOWN_FREE: u32 = 0xFFFFFFFF;
 
+    /// Create an FSInfo sector with valid signatures and unknown hints
+    pub fn new(sector_size: usize) -> Self {
+        let mut raw = vec![0u8; sector_size.max(512)];
+        raw[0..4].copy_from_slice(&Self::LEAD_SIG.to_le_bytes());
+        raw[484..488].copy_from_slice(&Self::STRUC_SIG.to_le_bytes());
+        raw[488..492].copy_from_slice(&Self::UNKNOWN_FREE.to_le_bytes());
+        raw[492..496].copy_from_slice(&Self::UNKNOWN_FREE.to_le_bytes());
+        raw[508..512].copy_from_slice(&Self::TRAIL_SIG.to_le_bytes());
+        Self { raw }
+    }
+
     /// Parse FSInfo from raw bytes
     /// The input must be at least 512 bytes and will be stored in full
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
@@ -696,4 +915,49 @@ mod tests {
         assert_eq!(fsinfo.next_free(), 100);
         assert_eq!(fsinfo.trail_sig(), FSInfo::TRAIL_SIG);
     }
+
+    #[test]
+    fn test_converted_to_fat32() {
+        // A FAT16 boot sector with an extended BPB
+        let mut data = [0u8; 512];
+        data[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        data[3..11].copy_from_slice(b"MSDOS5.0");
+        data[11..13].copy_from_slice(&512u16.to_le_bytes());
+        data[13] = 4;
+        data[14..16].copy_from_slice(&1u16.to_le_bytes());
+        data[16] = 2;
+        data[17..19].copy_from_slice(&512u16.to_le_bytes());
+        data[19..21].copy_from_slice(&40_000u16.to_le_bytes());
+        data[21] = 0xF8;
+        data[22..24].copy_from_slice(&40u16.to_le_bytes());
+        data[36] = 0x80;
+        data[38] = 0x29;
+        data[39..43].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+        data[43..54].copy_from_slice(b"MY VOLUME  ");
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        let fat16 = BootSector::from_bytes(&data).unwrap();
+        assert_eq!(fat16.fat_type(), FatType::Fat16);
+
+        let boot = fat16.converted_to_fat32(32, 2, 600_000, 1_200, 5);
+        assert_eq!(boot.fat_type(), FatType::Fat32);
+        assert_eq!(boot.jump_boot(), &[0xEB, 0x58, 0x90]);
+        assert_eq!(boot.oem_name(), b"MSDOS5.0");
+        assert_eq!(boot.sectors_per_cluster(), 4);
+        assert_eq!(boot.media_type(), 0xF8);
+        assert_eq!(boot.reserved_sectors(), 32);
+        assert_eq!(boot.root_entry_count(), 0);
+        assert_eq!(boot.total_sectors_16(), 0);
+        assert_eq!(boot.total_sectors(), 600_000);
+        assert_eq!(boot.fat_size(), 1_200);
+        assert_eq!(boot.root_cluster(), 5);
+        assert_eq!(boot.fs_info_sector(), 1);
+        assert_eq!(boot.backup_boot_sector(), 6);
+        assert_eq!(boot.drive_number(), 0x80);
+        assert_eq!(boot.boot_sig(), 0x29);
+        assert_eq!(boot.volume_id(), 0x1234_5678);
+        assert_eq!(boot.volume_label(), b"MY VOLUME  ");
+        assert_eq!(boot.fs_type(), b"FAT32   ");
+        assert!(boot.is_signature_valid());
+    }
 }
@@ -1,7 +1,7 @@
 use crate::error::{Error, Result};
-use crate::fat32::structs::{BootSector, FSInfo};
+use crate::fat32::structs::{BootSector, FSInfo, FatType};
 
-/// Validate a boot sector to ensure it's a valid FAT32 filesystem
+/// Validate a boot sector to ensure it's a valid FAT12, FAT16 or FAT32 filesystem
 pub fn validate_boot_sector(boot: &BootSector) -> Result<()> {
     validate_boot_sector_impl(boot, false)
 }
@@ -17,15 +17,15 @@ pub fn validate_boot_sector_for_recovery(boot: &BootSector) -> Result<()> {
 fn validate_boot_sector_impl(boot: &BootSector, allow_invalidated: bool) -> Result<()> {
     // Check boot signature (must be 0xAA55, or 0x0000 if recovery mode)
     let sig = boot.boot_signature();
-    if sig != 0xAA55 {
-        if allow_invalidated && sig == 0x0000 {
-            // Invalidated boot sector - allowed in recovery mode
-        } else {
-            return Err(Error::BootSectorValidation(format!(
-                "Invalid boot signature: {:#06X} (expected 0xAA55)",
-                sig
-            )));
+    if sig == 0x0000 {
+        if !allow_invalidated {
+            return Err(Error::InvalidatedBootSector);
         }
+    } else if sig != 0xAA55 {
+        return Err(Error::BootSectorValidation(format!(
+            "Invalid boot signature: {:#06X} (expected 0xAA55)",
+            sig
+        )));
     }
 
     // Check bytes per sector (must be 512, 1024, 2048, or 4096)
@@ -62,45 +62,66 @@ fn validate_boot_sector_impl(boot: &BootSector, allow_invalidated: bool) -> Resu
         )));
     }
 
-    // For FAT32, root entry count must be 0
-    if boot.root_entry_count() != 0 {
-        return Err(Error::InvalidFAT32(
-            "Root entry count is non-zero (not FAT32)".to_string(),
-        ));
-    }
-
-    // For FAT32, total sectors 16 should be 0
-    if boot.total_sectors_16() != 0 {
-        return Err(Error::InvalidFAT32(
-            "Total sectors 16 is non-zero (not FAT32)".to_string(),
+    // Check total sectors and FAT size (either field width)
+    if boot.total_sectors() == 0 {
+        return Err(Error::BootSectorValidation(
+            "Total sectors is 0".to_string(),
         ));
     }
-
-    // For FAT32, fat size 16 should be 0
-    if boot.fat_size_16() != 0 {
-        return Err(Error::InvalidFAT32(
-            "FAT size 16 is non-zero (not FAT32)".to_string(),
-        ));
+    if boot.fat_size() == 0 {
+        return Err(Error::BootSectorValidation("FAT size is 0".to_string()));
     }
-
-    // Check total sectors
-    if boot.total_sectors_32() == 0 {
+    if boot.first_data_sector() >= boot.total_sectors() as u64 {
         return Err(Error::BootSectorValidation(
-            "Total sectors is 0".to_string(),
+            "Filesystem has no data area".to_string(),
         ));
     }
 
-    // Check FAT size
-    if boot.fat_size_32() == 0 {
-        return Err(Error::BootSectorValidation("FAT size is 0".to_string()));
-    }
+    // A BPB without a 16-bit FAT size is FAT32; the cluster count must match
+    let cluster_count = boot.data_clusters();
+    if boot.fat_type() == FatType::Fat32 {
+        if boot.root_entry_count() != 0 {
+            return Err(Error::InvalidFAT32(
+                "Root entry count is non-zero (not FAT32)".to_string(),
+            ));
+        }
 
-    // Check root cluster (must be >= 2)
-    if boot.root_cluster() < 2 {
-        return Err(Error::BootSectorValidation(format!(
-            "Invalid root cluster: {} (must be >= 2)",
-            boot.root_cluster()
-        )));
+        if boot.total_sectors_16() != 0 {
+            return Err(Error::InvalidFAT32(
+                "Total sectors 16 is non-zero (not FAT32)".to_string(),
+            ));
+        }
+
+        // Check root cluster (must be >= 2)
+        if boot.root_cluster() < 2 {
+            return Err(Error::BootSectorValidation(format!(
+                "Invalid root cluster: {} (must be >= 2)",
+                boot.root_cluster()
+            )));
+        }
+
+        if cluster_count < FatType::Fat32.min_clusters() {
+            return Err(Error::InvalidFAT32(format!(
+                "Cluster count {} indicates FAT12/16, not FAT32 (need >= 65525)",
+                cluster_count
+            )));
+        }
+    } else {
+        // FAT12/16 keep the root directory in a fixed region
+        if boot.root_entry_count() == 0 {
+            return Err(Error::InvalidFAT32(format!(
+                "{} filesystem has a root entry count of 0",
+                boot.fat_type()
+            )));
+        }
+
+        if cluster_count > FatType::Fat16.max_clusters() {
+            return Err(Error::InvalidFAT32(format!(
+                "Cluster count {} is too large for FAT16 (at most {})",
+                cluster_count,
+                FatType::Fat16.max_clusters()
+            )));
+        }
     }
 
     // Check media type (should be 0xF0 or 0xF8-0xFF)
@@ -112,15 +133,6 @@ fn validate_boot_sector_impl(boot: &BootSector, allow_invalidated: bool) -> Resu
         )));
     }
 
-    // Verify this is actually FAT32 by cluster count
-    let cluster_count = boot.data_clusters();
-    if cluster_count < 65525 {
-        return Err(Error::InvalidFAT32(format!(
-            "Cluster count {} indicates FAT12/16, not FAT32 (need >= 65525)",
-            cluster_count
-        )));
-    }
-
     // Check FS type string (optional but recommended)
     let fs_type = boot.fs_type();
     if !fs_type.starts_with(b"FAT32") && !fs_type.starts_with(b"FAT") {
@@ -263,6 +275,13 @@ mod tests {
         let boot = BootSector::from_bytes(&data).unwrap();
         let result = validate_boot_sector(&boot);
         assert!(matches!(result, Err(Error::BootSectorValidation(_))));
+
+        // A zeroed signature is one an interrupted resize left
+        data[511] = 0x00;
+        let boot = BootSector::from_bytes(&data).unwrap();
+        let result = validate_boot_sector(&boot);
+        assert!(matches!(result, Err(Error::InvalidatedBootSector)));
+        assert!(validate_boot_sector_for_recovery(&boot).is_ok());
     }
 
     #[test]
@@ -276,6 +295,32 @@ mod tests {
         assert!(matches!(result, Err(Error::InvalidFAT32(_))));
     }
 
+    #[test]
+    fn test_valid_fat16_boot_sector() {
+        let mut data = [0u8; 512];
+        data[11..13].copy_from_slice(&512u16.to_le_bytes());
+        data[13] = 4;
+        data[14..16].copy_from_slice(&1u16.to_le_bytes());
+        data[16] = 2;
+        data[17..19].copy_from_slice(&512u16.to_le_bytes());
+        data[19..21].copy_from_slice(&40_000u16.to_le_bytes());
+        data[21] = 0xF8;
+        data[22..24].copy_from_slice(&40u16.to_le_bytes());
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        let boot = BootSector::from_bytes(&data).unwrap();
+        assert_eq!(boot.fat_type(), FatType::Fat16);
+        assert!(validate_boot_sector(&boot).is_ok());
+
+        // FAT16 needs its fixed root directory
+        data[17..19].copy_from_slice(&0u16.to_le_bytes());
+        let boot = BootSector::from_bytes(&data).unwrap();
+        assert!(matches!(
+            validate_boot_sector(&boot),
+            Err(Error::InvalidFAT32(_))
+        ));
+    }
+
     #[test]
     fn test_valid_fsinfo() {
         let mut data = [0u8; 512];
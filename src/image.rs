@@ -0,0 +1,226 @@
+//! Growing disk image files
+//!
+//! Replaces the `truncate -s` (or `qemu-img resize`) step before a resize:
+//! the image is extended (staying sparse, or thin for qcow2), a GPT's backup
+//! copy moves to the new end, and the selected partition grows into the
+//! added space, so the filesystem can then be grown to fill it.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::partition::{
+    grow_partition, read_partition_table, repair_gpt, GptRepair, PartitionGrowth,
+    PartitionTableKind, VolumeLocation,
+};
+use crate::resize::TargetSize;
+use std::path::Path;
+
+/// Sizes of image files are kept a whole number of these
+const IMAGE_SECTOR_SIZE: u64 = 512;
+
+/// Result of growing an image file
+#[derive(Debug, Clone)]
+pub struct ImageGrowth {
+    /// Image size before growing (the virtual disk size for qcow2)
+    pub old_size_bytes: u64,
+    /// Image size after growing
+    pub new_size_bytes: u64,
+    /// GPT repair that moved the backup GPT to the new end (`None` without
+    /// a GPT, and for dry runs)
+    pub gpt_repair: Option<GptRepair>,
+    /// Growth of the partition the filesystem is in (`None` unless a
+    /// partition was selected, and for dry runs)
+    pub partition_growth: Option<PartitionGrowth>,
+}
+
+impl ImageGrowth {
+    /// Check whether the image grew
+    pub fn grew(&self) -> bool {
+        self.new_size_bytes > self.old_size_bytes
+    }
+}
+
+/// Extend an image file to `target` and let its partition table follow
+///
+/// Raw files are extended with `set_len`, so the new space takes no room on
+/// disk until written; qcow2 images get a larger virtual disk. A GPT is then
+/// repaired for the new size, and with `VolumeLocation::Partition` that
+/// partition grows into the added space. Block devices and other special
+/// files are refused, as are targets smaller than the image or not a
+/// multiple of 512 bytes. Whether the partition can grow is checked before
+/// the file is touched.
+pub fn grow_image(
+    image_path: impl AsRef<Path>,
+    target: TargetSize,
+    location: VolumeLocation,
+    dry_run: bool,
+) -> Result<ImageGrowth> {
+    let image_path = image_path.as_ref();
+    let refuse = |reason: String| Error::CannotGrowImage(image_path.display().to_string(), reason);
+
+    let metadata = std::fs::metadata(image_path)?;
+    if !metadata.is_file() {
+        return Err(refuse(
+            "not a regular file; grow the device or partition instead".to_string(),
+        ));
+    }
+    let device = Device::open_readonly(image_path)?;
+    let old_size_bytes = device.size_bytes()?;
+    let new_size_bytes = match target {
+        TargetSize::Absolute(bytes) => bytes,
+        TargetSize::Relative(bytes) => old_size_bytes.saturating_add(bytes),
+    };
+    if new_size_bytes < old_size_bytes {
+        return Err(refuse(format!(
+            "{} bytes is smaller than the image ({} bytes)",
+            new_size_bytes, old_size_bytes
+        )));
+    }
+    if !new_size_bytes.is_multiple_of(IMAGE_SECTOR_SIZE) {
+        return Err(refuse(format!(
+            "{} bytes is not a multiple of {} bytes",
+            new_size_bytes, IMAGE_SECTOR_SIZE
+        )));
+    }
+
+    // Fail on a missing or blocked partition before the image changes
+    let table = read_partition_table(&device)?;
+    if let VolumeLocation::Partition(number) = location {
+        grow_partition(image_path, number, true)?;
+    }
+
+    let mut growth = ImageGrowth {
+        old_size_bytes,
+        new_size_bytes,
+        gpt_repair: None,
+        partition_growth: None,
+    };
+    if dry_run {
+        return Ok(growth);
+    }
+
+    // An image already at the target size still gets the table steps, which
+    // completes an interrupted earlier run
+    if growth.grew() {
+        drop(device);
+        Device::open(image_path)?.grow_image_to(new_size_bytes)?;
+    }
+
+    if table.is_some_and(|table| table.kind == PartitionTableKind::Gpt) {
+        growth.gpt_repair = Some(repair_gpt(image_path, false)?);
+    }
+    if let VolumeLocation::Partition(number) = location {
+        growth.partition_growth = Some(grow_partition(image_path, number, false)?);
+    }
+    Ok(growth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::gpt::{read_header, BASIC_DATA};
+    use crate::resize::{get_fs_info_at, resize_fat32, ResizeOptions};
+    use crate::test_image::{DiskImage, ImageSpec, TestImage};
+
+    #[test]
+    fn test_grow_gpt_image() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::gpt(&[(BASIC_DATA, image.path(), 70_000)]);
+        let old_size = std::fs::metadata(disk.path()).unwrap().len();
+        let location = VolumeLocation::Partition(1);
+
+        let preview =
+            grow_image(disk.path(), TargetSize::Absolute(100 << 20), location, true).unwrap();
+        assert!(preview.grew());
+        assert!(preview.partition_growth.is_none());
+        assert_eq!(std::fs::metadata(disk.path()).unwrap().len(), old_size);
+
+        let growth = grow_image(
+            disk.path(),
+            TargetSize::Absolute(100 << 20),
+            location,
+            false,
+        )
+        .unwrap();
+        assert_eq!(std::fs::metadata(disk.path()).unwrap().len(), 100 << 20);
+        assert!(growth.gpt_repair.unwrap().repaired());
+        let partition = growth.partition_growth.unwrap();
+        assert_eq!(
+            partition.start_bytes + partition.new_size_bytes,
+            (100 << 20) - 33 * 512
+        );
+        let device = Device::open_readonly(disk.path()).unwrap();
+        let backup = read_header(&device, (100 << 20) / 512 - 1, 512).unwrap();
+        assert!(backup.is_some());
+
+        // Running it again finds nothing left to do
+        let again = grow_image(
+            disk.path(),
+            TargetSize::Absolute(100 << 20),
+            location,
+            false,
+        )
+        .unwrap();
+        assert!(!again.grew());
+        assert!(!again.gpt_repair.unwrap().repaired());
+        assert!(!again.partition_growth.unwrap().grew());
+
+        let result = resize_fat32(ResizeOptions::new(disk.path()).location(location)).unwrap();
+        assert_eq!(result.new_size_bytes, partition.new_size_bytes);
+        assert!(!get_fs_info_at(disk.path(), location).unwrap().can_grow);
+    }
+
+    #[test]
+    fn test_grow_plain_image() {
+        let image = TestImage::create(ImageSpec::default());
+        let old_size = std::fs::metadata(image.path()).unwrap().len();
+
+        let growth = grow_image(
+            image.path(),
+            TargetSize::Relative(1 << 20),
+            VolumeLocation::Whole,
+            false,
+        )
+        .unwrap();
+        assert_eq!(growth.new_size_bytes, old_size + (1 << 20));
+        assert!(growth.gpt_repair.is_none());
+        assert!(growth.partition_growth.is_none());
+        assert!(
+            get_fs_info_at(image.path(), VolumeLocation::Whole)
+                .unwrap()
+                .can_grow
+        );
+    }
+
+    #[test]
+    fn test_refused_targets() {
+        let image = TestImage::create(ImageSpec::default());
+        let size = std::fs::metadata(image.path()).unwrap().len();
+        let whole = VolumeLocation::Whole;
+
+        for target in [
+            TargetSize::Absolute(size - 512),
+            TargetSize::Absolute(size + 100),
+        ] {
+            assert!(matches!(
+                grow_image(image.path(), target, whole, false),
+                Err(Error::CannotGrowImage(_, _))
+            ));
+        }
+        assert!(matches!(
+            grow_image("/dev/null", TargetSize::Relative(512), whole, false),
+            Err(Error::CannotGrowImage(_, _))
+        ));
+        assert_eq!(std::fs::metadata(image.path()).unwrap().len(), size);
+
+        // A partition that cannot grow leaves the image alone
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000), (0x83, image.path(), 2048)]);
+        assert!(grow_partition(disk.path(), 1, false).unwrap().grew());
+        let disk_size = std::fs::metadata(disk.path()).unwrap().len();
+        let blocked = VolumeLocation::Partition(1);
+        assert!(matches!(
+            grow_image(disk.path(), TargetSize::Relative(1 << 20), blocked, false),
+            Err(Error::CannotGrowPartition(1, _))
+        ));
+        assert_eq!(std::fs::metadata(disk.path()).unwrap().len(), disk_size);
+    }
+}
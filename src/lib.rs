@@ -1,11 +1,31 @@
 pub mod device;
 pub mod error;
+pub mod exfat;
 pub mod fat32;
+pub mod image;
+pub mod partition;
+pub mod qcow2;
 pub mod resize;
 pub mod system;
 
-pub use device::Device;
+#[cfg(test)]
+pub(crate) mod test_image;
+
+pub use device::{Device, IoStats, SparseStats};
 pub use error::{Error, Result};
-pub use fat32::{BootSector, FSInfo};
-pub use resize::{get_fs_info, resize_fat32, FSInfoReport, ResizeOptions, ResizeResult};
-pub use system::{check_not_mounted, check_root, get_block_device_size};
+pub use fat32::{BootSector, FSInfo, FatType};
+pub use image::{grow_image, ImageGrowth};
+pub use partition::{
+    check_gpt, grow_partition, list_partitions, repair_gpt, GptProblem, GptRepair, PartitionGrowth,
+    PartitionTable, VolumeLocation,
+};
+pub use resize::{
+    get_exfat_info, get_exfat_info_at, get_fs_info, get_fs_info_at, recluster_fat32, resize_exfat,
+    resize_fat32, shrink_fat32, trim_filesystem, Alignment, DiscardStats, ExfatInfoReport,
+    FSInfoReport, FatReserve, GrowStrategy, ResizeOptions, ResizeResult, SizeLimit, TargetSize,
+    TrimResult,
+};
+pub use system::{
+    check_not_mounted, check_not_mounted_at, check_root, get_alignment_hint, get_block_device_size,
+    get_partition_start, notify_partition_resize,
+};
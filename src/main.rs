@@ -1,8 +1,13 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use std::time::{Duration, UNIX_EPOCH};
 
-use fat32expander::{check_root, get_fs_info, resize_fat32, ResizeOptions};
+use fat32expander::{
+    check_gpt, check_not_mounted_at, check_root, exfat::is_exfat_at, get_exfat_info_at,
+    get_fs_info_at, grow_image, list_partitions, partition::PartitionTableKind, recluster_fat32,
+    repair_gpt, resize, resize_fat32, shrink_fat32, trim_filesystem, Alignment, Device, FatReserve,
+    FatType, GrowStrategy, PartitionGrowth, ResizeOptions, TargetSize, VolumeLocation,
+};
 
 const BUILD_TIMESTAMP: u64 = const_parse_u64(env!("BUILD_TIMESTAMP"));
 const GIT_HASH: &str = env!("GIT_HASH");
@@ -89,22 +94,192 @@ struct Cli {
     command: Commands,
 }
 
+/// Where the filesystem is on the device
+#[derive(Args)]
+struct LocationArgs {
+    /// Partition number on a whole-disk device or image (MBR or GPT)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    partition: Option<u32>,
+
+    /// Byte offset of the filesystem on the device or image (e.g. 1M)
+    #[arg(long, value_name = "BYTES", value_parser = resize::parse_size, conflicts_with = "partition")]
+    offset: Option<u64>,
+}
+
+impl LocationArgs {
+    fn location(&self) -> VolumeLocation {
+        match (self.partition, self.offset) {
+            (Some(number), _) => VolumeLocation::Partition(number),
+            (None, Some(offset)) => VolumeLocation::Offset(offset),
+            (None, None) => VolumeLocation::Whole,
+        }
+    }
+}
+
+/// Refuse to treat a partitioned device as a filesystem
+fn check_location(device: &str, location: VolumeLocation) -> Result<()> {
+    if location != VolumeLocation::Whole {
+        return Ok(());
+    }
+    if let Some(table) = list_partitions(device)
+        .with_context(|| format!("Failed to read the partition table of {}", device))?
+    {
+        eprintln!("{}", table);
+        anyhow::bail!(
+            "{} is partitioned ({}); choose a partition with --partition N",
+            device,
+            table.kind
+        );
+    }
+    Ok(())
+}
+
+/// Whether reading a boot sector failed because an interrupted resize
+/// invalidated it, which the resize itself recovers from
+fn is_invalidated_boot_sector(err: &fat32expander::Error) -> bool {
+    matches!(err, fat32expander::Error::InvalidatedBootSector)
+}
+
+/// Check what a resize checks about the filesystem as it is, before the
+/// partition table or image is changed for it
+///
+/// Fails if the device is mounted, holds no FAT or exFAT filesystem at
+/// `location`, or (without `force`) its backup boot sector differs.
+fn check_resize_preconditions(device: &str, location: VolumeLocation, force: bool) -> Result<()> {
+    check_not_mounted_at(device, location)?;
+    check_location(device, location)?;
+    let exfat = is_exfat_at(device, location)
+        .with_context(|| format!("Failed to read filesystem info from {}", device))?;
+    let backup_matches = if exfat {
+        get_exfat_info_at(device, location).map(|info| info.backup_matches)
+    } else {
+        get_fs_info_at(device, location).map(|info| info.backup_matches)
+    };
+    match backup_matches {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            eprintln!("Warning: Backup boot sector does not match primary boot sector.");
+            eprintln!("         This could indicate filesystem corruption.");
+            if !force {
+                anyhow::bail!("Use --force to proceed anyway");
+            }
+            Ok(())
+        }
+        Err(e) if is_invalidated_boot_sector(&e) => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read filesystem info from {}", device)),
+    }
+}
+
+/// Print how a partition grew, and whether the kernel has caught up
+fn print_partition_growth(growth: &PartitionGrowth) {
+    if growth.grew() {
+        println!(
+            "Partition {}: {:.2} MB -> {:.2} MB",
+            growth.number,
+            growth.old_size_bytes as f64 / (1024.0 * 1024.0),
+            growth.new_size_bytes as f64 / (1024.0 * 1024.0)
+        );
+    } else {
+        println!(
+            "Partition {} already fills the free space after it ({:.2} MB)",
+            growth.number,
+            growth.old_size_bytes as f64 / (1024.0 * 1024.0)
+        );
+    }
+    if growth.kernel_notified == Some(false) {
+        eprintln!("Warning: The kernel did not accept the new partition size.");
+        eprintln!("         Run partprobe or reboot before using the partition.");
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
-    /// Display information about a FAT32 filesystem
+    /// Display information about a FAT12, FAT16, FAT32 or exFAT filesystem
     Info {
         /// Path to the device or image file
         device: String,
+
+        #[command(flatten)]
+        location: LocationArgs,
     },
 
     /// Show detailed version and build information
     Version,
 
-    /// Resize a FAT32 filesystem to fill its partition
+    /// Resize a FAT12, FAT16, FAT32 or exFAT filesystem to fill its partition
     Resize {
         /// Path to the device or image file
         device: String,
 
+        #[command(flatten)]
+        location: LocationArgs,
+
+        /// Target filesystem size instead of filling the device
+        /// (e.g. 8G, or +2G to grow by 2 GiB)
+        #[arg(short, long, value_name = "SIZE")]
+        size: Option<TargetSize>,
+
+        /// How to make room when the FAT grows: shift (move all data),
+        /// renumber (move only the clusters the FAT grows into) or auto
+        #[arg(long, value_name = "STRATEGY", default_value_t = GrowStrategy::Auto)]
+        strategy: GrowStrategy,
+
+        /// Size the FAT for a future filesystem size (e.g. 64G) or a percentage
+        /// above the new size (e.g. 50%), so later grows only update metadata
+        #[arg(long, value_name = "SIZE|PERCENT")]
+        fat_reserve: Option<FatReserve>,
+
+        /// Start the data area on this boundary when the FAT grows (e.g. 4M),
+        /// or auto to use the device's block and erase sizes, at least 1 MiB
+        #[arg(long, value_name = "SIZE|auto")]
+        align: Option<Alignment>,
+
+        /// Number of FAT copies after the resize (1 or 2)
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..=2))]
+        fats: Option<u8>,
+
+        /// Convert a FAT12 or FAT16 filesystem to FAT32 while growing it,
+        /// so it can grow past the FAT12/FAT16 cluster limit
+        #[arg(long)]
+        fat32: bool,
+
+        /// Grow the partition (--partition N) into the free space after it
+        /// before growing the filesystem
+        #[arg(long, requires = "partition")]
+        grow_partition: bool,
+
+        /// Set the boot sector's hidden sectors to the partition start if
+        /// they differ
+        #[arg(long)]
+        fix_hidden_sectors: bool,
+
+        /// Extend the image file to this size first (e.g. 16G, or +8G), then
+        /// grow its GPT and partition (--partition N) into the new space
+        #[arg(long, value_name = "SIZE")]
+        grow_image_to: Option<TargetSize>,
+
+        /// Runs of clusters (up to 8 MiB each) to read ahead of writing when
+        /// data shifts on a block device or qcow2 image; 0 reads and writes
+        /// in turn
+        #[arg(long, value_name = "N", default_value_t = resize::DEFAULT_QUEUE_DEPTH)]
+        queue_depth: usize,
+
+        /// Bypass the page cache (O_DIRECT) on block devices; image files
+        /// keep buffered I/O
+        #[arg(long)]
+        direct_io: bool,
+
+        /// Keep the FAT, the data shift plan and the read-ahead runs within
+        /// about this much memory (e.g. 64M); larger FATs are read a window
+        /// at a time
+        #[arg(long, value_name = "SIZE", value_parser = resize::parse_size)]
+        memory_limit: Option<u64>,
+
+        /// Discard (TRIM) free clusters afterwards: the added space, and all
+        /// free clusters if data moved
+        #[arg(long)]
+        discard: bool,
+
         /// Dry run - show what would be done without making changes
         #[arg(short = 'n', long)]
         dry_run: bool,
@@ -117,16 +292,145 @@ enum Commands {
         #[arg(short, long)]
         force: bool,
     },
+
+    /// Shrink a FAT32 filesystem, moving data out of the space given up
+    Shrink {
+        /// Path to the device or image file
+        device: String,
+
+        #[command(flatten)]
+        location: LocationArgs,
+
+        /// Target filesystem size (e.g. 4G)
+        #[arg(short, long, value_name = "SIZE")]
+        size: TargetSize,
+
+        /// Dry run - show what would be done without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Verbose output
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Force shrink even if warnings are present
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Change the cluster size of a FAT32 filesystem in place
+    Recluster {
+        /// Path to the device or image file
+        device: String,
+
+        #[command(flatten)]
+        location: LocationArgs,
+
+        /// New cluster size (e.g. 32K), larger than the current one
+        #[arg(short, long, value_name = "SIZE", value_parser = resize::parse_size)]
+        cluster_size: u64,
+
+        /// Dry run - show what would be done without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Verbose output
+        #[arg(short, long)]
+        verbose: bool,
+
+        /// Force re-clustering even if warnings are present
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Discard (TRIM) all free clusters of a FAT12, FAT16, FAT32 or exFAT
+    /// filesystem
+    Trim {
+        /// Path to the device or image file
+        device: String,
+
+        #[command(flatten)]
+        location: LocationArgs,
+
+        /// Read the FAT within about this much memory (e.g. 64M)
+        #[arg(long, value_name = "SIZE", value_parser = resize::parse_size)]
+        memory_limit: Option<u64>,
+
+        /// Dry run - show what would be done without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Force trim even if warnings are present
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Move a GPT's backup copy to the end of a grown disk and repair
+    /// damaged GPT copies
+    RepairGpt {
+        /// Path to the whole-disk device or image file
+        device: String,
+
+        /// Dry run - show what would be done without making changes
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Force repair even if warnings are present
+        #[arg(short, long)]
+        force: bool,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Info { device } => {
-            let info = get_fs_info(&device)
+        Commands::Info { device, location } => {
+            let location = location.location();
+            let disk = Device::open_readonly(&device)
+                .with_context(|| format!("Failed to open {}", device))?;
+            if disk.is_qcow2() {
+                println!(
+                    "qcow2 image: {:.2} MB virtual disk",
+                    disk.size_bytes()? as f64 / (1024.0 * 1024.0)
+                );
+                println!();
+            }
+            drop(disk);
+            if !matches!(location, VolumeLocation::Offset(_)) {
+                let table = list_partitions(&device)
+                    .with_context(|| format!("Failed to read the partition table of {}", device))?;
+                if let Some(table) = table {
+                    println!("{}", table);
+                    if table.kind == PartitionTableKind::Gpt {
+                        let problems = check_gpt(&device)
+                            .with_context(|| format!("Failed to check the GPT of {}", device))?;
+                        for problem in &problems {
+                            println!("Warning: {}", problem);
+                        }
+                        if !problems.is_empty() {
+                            println!("Run `fat32expander repair-gpt {}` to fix it.", device);
+                            println!();
+                        }
+                    }
+                    if location == VolumeLocation::Whole {
+                        println!("Use --partition N to show the filesystem in a partition.");
+                        return Ok(());
+                    }
+                }
+            }
+
+            let exfat = is_exfat_at(&device, location)
                 .with_context(|| format!("Failed to read filesystem info from {}", device))?;
-            println!("{}", info);
+            if exfat {
+                let info = get_exfat_info_at(&device, location)
+                    .with_context(|| format!("Failed to read filesystem info from {}", device))?;
+                println!("{}", info);
+            } else {
+                let info = get_fs_info_at(&device, location)
+                    .with_context(|| format!("Failed to read filesystem info from {}", device))?;
+                println!("{}", info);
+            }
         }
 
         Commands::Version => {
@@ -135,6 +439,20 @@ fn main() -> Result<()> {
 
         Commands::Resize {
             device,
+            location,
+            size,
+            strategy,
+            fat_reserve,
+            align,
+            fats,
+            fat32,
+            grow_partition,
+            fix_hidden_sectors,
+            grow_image_to,
+            queue_depth,
+            direct_io,
+            memory_limit,
+            discard,
             dry_run,
             verbose,
             force,
@@ -148,39 +466,122 @@ fn main() -> Result<()> {
                 }
             }
 
-            // Try to show current state - may fail if boot sector is invalidated from crash
-            let info_result = get_fs_info(&device);
-            let (show_pre_info, current_size, new_size) = match info_result {
-                Ok(info) => {
+            let location = location.location();
+            check_resize_preconditions(&device, location, force)?;
+            if let Some(target) = grow_image_to {
+                let growth = grow_image(&device, target, location, dry_run)
+                    .with_context(|| format!("Failed to grow image {}", device))?;
+                println!(
+                    "Image: {:.2} MB -> {:.2} MB",
+                    growth.old_size_bytes as f64 / (1024.0 * 1024.0),
+                    growth.new_size_bytes as f64 / (1024.0 * 1024.0)
+                );
+                if let Some(repair) = growth
+                    .gpt_repair
+                    .as_ref()
+                    .filter(|repair| repair.repaired())
+                {
+                    println!("Moved the backup GPT to sector {}", repair.backup_lba);
+                }
+                if let Some(partition) = &growth.partition_growth {
+                    print_partition_growth(partition);
+                }
+                println!();
+                if dry_run && growth.grew() {
+                    // The filesystem can only be previewed in the image as it is
+                    println!("DRY RUN MODE - No changes were made");
+                    println!("The filesystem would then be grown to fill the new space.");
+                    return Ok(());
+                }
+            } else if let (true, VolumeLocation::Partition(number)) = (grow_partition, location) {
+                let growth =
+                    fat32expander::grow_partition(&device, number, dry_run).with_context(|| {
+                        format!("Failed to grow partition {} of {}", number, device)
+                    })?;
+                print_partition_growth(&growth);
+                println!();
+                if dry_run && growth.grew() {
+                    // The filesystem can only be previewed in the partition as it is
+                    println!("DRY RUN MODE - No changes were made");
+                    println!("The filesystem would then be grown to fill the partition.");
+                    return Ok(());
+                }
+            }
+
+            // Try to show current state - may fail if boot sector is invalidated from crash.
+            // exFAT and FAT report different details, but the same checks apply.
+            let exfat = is_exfat_at(&device, location)
+                .with_context(|| format!("Failed to read filesystem info from {}", device))?;
+            let info_result = if exfat {
+                get_exfat_info_at(&device, location).map(|info| {
+                    if verbose {
+                        println!("Current filesystem state:");
+                        println!("{}", info);
+                        println!();
+                    }
+                    let max_size = info.max_new_size_bytes.unwrap_or(info.current_size_bytes);
+                    (info.can_grow, info.current_size_bytes, max_size)
+                })
+            } else {
+                get_fs_info_at(&device, location).map(|info| {
                     if verbose {
                         println!("Current filesystem state:");
                         println!("{}", info);
                         println!();
                     }
+                    if let (false, Some(start)) =
+                        (info.hidden_sectors_match(), info.partition_start_sector)
+                    {
+                        eprintln!(
+                            "Warning: Hidden sectors ({}) do not match the partition start ({}).",
+                            info.hidden_sectors, start
+                        );
+                        if !fix_hidden_sectors {
+                            eprintln!("         Some firmware will not boot from this partition.");
+                            eprintln!("         Use --fix-hidden-sectors to correct them.");
+                        }
+                    }
 
+                    // Converting lifts the FAT12/16 cluster limit on the new size
+                    let converting = fat32 && info.fat_type != FatType::Fat32;
+                    let device_bytes = info.device_sectors * info.bytes_per_sector as u64;
+                    let can_grow =
+                        info.can_grow || (converting && device_bytes > info.current_size_bytes);
+                    let max_size = if converting {
+                        device_bytes
+                    } else {
+                        info.max_new_size_bytes.unwrap_or(info.current_size_bytes)
+                    };
+                    (can_grow, info.current_size_bytes, max_size)
+                })
+            };
+            let (show_pre_info, current_size, new_size) = match info_result {
+                Ok((can_grow, current_size, max_size)) => {
                     // Check if resize is possible
-                    if !info.can_grow {
+                    if !can_grow {
                         anyhow::bail!(
                             "Filesystem is already at maximum size for the device ({} bytes)",
-                            info.current_size_bytes
+                            current_size
                         );
                     }
 
-                    if !info.backup_matches && !force {
-                        eprintln!(
-                            "Warning: Backup boot sector does not match primary boot sector."
+                    let new_size = match size {
+                        Some(TargetSize::Absolute(bytes)) => bytes,
+                        Some(TargetSize::Relative(bytes)) => current_size + bytes,
+                        None => max_size,
+                    };
+                    if new_size > max_size {
+                        anyhow::bail!(
+                            "Target size {} bytes exceeds the device size ({} bytes)",
+                            new_size,
+                            max_size
                         );
-                        eprintln!("         This could indicate filesystem corruption.");
-                        anyhow::bail!("Use --force to proceed anyway");
                     }
-
-                    let new_size = info.max_new_size_bytes.unwrap_or(info.current_size_bytes);
-                    (true, info.current_size_bytes, new_size)
+                    (true, current_size, new_size)
                 }
                 Err(e) => {
                     // Check if this might be an invalidated boot sector from a crash
-                    let err_msg = format!("{:?}", e);
-                    if err_msg.contains("Invalid boot signature: 0x0000") {
+                    if is_invalidated_boot_sector(&e) {
                         eprintln!("Warning: Boot sector appears to be invalidated.");
                         eprintln!("         This may indicate an interrupted resize operation.");
                         eprintln!("         Attempting recovery...");
@@ -201,6 +602,9 @@ fn main() -> Result<()> {
             if show_pre_info {
                 println!("Resize operation:");
                 println!("  Device: {}", device);
+                if location != VolumeLocation::Whole {
+                    println!("  Location: {}", location);
+                }
                 println!(
                     "  Current size: {:.2} MB ({} bytes)",
                     current_size as f64 / (1024.0 * 1024.0),
@@ -226,8 +630,20 @@ fn main() -> Result<()> {
 
             // Perform the resize
             let options = ResizeOptions::new(&device)
+                .location(location)
                 .dry_run(dry_run)
-                .verbose(verbose);
+                .verbose(verbose)
+                .target_size(size)
+                .strategy(strategy)
+                .fat_reserve(fat_reserve)
+                .align(align)
+                .num_fats(fats)
+                .convert_to_fat32(fat32)
+                .fix_hidden_sectors(fix_hidden_sectors)
+                .queue_depth(queue_depth)
+                .direct_io(direct_io)
+                .memory_limit(memory_limit)
+                .discard(discard);
 
             let result = resize_fat32(options)
                 .with_context(|| format!("Failed to resize filesystem on {}", device))?;
@@ -258,15 +674,356 @@ fn main() -> Result<()> {
                 result.new_size_bytes as f64 / (1024.0 * 1024.0)
             );
             println!("  FAT tables grew: {}", result.fat_grew);
+            if let Some(from) = result.converted_from {
+                println!("  Converted: {} -> FAT32", from);
+            }
+            if let Some(strategy) = result.strategy {
+                println!("  Strategy: {}", strategy);
+            }
             if result.clusters_relocated > 0 {
                 println!("  Clusters relocated: {}", result.clusters_relocated);
             }
+            if let Some((old, new)) = result.hidden_sectors_change {
+                println!("  Hidden sectors: {} -> {}", old, new);
+            }
+            if let Some((old, new)) = result.partition_type_change {
+                println!("  Partition type: 0x{:02X} -> 0x{:02X}", old, new);
+            }
+            if result.sparse.saved_bytes() > 0 {
+                println!(
+                    "  Kept sparse: {:.2} MB ({:.2} MB of holes skipped, {:.2} MB punched)",
+                    result.sparse.saved_bytes() as f64 / (1024.0 * 1024.0),
+                    result.sparse.skipped_bytes as f64 / (1024.0 * 1024.0),
+                    result.sparse.punched_bytes as f64 / (1024.0 * 1024.0)
+                );
+            }
+            if result.io.write_ops > 0 {
+                println!(
+                    "  I/O: {} reads, {} writes, {:.2} MB written at {:.2} MB/s",
+                    result.io.read_ops,
+                    result.io.write_ops,
+                    result.io.bytes_written as f64 / (1024.0 * 1024.0),
+                    result.io.throughput() / (1024.0 * 1024.0)
+                );
+            }
+            if let Some(stats) = result.discard {
+                if stats.supported {
+                    println!(
+                        "  Discarded: {:.2} MB of free clusters",
+                        stats.discarded_bytes as f64 / (1024.0 * 1024.0)
+                    );
+                } else {
+                    println!("  Discarded: nothing (the device does not support discards)");
+                }
+            }
+            if let Some(limit) = result.size_limit {
+                eprintln!();
+                eprintln!(
+                    "Warning: growth stopped at the {}; the last {:.2} MB of the device stay unused.",
+                    limit,
+                    result.unused_bytes as f64 / (1024.0 * 1024.0)
+                );
+            }
 
             if !dry_run {
                 println!();
                 println!("The filesystem has been resized successfully.");
             }
         }
+
+        Commands::Shrink {
+            device,
+            location,
+            size,
+            dry_run,
+            verbose,
+            force,
+        } => {
+            // Check for root privileges
+            if !check_root() && !dry_run {
+                eprintln!("Warning: This tool requires root privileges to modify block devices.");
+                eprintln!("         Use --dry-run to preview changes without root.");
+                if !force {
+                    anyhow::bail!("Run as root or use --force to continue anyway");
+                }
+            }
+
+            // Boot sector may be invalidated by an interrupted shrink; shrink_fat32 recovers
+            let location = location.location();
+            check_location(&device, location)?;
+            match get_fs_info_at(&device, location) {
+                Ok(info) => {
+                    if verbose {
+                        println!("Current filesystem state:");
+                        println!("{}", info);
+                        println!();
+                    }
+                    if !info.backup_matches && !force {
+                        eprintln!(
+                            "Warning: Backup boot sector does not match primary boot sector."
+                        );
+                        eprintln!("         This could indicate filesystem corruption.");
+                        anyhow::bail!("Use --force to proceed anyway");
+                    }
+                }
+                Err(e) => {
+                    if is_invalidated_boot_sector(&e) {
+                        eprintln!("Warning: Boot sector appears to be invalidated.");
+                        eprintln!("         This may indicate an interrupted shrink operation.");
+                        eprintln!("         Attempting recovery...");
+                        eprintln!();
+                    } else {
+                        return Err(e).with_context(|| {
+                            format!("Failed to read filesystem info from {}", device)
+                        });
+                    }
+                }
+            }
+
+            if dry_run {
+                println!("DRY RUN MODE - No changes will be made");
+                println!();
+            }
+
+            let options = ResizeOptions::new(&device)
+                .location(location)
+                .dry_run(dry_run)
+                .verbose(verbose)
+                .target_size(Some(size));
+
+            let result = shrink_fat32(options)
+                .with_context(|| format!("Failed to shrink filesystem on {}", device))?;
+
+            println!();
+            println!(
+                "Shrink {}!",
+                if dry_run {
+                    "preview complete"
+                } else {
+                    "complete"
+                }
+            );
+            println!();
+            println!("Operations performed:");
+            for op in &result.operations {
+                println!("  - {}", op);
+            }
+            println!();
+            println!("Summary:");
+            println!(
+                "  Old size: {:.2} MB",
+                result.old_size_bytes as f64 / (1024.0 * 1024.0)
+            );
+            println!(
+                "  New size: {:.2} MB",
+                result.new_size_bytes as f64 / (1024.0 * 1024.0)
+            );
+            println!("  Clusters moved: {}", result.clusters_relocated);
+
+            if !dry_run {
+                println!();
+                println!("The filesystem has been shrunk successfully.");
+                println!(
+                    "The device can now be reduced to {} bytes.",
+                    result.new_size_bytes
+                );
+            }
+        }
+
+        Commands::Recluster {
+            device,
+            location,
+            cluster_size,
+            dry_run,
+            verbose,
+            force,
+        } => {
+            // Check for root privileges
+            if !check_root() && !dry_run {
+                eprintln!("Warning: This tool requires root privileges to modify block devices.");
+                eprintln!("         Use --dry-run to preview changes without root.");
+                if !force {
+                    anyhow::bail!("Run as root or use --force to continue anyway");
+                }
+            }
+
+            // Boot sector may be invalidated by an interrupted run; recluster_fat32 recovers
+            let location = location.location();
+            check_location(&device, location)?;
+            match get_fs_info_at(&device, location) {
+                Ok(info) => {
+                    if verbose {
+                        println!("Current filesystem state:");
+                        println!("{}", info);
+                        println!();
+                    }
+                    if !info.backup_matches && !force {
+                        eprintln!(
+                            "Warning: Backup boot sector does not match primary boot sector."
+                        );
+                        eprintln!("         This could indicate filesystem corruption.");
+                        anyhow::bail!("Use --force to proceed anyway");
+                    }
+                }
+                Err(e) => {
+                    if is_invalidated_boot_sector(&e) {
+                        eprintln!("Warning: Boot sector appears to be invalidated.");
+                        eprintln!(
+                            "         This may indicate an interrupted re-clustering operation."
+                        );
+                        eprintln!("         Attempting recovery...");
+                        eprintln!();
+                    } else {
+                        return Err(e).with_context(|| {
+                            format!("Failed to read filesystem info from {}", device)
+                        });
+                    }
+                }
+            }
+
+            if dry_run {
+                println!("DRY RUN MODE - No changes will be made");
+                println!();
+            }
+
+            let options = ResizeOptions::new(&device)
+                .location(location)
+                .dry_run(dry_run)
+                .verbose(verbose)
+                .cluster_size(Some(cluster_size));
+
+            let result = recluster_fat32(options)
+                .with_context(|| format!("Failed to change the cluster size on {}", device))?;
+
+            println!();
+            println!(
+                "Re-clustering {}!",
+                if dry_run {
+                    "preview complete"
+                } else {
+                    "complete"
+                }
+            );
+            println!();
+            println!("Operations performed:");
+            for op in &result.operations {
+                println!("  - {}", op);
+            }
+            println!();
+            println!("Summary:");
+            println!("  Cluster size: {} bytes", cluster_size);
+            println!("  Data clusters: {}", result.calculation.new_data_clusters);
+            println!("  Clusters moved: {}", result.clusters_relocated);
+
+            if !dry_run {
+                println!();
+                println!("The cluster size has been changed successfully.");
+            }
+        }
+
+        Commands::Trim {
+            device,
+            location,
+            memory_limit,
+            dry_run,
+            force,
+        } => {
+            if !check_root() && !dry_run {
+                eprintln!("Warning: This tool requires root privileges to modify block devices.");
+                eprintln!("         Use --dry-run to preview changes without root.");
+                if !force {
+                    anyhow::bail!("Run as root or use --force to continue anyway");
+                }
+            }
+
+            let location = location.location();
+            check_location(&device, location)?;
+
+            if dry_run {
+                println!("DRY RUN MODE - No changes will be made");
+                println!();
+            }
+
+            let options = ResizeOptions::new(&device)
+                .location(location)
+                .dry_run(dry_run)
+                .memory_limit(memory_limit);
+
+            let result = trim_filesystem(options)
+                .with_context(|| format!("Failed to trim filesystem on {}", device))?;
+
+            println!(
+                "Trim {}!",
+                if dry_run {
+                    "preview complete"
+                } else {
+                    "complete"
+                }
+            );
+            println!();
+            println!("Operations performed:");
+            for op in &result.operations {
+                println!("  - {}", op);
+            }
+            println!();
+            println!("Summary:");
+            println!("  Filesystem: {}", result.fat_type);
+            if result.discard.supported {
+                println!(
+                    "  Free space discarded: {:.2} MB in {} ranges",
+                    result.discard.discarded_bytes as f64 / (1024.0 * 1024.0),
+                    result.discard.ranges
+                );
+            } else {
+                println!("  Nothing was discarded: the device does not support discards");
+            }
+        }
+
+        Commands::RepairGpt {
+            device,
+            dry_run,
+            force,
+        } => {
+            if !check_root() && !dry_run {
+                eprintln!("Warning: This tool requires root privileges to modify block devices.");
+                eprintln!("         Use --dry-run to preview changes without root.");
+                if !force {
+                    anyhow::bail!("Run as root or use --force to continue anyway");
+                }
+            }
+
+            let repair = repair_gpt(&device, dry_run)
+                .with_context(|| format!("Failed to repair the GPT of {}", device))?;
+            if !repair.repaired() {
+                println!("The GPT of {} is intact; nothing to repair.", device);
+                return Ok(());
+            }
+
+            if dry_run {
+                println!("DRY RUN MODE - No changes were made");
+                println!();
+            }
+            for problem in &repair.problems {
+                println!("Found: {}", problem);
+            }
+            println!();
+            println!("Backup GPT header: sector {}", repair.backup_lba);
+            println!(
+                "Last usable sector: {} -> {}",
+                repair.old_last_usable_lba, repair.last_usable_lba
+            );
+            if repair.reclaimed_bytes() > 0 {
+                println!(
+                    "Partitions can grow into {:.2} MB more at the end of the disk",
+                    repair.reclaimed_bytes() as f64 / (1024.0 * 1024.0)
+                );
+                println!("(use resize --partition N --grow-partition)");
+            }
+            if !dry_run {
+                println!();
+                println!("The GPT has been repaired successfully.");
+            }
+        }
     }
 
     Ok(())
@@ -0,0 +1,435 @@
+//! GUID Partition Tables
+//!
+//! A protective MBR, the primary header at LBA 1 followed by the partition
+//! entry array, and a backup entry array and header at the end of the disk.
+//! Headers and entry arrays carry CRC32 checksums, so a damaged primary
+//! table is detected and the backup used instead.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::partition::{mbr, Partition, PartitionTable, PartitionTableKind, PartitionType};
+
+/// Signature at the start of a GPT header
+pub const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Smallest header size (the fields defined by the UEFI specification)
+pub const MIN_HEADER_SIZE: usize = 92;
+
+/// Smallest partition entry size
+pub const MIN_ENTRY_SIZE: usize = 128;
+
+/// Largest entry array read (the usual array is 16 KiB)
+const MAX_ENTRY_ARRAY: usize = 1 << 20;
+
+/// Sector sizes a GPT is looked for with, in this order
+const SECTOR_SIZES: [u32; 2] = [512, 4096];
+
+/// Partition type GUID in on-disk byte order, from its textual form
+/// `d1-d2-d3-d4`
+const fn guid(d1: u32, d2: u16, d3: u16, d4: [u8; 8]) -> [u8; 16] {
+    let a = d1.to_le_bytes();
+    let b = d2.to_le_bytes();
+    let c = d3.to_le_bytes();
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d4[0], d4[1], d4[2], d4[3], d4[4], d4[5],
+        d4[6], d4[7],
+    ]
+}
+
+/// Microsoft basic data partition (FAT, exFAT and NTFS)
+pub const BASIC_DATA: [u8; 16] = guid(
+    0xEBD0A0A2,
+    0xB9E5,
+    0x4433,
+    [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7],
+);
+
+/// EFI system partition
+pub const EFI_SYSTEM: [u8; 16] = guid(
+    0xC12A7328,
+    0xF81F,
+    0x11D2,
+    [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B],
+);
+
+/// Microsoft reserved partition
+pub const MICROSOFT_RESERVED: [u8; 16] = guid(
+    0xE3C9E316,
+    0x0B5C,
+    0x4DB8,
+    [0x81, 0x7D, 0xF9, 0x2D, 0xF0, 0x02, 0x15, 0xAE],
+);
+
+/// Linux filesystem partition
+pub const LINUX_FILESYSTEM: [u8; 16] = guid(
+    0x0FC63DAF,
+    0x8483,
+    0x4772,
+    [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4],
+);
+
+/// Linux swap partition
+pub const LINUX_SWAP: [u8; 16] = guid(
+    0x0657FD6D,
+    0xA4AB,
+    0x43C4,
+    [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F],
+);
+
+/// Common name of a GPT partition type
+pub fn type_name(type_guid: &[u8; 16]) -> Option<&'static str> {
+    match *type_guid {
+        BASIC_DATA => Some("Basic data"),
+        EFI_SYSTEM => Some("EFI System"),
+        MICROSOFT_RESERVED => Some("Microsoft reserved"),
+        LINUX_FILESYSTEM => Some("Linux filesystem"),
+        LINUX_SWAP => Some("Linux swap"),
+        _ => None,
+    }
+}
+
+/// Format a GUID in its textual form, e.g. `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`
+pub fn format_guid(guid: &[u8; 16]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{}-{}",
+        u32::from_le_bytes(guid[0..4].try_into().unwrap()),
+        u16::from_le_bytes(guid[4..6].try_into().unwrap()),
+        u16::from_le_bytes(guid[6..8].try_into().unwrap()),
+        hex(&guid[8..10]),
+        hex(&guid[10..16])
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// A GPT header (primary or backup)
+#[derive(Debug, Clone)]
+pub struct GptHeader {
+    /// Header bytes (`header_size` long)
+    raw: Vec<u8>,
+}
+
+impl GptHeader {
+    /// Parse a header, checking its signature, size and CRC
+    ///
+    /// Returns `None` if the sector does not hold a valid header.
+    pub fn from_bytes(sector: &[u8]) -> Option<Self> {
+        if sector.len() < MIN_HEADER_SIZE || &sector[0..8] != SIGNATURE {
+            return None;
+        }
+        let header_size = u32::from_le_bytes(sector[12..16].try_into().unwrap()) as usize;
+        if !(MIN_HEADER_SIZE..=sector.len()).contains(&header_size) {
+            return None;
+        }
+        let header = Self {
+            raw: sector[..header_size].to_vec(),
+        };
+        (header.header_crc() == header.compute_crc()).then_some(header)
+    }
+
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.raw[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.raw[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// CRC32 stored in the header
+    pub fn header_crc(&self) -> u32 {
+        self.u32_at(16)
+    }
+
+    /// CRC32 of the header, computed with the CRC field zeroed
+    pub fn compute_crc(&self) -> u32 {
+        let mut raw = self.raw.clone();
+        raw[16..20].fill(0);
+        crc32fast::hash(&raw)
+    }
+
+    /// Sector holding this header
+    pub fn current_lba(&self) -> u64 {
+        self.u64_at(24)
+    }
+
+    /// Sector holding the other header
+    pub fn backup_lba(&self) -> u64 {
+        self.u64_at(32)
+    }
+
+    /// First sector partitions may use
+    pub fn first_usable_lba(&self) -> u64 {
+        self.u64_at(40)
+    }
+
+    /// Last sector partitions may use
+    pub fn last_usable_lba(&self) -> u64 {
+        self.u64_at(48)
+    }
+
+    /// First sector of this header's partition entry array
+    pub fn entries_lba(&self) -> u64 {
+        self.u64_at(72)
+    }
+
+    /// Number of entries in the array
+    pub fn num_entries(&self) -> u32 {
+        self.u32_at(80)
+    }
+
+    /// Size of each entry in bytes
+    pub fn entry_size(&self) -> u32 {
+        self.u32_at(84)
+    }
+
+    /// CRC32 of the entry array
+    pub fn entries_crc(&self) -> u32 {
+        self.u32_at(88)
+    }
+
+    /// Size of the entry array in bytes
+    pub fn entry_array_bytes(&self) -> usize {
+        self.num_entries() as usize * self.entry_size() as usize
+    }
+
+    fn set_u32_at(&mut self, offset: usize, value: u32) {
+        self.raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u64_at(&mut self, offset: usize, value: u64) {
+        self.raw[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Set where this header, the other header and this header's entry
+    /// array are
+    pub fn set_location(&mut self, current_lba: u64, backup_lba: u64, entries_lba: u64) {
+        self.set_u64_at(24, current_lba);
+        self.set_u64_at(32, backup_lba);
+        self.set_u64_at(72, entries_lba);
+    }
+
+    /// Set the last sector partitions may use
+    pub fn set_last_usable_lba(&mut self, lba: u64) {
+        self.set_u64_at(48, lba);
+    }
+
+    /// Set the CRC32 of the entry array
+    pub fn set_entries_crc(&mut self, crc: u32) {
+        self.set_u32_at(88, crc);
+    }
+
+    /// Header as a full sector, with its CRC updated
+    pub fn to_sector(&self, sector_size: u32) -> Vec<u8> {
+        let mut sector = vec![0u8; sector_size as usize];
+        sector[..self.raw.len()].copy_from_slice(&self.raw);
+        sector[16..20].copy_from_slice(&self.compute_crc().to_le_bytes());
+        sector
+    }
+}
+
+/// Read the header in sector `lba`, if it holds a valid one
+pub fn read_header(device: &Device, lba: u64, sector_size: u32) -> Result<Option<GptHeader>> {
+    let sector = device.read_bytes_at(lba * sector_size as u64, sector_size as usize)?;
+    Ok(GptHeader::from_bytes(&sector).filter(|header| header.current_lba() == lba))
+}
+
+/// Read a header's partition entry array, if its CRC matches
+pub fn read_entries(
+    device: &Device,
+    header: &GptHeader,
+    sector_size: u32,
+) -> Result<Option<Vec<u8>>> {
+    let entry_size = header.entry_size() as usize;
+    let bytes = header.entry_array_bytes();
+    if entry_size < MIN_ENTRY_SIZE || !entry_size.is_multiple_of(8) || bytes > MAX_ENTRY_ARRAY {
+        return Ok(None);
+    }
+    let entries = device.read_bytes_at(header.entries_lba() * sector_size as u64, bytes)?;
+    Ok((crc32fast::hash(&entries) == header.entries_crc()).then_some(entries))
+}
+
+/// A GPT as read from a device: the header it was read through (primary or
+/// backup) and that header's entry array
+#[derive(Debug, Clone)]
+pub struct Gpt {
+    pub header: GptHeader,
+    pub entries: Vec<u8>,
+    /// Sector size the GPT's addresses are given in
+    pub sector_size: u32,
+}
+
+impl Gpt {
+    /// Check whether the primary header was unusable and the backup was read
+    pub fn from_backup(&self) -> bool {
+        self.header.current_lba() != 1
+    }
+
+    /// Sectors taken by the entry array
+    pub fn entry_array_sectors(&self) -> u64 {
+        (self.entries.len() as u64).div_ceil(self.sector_size as u64)
+    }
+
+    /// Last sector partitions may use on a disk of `total_sectors`, with the
+    /// backup entry array and header at its end
+    pub fn last_usable_for(&self, total_sectors: u64) -> u64 {
+        total_sectors - 2 - self.entry_array_sectors()
+    }
+
+    /// Partitions described by the entry array
+    pub fn partitions(&self) -> Result<Vec<Partition>> {
+        parse_entries(&self.header, &self.entries, self.sector_size)
+    }
+
+    /// Set the last sector of a partition
+    pub fn set_last_lba(&mut self, number: u32, last_lba: u64) {
+        let offset = (number as usize - 1) * self.header.entry_size() as usize;
+        self.entries[offset + 40..offset + 48].copy_from_slice(&last_lba.to_le_bytes());
+    }
+
+    /// Write the primary and backup GPT for a disk of `total_sectors`
+    ///
+    /// The backup entry array and header move to the end of the disk and the
+    /// last usable sector follows them; a stale backup header left inside
+    /// the disk is wiped. The backup is written first, so a crash leaves at
+    /// least one consistent copy.
+    pub fn write(&self, device: &Device, total_sectors: u64) -> Result<()> {
+        let sector_size = self.sector_size as u64;
+        let backup_lba = total_sectors - 1;
+        let backup_entries_lba = backup_lba - self.entry_array_sectors();
+        let primary_entries_lba = if self.from_backup() {
+            2
+        } else {
+            self.header.entries_lba()
+        };
+        let old_backup_lba = if self.from_backup() {
+            self.header.current_lba()
+        } else {
+            self.header.backup_lba()
+        };
+
+        let mut header = self.header.clone();
+        header.set_last_usable_lba(self.last_usable_for(total_sectors));
+        header.set_entries_crc(crc32fast::hash(&self.entries));
+
+        header.set_location(backup_lba, 1, backup_entries_lba);
+        device.write_bytes_at(backup_entries_lba * sector_size, &self.entries)?;
+        device.write_bytes_at(
+            backup_lba * sector_size,
+            &header.to_sector(self.sector_size),
+        )?;
+        device.sync()?;
+
+        header.set_location(1, backup_lba, primary_entries_lba);
+        device.write_bytes_at(primary_entries_lba * sector_size, &self.entries)?;
+        device.write_bytes_at(sector_size, &header.to_sector(self.sector_size))?;
+
+        if old_backup_lba != backup_lba && old_backup_lba < total_sectors {
+            device.write_bytes_at(
+                old_backup_lba * sector_size,
+                &vec![0u8; self.sector_size as usize],
+            )?;
+        }
+        device.sync()
+    }
+}
+
+/// Read the GPT of a device with a protective MBR
+///
+/// The primary header and entries are used if their CRCs match, otherwise
+/// the backup ones in the last sector. If the disk grew since it was
+/// partitioned, the backup is still where the protective partition ends.
+pub fn read_gpt(device: &Device) -> Result<Gpt> {
+    for sector_size in SECTOR_SIZES {
+        let sectors = device.size_bytes()? / sector_size as u64;
+        if sectors < 3 {
+            continue;
+        }
+        let mut candidates = vec![1, sectors - 1];
+        candidates.extend(protective_end(device)?.filter(|&lba| lba > 1 && lba < sectors - 1));
+        for lba in candidates {
+            let Some(header) = read_header(device, lba, sector_size)? else {
+                continue;
+            };
+            if let Some(entries) = read_entries(device, &header, sector_size)? {
+                return Ok(Gpt {
+                    header,
+                    entries,
+                    sector_size,
+                });
+            }
+        }
+    }
+    Err(Error::InvalidPartitionTable(
+        "protective MBR without a valid GPT header".to_string(),
+    ))
+}
+
+/// Last sector of the protective MBR partition, where the backup header was
+/// put when the disk was partitioned
+fn protective_end(device: &Device) -> Result<Option<u64>> {
+    let sector = device.read_bytes_at(0, mbr::MBR_SIZE)?;
+    let protective = mbr::parse_entries(&sector).and_then(|entries| {
+        entries
+            .into_iter()
+            .find(|entry| entry.system_id == mbr::GPT_PROTECTIVE)
+    });
+    // A full entry means the disk was too large to describe
+    Ok(protective
+        .filter(|entry| entry.sectors != u32::MAX)
+        .map(|entry| entry.start_lba as u64 + entry.sectors as u64 - 1))
+}
+
+/// Read the partition table of a device with a protective MBR
+pub fn read_table(device: &Device) -> Result<PartitionTable> {
+    let gpt = read_gpt(device)?;
+    Ok(PartitionTable {
+        kind: PartitionTableKind::Gpt,
+        sector_size: gpt.sector_size,
+        partitions: gpt.partitions()?,
+    })
+}
+
+/// Turn the used entries of an entry array into partitions, numbered by
+/// their index in the array
+fn parse_entries(header: &GptHeader, entries: &[u8], sector_size: u32) -> Result<Vec<Partition>> {
+    let sector_size = sector_size as u64;
+    let entry_size = header.entry_size() as usize;
+    let mut partitions = Vec::new();
+
+    for (index, entry) in entries.chunks_exact(entry_size).enumerate() {
+        let type_guid: [u8; 16] = entry[0..16].try_into().unwrap();
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+        if last_lba < first_lba {
+            return Err(Error::InvalidPartitionTable(format!(
+                "partition {} ends (sector {}) before it starts (sector {})",
+                index + 1,
+                last_lba,
+                first_lba
+            )));
+        }
+
+        // UTF-16LE name, padded with zeros
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+
+        partitions.push(Partition {
+            number: index as u32 + 1,
+            start_bytes: first_lba * sector_size,
+            size_bytes: (last_lba - first_lba + 1) * sector_size,
+            partition_type: PartitionType::Gpt(type_guid),
+            name: String::from_utf16_lossy(&name),
+            entry_offset: header.entries_lba() * sector_size + (index * entry_size) as u64,
+            filesystem: None,
+        });
+    }
+    Ok(partitions)
+}
@@ -0,0 +1,301 @@
+//! Growing a partition into the free space after it
+//!
+//! Does what `growpart` or `parted resizepart` would before a filesystem
+//! resize: the partition's end moves up to the next partition, the end of
+//! its extended partition or the end of the disk, whichever comes first.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::partition::{
+    gpt, mbr, read_partition_table, Partition, PartitionTable, PartitionTableKind,
+};
+use crate::system::notify_partition_resize;
+use std::path::Path;
+
+/// Result of growing a partition
+#[derive(Debug, Clone)]
+pub struct PartitionGrowth {
+    /// Partition number
+    pub number: u32,
+    /// MBR or GPT
+    pub kind: PartitionTableKind,
+    /// Byte offset of the partition on the device
+    pub start_bytes: u64,
+    /// Partition size before growing
+    pub old_size_bytes: u64,
+    /// Partition size after growing (equal to the old size if there was no
+    /// free space after it)
+    pub new_size_bytes: u64,
+    /// Whether the kernel accepted the new partition size (`None` for image
+    /// files and dry runs)
+    pub kernel_notified: Option<bool>,
+}
+
+impl PartitionGrowth {
+    /// Check whether the partition grew
+    pub fn grew(&self) -> bool {
+        self.new_size_bytes > self.old_size_bytes
+    }
+}
+
+/// Grow a partition into the free space after it
+///
+/// For GPT disks the backup header and entry array move to the end of the
+/// disk first, so a disk (or image) that grew since it was partitioned is
+/// used up to its end. Fails if another partition starts right at the
+/// partition's end. On block devices the kernel is told about the new size.
+pub fn grow_partition(
+    device_path: impl AsRef<Path>,
+    number: u32,
+    dry_run: bool,
+) -> Result<PartitionGrowth> {
+    let device_path = device_path.as_ref();
+    let device = if dry_run {
+        Device::open_readonly(device_path)?
+    } else {
+        Device::open(device_path)?
+    };
+    let table = read_partition_table(&device)?
+        .ok_or_else(|| Error::NoPartitionTable(device_path.display().to_string()))?;
+    let partition = table
+        .partition(number)
+        .ok_or(Error::PartitionNotFound(number))?
+        .clone();
+    let sector_size = table.sector_size as u64;
+    let device_sectors = device.size_bytes()? / sector_size;
+
+    // Byte offset the partition may grow up to at most
+    let mut gpt = None;
+    let limit = match table.kind {
+        PartitionTableKind::Mbr => mbr_limit(&table, &partition, device_sectors * sector_size)?,
+        PartitionTableKind::Gpt => {
+            let table_gpt = gpt::read_gpt(&device)?;
+            let limit = (table_gpt.last_usable_for(device_sectors) + 1) * sector_size;
+            gpt = Some(table_gpt);
+            limit
+        }
+    };
+    let new_end = growth_end(&table, &partition, limit)?;
+
+    let mut growth = PartitionGrowth {
+        number,
+        kind: table.kind,
+        start_bytes: partition.start_bytes,
+        old_size_bytes: partition.size_bytes,
+        new_size_bytes: partition.size_bytes.max(new_end - partition.start_bytes),
+        kernel_notified: None,
+    };
+    if dry_run || !growth.grew() {
+        return Ok(growth);
+    }
+
+    let new_sectors = growth.new_size_bytes / sector_size;
+    match gpt {
+        None => {
+            mbr::write_entry_sectors(&device, partition.entry_offset, new_sectors as u32)?;
+            device.sync()?;
+        }
+        Some(mut gpt) => {
+            let first_lba = partition.start_bytes / sector_size;
+            gpt.set_last_lba(number, first_lba + new_sectors - 1);
+            gpt.write(&device, device_sectors)?;
+            mbr::write_protective_entry(&device, device_sectors)?;
+        }
+    }
+
+    growth.kernel_notified = match notify_partition_resize(
+        device_path,
+        number,
+        growth.start_bytes,
+        growth.new_size_bytes,
+    ) {
+        Ok(true) => Some(true),
+        Ok(false) => None,
+        Err(_) => Some(false),
+    };
+    Ok(growth)
+}
+
+/// Furthest an MBR partition can reach: the end of the disk, or of the
+/// extended partition holding it, and the largest size an entry can hold
+fn mbr_limit(table: &PartitionTable, partition: &Partition, device_bytes: u64) -> Result<u64> {
+    if partition.is_extended() {
+        return Err(Error::CannotGrowPartition(
+            partition.number,
+            "it is an extended partition; grow a logical partition in it".to_string(),
+        ));
+    }
+
+    let sector_size = mbr::SECTOR_SIZE as u64;
+    let mut limit = device_bytes.min(partition.start_bytes + u32::MAX as u64 * sector_size);
+    if partition.number >= mbr::FIRST_LOGICAL {
+        if let Some(extended) = table.partitions.iter().find(|p| p.is_extended()) {
+            limit = limit.min(extended.end_bytes());
+        }
+    }
+    Ok(limit / sector_size * sector_size)
+}
+
+/// Where a partition can grow to: `limit`, or the start of the next
+/// partition (or extended boot record) if that comes first
+fn growth_end(table: &PartitionTable, partition: &Partition, limit: u64) -> Result<u64> {
+    let is_mbr = table.kind == PartitionTableKind::Mbr;
+    let mut end = limit;
+    let mut blocker = None;
+    for other in &table.partitions {
+        if other.number == partition.number {
+            continue;
+        }
+        // A logical partition is preceded by its extended boot record
+        let start = if is_mbr && other.number >= mbr::FIRST_LOGICAL {
+            other.entry_offset - mbr::ENTRIES_OFFSET as u64
+        } else {
+            other.start_bytes
+        };
+        // Partitions starting before this one are the extended partition
+        // holding it, or lie before it
+        if start > partition.start_bytes && start < end {
+            end = start;
+            blocker = Some(other.number);
+        }
+    }
+
+    match blocker {
+        Some(other) if end <= partition.end_bytes() => Err(Error::CannotGrowPartition(
+            partition.number,
+            format!("partition {} is in the way", other),
+        )),
+        _ => Ok(end),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::gpt::{read_header, BASIC_DATA, LINUX_FILESYSTEM};
+    use crate::partition::VolumeLocation;
+    use crate::resize::{get_fs_info_at, resize_fat32, ResizeOptions};
+    use crate::test_image::{DiskImage, ImageSpec, TestImage};
+
+    fn table(disk: &DiskImage) -> PartitionTable {
+        read_partition_table(&Device::open_readonly(disk.path()).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_grow_mbr_partition_up_to_next() {
+        let image = TestImage::create(ImageSpec::default());
+        // Partition 2 starts at the first 1 MiB boundary after partition 1
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000), (0x83, image.path(), 2048)]);
+
+        let preview = grow_partition(disk.path(), 1, true).unwrap();
+        assert_eq!(preview.new_size_bytes, (73_728 - 2048) * 512);
+        assert_eq!(table(&disk).partition(1).unwrap().size_bytes, 70_000 * 512);
+
+        let growth = grow_partition(disk.path(), 1, false).unwrap();
+        assert!(growth.grew());
+        assert_eq!(growth.kernel_notified, None);
+        assert_eq!(
+            table(&disk).partition(1).unwrap().size_bytes,
+            growth.new_size_bytes
+        );
+
+        // Now partition 2 starts right at the end
+        assert!(matches!(
+            grow_partition(disk.path(), 1, false),
+            Err(Error::CannotGrowPartition(1, _))
+        ));
+
+        // The last partition grows to the end of the disk
+        let growth = grow_partition(disk.path(), 2, false).unwrap();
+        assert_eq!(
+            growth.start_bytes + growth.new_size_bytes,
+            std::fs::metadata(disk.path()).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_grow_mbr_partition_updates_chs_end() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000)]);
+        let chs_end = || {
+            let device = Device::open_readonly(disk.path()).unwrap();
+            device.read_bytes_at(446 + 5, 3).unwrap()
+        };
+
+        // Sector 99_999 is cylinder 6, head 57, sector 19
+        disk.extend_to_sectors(100_000);
+        grow_partition(disk.path(), 1, false).unwrap();
+        assert_eq!(chs_end(), [57, 19, 6]);
+
+        // Past the CHS limit the end is 1023/254/63
+        disk.extend_to_sectors(mbr::CHS_LIMIT_SECTORS + 4096);
+        grow_partition(disk.path(), 1, false).unwrap();
+        assert_eq!(chs_end(), [254, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_grow_logical_partition_within_extended() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr_logical(0x0C, image.path(), 70_000);
+        let extended_end = table(&disk).partition(1).unwrap().end_bytes();
+        disk.extend_to_sectors(200_000);
+
+        let growth = grow_partition(disk.path(), 5, false).unwrap();
+        assert_eq!(growth.start_bytes + growth.new_size_bytes, extended_end);
+        assert!(matches!(
+            grow_partition(disk.path(), 1, false),
+            Err(Error::CannotGrowPartition(1, _))
+        ));
+    }
+
+    #[test]
+    fn test_grow_gpt_partition_on_grown_disk() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::gpt(&[
+            (LINUX_FILESYSTEM, image.path(), 2048),
+            (BASIC_DATA, image.path(), 70_000),
+        ]);
+        let old_backup = std::fs::metadata(disk.path()).unwrap().len() / 512 - 1;
+        disk.extend_to_sectors(300_000);
+
+        let growth = grow_partition(disk.path(), 2, false).unwrap();
+        // Up to the backup entry array (32 sectors) and header at the end
+        assert_eq!(
+            growth.start_bytes + growth.new_size_bytes,
+            (300_000 - 33) * 512
+        );
+
+        // Both headers are valid and agree; the old backup header is gone
+        let device = Device::open_readonly(disk.path()).unwrap();
+        let primary = read_header(&device, 1, 512).unwrap().unwrap();
+        let backup = read_header(&device, 299_999, 512).unwrap().unwrap();
+        assert_eq!(primary.backup_lba(), 299_999);
+        assert_eq!(primary.last_usable_lba(), 300_000 - 34);
+        assert_eq!(backup.entries_lba(), 300_000 - 33);
+        assert!(read_header(&device, old_backup, 512).unwrap().is_none());
+        let protective = mbr::parse_entries(&device.read_bytes_at(0, 512).unwrap()).unwrap();
+        assert_eq!(protective[0].sectors, 299_999);
+
+        // The backup alone describes the grown partition too
+        disk.corrupt(512 + 40);
+        assert_eq!(
+            table(&disk).partition(2).unwrap().size_bytes,
+            growth.new_size_bytes
+        );
+    }
+
+    #[test]
+    fn test_resize_after_growing_partition() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000)]);
+        disk.extend_to_sectors(180_000);
+
+        let growth = grow_partition(disk.path(), 1, false).unwrap();
+        let location = VolumeLocation::Partition(1);
+        let result = resize_fat32(ResizeOptions::new(disk.path()).location(location)).unwrap();
+        assert_eq!(result.new_size_bytes, growth.new_size_bytes);
+        assert!(!get_fs_info_at(disk.path(), location).unwrap().can_grow);
+    }
+}
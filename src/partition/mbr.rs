@@ -0,0 +1,274 @@
+//! Master Boot Record partition tables
+//!
+//! Four primary entries at offset 446 of the first sector. An extended
+//! partition holds a chain of Extended Boot Records (EBRs), each describing
+//! one logical partition and linking to the next.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::FatType;
+use crate::partition::{Partition, PartitionTable, PartitionTableKind, PartitionType};
+
+/// Size of the MBR (and of every EBR)
+pub const MBR_SIZE: usize = 512;
+
+/// Offset of the first partition entry
+pub const ENTRIES_OFFSET: usize = 446;
+
+/// Size of a partition entry
+pub const ENTRY_SIZE: usize = 16;
+
+/// System ID of the protective partition in front of a GPT
+pub const GPT_PROTECTIVE: u8 = 0xEE;
+
+/// Logical partitions are numbered from 5, after the four primary slots
+pub const FIRST_LOGICAL: u32 = 5;
+
+/// Longest EBR chain followed, so a looping chain cannot hang the parser
+const MAX_LOGICAL: u32 = 128;
+
+/// Sector size MBR addresses are given in
+///
+/// MBRs do not record their sector size; disk images and almost all disks
+/// use 512 bytes.
+pub const SECTOR_SIZE: u32 = 512;
+
+/// Sectors CHS addresses reach (1024 cylinders, 255 heads, 63 sectors);
+/// partitions ending past them need an LBA partition type
+pub const CHS_LIMIT_SECTORS: u64 = 1024 * 255 * 63;
+
+/// A partition entry of the MBR or an EBR
+#[derive(Debug, Clone, Copy)]
+pub struct MbrEntry {
+    /// 0x80 for the active partition, 0x00 otherwise
+    pub status: u8,
+    /// Partition type
+    pub system_id: u8,
+    /// First sector, relative to the MBR or (in EBRs) an extended partition
+    pub start_lba: u32,
+    /// Length in sectors
+    pub sectors: u32,
+    /// Byte offset of the entry in its sector
+    pub offset: usize,
+}
+
+impl MbrEntry {
+    /// Parse the entry at `offset` of a sector
+    pub fn from_bytes(sector: &[u8], offset: usize) -> Self {
+        let entry = &sector[offset..offset + ENTRY_SIZE];
+        Self {
+            status: entry[0],
+            system_id: entry[4],
+            start_lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+            sectors: u32::from_le_bytes(entry[12..16].try_into().unwrap()),
+            offset,
+        }
+    }
+
+    /// Check whether the slot is unused
+    pub fn is_empty(&self) -> bool {
+        self.system_id == 0 || self.sectors == 0
+    }
+
+    /// Check whether the entry is an extended partition holding an EBR chain
+    pub fn is_extended(&self) -> bool {
+        matches!(self.system_id, 0x05 | 0x0F | 0x85)
+    }
+}
+
+/// Set the length in sectors of the partition entry at `entry_offset`
+///
+/// The CHS address of the last sector is updated to match.
+pub fn write_entry_sectors(device: &Device, entry_offset: u64, sectors: u32) -> Result<()> {
+    let mut entry = device.read_bytes_at(entry_offset, ENTRY_SIZE)?;
+    // Starts are relative to the sector holding the entry (the MBR or an EBR)
+    let start_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64
+        + entry_offset / SECTOR_SIZE as u64;
+    let end_lba = (start_lba + sectors as u64).saturating_sub(1);
+    entry[5..8].copy_from_slice(&chs_address(end_lba));
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    device.write_bytes_at(entry_offset, &entry)
+}
+
+/// CHS address of a sector as a partition entry stores it (head, then
+/// sector and cylinder), assuming 255 heads and 63 sectors per track
+///
+/// Sectors past the CHS limit get the largest address, 1023/254/63.
+fn chs_address(lba: u64) -> [u8; 3] {
+    let (cylinder, head, sector) = if lba < CHS_LIMIT_SECTORS {
+        (lba / (255 * 63), lba / 63 % 255, lba % 63 + 1)
+    } else {
+        (1023, 254, 63)
+    };
+    [
+        head as u8,
+        sector as u8 | ((cylinder >> 8) as u8) << 6,
+        cylinder as u8,
+    ]
+}
+
+/// Set the partition type of the partition entry at `entry_offset`
+pub fn write_entry_type(device: &Device, entry_offset: u64, system_id: u8) -> Result<()> {
+    device.write_bytes_at(entry_offset + 4, &[system_id])
+}
+
+/// Partition type an MBR partition holding a `fat_type` filesystem should
+/// be changed to, if any
+///
+/// FAT32 partitions are 0x0B, or 0x0C (LBA) once they end past the CHS
+/// limit; FAT16 partitions likewise 0x04/0x06 or 0x0E. An LBA type is kept,
+/// and FAT12, GPT and non-FAT partitions are left alone.
+pub fn fat_partition_type(partition: &Partition, fat_type: FatType) -> Option<u8> {
+    let PartitionType::Mbr(system_id) = partition.partition_type else {
+        return None;
+    };
+    if !matches!(system_id, 0x01 | 0x04 | 0x06 | 0x0B | 0x0C | 0x0E) {
+        return None;
+    }
+    let sector_size = SECTOR_SIZE as u64;
+    let end_sector = partition.end_bytes().div_ceil(sector_size);
+    let lba = end_sector > CHS_LIMIT_SECTORS || matches!(system_id, 0x0C | 0x0E);
+    let wanted = match fat_type {
+        FatType::Fat32 if lba => 0x0C,
+        FatType::Fat32 => 0x0B,
+        FatType::Fat16 if lba => 0x0E,
+        // Type 0x04 only covers FAT16 volumes below 32 MiB
+        FatType::Fat16 if system_id == 0x04 && partition.size_bytes < 65536 * sector_size => 0x04,
+        FatType::Fat16 => 0x06,
+        _ => system_id,
+    };
+    (wanted != system_id).then_some(wanted)
+}
+
+/// Let the protective partition in front of a GPT cover a disk of
+/// `device_sectors` (in GPT sectors), up to the largest size an entry holds
+pub fn write_protective_entry(device: &Device, device_sectors: u64) -> Result<()> {
+    let sector = device.read_bytes_at(0, MBR_SIZE)?;
+    let protective = parse_entries(&sector).and_then(|entries| {
+        entries
+            .into_iter()
+            .find(|entry| entry.system_id == GPT_PROTECTIVE)
+    });
+    if let Some(entry) = protective {
+        let sectors = (device_sectors - entry.start_lba as u64).min(u32::MAX as u64);
+        write_entry_sectors(device, entry.offset as u64, sectors as u32)?;
+        device.sync()?;
+    }
+    Ok(())
+}
+
+/// Parse the four primary entries of an MBR
+///
+/// Returns `None` unless the sector looks like an MBR: it ends in `0xAA55`,
+/// every status byte is 0x00 or 0x80, and at least one slot is in use.
+pub fn parse_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector.len() < MBR_SIZE || sector[510] != 0x55 || sector[511] != 0xAA {
+        return None;
+    }
+    let entries: [MbrEntry; 4] =
+        std::array::from_fn(|i| MbrEntry::from_bytes(sector, ENTRIES_OFFSET + i * ENTRY_SIZE));
+    let valid = entries
+        .iter()
+        .all(|entry| entry.status == 0x00 || entry.status == 0x80)
+        && entries.iter().any(|entry| !entry.is_empty());
+    valid.then_some(entries)
+}
+
+/// Build the partition table from the primary entries, following the EBR
+/// chain of an extended partition
+pub fn read_table(device: &Device, entries: &[MbrEntry; 4]) -> Result<PartitionTable> {
+    let sector_size = SECTOR_SIZE as u64;
+    let mut partitions = Vec::new();
+
+    for (slot, entry) in entries.iter().enumerate() {
+        if entry.is_empty() {
+            continue;
+        }
+        partitions.push(Partition {
+            number: slot as u32 + 1,
+            start_bytes: entry.start_lba as u64 * sector_size,
+            size_bytes: entry.sectors as u64 * sector_size,
+            partition_type: PartitionType::Mbr(entry.system_id),
+            name: String::new(),
+            entry_offset: entry.offset as u64,
+            filesystem: None,
+        });
+    }
+
+    if let Some(extended) = entries.iter().find(|entry| entry.is_extended()) {
+        partitions.extend(read_logical_partitions(device, extended)?);
+    }
+
+    Ok(PartitionTable {
+        kind: PartitionTableKind::Mbr,
+        sector_size: SECTOR_SIZE,
+        partitions,
+    })
+}
+
+/// Follow the EBR chain of an extended partition
+///
+/// The logical partition of each EBR starts relative to the EBR itself, the
+/// link to the next EBR relative to the extended partition.
+fn read_logical_partitions(device: &Device, extended: &MbrEntry) -> Result<Vec<Partition>> {
+    let sector_size = SECTOR_SIZE as u64;
+    let extended_start = extended.start_lba as u64;
+    let mut partitions = Vec::new();
+    let mut ebr_lba = extended_start;
+
+    for number in FIRST_LOGICAL..FIRST_LOGICAL + MAX_LOGICAL {
+        let ebr = device.read_bytes_at(ebr_lba * sector_size, MBR_SIZE)?;
+        if ebr[510] != 0x55 || ebr[511] != 0xAA {
+            return Err(Error::InvalidPartitionTable(format!(
+                "extended boot record at sector {} has no signature",
+                ebr_lba
+            )));
+        }
+
+        let logical = MbrEntry::from_bytes(&ebr, ENTRIES_OFFSET);
+        if !logical.is_empty() {
+            partitions.push(Partition {
+                number,
+                start_bytes: (ebr_lba + logical.start_lba as u64) * sector_size,
+                size_bytes: logical.sectors as u64 * sector_size,
+                partition_type: PartitionType::Mbr(logical.system_id),
+                name: String::new(),
+                entry_offset: ebr_lba * sector_size + logical.offset as u64,
+                filesystem: None,
+            });
+        }
+
+        let next = MbrEntry::from_bytes(&ebr, ENTRIES_OFFSET + ENTRY_SIZE);
+        if next.is_empty() {
+            return Ok(partitions);
+        }
+        ebr_lba = extended_start + next.start_lba as u64;
+    }
+
+    Err(Error::InvalidPartitionTable(format!(
+        "more than {} logical partitions (looping extended boot records?)",
+        MAX_LOGICAL
+    )))
+}
+
+/// Common name of an MBR partition type
+pub fn type_name(system_id: u8) -> Option<&'static str> {
+    Some(match system_id {
+        0x01 => "FAT12",
+        0x04 => "FAT16 <32M",
+        0x05 => "Extended",
+        0x06 => "FAT16",
+        0x07 => "NTFS/exFAT",
+        0x0B => "FAT32 (CHS)",
+        0x0C => "FAT32 (LBA)",
+        0x0E => "FAT16 (LBA)",
+        0x0F => "Extended (LBA)",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x85 => "Linux extended",
+        0x8E => "Linux LVM",
+        0xEE => "GPT protective",
+        0xEF => "EFI System",
+        _ => return None,
+    })
+}
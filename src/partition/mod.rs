@@ -0,0 +1,511 @@
+//! MBR and GPT partition tables
+//!
+//! Lets the tool work on one partition of a whole-disk device or image, such
+//! as `sdcard.img`, without setting up a loop device: [`Device::open_at`]
+//! finds the partition and confines all I/O to it.
+
+pub mod gpt;
+pub mod grow;
+pub mod mbr;
+pub mod repair;
+
+pub use grow::{grow_partition, PartitionGrowth};
+pub use repair::{check_gpt, repair_gpt, GptProblem, GptRepair};
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::FatType;
+use std::path::Path;
+
+/// Where the filesystem starts on a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VolumeLocation {
+    /// The filesystem starts at the first byte of the device
+    #[default]
+    Whole,
+    /// Partition with this number (from 1; logical MBR partitions from 5)
+    Partition(u32),
+    /// The filesystem starts this many bytes into the device and runs to
+    /// its end
+    Offset(u64),
+}
+
+impl std::fmt::Display for VolumeLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Whole => write!(f, "whole device"),
+            Self::Partition(number) => write!(f, "partition {}", number),
+            Self::Offset(offset) => write!(f, "offset {}", offset),
+        }
+    }
+}
+
+/// Kind of partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionTableKind {
+    Mbr,
+    Gpt,
+}
+
+impl std::fmt::Display for PartitionTableKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mbr => write!(f, "MBR"),
+            Self::Gpt => write!(f, "GPT"),
+        }
+    }
+}
+
+/// Partition type as stored in the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR system ID
+    Mbr(u8),
+    /// GPT partition type GUID, in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+impl PartitionType {
+    /// Common name of the partition type, if known
+    pub fn name(&self) -> Option<&'static str> {
+        match self {
+            Self::Mbr(id) => mbr::type_name(*id),
+            Self::Gpt(guid) => gpt::type_name(guid),
+        }
+    }
+}
+
+impl std::fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            Self::Mbr(id) => format!("{:#04x}", id),
+            Self::Gpt(guid) => gpt::format_guid(guid),
+        };
+        match self.name() {
+            Some(name) => write!(f, "{} ({})", name, code),
+            None => write!(f, "{}", code),
+        }
+    }
+}
+
+/// A partition found in an MBR or GPT partition table
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// Partition number, as Linux numbers them (sdX1, mmcblk0p1, ...)
+    pub number: u32,
+    /// Byte offset of the partition on the device
+    pub start_bytes: u64,
+    /// Partition size in bytes
+    pub size_bytes: u64,
+    /// Partition type
+    pub partition_type: PartitionType,
+    /// Partition name (GPT only; empty for MBR)
+    pub name: String,
+    /// Byte offset of the partition's entry in the table on the device
+    pub entry_offset: u64,
+    /// Filesystem found at the start of the partition (filled in by
+    /// [`list_partitions`])
+    pub filesystem: Option<FatType>,
+}
+
+impl Partition {
+    /// Byte offset of the first byte after the partition
+    pub fn end_bytes(&self) -> u64 {
+        self.start_bytes + self.size_bytes
+    }
+
+    /// Check whether this is an MBR extended partition holding logical ones
+    pub fn is_extended(&self) -> bool {
+        matches!(self.partition_type, PartitionType::Mbr(0x05 | 0x0F | 0x85))
+    }
+}
+
+/// Partitions of a device
+#[derive(Debug, Clone)]
+pub struct PartitionTable {
+    /// MBR or GPT
+    pub kind: PartitionTableKind,
+    /// Sector size the table's addresses are given in
+    pub sector_size: u32,
+    /// Partitions in table order
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Find a partition by its number
+    pub fn partition(&self, number: u32) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.number == number)
+    }
+}
+
+impl std::fmt::Display for PartitionTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} partition table ({} partitions, {}-byte sectors):",
+            self.kind,
+            self.partitions.len(),
+            self.sector_size
+        )?;
+        writeln!(
+            f,
+            "  {:>3}  {:>14}  {:>14}  {:<10}  Type",
+            "#", "Start (bytes)", "Size (MB)", "Filesystem"
+        )?;
+        for partition in &self.partitions {
+            let filesystem = partition
+                .filesystem
+                .map_or_else(|| "-".to_string(), |fat_type| fat_type.to_string());
+            write!(
+                f,
+                "  {:>3}  {:>14}  {:>14.2}  {:<10}  {}",
+                partition.number,
+                partition.start_bytes,
+                partition.size_bytes as f64 / (1024.0 * 1024.0),
+                filesystem,
+                partition.partition_type
+            )?;
+            if !partition.name.is_empty() {
+                write!(f, " \"{}\"", partition.name)?;
+            }
+            if partition.filesystem == Some(FatType::Fat32) {
+                write!(f, "  <- FAT32")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the MBR or GPT partition table at the start of a device
+///
+/// Returns `None` if the device does not start with a partition table,
+/// including when it holds a FAT or exFAT filesystem from its first sector
+/// (whose boot sector also ends in the `0xAA55` signature).
+pub fn read_partition_table(device: &Device) -> Result<Option<PartitionTable>> {
+    if device.size_bytes()? < mbr::MBR_SIZE as u64 {
+        return Ok(None);
+    }
+    let sector = device.read_bytes_at(0, mbr::MBR_SIZE)?;
+    if starts_filesystem(&sector) {
+        return Ok(None);
+    }
+    let Some(entries) = mbr::parse_entries(&sector) else {
+        return Ok(None);
+    };
+    if entries
+        .iter()
+        .any(|entry| entry.system_id == mbr::GPT_PROTECTIVE)
+    {
+        return gpt::read_table(device).map(Some);
+    }
+    mbr::read_table(device, &entries).map(Some)
+}
+
+/// Read the partition table of a device or image and check which
+/// filesystem each partition holds
+pub fn list_partitions(device_path: impl AsRef<Path>) -> Result<Option<PartitionTable>> {
+    let device_path = device_path.as_ref();
+    let Some(mut table) = read_partition_table(&Device::open_readonly(device_path)?)? else {
+        return Ok(None);
+    };
+    for partition in &mut table.partitions {
+        partition.filesystem =
+            probe_filesystem(device_path, VolumeLocation::Partition(partition.number))?;
+    }
+    Ok(Some(table))
+}
+
+/// Partition a filesystem at `location` is in
+///
+/// `--partition N` names it; an offset is matched against the partition
+/// starts. Returns `None` for a whole device, and for an offset no partition
+/// starts at.
+pub fn partition_at(
+    device_path: impl AsRef<Path>,
+    location: VolumeLocation,
+) -> Result<Option<Partition>> {
+    let device_path = device_path.as_ref();
+    if location == VolumeLocation::Whole {
+        return Ok(None);
+    }
+    let table = read_partition_table(&Device::open_readonly(device_path)?)?;
+    match (location, table) {
+        (VolumeLocation::Partition(number), Some(table)) => table
+            .partition(number)
+            .cloned()
+            .map(Some)
+            .ok_or(Error::PartitionNotFound(number)),
+        (VolumeLocation::Partition(_), None) => {
+            Err(Error::NoPartitionTable(device_path.display().to_string()))
+        }
+        (VolumeLocation::Offset(offset), Some(table)) => Ok(table
+            .partitions
+            .into_iter()
+            .find(|p| p.start_bytes == offset && !p.is_extended())),
+        _ => Ok(None),
+    }
+}
+
+/// FAT type of the filesystem at `location`, if it holds a FAT or exFAT
+/// filesystem
+pub fn probe_filesystem(
+    device_path: impl AsRef<Path>,
+    location: VolumeLocation,
+) -> Result<Option<FatType>> {
+    let mut device = Device::open_readonly_at(device_path, location)?;
+    if device.size_bytes()? < mbr::MBR_SIZE as u64 {
+        return Ok(None);
+    }
+    let sector = device.read_bytes_at(0, mbr::MBR_SIZE)?;
+    if crate::exfat::ExfatBootSector::is_exfat(&sector) {
+        return Ok(Some(FatType::ExFat));
+    }
+    match crate::fat32::read_boot_sector(&mut device) {
+        Ok(boot) => Ok(Some(boot.fat_type())),
+        // Partitions too small for a boot sector hold no filesystem either
+        Err(Error::Io(e)) if e.kind() != std::io::ErrorKind::UnexpectedEof => Err(Error::Io(e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Check whether a first sector is the boot sector of a FAT or exFAT
+/// filesystem rather than a partition table
+fn starts_filesystem(sector: &[u8]) -> bool {
+    crate::exfat::ExfatBootSector::is_exfat(sector)
+        || crate::fat32::BootSector::from_bytes(sector)
+            .and_then(|boot| crate::fat32::validate_boot_sector_for_recovery(&boot))
+            .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resize::{get_fs_info_at, resize_fat32, ResizeOptions, TargetSize};
+    use crate::test_image::{
+        assert_consistent, pattern, read_root_file, DiskImage, ImageSpec, TestImage,
+    };
+
+    #[test]
+    fn test_filesystem_without_table() {
+        let image = TestImage::create(ImageSpec::default());
+        let device = Device::open_readonly(image.path()).unwrap();
+        assert!(read_partition_table(&device).unwrap().is_none());
+        assert!(list_partitions(image.path()).unwrap().is_none());
+        assert!(matches!(
+            Device::open_readonly_at(image.path(), VolumeLocation::Partition(1)),
+            Err(Error::NoPartitionTable(_))
+        ));
+    }
+
+    #[test]
+    fn test_mbr_partitions() {
+        let fat32 = TestImage::create(ImageSpec::default());
+        let exfat = TestImage::create(ImageSpec::exfat());
+        let disk = DiskImage::mbr(&[(0x0C, fat32.path(), 80_000), (0x07, exfat.path(), 20_000)]);
+
+        let table = list_partitions(disk.path()).unwrap().unwrap();
+        assert_eq!(table.kind, PartitionTableKind::Mbr);
+        assert_eq!(table.partitions.len(), 2);
+        let first = table.partition(1).unwrap();
+        assert_eq!(first.start_bytes, 2048 * 512);
+        assert_eq!(first.size_bytes, 80_000 * 512);
+        assert_eq!(first.partition_type, PartitionType::Mbr(0x0C));
+        assert_eq!(first.filesystem, Some(FatType::Fat32));
+        assert_eq!(table.partition(2).unwrap().filesystem, Some(FatType::ExFat));
+        assert!(table.to_string().contains("<- FAT32"));
+        assert!(matches!(
+            Device::open_readonly_at(disk.path(), VolumeLocation::Partition(3)),
+            Err(Error::PartitionNotFound(3))
+        ));
+    }
+
+    #[test]
+    fn test_mbr_logical_partitions() {
+        let fat32 = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr_logical(0x0C, fat32.path(), 75_000);
+
+        let table = list_partitions(disk.path()).unwrap().unwrap();
+        let logical = table.partition(5).unwrap();
+        assert_eq!(logical.filesystem, Some(FatType::Fat32));
+        assert_eq!(logical.size_bytes, 75_000 * 512);
+        assert_eq!(
+            table.partition(1).unwrap().partition_type.name(),
+            Some("Extended (LBA)")
+        );
+    }
+
+    #[test]
+    fn test_gpt_partitions() {
+        let fat32 = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::gpt(&[(gpt::BASIC_DATA, fat32.path(), 80_000)]);
+
+        let table = list_partitions(disk.path()).unwrap().unwrap();
+        assert_eq!(table.kind, PartitionTableKind::Gpt);
+        let partition = table.partition(1).unwrap();
+        assert_eq!(partition.name, "part1");
+        assert_eq!(partition.filesystem, Some(FatType::Fat32));
+        assert_eq!(
+            partition.partition_type.to_string(),
+            "Basic data (EBD0A0A2-B9E5-4433-87C0-68B6B72699C7)"
+        );
+
+        // A damaged primary header falls back to the backup header
+        disk.corrupt(512 + 40);
+        let table = read_partition_table(&Device::open_readonly(disk.path()).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(table.partition(1).unwrap().start_bytes, 2048 * 512);
+    }
+
+    #[test]
+    fn test_resize_inside_partition() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let contents = pattern(3, 20_000);
+        image.add_file(b"DATA    BIN", &contents, 1);
+        let disk = DiskImage::gpt(&[
+            (gpt::BASIC_DATA, image.path(), 150_000),
+            (gpt::LINUX_FILESYSTEM, image.path(), 10_000),
+        ]);
+        let second_before = disk.read_partition(2);
+
+        let location = VolumeLocation::Partition(1);
+        let result = resize_fat32(ResizeOptions::new(disk.path()).location(location)).unwrap();
+        assert_eq!(result.new_size_bytes, 150_000 * 512);
+
+        let info = get_fs_info_at(disk.path(), location).unwrap();
+        assert_eq!(info.total_sectors, 150_000);
+        assert_eq!(info.location, location);
+        let partition = disk.extract_partition(1);
+        assert_consistent(partition.path());
+        assert_eq!(
+            read_root_file(partition.path(), b"DATA    BIN").unwrap(),
+            contents
+        );
+
+        // Nothing outside the partition changed
+        assert_eq!(disk.read_partition(2), second_before);
+        assert!(list_partitions(disk.path()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_fat_partition_type() {
+        let partition = |system_id: u8, sectors: u64| Partition {
+            number: 1,
+            start_bytes: 2048 * 512,
+            size_bytes: sectors * 512,
+            partition_type: PartitionType::Mbr(system_id),
+            name: String::new(),
+            entry_offset: 446,
+            filesystem: None,
+        };
+        let past_chs = mbr::CHS_LIMIT_SECTORS;
+
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x0B, 70_000), FatType::Fat32),
+            None
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x0B, past_chs), FatType::Fat32),
+            Some(0x0C)
+        );
+        // LBA types stay, even for small partitions
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x0C, 70_000), FatType::Fat32),
+            None
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x0E, 70_000), FatType::Fat32),
+            Some(0x0C)
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x06, 70_000), FatType::Fat32),
+            Some(0x0B)
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x04, 60_000), FatType::Fat16),
+            None
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x04, 70_000), FatType::Fat16),
+            Some(0x06)
+        );
+        assert_eq!(
+            mbr::fat_partition_type(&partition(0x83, past_chs), FatType::Fat32),
+            None
+        );
+    }
+
+    #[test]
+    fn test_hidden_sectors_and_partition_type() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr(&[(0x0B, image.path(), 70_000)]);
+        let location = VolumeLocation::Partition(1);
+
+        let info = get_fs_info_at(disk.path(), location).unwrap();
+        assert_eq!(info.hidden_sectors, 0);
+        assert_eq!(info.partition_start_sector, Some(2048));
+        assert!(!info.hidden_sectors_match());
+        assert_eq!(info.partition_type, Some(0x0B));
+        assert_eq!(info.expected_partition_type, None);
+        assert!(get_fs_info_at(image.path(), VolumeLocation::Whole)
+            .unwrap()
+            .hidden_sectors_match());
+
+        // Let the (sparse) partition end past the CHS limit
+        let end = mbr::CHS_LIMIT_SECTORS + 4096;
+        disk.extend_to_sectors(end + 2048);
+        let device = Device::open(disk.path()).unwrap();
+        mbr::write_entry_sectors(&device, 446, (end - 2048) as u32).unwrap();
+        let info = get_fs_info_at(disk.path(), location).unwrap();
+        assert_eq!(info.expected_partition_type, Some(0x0C));
+
+        let options = ResizeOptions::new(disk.path())
+            .location(location)
+            .target_size(Some(TargetSize::Relative(1 << 20)))
+            .fix_hidden_sectors(true);
+        let preview = resize_fat32(options.clone().dry_run(true)).unwrap();
+        assert_eq!(preview.hidden_sectors_change, Some((0, 2048)));
+        assert_eq!(preview.partition_type_change, Some((0x0B, 0x0C)));
+        assert_eq!(
+            get_fs_info_at(disk.path(), location)
+                .unwrap()
+                .partition_type,
+            Some(0x0B)
+        );
+
+        let result = resize_fat32(options).unwrap();
+        assert_eq!(result.hidden_sectors_change, Some((0, 2048)));
+        assert_eq!(result.partition_type_change, Some((0x0B, 0x0C)));
+        let info = get_fs_info_at(disk.path(), location).unwrap();
+        assert_eq!(info.hidden_sectors, 2048);
+        assert!(info.backup_matches);
+        assert_eq!(info.partition_type, Some(0x0C));
+        assert_eq!(info.expected_partition_type, None);
+    }
+
+    #[test]
+    fn test_offset_and_bounds() {
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000)]);
+        let start = 2048 * 512;
+
+        let device = Device::open_readonly_at(disk.path(), VolumeLocation::Offset(start)).unwrap();
+        assert_eq!(device.start_offset(), start);
+        assert_eq!(
+            probe_filesystem(disk.path(), VolumeLocation::Offset(start)).unwrap(),
+            Some(FatType::Fat32)
+        );
+        assert!(matches!(
+            Device::open_readonly_at(disk.path(), VolumeLocation::Offset(1 << 40)),
+            Err(Error::OffsetOutOfRange(_))
+        ));
+
+        // A partition cannot be read past its end, even if the device goes on
+        let device = Device::open_at(disk.path(), VolumeLocation::Partition(1)).unwrap();
+        assert_eq!(device.total_sectors(), 70_000);
+        assert!(device.read_sector(69_999).is_ok());
+        assert!(device.read_sector(70_000).is_err());
+        assert!(device.write_sector(70_000, &[0; 512]).is_err());
+    }
+}
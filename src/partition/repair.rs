@@ -0,0 +1,288 @@
+//! Repairing a GPT whose backup copy is damaged or no longer at the end of
+//! the disk
+//!
+//! Growing a disk image with `truncate`, or copying it to a larger card,
+//! leaves the backup header and entry array where the old disk ended.
+//! Partitioning tools then complain, and the space after the old end is
+//! outside the last usable LBA, so no partition can grow into it.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::partition::gpt::{self, Gpt};
+use crate::partition::{mbr, read_partition_table, PartitionTableKind};
+use std::path::Path;
+
+/// Something wrong with a GPT that [`repair_gpt`] fixes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GptProblem {
+    /// The primary header or entry array fails its CRC; the backup was read
+    PrimaryDamaged,
+    /// The backup header is at `lba` instead of the last sector, usually
+    /// because the disk grew
+    BackupMisplaced { lba: u64, last_lba: u64 },
+    /// The backup header or entry array in the last sector is missing,
+    /// fails its CRC or disagrees with the primary
+    BackupDamaged,
+}
+
+impl std::fmt::Display for GptProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PrimaryDamaged => write!(f, "the primary GPT header or entries are damaged"),
+            Self::BackupMisplaced { lba, last_lba } => write!(
+                f,
+                "the backup GPT header is at sector {}, not in the last sector ({})",
+                lba, last_lba
+            ),
+            Self::BackupDamaged => write!(f, "the backup GPT header or entries are damaged"),
+        }
+    }
+}
+
+/// Result of repairing a GPT
+#[derive(Debug, Clone)]
+pub struct GptRepair {
+    /// Problems found (and fixed, unless this was a dry run); empty if the
+    /// GPT was fine
+    pub problems: Vec<GptProblem>,
+    /// Sector size the GPT's addresses are given in
+    pub sector_size: u32,
+    /// Sector of the backup header after the repair
+    pub backup_lba: u64,
+    /// Last sector partitions could use before the repair
+    pub old_last_usable_lba: u64,
+    /// Last sector partitions can use after the repair
+    pub last_usable_lba: u64,
+}
+
+impl GptRepair {
+    /// Check whether anything needed repairing
+    pub fn repaired(&self) -> bool {
+        !self.problems.is_empty()
+    }
+
+    /// Space after the old last usable sector that partitions can now grow
+    /// into
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.last_usable_lba
+            .saturating_sub(self.old_last_usable_lba)
+            * self.sector_size as u64
+    }
+}
+
+/// Check the GPT of a whole-disk device or image for problems
+pub fn check_gpt(device_path: impl AsRef<Path>) -> Result<Vec<GptProblem>> {
+    let device_path = device_path.as_ref();
+    let device = Device::open_readonly(device_path)?;
+    let gpt = read_device_gpt(&device, device_path)?;
+    find_problems(&device, &gpt)
+}
+
+/// Rewrite the backup GPT at the end of the disk, and the primary GPT from
+/// it if the primary is damaged
+///
+/// The primary header's backup LBA and both headers' last usable LBA are
+/// updated for the disk's current size, so partitions can grow into space
+/// added after it was partitioned. Fails if a partition ends past the last
+/// usable sector, which happens when the disk shrank.
+pub fn repair_gpt(device_path: impl AsRef<Path>, dry_run: bool) -> Result<GptRepair> {
+    let device_path = device_path.as_ref();
+    let device = if dry_run {
+        Device::open_readonly(device_path)?
+    } else {
+        Device::open(device_path)?
+    };
+    let gpt = read_device_gpt(&device, device_path)?;
+    let device_sectors = device.size_bytes()? / gpt.sector_size as u64;
+    let last_usable_lba = gpt.last_usable_for(device_sectors);
+
+    let repair = GptRepair {
+        problems: find_problems(&device, &gpt)?,
+        sector_size: gpt.sector_size,
+        backup_lba: device_sectors - 1,
+        old_last_usable_lba: gpt.header.last_usable_lba(),
+        last_usable_lba,
+    };
+    if !repair.repaired() {
+        return Ok(repair);
+    }
+
+    let sector_size = gpt.sector_size as u64;
+    if let Some(partition) = gpt
+        .partitions()?
+        .into_iter()
+        .find(|p| p.end_bytes() > (last_usable_lba + 1) * sector_size)
+    {
+        return Err(Error::InvalidPartitionTable(format!(
+            "partition {} ends past the last usable sector ({}) of the disk",
+            partition.number, last_usable_lba
+        )));
+    }
+
+    if !dry_run {
+        gpt.write(&device, device_sectors)?;
+        mbr::write_protective_entry(&device, device_sectors)?;
+    }
+    Ok(repair)
+}
+
+/// Read the GPT of a device, failing if it has an MBR or no table
+fn read_device_gpt(device: &Device, device_path: &Path) -> Result<Gpt> {
+    let table = read_partition_table(device)?
+        .ok_or_else(|| Error::NoPartitionTable(device_path.display().to_string()))?;
+    if table.kind != PartitionTableKind::Gpt {
+        return Err(Error::NotGpt(device_path.display().to_string()));
+    }
+    gpt::read_gpt(device)
+}
+
+/// Compare the GPT that was read with the copies on the device
+fn find_problems(device: &Device, gpt: &Gpt) -> Result<Vec<GptProblem>> {
+    let sector_size = gpt.sector_size;
+    let last_lba = device.size_bytes()? / sector_size as u64 - 1;
+    let mut problems = Vec::new();
+
+    let backup_lba = if gpt.from_backup() {
+        problems.push(GptProblem::PrimaryDamaged);
+        gpt.header.current_lba()
+    } else {
+        gpt.header.backup_lba()
+    };
+    if backup_lba != last_lba {
+        problems.push(GptProblem::BackupMisplaced {
+            lba: backup_lba,
+            last_lba,
+        });
+    } else if !gpt.from_backup() {
+        let backup_ok = match gpt::read_header(device, last_lba, sector_size)? {
+            Some(backup) => {
+                backup.entries_crc() == gpt.header.entries_crc()
+                    && gpt::read_entries(device, &backup, sector_size)?.is_some()
+            }
+            None => false,
+        };
+        if !backup_ok {
+            problems.push(GptProblem::BackupDamaged);
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::gpt::{read_header, BASIC_DATA};
+    use crate::partition::grow_partition;
+    use crate::test_image::{DiskImage, ImageSpec, TestImage};
+
+    fn disk() -> DiskImage {
+        let image = TestImage::create(ImageSpec::default());
+        DiskImage::gpt(&[(BASIC_DATA, image.path(), 70_000)])
+    }
+
+    fn sectors(disk: &DiskImage) -> u64 {
+        std::fs::metadata(disk.path()).unwrap().len() / 512
+    }
+
+    #[test]
+    fn test_intact_gpt() {
+        let disk = disk();
+        assert!(check_gpt(disk.path()).unwrap().is_empty());
+        let repair = repair_gpt(disk.path(), false).unwrap();
+        assert!(!repair.repaired());
+        assert_eq!(repair.reclaimed_bytes(), 0);
+    }
+
+    #[test]
+    fn test_repair_after_extending_image() {
+        let disk = disk();
+        let old_last = sectors(&disk) - 1;
+        disk.extend_to_sectors(200_000);
+
+        let expected = GptProblem::BackupMisplaced {
+            lba: old_last,
+            last_lba: 199_999,
+        };
+        assert_eq!(check_gpt(disk.path()).unwrap(), vec![expected]);
+
+        let preview = repair_gpt(disk.path(), true).unwrap();
+        assert_eq!(preview.problems, vec![expected]);
+        assert_eq!(check_gpt(disk.path()).unwrap(), vec![expected]);
+
+        let repair = repair_gpt(disk.path(), false).unwrap();
+        assert_eq!(repair.backup_lba, 199_999);
+        assert_eq!(repair.last_usable_lba, 200_000 - 34);
+        assert_eq!(repair.reclaimed_bytes(), (200_000 - 1 - old_last) * 512);
+        assert!(check_gpt(disk.path()).unwrap().is_empty());
+
+        let device = Device::open_readonly(disk.path()).unwrap();
+        let primary = read_header(&device, 1, 512).unwrap().unwrap();
+        assert_eq!(primary.backup_lba(), 199_999);
+        assert_eq!(primary.last_usable_lba(), 200_000 - 34);
+        assert!(read_header(&device, old_last, 512).unwrap().is_none());
+
+        // The partition can grow into the reclaimed space
+        let growth = grow_partition(disk.path(), 1, false).unwrap();
+        assert_eq!(
+            growth.start_bytes + growth.new_size_bytes,
+            (200_000 - 33) * 512
+        );
+    }
+
+    #[test]
+    fn test_repair_damaged_copies() {
+        let disk = disk();
+        let last = sectors(&disk) - 1;
+
+        disk.corrupt(last * 512 + 40);
+        assert_eq!(
+            check_gpt(disk.path()).unwrap(),
+            vec![GptProblem::BackupDamaged]
+        );
+        repair_gpt(disk.path(), false).unwrap();
+        assert!(check_gpt(disk.path()).unwrap().is_empty());
+
+        disk.corrupt(512 + 40);
+        assert_eq!(
+            check_gpt(disk.path()).unwrap(),
+            vec![GptProblem::PrimaryDamaged]
+        );
+        repair_gpt(disk.path(), false).unwrap();
+        assert!(check_gpt(disk.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_damaged_primary_on_extended_image() {
+        // The old backup is found through the protective MBR partition
+        let disk = disk();
+        let old_last = sectors(&disk) - 1;
+        disk.extend_to_sectors(200_000);
+        disk.corrupt(512 + 40);
+
+        let problems = check_gpt(disk.path()).unwrap();
+        assert_eq!(problems[0], GptProblem::PrimaryDamaged);
+        assert_eq!(
+            problems[1],
+            GptProblem::BackupMisplaced {
+                lba: old_last,
+                last_lba: 199_999
+            }
+        );
+        repair_gpt(disk.path(), false).unwrap();
+        assert!(check_gpt(disk.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_refuses_shrunk_disk_and_mbr() {
+        let disk = disk();
+        disk.extend_to_sectors(50_000);
+        assert!(matches!(
+            repair_gpt(disk.path(), false),
+            Err(Error::InvalidPartitionTable(_))
+        ));
+
+        let image = TestImage::create(ImageSpec::default());
+        let disk = DiskImage::mbr(&[(0x0C, image.path(), 70_000)]);
+        assert!(matches!(check_gpt(disk.path()), Err(Error::NotGpt(_))));
+    }
+}
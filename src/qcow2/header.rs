@@ -0,0 +1,279 @@
+//! qcow2 header
+//!
+//! All fields are big-endian. Version 2 headers end after the snapshot
+//! offset (72 bytes); version 3 adds feature bits, the refcount width and
+//! the header length.
+
+use crate::error::{Error, Result};
+
+/// "QFI\xfb" at the start of every qcow2 image
+pub const MAGIC: [u8; 4] = *b"QFI\xfb";
+
+/// Bytes of the header that are read (version 3 with the compression type)
+pub const HEADER_READ_SIZE: usize = 112;
+
+// Field offsets
+pub const OFFSET_SIZE: u64 = 24;
+pub const OFFSET_L1_SIZE: u64 = 36;
+pub const OFFSET_REFCOUNT_TABLE: u64 = 48;
+pub const OFFSET_AUTOCLEAR_FEATURES: u64 = 88;
+
+// Incompatible feature bits
+pub const INCOMPAT_DIRTY: u64 = 1 << 0;
+pub const INCOMPAT_CORRUPT: u64 = 1 << 1;
+pub const INCOMPAT_DATA_FILE: u64 = 1 << 2;
+pub const INCOMPAT_COMPRESSION: u64 = 1 << 3;
+pub const INCOMPAT_EXTL2: u64 = 1 << 4;
+
+/// Host offset bits of L1 and L2 entries
+pub const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// Host offset bits of refcount table entries
+pub const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+/// Entry flag: the cluster's refcount is exactly 1, so it may be written in place
+pub const FLAG_COPIED: u64 = 1 << 63;
+/// L2 entry flag: the cluster is compressed
+pub const FLAG_COMPRESSED: u64 = 1 << 62;
+/// L2 entry flag (version 3): the cluster reads as zeros
+pub const FLAG_ZERO: u64 = 1;
+
+/// Parsed qcow2 header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Qcow2Header {
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub cluster_bits: u32,
+    /// Virtual disk size in bytes
+    pub size: u64,
+    pub crypt_method: u32,
+    /// Number of L1 entries
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    /// Refcounts are `1 << refcount_order` bits wide
+    pub refcount_order: u32,
+    pub header_length: u32,
+}
+
+impl Qcow2Header {
+    /// Parse the header from the start of an image
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 72 || data[0..4] != MAGIC {
+            return Err(Error::InvalidQcow2("bad magic".to_string()));
+        }
+        let u32_at =
+            |offset: usize| u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap());
+
+        let version = u32_at(4);
+        let mut header = Self {
+            version,
+            backing_file_offset: u64_at(8),
+            cluster_bits: u32_at(20),
+            size: u64_at(24),
+            crypt_method: u32_at(32),
+            l1_size: u32_at(36),
+            l1_table_offset: u64_at(40),
+            refcount_table_offset: u64_at(48),
+            refcount_table_clusters: u32_at(56),
+            nb_snapshots: u32_at(60),
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: 72,
+        };
+        match version {
+            2 => {}
+            3 => {
+                if data.len() < 104 {
+                    return Err(Error::InvalidQcow2("truncated header".to_string()));
+                }
+                header.incompatible_features = u64_at(72);
+                header.compatible_features = u64_at(80);
+                header.autoclear_features = u64_at(88);
+                header.refcount_order = u32_at(96);
+                header.header_length = u32_at(100);
+            }
+            _ => {
+                return Err(Error::UnsupportedQcow2(format!("version {}", version)));
+            }
+        }
+        header.validate()?;
+        Ok(header)
+    }
+
+    /// Check that the image can be read at all
+    fn validate(&self) -> Result<()> {
+        if !(9..=21).contains(&self.cluster_bits) {
+            return Err(Error::InvalidQcow2(format!(
+                "cluster size 2^{} is out of range",
+                self.cluster_bits
+            )));
+        }
+        if self.refcount_order > 6 {
+            return Err(Error::InvalidQcow2(format!(
+                "refcount order {} is out of range",
+                self.refcount_order
+            )));
+        }
+        if self.l1_table_offset & (self.cluster_size() - 1) != 0
+            || self.refcount_table_offset & (self.cluster_size() - 1) != 0
+        {
+            return Err(Error::InvalidQcow2(
+                "L1 or refcount table is not cluster aligned".to_string(),
+            ));
+        }
+        if (self.l1_size as u64) < self.l1_entries_for(self.size) {
+            return Err(Error::InvalidQcow2(format!(
+                "L1 table ({} entries) is too small for the virtual size",
+                self.l1_size
+            )));
+        }
+        if self.backing_file_offset != 0 {
+            return Err(Error::UnsupportedQcow2(
+                "images with a backing file; run 'qemu-img rebase -b \"\"' or 'qemu-img convert' first"
+                    .to_string(),
+            ));
+        }
+        if self.crypt_method != 0 {
+            return Err(Error::UnsupportedQcow2("encrypted images".to_string()));
+        }
+        if self.incompatible_features & INCOMPAT_DATA_FILE != 0 {
+            return Err(Error::UnsupportedQcow2(
+                "images with an external data file".to_string(),
+            ));
+        }
+        if self.incompatible_features & INCOMPAT_EXTL2 != 0 {
+            return Err(Error::UnsupportedQcow2("extended L2 entries".to_string()));
+        }
+        let known = INCOMPAT_DIRTY
+            | INCOMPAT_CORRUPT
+            | INCOMPAT_DATA_FILE
+            | INCOMPAT_COMPRESSION
+            | INCOMPAT_EXTL2;
+        if self.incompatible_features & !known != 0 {
+            return Err(Error::UnsupportedQcow2(format!(
+                "unknown incompatible features {:#x}",
+                self.incompatible_features & !known
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that the image can also be written
+    pub fn check_writable(&self) -> Result<()> {
+        if self.incompatible_features & INCOMPAT_CORRUPT != 0 {
+            return Err(Error::UnsupportedQcow2(
+                "writing to an image marked corrupt; run 'qemu-img check -r all' first".to_string(),
+            ));
+        }
+        if self.incompatible_features & INCOMPAT_DIRTY != 0 {
+            return Err(Error::UnsupportedQcow2(
+                "writing to an image with unflushed refcounts; run 'qemu-img check -r all' first"
+                    .to_string(),
+            ));
+        }
+        if self.nb_snapshots != 0 {
+            return Err(Error::UnsupportedQcow2(format!(
+                "writing to an image with {} internal snapshot(s)",
+                self.nb_snapshots
+            )));
+        }
+        if self.refcount_order < 3 {
+            return Err(Error::UnsupportedQcow2(format!(
+                "writing with {}-bit refcounts",
+                1u32 << self.refcount_order
+            )));
+        }
+        Ok(())
+    }
+
+    /// Cluster size in bytes
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Number of 8-byte entries in an L2 table
+    pub fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Refcount width in bits
+    pub fn refcount_bits(&self) -> u32 {
+        1 << self.refcount_order
+    }
+
+    /// Number of refcounts in a refcount block
+    pub fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size() * 8 / self.refcount_bits() as u64
+    }
+
+    /// L1 entries needed to map a virtual disk of `size` bytes
+    pub fn l1_entries_for(&self, size: u64) -> u64 {
+        size.div_ceil(self.cluster_size() * self.l2_entries())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(version: u32) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_READ_SIZE];
+        data[0..4].copy_from_slice(&MAGIC);
+        data[4..8].copy_from_slice(&version.to_be_bytes());
+        data[20..24].copy_from_slice(&16u32.to_be_bytes());
+        data[24..32].copy_from_slice(&(1u64 << 30).to_be_bytes());
+        data[36..40].copy_from_slice(&2u32.to_be_bytes());
+        data[40..48].copy_from_slice(&0x30000u64.to_be_bytes());
+        data[48..56].copy_from_slice(&0x10000u64.to_be_bytes());
+        data[56..60].copy_from_slice(&1u32.to_be_bytes());
+        data[96..100].copy_from_slice(&4u32.to_be_bytes());
+        data[100..104].copy_from_slice(&112u32.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let header = Qcow2Header::parse(&header_bytes(3)).unwrap();
+        assert_eq!(header.cluster_size(), 65536);
+        assert_eq!(header.size, 1 << 30);
+        assert_eq!(header.l1_entries_for(header.size), 2);
+        assert_eq!(header.refcount_block_entries(), 32768);
+        header.check_writable().unwrap();
+
+        let v2 = Qcow2Header::parse(&header_bytes(2)[..72]).unwrap();
+        assert_eq!(v2.refcount_order, 4);
+        assert_eq!(v2.header_length, 72);
+    }
+
+    #[test]
+    fn test_refused_headers() {
+        let mut backing = header_bytes(3);
+        backing[8..16].copy_from_slice(&0x200u64.to_be_bytes());
+        assert!(matches!(
+            Qcow2Header::parse(&backing),
+            Err(Error::UnsupportedQcow2(_))
+        ));
+
+        let mut small_l1 = header_bytes(3);
+        small_l1[36..40].copy_from_slice(&1u32.to_be_bytes());
+        assert!(matches!(
+            Qcow2Header::parse(&small_l1),
+            Err(Error::InvalidQcow2(_))
+        ));
+
+        let mut snapshots = header_bytes(3);
+        snapshots[60..64].copy_from_slice(&1u32.to_be_bytes());
+        let header = Qcow2Header::parse(&snapshots).unwrap();
+        assert!(header.check_writable().is_err());
+
+        assert!(Qcow2Header::parse(&[0u8; HEADER_READ_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,660 @@
+//! qcow2 image support
+//!
+//! Lets [`Device`](crate::device::Device) read and write the virtual disk
+//! inside a qcow2 image, so VM disk images can be resized without converting
+//! them to raw and back.
+//!
+//! Guest clusters are mapped through a two-level table: an L1 table points
+//! to L2 tables, whose entries give the host offset of each data cluster.
+//! Unallocated clusters read as zeros. Writing non-zero data to one appends
+//! a new cluster at the end of the file (all-zero writes are skipped), so the
+//! image stays thin. Every host cluster in use has a refcount, kept in
+//! refcount blocks listed by the refcount table.
+//!
+//! Metadata is written through to the file at once, and always after the
+//! data or table it points to, so an interrupted write at worst leaks a
+//! cluster. Images with backing files, encryption, compressed clusters,
+//! external data files or extended L2 entries are refused; internal
+//! snapshots only prevent writing.
+
+pub mod header;
+
+use crate::error::{Error, Result};
+use crate::system;
+use header::{
+    Qcow2Header, ENTRY_OFFSET_MASK, FLAG_COMPRESSED, FLAG_COPIED, FLAG_ZERO, HEADER_READ_SIZE,
+    MAGIC, OFFSET_AUTOCLEAR_FEATURES, OFFSET_L1_SIZE, OFFSET_REFCOUNT_TABLE, OFFSET_SIZE,
+    REFCOUNT_TABLE_OFFSET_MASK,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::{Mutex, MutexGuard};
+
+/// Check whether a file starts with the qcow2 magic
+pub fn is_qcow2(file: &File) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(magic == MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Where a guest cluster's data is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mapping {
+    /// No host cluster; reads as zeros
+    Unallocated,
+    /// Reads as zeros; the host cluster (if any) is preallocated
+    Zero(Option<u64>),
+    /// Host offset of the data, and whether it may be written in place
+    Data(u64, bool),
+}
+
+/// Mutable state of an open image
+struct State {
+    header: Qcow2Header,
+    l1: Vec<u64>,
+    /// L2 tables read so far, by host offset
+    l2_cache: HashMap<u64, Vec<u64>>,
+    refcount_table: Vec<u64>,
+    /// Where the next cluster is allocated
+    file_end: u64,
+    /// Whether anything was written since the image was opened
+    modified: bool,
+}
+
+/// A qcow2 image opened for reading, or reading and writing
+pub struct Qcow2Image {
+    file: File,
+    writable: bool,
+    state: Mutex<State>,
+}
+
+impl Qcow2Image {
+    /// Open the image in `file`, refusing features that cannot be supported
+    pub fn open(file: File, writable: bool) -> Result<Self> {
+        let file_len = file.metadata()?.len();
+        let mut data = vec![0u8; HEADER_READ_SIZE.min(file_len as usize)];
+        file.read_exact_at(&mut data, 0)?;
+        let header = Qcow2Header::parse(&data)?;
+        if writable {
+            header.check_writable()?;
+        }
+
+        let l1 = read_table(&file, header.l1_table_offset, header.l1_size as u64)?;
+        let refcount_entries = header.refcount_table_clusters as u64 * header.cluster_size() / 8;
+        let refcount_table = read_table(&file, header.refcount_table_offset, refcount_entries)?;
+        let file_end = file_len.next_multiple_of(header.cluster_size());
+
+        Ok(Self {
+            file,
+            writable,
+            state: Mutex::new(State {
+                header,
+                l1,
+                l2_cache: HashMap::new(),
+                refcount_table,
+                file_end,
+                modified: false,
+            }),
+        })
+    }
+
+    /// Virtual disk size in bytes
+    pub fn size(&self) -> u64 {
+        self.lock().header.size
+    }
+
+    /// Cluster size in bytes
+    pub fn cluster_size(&self) -> u64 {
+        self.lock().header.cluster_size()
+    }
+
+    /// Read from the virtual disk
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let mut state = self.lock();
+        check_range(&state.header, offset, buf.len())?;
+        let cluster_size = state.header.cluster_size();
+
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let in_cluster = position % cluster_size;
+            let len = ((cluster_size - in_cluster) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+            match self.lookup(&mut state, position / cluster_size)? {
+                Mapping::Data(host, _) => self.file.read_exact_at(chunk, host + in_cluster)?,
+                Mapping::Unallocated | Mapping::Zero(_) => chunk.fill(0),
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Write to the virtual disk, allocating clusters for non-zero data
+    pub fn write_all_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        if !self.writable {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "qcow2 image is open read-only",
+            )));
+        }
+        let mut state = self.lock();
+        check_range(&state.header, offset, data.len())?;
+        self.mark_modified(&mut state)?;
+        let cluster_size = state.header.cluster_size();
+
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let guest_cluster = position / cluster_size;
+            let in_cluster = position % cluster_size;
+            let len = ((cluster_size - in_cluster) as usize).min(data.len() - done);
+            let chunk = &data[done..done + len];
+            done += len;
+
+            let host = match self.lookup(&mut state, guest_cluster)? {
+                Mapping::Data(host, true) => {
+                    self.file.write_all_at(chunk, host + in_cluster)?;
+                    continue;
+                }
+                Mapping::Data(host, false) => {
+                    return Err(Error::UnsupportedQcow2(format!(
+                        "writing to shared cluster at {:#x}",
+                        host
+                    )));
+                }
+                // Zeros are what the cluster reads as already
+                Mapping::Unallocated | Mapping::Zero(_) if is_zero(chunk) => continue,
+                Mapping::Zero(Some(host)) => host,
+                Mapping::Unallocated | Mapping::Zero(None) => self.alloc_clusters(&mut state, 1)?,
+            };
+
+            // Fill the whole cluster, so no stale data becomes visible
+            let mut cluster = vec![0u8; cluster_size as usize];
+            cluster[in_cluster as usize..in_cluster as usize + len].copy_from_slice(chunk);
+            self.file.write_all_at(&cluster, host)?;
+            self.set_l2_entry(&mut state, guest_cluster, host | FLAG_COPIED)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether a range of the virtual disk has no clusters allocated
+    /// (or only clusters marked as reading zeros)
+    pub fn is_unallocated(&self, offset: u64, len: u64) -> Result<bool> {
+        let mut state = self.lock();
+        check_range(&state.header, offset, len as usize)?;
+        let cluster_size = state.header.cluster_size();
+        let first = offset / cluster_size;
+        let last = (offset + len).div_ceil(cluster_size);
+        for guest_cluster in first..last {
+            if let Mapping::Data(..) = self.lookup(&mut state, guest_cluster)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Make a range of the virtual disk read as zeros, freeing the clusters
+    /// it covers completely
+    ///
+    /// Freed clusters are punched out of the image file where the host
+    /// filesystem supports it; partly covered clusters are zeroed.
+    pub fn discard(&self, offset: u64, len: u64) -> Result<bool> {
+        let cluster_size = self.cluster_size();
+        let first = offset.div_ceil(cluster_size);
+        let end = (offset + len) / cluster_size;
+        if first >= end {
+            self.write_all_at(&vec![0u8; len as usize], offset)?;
+            return Ok(true);
+        }
+        let head = first * cluster_size - offset;
+        let tail = offset + len - end * cluster_size;
+        self.write_all_at(&vec![0u8; head as usize], offset)?;
+        self.write_all_at(&vec![0u8; tail as usize], end * cluster_size)?;
+
+        let mut state = self.lock();
+        self.mark_modified(&mut state)?;
+        for guest_cluster in first..end {
+            let host = match self.lookup(&mut state, guest_cluster)? {
+                Mapping::Data(host, true) | Mapping::Zero(Some(host)) => host,
+                Mapping::Unallocated | Mapping::Zero(None) => continue,
+                Mapping::Data(host, false) => {
+                    return Err(Error::UnsupportedQcow2(format!(
+                        "discarding shared cluster at {:#x}",
+                        host
+                    )));
+                }
+            };
+            // The entry goes first, so the cluster is never used while free
+            self.set_l2_entry(&mut state, guest_cluster, 0)?;
+            self.set_refcount(&mut state, host, 0)?;
+            system::punch_file_hole(&self.file, host, cluster_size)?;
+        }
+        Ok(true)
+    }
+
+    /// Flush all writes to disk
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Grow the virtual disk to `size` bytes
+    ///
+    /// The L1 table is extended in place when its last cluster has room, or
+    /// copied to the end of the file otherwise. The new space is unallocated.
+    pub fn set_virtual_size(&self, size: u64) -> Result<()> {
+        let mut state = self.lock();
+        let old_size = state.header.size;
+        if size < old_size {
+            return Err(Error::UnsupportedQcow2(format!(
+                "shrinking the virtual disk from {} to {} bytes",
+                old_size, size
+            )));
+        }
+        if size == old_size {
+            return Ok(());
+        }
+        self.mark_modified(&mut state)?;
+
+        let cluster_size = state.header.cluster_size();
+        let l1_entries = state.header.l1_entries_for(size);
+        let l1_clusters = |entries: u64| (entries * 8).div_ceil(cluster_size);
+        if l1_entries > state.l1.len() as u64 {
+            let old_offset = state.header.l1_table_offset;
+            let old_clusters = l1_clusters(state.l1.len() as u64);
+            let old_len = state.l1.len();
+            state.l1.resize(l1_entries as usize, 0);
+
+            let new_offset = if l1_clusters(l1_entries) <= old_clusters {
+                self.file.write_all_at(
+                    &table_bytes(&state.l1[old_len..]),
+                    old_offset + old_len as u64 * 8,
+                )?;
+                old_offset
+            } else {
+                let new_offset = self.alloc_clusters(&mut state, l1_clusters(l1_entries))?;
+                self.file
+                    .write_all_at(&table_bytes(&state.l1), new_offset)?;
+                new_offset
+            };
+            self.file.sync_all()?;
+
+            let mut fields = Vec::with_capacity(12);
+            fields.extend_from_slice(&(l1_entries as u32).to_be_bytes());
+            fields.extend_from_slice(&new_offset.to_be_bytes());
+            self.file.write_all_at(&fields, OFFSET_L1_SIZE)?;
+            state.header.l1_size = l1_entries as u32;
+            state.header.l1_table_offset = new_offset;
+
+            if new_offset != old_offset {
+                self.file.sync_all()?;
+                for i in 0..old_clusters {
+                    self.set_refcount(&mut state, old_offset + i * cluster_size, 0)?;
+                }
+            }
+        }
+
+        self.file.write_all_at(&size.to_be_bytes(), OFFSET_SIZE)?;
+        state.header.size = size;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Clear autoclear feature bits before the first change, as required of
+    /// writers that do not know them
+    fn mark_modified(&self, state: &mut State) -> Result<()> {
+        if !state.modified {
+            if state.header.autoclear_features != 0 {
+                self.file
+                    .write_all_at(&0u64.to_be_bytes(), OFFSET_AUTOCLEAR_FEATURES)?;
+                state.header.autoclear_features = 0;
+            }
+            state.modified = true;
+        }
+        Ok(())
+    }
+
+    /// Find where a guest cluster's data is
+    fn lookup(&self, state: &mut State, guest_cluster: u64) -> Result<Mapping> {
+        let l2_entries = state.header.l2_entries();
+        let l1_entry = state.l1[(guest_cluster / l2_entries) as usize];
+        let l2_offset = l1_entry & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.l2_table(state, l2_offset)?[(guest_cluster % l2_entries) as usize];
+
+        if entry & FLAG_COMPRESSED != 0 {
+            return Err(Error::UnsupportedQcow2(
+                "compressed clusters; run 'qemu-img convert' without -c first".to_string(),
+            ));
+        }
+        let host = entry & ENTRY_OFFSET_MASK;
+        let in_place = entry & FLAG_COPIED != 0;
+        Ok(if state.header.version >= 3 && entry & FLAG_ZERO != 0 {
+            Mapping::Zero((host != 0 && in_place).then_some(host))
+        } else if host == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data(host, in_place)
+        })
+    }
+
+    /// Get an L2 table, reading it on first use
+    fn l2_table<'a>(&self, state: &'a mut State, offset: u64) -> Result<&'a mut Vec<u64>> {
+        let entries = state.header.l2_entries();
+        Ok(match state.l2_cache.entry(offset) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(read_table(&self.file, offset, entries)?),
+        })
+    }
+
+    /// Point a guest cluster at `entry`, allocating its L2 table if needed
+    fn set_l2_entry(&self, state: &mut State, guest_cluster: u64, entry: u64) -> Result<()> {
+        let l2_entries = state.header.l2_entries();
+        let l1_index = (guest_cluster / l2_entries) as usize;
+        let mut l2_offset = state.l1[l1_index] & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.alloc_clusters(state, 1)?;
+            self.file
+                .write_all_at(&vec![0u8; state.header.cluster_size() as usize], l2_offset)?;
+            state
+                .l2_cache
+                .insert(l2_offset, vec![0; l2_entries as usize]);
+
+            let l1_entry = l2_offset | FLAG_COPIED;
+            let l1_entry_offset = state.header.l1_table_offset + l1_index as u64 * 8;
+            self.file
+                .write_all_at(&l1_entry.to_be_bytes(), l1_entry_offset)?;
+            state.l1[l1_index] = l1_entry;
+        } else if state.l1[l1_index] & FLAG_COPIED == 0 {
+            return Err(Error::UnsupportedQcow2(format!(
+                "writing to shared L2 table at {:#x}",
+                l2_offset
+            )));
+        }
+
+        let l2_index = guest_cluster % l2_entries;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index * 8)?;
+        self.l2_table(state, l2_offset)?[l2_index as usize] = entry;
+        Ok(())
+    }
+
+    /// Allocate `count` contiguous clusters at the end of the file
+    fn alloc_clusters(&self, state: &mut State, count: u64) -> Result<u64> {
+        let cluster_size = state.header.cluster_size();
+        let offset = state.file_end;
+        state.file_end += count * cluster_size;
+        for i in 0..count {
+            self.set_refcount(state, offset + i * cluster_size, 1)?;
+        }
+        Ok(offset)
+    }
+
+    /// Set the refcount of the host cluster at `offset`, allocating a
+    /// refcount block (and growing the refcount table) if needed
+    fn set_refcount(&self, state: &mut State, offset: u64, value: u64) -> Result<()> {
+        let cluster_size = state.header.cluster_size();
+        let cluster = offset / cluster_size;
+        let block_entries = state.header.refcount_block_entries();
+        let table_index = (cluster / block_entries) as usize;
+        if table_index >= state.refcount_table.len() {
+            self.grow_refcount_table(state, table_index + 1)?;
+        }
+
+        let mut block = state.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block == 0 {
+            block = state.file_end;
+            state.file_end += cluster_size;
+            self.file
+                .write_all_at(&vec![0u8; cluster_size as usize], block)?;
+            let entry_offset = state.header.refcount_table_offset + table_index as u64 * 8;
+            self.file.write_all_at(&block.to_be_bytes(), entry_offset)?;
+            state.refcount_table[table_index] = block;
+            // The new block counts itself if it falls in its own range
+            self.set_refcount(state, block, 1)?;
+        }
+
+        let width = state.header.refcount_bits() as u64 / 8;
+        let bytes = value.to_be_bytes();
+        self.file.write_all_at(
+            &bytes[(8 - width) as usize..],
+            block + (cluster % block_entries) * width,
+        )?;
+        Ok(())
+    }
+
+    /// Copy the refcount table to a larger one at the end of the file
+    fn grow_refcount_table(&self, state: &mut State, min_entries: usize) -> Result<()> {
+        let cluster_size = state.header.cluster_size();
+        let entries = min_entries.max(state.refcount_table.len() * 2);
+        let clusters = (entries as u64 * 8).div_ceil(cluster_size);
+        let old_offset = state.header.refcount_table_offset;
+        let old_clusters = state.header.refcount_table_clusters as u64;
+
+        let new_offset = state.file_end;
+        state.file_end += clusters * cluster_size;
+        let mut table = state.refcount_table.clone();
+        table.resize((clusters * cluster_size / 8) as usize, 0);
+        self.file.write_all_at(&table_bytes(&table), new_offset)?;
+        self.file.sync_all()?;
+
+        let mut fields = Vec::with_capacity(12);
+        fields.extend_from_slice(&new_offset.to_be_bytes());
+        fields.extend_from_slice(&(clusters as u32).to_be_bytes());
+        self.file.write_all_at(&fields, OFFSET_REFCOUNT_TABLE)?;
+        state.header.refcount_table_offset = new_offset;
+        state.header.refcount_table_clusters = clusters as u32;
+        state.refcount_table = table;
+
+        for i in 0..clusters {
+            self.set_refcount(state, new_offset + i * cluster_size, 1)?;
+        }
+        for i in 0..old_clusters {
+            self.set_refcount(state, old_offset + i * cluster_size, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fail for I/O past the end of the virtual disk
+fn check_range(header: &Qcow2Header, offset: u64, len: usize) -> Result<()> {
+    if offset.saturating_add(len as u64) > header.size {
+        return Err(Error::Io(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!(
+                "Access to {} bytes at offset {} is past the end of the virtual disk ({} bytes)",
+                len, offset, header.size
+            ),
+        )));
+    }
+    Ok(())
+}
+
+/// Read a table of big-endian 64-bit entries
+fn read_table(file: &File, offset: u64, entries: u64) -> Result<Vec<u64>> {
+    let mut data = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut data, offset).map_err(|_| {
+        Error::InvalidQcow2(format!(
+            "table at {:#x} is past the end of the file",
+            offset
+        ))
+    })?;
+    Ok(data
+        .chunks_exact(8)
+        .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// Encode a table of big-endian 64-bit entries
+fn table_bytes(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|entry| entry.to_be_bytes()).collect()
+}
+
+fn is_zero(data: &[u8]) -> bool {
+    data.iter().all(|&b| b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::Device;
+    use crate::image::grow_image;
+    use crate::partition::VolumeLocation;
+    use crate::resize::{get_fs_info, resize_fat32, ResizeOptions, TargetSize};
+    use crate::test_image::{
+        assert_consistent, pattern, read_root_file, ImageSpec, Qcow2Disk, TestImage,
+    };
+
+    #[test]
+    fn test_read_write_allocates_clusters() {
+        let disk = Qcow2Disk::create(1 << 20, 9);
+        let empty_size = disk.file_size();
+        let device = Device::open(disk.path()).unwrap();
+        assert!(device.is_qcow2());
+        assert_eq!(device.size_bytes().unwrap(), 1 << 20);
+        assert_eq!(device.read_bytes_at(4096, 1024).unwrap(), vec![0u8; 1024]);
+
+        // Zeros need no clusters
+        device.write_bytes_at(0, &[0u8; 4096]).unwrap();
+        assert_eq!(disk.file_size(), empty_size);
+
+        // Unaligned writes spanning clusters and L2 tables
+        let data = pattern(1, 70_000);
+        device.write_bytes_at(1000, &data).unwrap();
+        device.write_bytes_at(900_000, &pattern(2, 300)).unwrap();
+        device.write_bytes_at(1100, &[0u8; 100]).unwrap();
+        device.sync().unwrap();
+        disk.assert_consistent();
+        assert!(disk.file_size() < 100_000);
+        assert!(device.write_bytes_at((1 << 20) - 10, &[1u8; 20]).is_err());
+
+        let device = Device::open_readonly(disk.path()).unwrap();
+        let mut expected = data.clone();
+        expected[100..200].fill(0);
+        assert_eq!(device.read_bytes_at(1000, 70_000).unwrap(), expected);
+        assert_eq!(device.read_bytes_at(900_000, 300).unwrap(), pattern(2, 300));
+        assert_eq!(device.read_bytes_at(0, 1000).unwrap(), vec![0u8; 1000]);
+        assert!(device.write_bytes_at(0, &[1u8; 512]).is_err());
+    }
+
+    #[test]
+    fn test_discard_frees_clusters() {
+        let disk = Qcow2Disk::create(1 << 20, 9);
+        let device = Device::open(disk.path()).unwrap();
+        device.write_bytes_at(0, &pattern(6, 8192)).unwrap();
+        assert!(!device.is_hole(1024, 2048).unwrap());
+
+        // Whole clusters are freed, the partly covered ones zeroed
+        assert!(device.punch_hole(1000, 3000).unwrap());
+        assert!(device.is_hole(1024, 2048).unwrap());
+        assert!(!device.is_hole(512, 1024).unwrap());
+        let mut expected = pattern(6, 8192);
+        expected[1000..4000].fill(0);
+        assert_eq!(device.read_bytes_at(0, 8192).unwrap(), expected);
+        disk.assert_consistent();
+    }
+
+    #[test]
+    fn test_refcount_table_grows() {
+        // 512-byte clusters: a refcount block covers 256 clusters and the
+        // one-cluster refcount table 64 blocks, i.e. 8 MiB of file
+        let disk = Qcow2Disk::create(16 << 20, 9);
+        let device = Device::open(disk.path()).unwrap();
+        let data = pattern(3, 10 << 20);
+        device.write_bytes_at(0, &data).unwrap();
+        disk.assert_consistent();
+
+        let device = Device::open_readonly(disk.path()).unwrap();
+        assert_eq!(device.read_bytes_at(0, 10 << 20).unwrap(), data);
+    }
+
+    #[test]
+    fn test_grow_virtual_size() {
+        let disk = Qcow2Disk::create(64 << 10, 9);
+        let device = Device::open(disk.path()).unwrap();
+        device.write_bytes_at(60_000, &pattern(4, 5000)).unwrap();
+
+        // Still fits the L1 table's cluster, then needs a larger one
+        for size in [2 << 20, 8 << 20] {
+            device.grow_image_to(size).unwrap();
+            disk.assert_consistent();
+            let device = Device::open(disk.path()).unwrap();
+            assert_eq!(device.size_bytes().unwrap(), size);
+            assert_eq!(
+                device.read_bytes_at(60_000, 5000).unwrap(),
+                pattern(4, 5000)
+            );
+            device.write_bytes_at(size - 512, &[7u8; 512]).unwrap();
+            assert_eq!(device.read_bytes_at(size - 512, 512).unwrap(), [7u8; 512]);
+        }
+        assert!(device.grow_image_to(1 << 20).is_err());
+        disk.assert_consistent();
+    }
+
+    #[test]
+    fn test_resize_fat32_in_qcow2() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let file = pattern(5, 100_000);
+        image.add_file(b"FILE    BIN", &file, 1);
+        let disk = Qcow2Disk::from_raw(image.path(), 16);
+
+        let growth = grow_image(
+            disk.path(),
+            TargetSize::Absolute(100 << 20),
+            VolumeLocation::Whole,
+            false,
+        )
+        .unwrap();
+        assert!(growth.grew());
+        assert!(get_fs_info(disk.path()).unwrap().can_grow);
+
+        let result = resize_fat32(ResizeOptions::new(disk.path())).unwrap();
+        assert_eq!(result.new_size_bytes, 100 << 20);
+        disk.assert_consistent();
+        // Only the metadata written for the grown space was allocated
+        assert!(disk.file_size() < 10 << 20);
+
+        let raw = disk.to_raw();
+        assert_consistent(raw.path());
+        assert_eq!(read_root_file(raw.path(), b"FILE    BIN").unwrap(), file);
+    }
+
+    #[test]
+    fn test_refused_clusters() {
+        let disk = Qcow2Disk::create(1 << 20, 9);
+        let device = Device::open(disk.path()).unwrap();
+        device.write_bytes_at(0, &[1u8; 512]).unwrap();
+        drop(device);
+
+        // Mark the data cluster compressed
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(disk.path())
+            .unwrap();
+        let mut l1_entry = [0u8; 8];
+        file.read_exact_at(&mut l1_entry, 3 * 512).unwrap();
+        let l2_offset = u64::from_be_bytes(l1_entry) & ENTRY_OFFSET_MASK;
+        let mut entry = [0u8; 8];
+        file.read_exact_at(&mut entry, l2_offset).unwrap();
+        let entry = u64::from_be_bytes(entry) | FLAG_COMPRESSED;
+        file.write_all_at(&entry.to_be_bytes(), l2_offset).unwrap();
+
+        let device = Device::open_readonly(disk.path()).unwrap();
+        assert!(matches!(
+            device.read_bytes_at(0, 512),
+            Err(Error::UnsupportedQcow2(_))
+        ));
+    }
+}
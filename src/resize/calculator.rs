@@ -1,5 +1,255 @@
 use crate::error::{Error, Result};
-use crate::fat32::BootSector;
+use crate::fat32::{BootSector, FatType};
+use std::cmp::Ordering;
+
+/// Maximum number of data clusters a FAT32 filesystem can address
+pub const FAT32_MAX_CLUSTERS: u32 = 0x0FFFFFF5;
+
+/// Minimum number of data clusters for a filesystem to be FAT32
+pub const FAT32_MIN_CLUSTERS: u32 = 65525;
+
+/// Fewest reserved sectors of a FAT12/16 filesystem after conversion to FAT32,
+/// the usual FAT32 value with room for FSInfo and the backup boot sector
+pub const FAT32_RESERVED_SECTORS: u16 = 32;
+
+/// Smallest alignment [`Alignment::Auto`] picks, the usual partition alignment
+pub const AUTO_ALIGN_MIN_BYTES: u64 = 1 << 20;
+
+/// Parse a size string such as `512`, `64K`, `8G` or `1.5T` into bytes
+///
+/// Suffixes are binary (K = 1024) and case-insensitive; an optional trailing
+/// `B` or `iB` is accepted (`8GiB`, `8GB`). A plain number is taken as bytes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let trimmed = s.trim();
+    let invalid = || Error::InvalidSize(s.to_string());
+
+    let upper = trimmed.to_ascii_uppercase();
+    let without_b = upper
+        .strip_suffix("IB")
+        .or_else(|| upper.strip_suffix('B'))
+        .unwrap_or(&upper);
+
+    let (number, multiplier) = match without_b.chars().last() {
+        Some('K') => (&without_b[..without_b.len() - 1], 1u64 << 10),
+        Some('M') => (&without_b[..without_b.len() - 1], 1u64 << 20),
+        Some('G') => (&without_b[..without_b.len() - 1], 1u64 << 30),
+        Some('T') => (&without_b[..without_b.len() - 1], 1u64 << 40),
+        Some(_) => (without_b, 1u64),
+        None => return Err(invalid()),
+    };
+
+    if let Ok(value) = number.parse::<u64>() {
+        return value.checked_mul(multiplier).ok_or_else(invalid);
+    }
+
+    // Allow fractional values with a suffix, e.g. "1.5G"
+    let value: f64 = number.parse().map_err(|_| invalid())?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(invalid());
+    }
+    let bytes = value * multiplier as f64;
+    if bytes > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// Requested size of the filesystem after a resize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TargetSize {
+    /// Absolute filesystem size in bytes (e.g. `8G`)
+    Absolute(u64),
+    /// Number of bytes to add to the current filesystem size (e.g. `+2G`)
+    Relative(u64),
+}
+
+impl TargetSize {
+    /// Resolve the target to a sector count for a filesystem of `current_sectors`
+    ///
+    /// Byte counts that are not a whole number of sectors are rounded down.
+    pub fn to_sectors(self, current_sectors: u64, bytes_per_sector: u16) -> u64 {
+        match self {
+            Self::Absolute(bytes) => bytes / bytes_per_sector as u64,
+            Self::Relative(bytes) => {
+                current_sectors.saturating_add(bytes / bytes_per_sector as u64)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for TargetSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().strip_prefix('+') {
+            Some(rest) => Ok(Self::Relative(parse_size(rest)?)),
+            None => Ok(Self::Absolute(parse_size(s)?)),
+        }
+    }
+}
+
+impl std::fmt::Display for TargetSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Absolute(bytes) => write!(f, "{} bytes", bytes),
+            Self::Relative(bytes) => write!(f, "+{} bytes", bytes),
+        }
+    }
+}
+
+/// FAT capacity to allocate beyond what the new size needs
+///
+/// A FAT sized for a larger filesystem lets later grows up to that size
+/// update only the metadata, without moving any data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatReserve {
+    /// Size the FAT for a filesystem of this many bytes (e.g. `64G`)
+    UpTo(u64),
+    /// Size the FAT for a filesystem this many percent larger than the new size (e.g. `50%`)
+    Percent(u32),
+}
+
+impl FatReserve {
+    /// Resolve to the filesystem size in sectors the FAT must cover, for a new
+    /// size of `new_sectors`
+    pub fn to_sectors(self, new_sectors: u64, bytes_per_sector: u16) -> u64 {
+        match self {
+            Self::UpTo(bytes) => bytes / bytes_per_sector as u64,
+            Self::Percent(percent) => {
+                new_sectors.saturating_add(new_sectors.saturating_mul(percent as u64) / 100)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for FatReserve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().strip_suffix('%') {
+            Some(percent) => percent
+                .trim()
+                .parse()
+                .map(Self::Percent)
+                .map_err(|_| Error::InvalidFatReserve(s.to_string())),
+            None => parse_size(s)
+                .map(Self::UpTo)
+                .map_err(|_| Error::InvalidFatReserve(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for FatReserve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UpTo(bytes) => write!(f, "{} bytes", bytes),
+            Self::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// Boundary the data area should start on when the FAT grows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// Align to this many bytes (e.g. `4M`)
+    Bytes(u64),
+    /// Align to what the device reports (physical block, I/O and erase block
+    /// sizes), but at least [`AUTO_ALIGN_MIN_BYTES`]
+    Auto,
+}
+
+impl std::str::FromStr for Alignment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().eq_ignore_ascii_case("auto") {
+            Ok(Self::Auto)
+        } else {
+            Ok(Self::Bytes(parse_size(s)?))
+        }
+    }
+}
+
+impl std::fmt::Display for Alignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{} bytes", bytes),
+            Self::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Optional constraints for [`calculate_new_size_with_options`]
+#[derive(Debug, Clone, Default)]
+pub struct CalculationOptions {
+    target_size: Option<TargetSize>,
+    fat_reserve: Option<FatReserve>,
+    align: Option<u64>,
+    num_fats: Option<u8>,
+    convert_to_fat32: bool,
+}
+
+impl CalculationOptions {
+    /// Create options that grow the filesystem to fill the device
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grow to the given size instead of filling the device
+    pub fn target_size(mut self, target: Option<TargetSize>) -> Self {
+        self.target_size = target;
+        self
+    }
+
+    /// Get the requested target size, if any
+    pub fn get_target_size(&self) -> Option<TargetSize> {
+        self.target_size
+    }
+
+    /// Make the FAT large enough for a future size, not just the new one
+    pub fn fat_reserve(mut self, reserve: Option<FatReserve>) -> Self {
+        self.fat_reserve = reserve;
+        self
+    }
+
+    /// Get the requested FAT reserve, if any
+    pub fn get_fat_reserve(&self) -> Option<FatReserve> {
+        self.fat_reserve
+    }
+
+    /// Start the data area on a multiple of this many bytes when the FAT grows
+    pub fn align(mut self, bytes: Option<u64>) -> Self {
+        self.align = bytes;
+        self
+    }
+
+    /// Get the requested data area alignment in bytes, if any
+    pub fn get_align(&self) -> Option<u64> {
+        self.align
+    }
+
+    /// Change the number of FAT copies (1 or 2)
+    pub fn num_fats(mut self, num_fats: Option<u8>) -> Self {
+        self.num_fats = num_fats;
+        self
+    }
+
+    /// Get the requested number of FAT copies, if any
+    pub fn get_num_fats(&self) -> Option<u8> {
+        self.num_fats
+    }
+
+    /// Convert a FAT12/16 filesystem to FAT32 while growing it
+    pub fn convert_to_fat32(mut self, enable: bool) -> Self {
+        self.convert_to_fat32 = enable;
+        self
+    }
+
+    /// Check if conversion to FAT32 is requested
+    pub fn is_fat32_conversion(&self) -> bool {
+        self.convert_to_fat32
+    }
+}
 
 /// Result of size calculations for a resize operation
 #[derive(Debug, Clone)]
@@ -12,11 +262,13 @@ pub struct SizeCalculation {
     pub old_fat_size: u32,
     /// New FAT size in sectors
     pub new_fat_size: u32,
+    /// Number of FAT copies after resize
+    pub new_num_fats: u8,
     /// Number of data clusters after resize
     pub new_data_clusters: u32,
     /// Number of free clusters after resize
     pub new_free_clusters: u32,
-    /// Whether FAT tables need to grow
+    /// Whether FAT tables need to grow (a larger FAT or a different number of copies)
     pub fat_needs_growth: bool,
     /// Number of sectors the FAT grows by
     pub fat_growth_sectors: u32,
@@ -24,6 +276,33 @@ pub struct SizeCalculation {
     pub first_affected_cluster: u32,
     /// Last cluster that would be overwritten if FAT grows
     pub last_affected_cluster: u32,
+    /// FAT32 limit that stopped the filesystem short of filling the device
+    pub size_limit: Option<SizeLimit>,
+    /// FAT type after resize (FAT32 when a FAT12/16 filesystem is converted)
+    pub new_fat_type: FatType,
+}
+
+/// FAT limit on the size of a filesystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    /// The total sector count is a 32-bit number
+    SectorCount,
+    /// At most [`FatType::max_clusters`] data clusters for the filesystem's FAT type
+    ClusterCount(FatType),
+}
+
+impl std::fmt::Display for SizeLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SectorCount => write!(f, "FAT maximum of {} sectors", u32::MAX),
+            Self::ClusterCount(fat_type) => write!(
+                f,
+                "{} maximum of {} clusters",
+                fat_type,
+                fat_type.max_clusters()
+            ),
+        }
+    }
 }
 
 impl SizeCalculation {
@@ -44,72 +323,144 @@ impl SizeCalculation {
     }
 }
 
-/// Calculate the new size parameters for a resize operation
+/// Calculate the new size parameters for growing the filesystem to fill the device
 pub fn calculate_new_size(boot: &BootSector, device_sectors: u64) -> Result<SizeCalculation> {
+    calculate_new_size_with_options(boot, device_sectors, &CalculationOptions::default())
+}
+
+/// Calculate the new size parameters for a resize operation
+///
+/// Without a target size the filesystem grows to fill the device, or as far as
+/// the FAT32 limits allow (see `size_limit`). An explicit target is checked
+/// against the device size and the FAT32 limits, and rounded down so the data
+/// area ends on a cluster boundary. The FAT never shrinks, and with a FAT
+/// reserve it is sized for the larger future size. With an alignment, a
+/// growing FAT is padded until the data area starts on a multiple of the
+/// alignment. With a different number of FAT copies, the data area moves
+/// forward for an added copy, and back into the sectors of a dropped one, so
+/// the filesystem gains their clusters.
+/// FAT12 and FAT16 filesystems keep their type, so they grow at most to the
+/// cluster limit of that type, unless they are converted to FAT32 (see
+/// [`CalculationOptions::convert_to_fat32`]).
+pub fn calculate_new_size_with_options(
+    boot: &BootSector,
+    device_sectors: u64,
+    options: &CalculationOptions,
+) -> Result<SizeCalculation> {
+    if options.convert_to_fat32 && boot.fat_type() != FatType::Fat32 {
+        return calculate_conversion_size(boot, device_sectors, options);
+    }
+
     let old_total_sectors = boot.total_sectors();
     let old_fat_size = boot.fat_size();
     let old_data_clusters = boot.data_clusters();
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    let old_num_fats = boot.num_fats() as u32;
+    let new_num_fats = options.num_fats.unwrap_or(boot.num_fats());
+    if !(1..=2).contains(&new_num_fats) {
+        return Err(Error::InvalidFatCount(new_num_fats));
+    }
+    let num_fats = new_num_fats as u32;
+    let fat_type = boot.fat_type();
+    let max_clusters = fat_type.max_clusters();
+    let root_dir_sectors = boot.root_dir_sectors() as u32;
 
-    // New total sectors is the device size (in sectors)
-    // Cap at u32::MAX since FAT32 uses 32-bit sector counts
-    let new_total_sectors = if device_sectors > u32::MAX as u64 {
-        return Err(Error::Calculation(format!(
-            "Device size {} sectors exceeds FAT32 maximum",
-            device_sectors
-        )));
-    } else {
-        device_sectors as u32
-    };
+    let (mut new_total_sectors, mut size_limit) =
+        requested_total_sectors(boot, device_sectors, options)?;
 
-    // Check for shrink (not supported)
-    if new_total_sectors < old_total_sectors {
-        return Err(Error::ShrinkNotSupported);
-    }
+    // Calculate the new FAT size, and cap the size at the cluster limit of the
+    // FAT type when filling the device. A smaller size never needs a larger FAT,
+    // so this settles after a few rounds.
+    let (new_fat_size, first_data_sector, new_data_clusters) = loop {
+        let new_fat_size = grown_fat_size(boot, new_total_sectors, options)?;
+        let first_data_sector =
+            boot.reserved_sectors() as u32 + num_fats * new_fat_size + root_dir_sectors;
+        let new_data_sectors = new_total_sectors
+            .checked_sub(first_data_sector)
+            .ok_or_else(|| {
+                Error::Calculation(format!(
+                    "Size {} sectors is too small for the FAT tables",
+                    new_total_sectors
+                ))
+            })?;
+        let new_data_clusters = new_data_sectors / sectors_per_cluster;
+
+        if options.target_size.is_none() && new_data_clusters > max_clusters {
+            new_total_sectors = first_data_sector + max_clusters * sectors_per_cluster;
+            size_limit = Some(SizeLimit::ClusterCount(fat_type));
+            continue;
+        }
+        break (new_fat_size, first_data_sector, new_data_clusters);
+    };
 
     // Check if already at max size
-    if new_total_sectors == old_total_sectors {
+    if new_total_sectors <= old_total_sectors {
         return Err(Error::AlreadyMaxSize);
     }
 
-    // Calculate new FAT size
-    let new_fat_size = calculate_fat_size(
-        new_total_sectors,
-        boot.reserved_sectors(),
-        boot.num_fats(),
-        boot.sectors_per_cluster(),
-        boot.bytes_per_sector(),
-    )?;
+    // An explicit target ends on a cluster boundary so no partial cluster is left over
+    let new_total_sectors = if options.target_size.is_some() {
+        let aligned = first_data_sector + new_data_clusters * sectors_per_cluster;
+        if aligned <= old_total_sectors || new_data_clusters <= old_data_clusters {
+            return Err(Error::Calculation(format!(
+                "Target size {} sectors does not add a whole cluster to the filesystem",
+                new_total_sectors
+            )));
+        }
+        aligned
+    } else {
+        new_total_sectors
+    };
 
-    // Calculate new data clusters
-    let new_data_sectors = new_total_sectors
-        - boot.reserved_sectors() as u32
-        - (boot.num_fats() as u32 * new_fat_size);
-    let new_data_clusters = new_data_sectors / boot.sectors_per_cluster() as u32;
+    // Verify the FAT type does not change (FAT32 needs >= 65525 clusters)
+    if new_data_clusters < fat_type.min_clusters() {
+        return Err(Error::Calculation(format!(
+            "New cluster count {} would not be {}",
+            new_data_clusters, fat_type
+        )));
+    }
 
-    // Verify we still have FAT32 (>= 65525 clusters)
-    if new_data_clusters < 65525 {
+    // Cluster numbers are 12, 16 or 28 bits wide, with the top values reserved
+    if new_data_clusters > max_clusters {
         return Err(Error::Calculation(format!(
-            "New cluster count {} would not be FAT32",
-            new_data_clusters
+            "New cluster count {} exceeds {} maximum of {}",
+            new_data_clusters, fat_type, max_clusters
         )));
     }
 
-    // Check if FAT needs to grow
-    let fat_needs_growth = new_fat_size > old_fat_size;
+    // Check if FAT needs to grow; the FAT tables take less space than before
+    // only when a copy is dropped, and then the data area moves back
+    let total_growth = (num_fats * new_fat_size).saturating_sub(old_num_fats * old_fat_size);
+    let fat_needs_growth = new_fat_size > old_fat_size || num_fats != old_num_fats;
     let fat_growth_sectors = new_fat_size.saturating_sub(old_fat_size);
 
-    // Calculate which clusters would be affected by FAT growth
-    let (first_affected_cluster, last_affected_cluster) = if fat_needs_growth {
-        // FAT growth affects the data area right after the current FAT tables
-        // The first data sector moves forward by (fat_growth_sectors * num_fats)
-        let growth_per_fat = fat_growth_sectors;
-        let total_growth = growth_per_fat * boot.num_fats() as u32;
+    // The resize checkpoint lives in the last sector of the new filesystem, which
+    // must not be reached by shifted cluster data
+    if fat_needs_growth && new_data_clusters <= old_data_clusters {
+        return Err(Error::Calculation(
+            "FAT growth would leave no room for additional clusters".to_string(),
+        ));
+    }
 
-        // These are the clusters that currently occupy the space where
-        // the new FAT sectors will go
-        let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    // A fixed root directory is staged just below the checkpoint while it
+    // moves, so that area must lie beyond the shifted cluster data
+    let old_first_data_sector = boot.first_data_sector() as u32;
+    if root_dir_sectors > 0 && first_data_sector != old_first_data_sector {
+        let shifted_end =
+            first_data_sector.max(old_first_data_sector) + old_data_clusters * sectors_per_cluster;
+        if new_total_sectors - 1 - root_dir_sectors < shifted_end {
+            return Err(Error::Calculation(
+                "Growth leaves no room to stage the root directory".to_string(),
+            ));
+        }
+    }
 
-        // Number of clusters that will be overwritten
+    // Calculate which clusters would be affected by FAT growth
+    let (first_affected_cluster, last_affected_cluster) = if total_growth > 0 {
+        // FAT growth affects the data area right after the current FAT tables,
+        // whose first data sector moves forward by total_growth
+        // Number of clusters that will be overwritten (exact, since
+        // `FatGrowth::pad` grows the FAT tables by whole clusters)
         let affected_clusters = total_growth.div_ceil(sectors_per_cluster);
 
         // First affected cluster is cluster 2 (the first data cluster)
@@ -131,15 +482,665 @@ pub fn calculate_new_size(boot: &BootSector, device_sectors: u64) -> Result<Size
         new_total_sectors,
         old_fat_size,
         new_fat_size,
+        new_num_fats,
         new_data_clusters,
         new_free_clusters,
         fat_needs_growth,
         fat_growth_sectors,
         first_affected_cluster,
         last_affected_cluster,
+        size_limit,
+        new_fat_type: fat_type,
     })
 }
 
+/// Requested size in sectors: either the target or the whole device
+///
+/// FAT uses 32-bit sector counts. When filling the device, this is capped at
+/// the largest legal size (reported as the size limit) instead of failing; an
+/// explicit target must fit.
+fn requested_total_sectors(
+    boot: &BootSector,
+    device_sectors: u64,
+    options: &CalculationOptions,
+) -> Result<(u32, Option<SizeLimit>)> {
+    let old_total_sectors = boot.total_sectors();
+    let requested_sectors = match options.target_size {
+        Some(target) => {
+            let target_sectors =
+                target.to_sectors(old_total_sectors as u64, boot.bytes_per_sector());
+            if target_sectors > device_sectors {
+                return Err(Error::TargetTooLarge {
+                    target: target_sectors,
+                    available: device_sectors,
+                });
+            }
+            target_sectors
+        }
+        None => device_sectors,
+    };
+
+    let (new_total_sectors, size_limit) = if requested_sectors > u32::MAX as u64 {
+        if options.target_size.is_some() {
+            return Err(Error::Calculation(format!(
+                "Size {} sectors exceeds FAT32 maximum",
+                requested_sectors
+            )));
+        }
+        (u32::MAX, Some(SizeLimit::SectorCount))
+    } else {
+        (requested_sectors as u32, None)
+    };
+
+    // Check for shrink (not supported)
+    if new_total_sectors < old_total_sectors {
+        return Err(Error::ShrinkNotSupported);
+    }
+
+    Ok((new_total_sectors, size_limit))
+}
+
+/// FAT size for growing the filesystem to `new_total_sectors`
+///
+/// Applies the FAT reserve, never shrinks the FAT or the space all copies take
+/// together, and pads the growth to whole clusters and to the requested
+/// alignment.
+fn grown_fat_size(
+    boot: &BootSector,
+    new_total_sectors: u32,
+    options: &CalculationOptions,
+) -> Result<u32> {
+    let old_fat_size = boot.fat_size();
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    let new_num_fats = options.num_fats.unwrap_or(boot.num_fats());
+    let num_fats = new_num_fats as u32;
+    let old_fats_sectors = boot.num_fats() as u32 * old_fat_size;
+    let fat_type = boot.fat_type();
+    let root_dir_sectors = boot.root_dir_sectors();
+    let fat_size_for = |total_sectors: u32| match fat_type {
+        FatType::Fat32 => calculate_fat_size(
+            total_sectors,
+            boot.reserved_sectors(),
+            new_num_fats,
+            boot.sectors_per_cluster(),
+            boot.bytes_per_sector(),
+        ),
+        _ => calculate_small_fat_size(
+            fat_type,
+            total_sectors,
+            boot.reserved_sectors(),
+            new_num_fats,
+            root_dir_sectors as u32,
+            boot.sectors_per_cluster(),
+            boot.bytes_per_sector(),
+        ),
+    };
+
+    // Calculate new FAT size
+    let mut new_fat_size = fat_size_for(new_total_sectors)?;
+
+    // Size the FAT for a future size, but never beyond what the FAT type can address
+    if let Some(reserve) = options.fat_reserve {
+        let future_sectors = reserve
+            .to_sectors(new_total_sectors as u64, boot.bytes_per_sector())
+            .min(u32::MAX as u64) as u32;
+        if future_sectors > new_total_sectors {
+            let reserved_fat_size = fat_size_for(future_sectors)?;
+            let max_fat_size = ((fat_type.max_clusters() as u64 + 2) * fat_type.entry_bits() as u64)
+                .div_ceil(8 * boot.bytes_per_sector() as u64) as u32;
+            new_fat_size = new_fat_size.max(reserved_fat_size.min(max_fat_size));
+        }
+    }
+
+    // A FAT that is already larger than needed (from an earlier reserve, or a
+    // formatter with a different formula) keeps its size, so the data area stays
+    new_fat_size = new_fat_size.max(old_fat_size);
+
+    let align_sectors = match options.align {
+        Some(bytes) => alignment_sectors(bytes, boot.bytes_per_sector())?,
+        None => 1,
+    };
+    let growth = FatGrowth {
+        num_fats,
+        old_fats_sectors,
+        sectors_per_cluster,
+        reserved_sectors: boot.reserved_sectors() as u64 + root_dir_sectors,
+        align_sectors,
+    };
+    growth.pad(new_fat_size).ok_or(Error::CannotAlign(
+        align_sectors * boot.bytes_per_sector() as u64,
+    ))
+}
+
+/// How the FAT tables of a filesystem grow, for padding the FAT size
+struct FatGrowth {
+    num_fats: u32,
+    /// Sectors all FAT copies take before the resize
+    old_fats_sectors: u32,
+    sectors_per_cluster: u32,
+    /// Sectors before the first FAT, plus a fixed root directory after them
+    reserved_sectors: u64,
+    /// Boundary the data area must start on (1 for none)
+    align_sectors: u64,
+}
+
+impl FatGrowth {
+    /// Pad `fat_size` so the FAT tables grow by whole clusters, and the data
+    /// area starts on the alignment boundary
+    ///
+    /// The data shift moves the data area by whole clusters, so FAT tables
+    /// growing by a part of a cluster would leave every cluster that many
+    /// sectors off its new place. A FAT larger than strictly needed is
+    /// harmless. FAT tables taking less space than before (a dropped copy) let
+    /// the data area move back by any number of sectors, and FAT tables taking
+    /// the same space keep it where it is, aligned or not. `None` if the
+    /// alignment cannot be met.
+    fn pad(&self, fat_size: u32) -> Option<u32> {
+        let fits = |fat_size: u32| {
+            let fats_sectors = self.num_fats * fat_size;
+            let aligned =
+                (self.reserved_sectors + fats_sectors as u64).is_multiple_of(self.align_sectors);
+            match fats_sectors.cmp(&self.old_fats_sectors) {
+                Ordering::Less => aligned,
+                Ordering::Equal => true,
+                Ordering::Greater => {
+                    aligned
+                        && (fats_sectors - self.old_fats_sectors)
+                            .is_multiple_of(self.sectors_per_cluster)
+                }
+            }
+        };
+
+        // Both conditions repeat with a period of at most align * cluster size
+        let limit = fat_size as u64 + self.align_sectors * self.sectors_per_cluster as u64;
+        (fat_size..)
+            .take_while(|&padded| padded as u64 <= limit)
+            .find(|&padded| fits(padded))
+    }
+}
+
+/// Calculate the new layout for converting a FAT12/16 filesystem to FAT32
+///
+/// The reserved area grows to at least [`FAT32_RESERVED_SECTORS`] for FSInfo
+/// and the backup boot sector, and the FAT is sized for 32-bit entries. The
+/// fixed root directory becomes a cluster chain, so the new metadata pushes
+/// the data area forward by whole clusters (padded to the requested
+/// alignment), like a growing FAT. The old FAT and root directory are staged below the
+/// checkpoint while they are rewritten, and the new root directory needs free
+/// clusters below that, so the filesystem must grow enough to leave room for
+/// both. It must also reach the FAT32 minimum of 65525 clusters.
+fn calculate_conversion_size(
+    boot: &BootSector,
+    device_sectors: u64,
+    options: &CalculationOptions,
+) -> Result<SizeCalculation> {
+    let old_total_sectors = boot.total_sectors();
+    let old_data_clusters = boot.data_clusters();
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    let new_num_fats = options.num_fats.unwrap_or(boot.num_fats());
+    if !(1..=2).contains(&new_num_fats) {
+        return Err(Error::InvalidFatCount(new_num_fats));
+    }
+    let (mut new_total_sectors, mut size_limit) =
+        requested_total_sectors(boot, device_sectors, options)?;
+
+    // Cap at the FAT32 cluster limit when filling the device, as when growing
+    let (new_fat_size, first_data_sector, new_data_clusters) = loop {
+        let new_fat_size = converted_fat_size(boot, new_total_sectors, new_num_fats, options)?;
+        let first_data_sector = converted_reserved_sectors(boot, new_num_fats, new_fat_size) as u32
+            + new_num_fats as u32 * new_fat_size;
+        let new_data_clusters = new_total_sectors
+            .checked_sub(first_data_sector)
+            .ok_or_else(|| {
+                Error::Calculation(format!(
+                    "Size {} sectors is too small for the FAT tables",
+                    new_total_sectors
+                ))
+            })?
+            / sectors_per_cluster;
+
+        if options.target_size.is_none() && new_data_clusters > FAT32_MAX_CLUSTERS {
+            new_total_sectors = first_data_sector + FAT32_MAX_CLUSTERS * sectors_per_cluster;
+            size_limit = Some(SizeLimit::ClusterCount(FatType::Fat32));
+            continue;
+        }
+        break (new_fat_size, first_data_sector, new_data_clusters);
+    };
+
+    if new_total_sectors <= old_total_sectors {
+        return Err(Error::AlreadyMaxSize);
+    }
+
+    // An explicit target ends on a cluster boundary, as when growing
+    let new_total_sectors = if options.target_size.is_some() {
+        first_data_sector + new_data_clusters * sectors_per_cluster
+    } else {
+        new_total_sectors
+    };
+
+    if new_data_clusters < FAT32_MIN_CLUSTERS {
+        return Err(Error::Calculation(format!(
+            "New cluster count {} is too small for FAT32 (minimum {})",
+            new_data_clusters, FAT32_MIN_CLUSTERS
+        )));
+    }
+    if new_data_clusters > FAT32_MAX_CLUSTERS {
+        return Err(Error::Calculation(format!(
+            "New cluster count {} exceeds FAT32 maximum of {}",
+            new_data_clusters, FAT32_MAX_CLUSTERS
+        )));
+    }
+
+    // Room for the staged FAT and root directory below the checkpoint, and
+    // for the root directory clusters between them and the shifted data
+    let staging_sectors = boot.fat_size() + boot.root_dir_sectors() as u32;
+    let root_dir_clusters = (boot.root_dir_sectors() as u32).div_ceil(sectors_per_cluster);
+    let needed_end = first_data_sector
+        + (old_data_clusters + root_dir_clusters) * sectors_per_cluster
+        + staging_sectors
+        + 1;
+    if new_total_sectors < needed_end {
+        return Err(Error::Calculation(
+            "Growth leaves no room to stage the FAT and root directory for conversion".to_string(),
+        ));
+    }
+
+    Ok(conversion_calculation(
+        boot,
+        new_total_sectors,
+        new_fat_size,
+        new_num_fats,
+        size_limit,
+    ))
+}
+
+/// Reserved sectors of a FAT12/16 filesystem after conversion to FAT32 with
+/// the given number of FATs and FAT size
+///
+/// At least [`FAT32_RESERVED_SECTORS`] (or the old reserved area if larger),
+/// plus up to a cluster less one, so that the data area moves by whole clusters.
+pub(crate) fn converted_reserved_sectors(
+    boot: &BootSector,
+    new_num_fats: u8,
+    new_fat_size: u32,
+) -> u16 {
+    let minimum = boot.reserved_sectors().max(FAT32_RESERVED_SECTORS) as i64;
+    let fats_sectors = new_num_fats as i64 * new_fat_size as i64;
+    let remainder = (boot.first_data_sector() as i64 - minimum - fats_sectors)
+        .rem_euclid(boot.sectors_per_cluster() as i64);
+    (minimum + remainder) as u16
+}
+
+/// Layout of a FAT12/16 filesystem converted to FAT32 with the given size,
+/// FAT size and number of FATs
+///
+/// The data area moves forward from behind the fixed root directory to behind
+/// the new FAT tables; the clusters it moves over are the affected ones.
+pub(crate) fn conversion_calculation(
+    boot: &BootSector,
+    new_total_sectors: u32,
+    new_fat_size: u32,
+    new_num_fats: u8,
+    size_limit: Option<SizeLimit>,
+) -> SizeCalculation {
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    let first_data_sector = converted_reserved_sectors(boot, new_num_fats, new_fat_size) as u32
+        + new_num_fats as u32 * new_fat_size;
+    let shift = first_data_sector.saturating_sub(boot.first_data_sector() as u32);
+    let affected_clusters = shift / sectors_per_cluster;
+    let new_data_clusters =
+        new_total_sectors.saturating_sub(first_data_sector) / sectors_per_cluster;
+
+    SizeCalculation {
+        old_total_sectors: boot.total_sectors(),
+        new_total_sectors,
+        old_fat_size: boot.fat_size(),
+        new_fat_size,
+        new_num_fats,
+        new_data_clusters,
+        new_free_clusters: new_data_clusters.saturating_sub(boot.data_clusters()),
+        fat_needs_growth: true,
+        fat_growth_sectors: new_fat_size.saturating_sub(boot.fat_size()),
+        first_affected_cluster: if affected_clusters > 0 { 2 } else { 0 },
+        last_affected_cluster: if affected_clusters > 0 {
+            2 + affected_clusters - 1
+        } else {
+            0
+        },
+        size_limit,
+        new_fat_type: FatType::Fat32,
+    }
+}
+
+/// FAT32 FAT size for converting a FAT12/16 filesystem of `new_total_sectors`
+///
+/// Applies the FAT reserve, and pads the FAT until the data area moves
+/// forward and starts on the requested alignment.
+fn converted_fat_size(
+    boot: &BootSector,
+    new_total_sectors: u32,
+    new_num_fats: u8,
+    options: &CalculationOptions,
+) -> Result<u32> {
+    let minimum_reserved = boot.reserved_sectors().max(FAT32_RESERVED_SECTORS);
+    let num_fats = new_num_fats as u64;
+    let fat_size_for = |total_sectors: u32| {
+        calculate_fat_size(
+            total_sectors,
+            minimum_reserved,
+            new_num_fats,
+            boot.sectors_per_cluster(),
+            boot.bytes_per_sector(),
+        )
+    };
+
+    let mut new_fat_size = fat_size_for(new_total_sectors)?;
+
+    if let Some(reserve) = options.fat_reserve {
+        let future_sectors = reserve
+            .to_sectors(new_total_sectors as u64, boot.bytes_per_sector())
+            .min(u32::MAX as u64) as u32;
+        if future_sectors > new_total_sectors {
+            let max_fat_size = ((FAT32_MAX_CLUSTERS as u64 + 2) * 4)
+                .div_ceil(boot.bytes_per_sector() as u64) as u32;
+            new_fat_size = new_fat_size.max(fat_size_for(future_sectors)?.min(max_fat_size));
+        }
+    }
+
+    // The data area never moves back
+    let old_first_data_sector = boot.first_data_sector();
+    if minimum_reserved as u64 + num_fats * (new_fat_size as u64) < old_first_data_sector {
+        new_fat_size = (old_first_data_sector - minimum_reserved as u64).div_ceil(num_fats) as u32;
+    }
+
+    // The reserved area keeps the shift to whole clusters (see
+    // `converted_reserved_sectors`); only the alignment needs padding
+    let Some(bytes) = options.align else {
+        return Ok(new_fat_size);
+    };
+    let align_sectors = alignment_sectors(bytes, boot.bytes_per_sector())?;
+    let first_data_sector = |fat_size: u32| {
+        converted_reserved_sectors(boot, new_num_fats, fat_size) as u64 + num_fats * fat_size as u64
+    };
+    let limit = new_fat_size as u64 + align_sectors * boot.sectors_per_cluster() as u64;
+    while !first_data_sector(new_fat_size).is_multiple_of(align_sectors) {
+        new_fat_size += 1;
+        if new_fat_size as u64 > limit {
+            return Err(Error::CannotAlign(
+                align_sectors * boot.bytes_per_sector() as u64,
+            ));
+        }
+    }
+
+    Ok(new_fat_size)
+}
+
+/// Calculate the new size parameters for shrinking the filesystem
+///
+/// The target is rounded down so the data area ends on a cluster boundary.
+/// The FAT shrinks with the filesystem, padded so the data area moves back by
+/// whole clusters. For the result, `first_affected_cluster` and
+/// `last_affected_cluster` are the range of clusters that no longer exist.
+/// Only FAT32 filesystems can shrink.
+pub fn calculate_shrink_size(boot: &BootSector, target: TargetSize) -> Result<SizeCalculation> {
+    if boot.fat_type() != FatType::Fat32 {
+        return Err(Error::UnsupportedFatType(boot.fat_type()));
+    }
+
+    let old_total_sectors = boot.total_sectors();
+    let old_fat_size = boot.fat_size();
+    let old_data_clusters = boot.data_clusters();
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    let num_fats = boot.num_fats() as u32;
+
+    let target_sectors = target.to_sectors(old_total_sectors as u64, boot.bytes_per_sector());
+    if target_sectors >= old_total_sectors as u64 {
+        return Err(Error::Calculation(format!(
+            "Target size {} sectors is not smaller than the filesystem ({} sectors)",
+            target_sectors, old_total_sectors
+        )));
+    }
+    let requested_sectors = target_sectors as u32;
+
+    let mut new_fat_size = calculate_fat_size(
+        requested_sectors,
+        boot.reserved_sectors(),
+        boot.num_fats(),
+        boot.sectors_per_cluster(),
+        boot.bytes_per_sector(),
+    )?
+    .min(old_fat_size);
+
+    // Keep the data area moving by whole clusters, as when growing
+    while !(num_fats * (old_fat_size - new_fat_size)).is_multiple_of(sectors_per_cluster) {
+        new_fat_size += 1;
+    }
+
+    let first_data_sector = boot.reserved_sectors() as u32 + num_fats * new_fat_size;
+    let new_data_clusters = requested_sectors
+        .checked_sub(first_data_sector)
+        .map(|sectors| sectors / sectors_per_cluster)
+        .ok_or_else(|| {
+            Error::Calculation(format!(
+                "Size {} sectors is too small for the FAT tables",
+                requested_sectors
+            ))
+        })?;
+
+    if new_data_clusters < FAT32_MIN_CLUSTERS {
+        return Err(Error::Calculation(format!(
+            "New cluster count {} would not be FAT32 (minimum {})",
+            new_data_clusters, FAT32_MIN_CLUSTERS
+        )));
+    }
+    if new_data_clusters >= old_data_clusters {
+        return Err(Error::Calculation(format!(
+            "Target size {} sectors does not remove a whole cluster from the filesystem",
+            requested_sectors
+        )));
+    }
+
+    let new_total_sectors = first_data_sector + new_data_clusters * sectors_per_cluster;
+
+    Ok(SizeCalculation {
+        old_total_sectors,
+        new_total_sectors,
+        old_fat_size,
+        new_fat_size,
+        new_num_fats: boot.num_fats(),
+        new_data_clusters,
+        new_free_clusters: 0, // Depends on the FAT contents
+        fat_needs_growth: false,
+        fat_growth_sectors: 0,
+        first_affected_cluster: new_data_clusters + 2,
+        last_affected_cluster: old_data_clusters + 1,
+        size_limit: None,
+        new_fat_type: FatType::Fat32,
+    })
+}
+
+/// Largest cluster size in bytes a re-clustered filesystem may use
+pub const MAX_CLUSTER_BYTES: u32 = 64 * 1024;
+
+/// Calculate the new layout for changing the cluster size
+///
+/// The filesystem keeps its size. The FAT shrinks to what the larger clusters
+/// need, padded so the data area moves back by whole new clusters. Every
+/// cluster gets a new number, so `first_affected_cluster` and
+/// `last_affected_cluster` span all old clusters. Only FAT32 filesystems can
+/// be re-clustered.
+pub fn calculate_recluster_size(
+    boot: &BootSector,
+    sectors_per_cluster: u8,
+) -> Result<SizeCalculation> {
+    if boot.fat_type() != FatType::Fat32 {
+        return Err(Error::UnsupportedFatType(boot.fat_type()));
+    }
+
+    let old_total_sectors = boot.total_sectors();
+    let old_fat_size = boot.fat_size();
+    let old_spc = boot.sectors_per_cluster();
+    let num_fats = boot.num_fats() as u32;
+    let cluster_bytes = sectors_per_cluster as u32 * boot.bytes_per_sector() as u32;
+
+    if !sectors_per_cluster.is_power_of_two()
+        || sectors_per_cluster <= old_spc
+        || cluster_bytes > MAX_CLUSTER_BYTES
+    {
+        return Err(Error::InvalidClusterSize(cluster_bytes as u64));
+    }
+    let spc = sectors_per_cluster as u32;
+
+    let mut new_fat_size = calculate_fat_size(
+        old_total_sectors,
+        boot.reserved_sectors(),
+        boot.num_fats(),
+        sectors_per_cluster,
+        boot.bytes_per_sector(),
+    )?
+    .min(old_fat_size);
+
+    // The data area moves back by whole clusters of the new size
+    while !(num_fats * (old_fat_size - new_fat_size)).is_multiple_of(spc) {
+        new_fat_size += 1;
+    }
+
+    let first_data_sector = boot.reserved_sectors() as u32 + num_fats * new_fat_size;
+    let new_data_clusters = (old_total_sectors - first_data_sector) / spc;
+    if new_data_clusters < FAT32_MIN_CLUSTERS {
+        return Err(Error::Calculation(format!(
+            "New cluster count {} would not be FAT32 (minimum {})",
+            new_data_clusters, FAT32_MIN_CLUSTERS
+        )));
+    }
+
+    Ok(SizeCalculation {
+        old_total_sectors,
+        new_total_sectors: old_total_sectors,
+        old_fat_size,
+        new_fat_size,
+        new_num_fats: boot.num_fats(),
+        new_data_clusters,
+        new_free_clusters: 0, // Depends on the FAT contents
+        fat_needs_growth: false,
+        fat_growth_sectors: 0,
+        first_affected_cluster: 2,
+        last_affected_cluster: boot.data_clusters() + 1,
+        size_limit: None,
+        new_fat_type: FatType::Fat32,
+    })
+}
+
+/// Convert an alignment in bytes to sectors
+pub(crate) fn alignment_sectors(bytes: u64, bytes_per_sector: u16) -> Result<u64> {
+    if bytes == 0 || !bytes.is_multiple_of(bytes_per_sector as u64) {
+        return Err(Error::Calculation(format!(
+            "Alignment of {} bytes is not a multiple of the {}-byte sector size",
+            bytes, bytes_per_sector
+        )));
+    }
+    Ok(bytes / bytes_per_sector as u64)
+}
+
+/// Largest filesystem size in sectors that a FAT of `fat_size` sectors covers
+///
+/// This is the inverse of [`calculate_fat_size`]: growing up to this size does
+/// not make the FAT grow, so no data has to move.
+pub fn max_sectors_for_fat_size(
+    fat_size: u32,
+    reserved_sectors: u16,
+    num_fats: u8,
+    sectors_per_cluster: u8,
+    bytes_per_sector: u16,
+) -> u64 {
+    let entries_per_sector = bytes_per_sector as u64 / 4;
+    let sectors_per_fat_sector =
+        entries_per_sector * sectors_per_cluster as u64 + num_fats as u64 / 2;
+    let by_fat = reserved_sectors as u64 + fat_size as u64 * sectors_per_fat_sector;
+
+    // FAT32 limits: cluster count and 32-bit sector count
+    let by_clusters = reserved_sectors as u64
+        + num_fats as u64 * fat_size as u64
+        + FAT32_MAX_CLUSTERS as u64 * sectors_per_cluster as u64;
+    by_fat.min(by_clusters).min(u32::MAX as u64)
+}
+
+/// Largest filesystem size in sectors that a FAT12/16 FAT of `fat_size` sectors covers
+///
+/// The FAT12/16 counterpart of [`max_sectors_for_fat_size`], which also keeps
+/// the filesystem within the cluster limit of its type.
+pub fn max_sectors_for_small_fat(
+    fat_type: FatType,
+    fat_size: u32,
+    reserved_sectors: u16,
+    num_fats: u8,
+    root_dir_sectors: u32,
+    sectors_per_cluster: u8,
+    bytes_per_sector: u16,
+) -> u64 {
+    let entries = fat_size as u64 * bytes_per_sector as u64 * 8 / fat_type.entry_bits() as u64;
+    let clusters = entries
+        .saturating_sub(2)
+        .min(fat_type.max_clusters() as u64);
+    let sectors = reserved_sectors as u64
+        + num_fats as u64 * fat_size as u64
+        + root_dir_sectors as u64
+        + clusters * sectors_per_cluster as u64;
+    sectors.min(u32::MAX as u64)
+}
+
+/// Calculate the required FAT size in sectors for a FAT12 or FAT16 filesystem
+///
+/// FAT12 packs two entries into three bytes, which the closed formula from the
+/// specification does not cover, so the size is iterated until the FAT holds
+/// an entry for every cluster. The result is the smallest size that does.
+pub fn calculate_small_fat_size(
+    fat_type: FatType,
+    total_sectors: u32,
+    reserved_sectors: u16,
+    num_fats: u8,
+    root_dir_sectors: u32,
+    sectors_per_cluster: u8,
+    bytes_per_sector: u16,
+) -> Result<u32> {
+    let fixed = reserved_sectors as u64 + root_dir_sectors as u64;
+    let entry_bits = fat_type.entry_bits() as u64;
+    let needed = |fat_size: u64| -> Result<u64> {
+        let data_sectors = (total_sectors as u64)
+            .checked_sub(fixed + num_fats as u64 * fat_size)
+            .ok_or_else(|| {
+                Error::Calculation(format!(
+                    "Size {} sectors is too small for the FAT tables",
+                    total_sectors
+                ))
+            })?;
+        let clusters = data_sectors / sectors_per_cluster as u64;
+        Ok(((clusters + 2) * entry_bits).div_ceil(8 * bytes_per_sector as u64))
+    };
+
+    // A larger FAT leaves fewer clusters, so iterating from below overshoots
+    // by at most a few sectors; step back to the smallest size that fits
+    let mut fat_size = 1u64;
+    loop {
+        let size = needed(fat_size)?;
+        if size <= fat_size {
+            break;
+        }
+        fat_size = size;
+    }
+    while fat_size > 1 && needed(fat_size - 1).is_ok_and(|size| size < fat_size) {
+        fat_size -= 1;
+    }
+
+    if fat_size > u16::MAX as u64 {
+        return Err(Error::Calculation(format!(
+            "{} FAT size exceeds the 16-bit FAT size field",
+            fat_type
+        )));
+    }
+    Ok(fat_size as u32)
+}
+
 /// Calculate the required FAT size in sectors
 ///
 /// This uses the algorithm from the Microsoft FAT specification.
@@ -187,6 +1188,14 @@ mod tests {
     use super::*;
 
     fn create_test_boot_sector(total_sectors: u32, fat_size: u32) -> BootSector {
+        create_test_boot_sector_with_clusters(total_sectors, fat_size, 8)
+    }
+
+    fn create_test_boot_sector_with_clusters(
+        total_sectors: u32,
+        fat_size: u32,
+        sectors_per_cluster: u8,
+    ) -> BootSector {
         let mut data = [0u8; 512];
 
         // Jump instruction
@@ -198,8 +1207,8 @@ mod tests {
         data[11] = 0x00;
         data[12] = 0x02;
 
-        // Sectors per cluster (8)
-        data[13] = 0x08;
+        // Sectors per cluster
+        data[13] = sectors_per_cluster;
 
         // Reserved sectors (32)
         data[14] = 0x20;
@@ -233,6 +1242,23 @@ mod tests {
         BootSector::from_bytes(&data).unwrap()
     }
 
+    /// FAT16 boot sector: 1 reserved sector, 512 root entries, 4 sectors per cluster
+    fn create_fat16_boot_sector(total_sectors: u16, fat_size: u16) -> BootSector {
+        let mut data = [0u8; 512];
+        data[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        data[11..13].copy_from_slice(&512u16.to_le_bytes());
+        data[13] = 4;
+        data[14..16].copy_from_slice(&1u16.to_le_bytes());
+        data[16] = 2;
+        data[17..19].copy_from_slice(&512u16.to_le_bytes());
+        data[19..21].copy_from_slice(&total_sectors.to_le_bytes());
+        data[21] = 0xF8;
+        data[22..24].copy_from_slice(&fat_size.to_le_bytes());
+        data[510] = 0x55;
+        data[511] = 0xAA;
+        BootSector::from_bytes(&data).unwrap()
+    }
+
     #[test]
     fn test_calculate_fat_size() {
         // Test with known values
@@ -269,6 +1295,532 @@ mod tests {
         assert!(matches!(result, Err(Error::ShrinkNotSupported)));
     }
 
+    #[test]
+    fn test_calculate_shrink_size() {
+        let boot = create_test_boot_sector(2_000_000, 2000);
+
+        let calc = calculate_shrink_size(&boot, TargetSize::Absolute(1_000_000 * 512)).unwrap();
+        assert!(calc.new_total_sectors <= 1_000_000);
+        assert!(calc.new_total_sectors > 1_000_000 - 8);
+        assert!(calc.new_fat_size < calc.old_fat_size);
+        assert_eq!((2 * (calc.old_fat_size - calc.new_fat_size)) % 8, 0);
+        assert!(calc.new_fat_size * 128 >= calc.new_data_clusters + 2);
+        assert_eq!(calc.first_affected_cluster, calc.new_data_clusters + 2);
+        assert_eq!(calc.last_affected_cluster, boot.data_clusters() + 1);
+
+        // Data area ends exactly at the new size
+        let first_data_sector = 32 + 2 * calc.new_fat_size;
+        assert_eq!(
+            calc.new_total_sectors,
+            first_data_sector + calc.new_data_clusters * 8
+        );
+    }
+
+    #[test]
+    fn test_calculate_shrink_size_invalid_targets() {
+        let boot = create_test_boot_sector(2_000_000, 2000);
+
+        // Not smaller
+        assert!(calculate_shrink_size(&boot, TargetSize::Absolute(2_000_000 * 512)).is_err());
+        assert!(calculate_shrink_size(&boot, TargetSize::Relative(512)).is_err());
+
+        // Too small for FAT32
+        assert!(calculate_shrink_size(&boot, TargetSize::Absolute(100_000 * 512)).is_err());
+    }
+
+    #[test]
+    fn test_calculate_recluster_size() {
+        let boot = create_test_boot_sector(2_000_000, 2000);
+
+        let calc = calculate_recluster_size(&boot, 16).unwrap();
+        assert_eq!(calc.new_total_sectors, 2_000_000);
+        assert!(calc.new_fat_size < calc.old_fat_size);
+        assert_eq!((2 * (calc.old_fat_size - calc.new_fat_size)) % 16, 0);
+        assert!(calc.new_fat_size * 128 >= calc.new_data_clusters + 2);
+        assert!(32 + 2 * calc.new_fat_size + calc.new_data_clusters * 16 <= 2_000_000);
+        assert_eq!(calc.last_affected_cluster, boot.data_clusters() + 1);
+
+        // Not larger, not a power of two, beyond 64 KiB
+        for spc in [8, 4, 24, 0] {
+            assert!(matches!(
+                calculate_recluster_size(&boot, spc),
+                Err(Error::InvalidClusterSize(_))
+            ));
+        }
+        let boot = create_test_boot_sector_with_clusters(12_000_000, 12_000, 2);
+        assert!(calculate_recluster_size(&boot, 128).is_ok());
+
+        // Too few clusters left for FAT32
+        let boot = create_test_boot_sector(600_000, 600);
+        assert!(matches!(
+            calculate_recluster_size(&boot, 64),
+            Err(Error::Calculation(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("8G").unwrap(), 8 << 30);
+        assert_eq!(parse_size("8gib").unwrap(), 8 << 30);
+        assert_eq!(parse_size("2MB").unwrap(), 2 << 20);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert!(matches!(parse_size(""), Err(Error::InvalidSize(_))));
+        assert!(matches!(parse_size("G"), Err(Error::InvalidSize(_))));
+        assert!(matches!(parse_size("12X"), Err(Error::InvalidSize(_))));
+        assert!(matches!(parse_size("-1G"), Err(Error::InvalidSize(_))));
+    }
+
+    #[test]
+    fn test_target_size_parsing() {
+        assert_eq!(
+            "8G".parse::<TargetSize>().unwrap(),
+            TargetSize::Absolute(8 << 30)
+        );
+        assert_eq!(
+            "+2G".parse::<TargetSize>().unwrap(),
+            TargetSize::Relative(2 << 30)
+        );
+        assert_eq!(TargetSize::Absolute(1 << 20).to_sectors(100, 512), 2048);
+        assert_eq!(TargetSize::Relative(1 << 20).to_sectors(100, 512), 2148);
+    }
+
+    #[test]
+    fn test_calculate_new_size_with_target() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let options = CalculationOptions::new()
+            .target_size(Some(TargetSize::Absolute(1_500_000 * 512 + 100)));
+
+        let calc = calculate_new_size_with_options(&boot, 4_000_000, &options).unwrap();
+
+        // Rounded down so the data area ends on a cluster boundary
+        let first_data_sector = 32 + 2 * calc.new_fat_size;
+        assert!(calc.new_total_sectors <= 1_500_000);
+        assert_eq!((calc.new_total_sectors - first_data_sector) % 8, 0);
+        assert_eq!(
+            calc.new_data_clusters,
+            (calc.new_total_sectors - first_data_sector) / 8
+        );
+    }
+
+    #[test]
+    fn test_calculate_new_size_with_relative_target() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let options =
+            CalculationOptions::new().target_size(Some(TargetSize::Relative(500_000 * 512)));
+
+        let calc = calculate_new_size_with_options(&boot, 4_000_000, &options).unwrap();
+        assert!(calc.new_total_sectors <= 1_500_000);
+        assert!(calc.new_total_sectors > 1_500_000 - 8);
+    }
+
+    #[test]
+    fn test_calculate_new_size_target_exceeds_device() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let options =
+            CalculationOptions::new().target_size(Some(TargetSize::Absolute(4_000_001 * 512)));
+
+        let result = calculate_new_size_with_options(&boot, 4_000_000, &options);
+        assert!(matches!(
+            result,
+            Err(Error::TargetTooLarge {
+                target: 4_000_001,
+                available: 4_000_000
+            })
+        ));
+    }
+
+    #[test]
+    fn test_calculate_new_size_target_below_one_cluster() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let options = CalculationOptions::new().target_size(Some(TargetSize::Relative(4 * 512)));
+
+        let result = calculate_new_size_with_options(&boot, 4_000_000, &options);
+        assert!(matches!(result, Err(Error::Calculation(_))));
+    }
+
+    #[test]
+    fn test_fat_growth_is_whole_clusters() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+
+        for device_sectors in [1_100_003u64, 2_345_678, 4_000_000] {
+            let calc = calculate_new_size(&boot, device_sectors).unwrap();
+            assert!(calc.fat_needs_growth);
+            assert_eq!((2 * calc.fat_growth_sectors) % 8, 0);
+            assert_eq!(
+                (calc.last_affected_cluster - calc.first_affected_cluster + 1) * 8,
+                2 * calc.fat_growth_sectors
+            );
+        }
+    }
+
+    #[test]
+    fn test_fat_growth_padding() {
+        let growth = FatGrowth {
+            num_fats: 2,
+            old_fats_sectors: 2000,
+            sectors_per_cluster: 8,
+            reserved_sectors: 32,
+            align_sectors: 1,
+        };
+        // 2 * 1001 - 2000 = 2 sectors: pad to 2 * 1004 - 2000 = 8
+        assert_eq!(growth.pad(1001), Some(1004));
+        assert_eq!(growth.pad(1004), Some(1004));
+        // FATs that do not grow stay as they are
+        assert_eq!(growth.pad(1000), Some(1000));
+
+        // 1 MiB alignment: 32 + 2 * 1008 = 2048 sectors
+        let aligned = FatGrowth {
+            align_sectors: 2048,
+            ..growth
+        };
+        assert_eq!(aligned.pad(1001), Some(1008));
+
+        // Clusters 2 sectors off a 4 KiB boundary can never reach it
+        let impossible = FatGrowth {
+            reserved_sectors: 34,
+            align_sectors: 8,
+            ..growth
+        };
+        assert_eq!(impossible.pad(1001), None);
+
+        // A dropped copy lets the data area move back, onto the boundary if
+        // there is one, or else stay where it is
+        let dropped = FatGrowth {
+            num_fats: 1,
+            old_fats_sectors: 2016,
+            ..growth
+        };
+        assert_eq!(dropped.pad(1001), Some(1001));
+        let aligned = FatGrowth {
+            align_sectors: 512,
+            ..dropped
+        };
+        assert_eq!(aligned.pad(1001), Some(1504));
+        let aligned = FatGrowth {
+            align_sectors: 4096,
+            ..dropped
+        };
+        assert_eq!(aligned.pad(1001), Some(2016));
+    }
+
+    #[test]
+    fn test_fat_reserve_parsing() {
+        assert_eq!(
+            "64G".parse::<FatReserve>().unwrap(),
+            FatReserve::UpTo(64 << 30)
+        );
+        assert_eq!(
+            "50%".parse::<FatReserve>().unwrap(),
+            FatReserve::Percent(50)
+        );
+        assert!(matches!(
+            "lots".parse::<FatReserve>(),
+            Err(Error::InvalidFatReserve(_))
+        ));
+        assert!(matches!(
+            "-5%".parse::<FatReserve>(),
+            Err(Error::InvalidFatReserve(_))
+        ));
+        assert_eq!(FatReserve::UpTo(1 << 20).to_sectors(100, 512), 2048);
+        assert_eq!(FatReserve::Percent(50).to_sectors(1000, 512), 1500);
+    }
+
+    #[test]
+    fn test_calculate_new_size_with_fat_reserve() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let plain = calculate_new_size(&boot, 2_000_000).unwrap();
+
+        for reserve in [FatReserve::UpTo(8_000_000 * 512), FatReserve::Percent(300)] {
+            let options = CalculationOptions::new().fat_reserve(Some(reserve));
+            let calc = calculate_new_size_with_options(&boot, 2_000_000, &options).unwrap();
+
+            // Sized for 8,000,000 sectors and still whole clusters of growth
+            assert_eq!(calc.new_total_sectors, 2_000_000);
+            assert!(calc.new_fat_size >= calculate_fat_size(8_000_000, 32, 2, 8, 512).unwrap());
+            assert!(calc.new_fat_size > plain.new_fat_size);
+            assert_eq!((2 * calc.fat_growth_sectors) % 8, 0);
+            assert_eq!(
+                calc.new_data_clusters,
+                (calc.new_total_sectors - 32 - 2 * calc.new_fat_size) / 8
+            );
+
+            // A later grow within the reserve leaves the FAT alone
+            let grown = create_test_boot_sector(calc.new_total_sectors, calc.new_fat_size);
+            let later = calculate_new_size(&grown, 8_000_000).unwrap();
+            assert!(!later.fat_needs_growth);
+            assert_eq!(later.new_fat_size, calc.new_fat_size);
+        }
+
+        // A reserve below the new size changes nothing
+        let options = CalculationOptions::new().fat_reserve(Some(FatReserve::UpTo(512)));
+        let calc = calculate_new_size_with_options(&boot, 2_000_000, &options).unwrap();
+        assert_eq!(calc.new_fat_size, plain.new_fat_size);
+    }
+
+    #[test]
+    fn test_oversized_fat_is_kept() {
+        // A FAT sized for far more than the filesystem holds must not shrink
+        let boot = create_test_boot_sector(1_000_000, 5000);
+        let calc = calculate_new_size(&boot, 1_200_000).unwrap();
+        assert_eq!(calc.new_fat_size, 5000);
+        assert!(!calc.fat_needs_growth);
+    }
+
+    #[test]
+    fn test_calculate_new_size_changing_num_fats() {
+        // Dropping the second FAT hands its sectors to the data area
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let one = CalculationOptions::new().num_fats(Some(1));
+        let calc = calculate_new_size_with_options(&boot, 1_200_000, &one).unwrap();
+        assert_eq!(calc.new_num_fats, 1);
+        assert!(calc.new_fat_size < 2000);
+        assert!(calc.fat_needs_growth);
+        assert_eq!(calc.first_affected_cluster, 0);
+        let first_data_sector = boot.reserved_sectors() as u32 + calc.new_fat_size;
+        assert_eq!(
+            calc.new_data_clusters,
+            (calc.new_total_sectors - first_data_sector) / 8
+        );
+
+        // Adding one moves the data area by whole clusters
+        let mut boot = create_test_boot_sector(1_000_000, 1000);
+        boot.set_num_fats(1);
+        let two = CalculationOptions::new().num_fats(Some(2));
+        let calc = calculate_new_size_with_options(&boot, 1_200_000, &two).unwrap();
+        let growth = 2 * calc.new_fat_size - 1000;
+        assert_eq!(growth % 8, 0);
+        assert_eq!(
+            calc.last_affected_cluster - calc.first_affected_cluster + 1,
+            growth / 8
+        );
+
+        let three = CalculationOptions::new().num_fats(Some(3));
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 1_200_000, &three),
+            Err(Error::InvalidFatCount(3))
+        ));
+    }
+
+    #[test]
+    fn test_max_sectors_for_fat_size() {
+        for total in [1_000_000u32, 2_345_678, 40_000_000] {
+            let fat_size = calculate_fat_size(total, 32, 2, 8, 512).unwrap();
+            let max = max_sectors_for_fat_size(fat_size, 32, 2, 8, 512);
+            assert!(max >= total as u64);
+            assert_eq!(
+                calculate_fat_size(max as u32, 32, 2, 8, 512).unwrap(),
+                fat_size
+            );
+            assert!(calculate_fat_size(max as u32 + 1, 32, 2, 8, 512).unwrap() > fat_size);
+        }
+
+        // Capped by the 32-bit sector count
+        assert_eq!(
+            max_sectors_for_fat_size(u32::MAX / 128, 32, 2, 64, 512),
+            u32::MAX as u64
+        );
+    }
+
+    #[test]
+    fn test_alignment_parsing() {
+        assert_eq!(
+            "4M".parse::<Alignment>().unwrap(),
+            Alignment::Bytes(4 << 20)
+        );
+        assert_eq!("Auto".parse::<Alignment>().unwrap(), Alignment::Auto);
+        assert!("sometimes".parse::<Alignment>().is_err());
+    }
+
+    #[test]
+    fn test_calculate_new_size_aligned() {
+        let boot = create_test_boot_sector(1_000_000, 1000);
+
+        for align in [1u64 << 20, 4 << 20] {
+            let options = CalculationOptions::new().align(Some(align));
+            let calc = calculate_new_size_with_options(&boot, 4_000_000, &options).unwrap();
+
+            let first_data_sector = 32 + 2 * calc.new_fat_size as u64;
+            assert_eq!((first_data_sector * 512) % align, 0);
+            assert_eq!((2 * calc.fat_growth_sectors) % 8, 0);
+            assert_eq!(
+                (calc.last_affected_cluster - calc.first_affected_cluster + 1) * 8,
+                2 * calc.fat_growth_sectors
+            );
+        }
+
+        // Alignment only applies when the FAT grows
+        let options = CalculationOptions::new().align(Some(4 << 20));
+        let calc = calculate_new_size_with_options(&boot, 1_000_100, &options).unwrap();
+        assert_eq!(calc.new_fat_size, 1000);
+    }
+
+    #[test]
+    fn test_calculate_new_size_alignment_impossible() {
+        // Clusters start 2 sectors off a 4 KiB boundary and can only move by whole clusters
+        let boot = create_test_boot_sector(1_000_000, 1001);
+        let options = CalculationOptions::new().align(Some(4096));
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 4_000_000, &options),
+            Err(Error::CannotAlign(4096))
+        ));
+
+        // Not a whole number of sectors
+        let options = CalculationOptions::new().align(Some(1000));
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 4_000_000, &options),
+            Err(Error::Calculation(_))
+        ));
+    }
+
+    #[test]
+    fn test_growth_capped_at_cluster_limit() {
+        // 4 TiB device: more than 2^32 sectors and, with 4 KiB clusters, far
+        // more than the FAT32 cluster limit
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        let calc = calculate_new_size(&boot, 8 << 30).unwrap();
+
+        assert_eq!(
+            calc.size_limit,
+            Some(SizeLimit::ClusterCount(FatType::Fat32))
+        );
+        assert_eq!(calc.new_data_clusters, FAT32_MAX_CLUSTERS);
+        assert_eq!(
+            calc.new_total_sectors,
+            32 + 2 * calc.new_fat_size + FAT32_MAX_CLUSTERS * 8
+        );
+        assert!(calc.new_fat_size as u64 * 128 >= FAT32_MAX_CLUSTERS as u64 + 2);
+        assert_eq!((2 * calc.fat_growth_sectors) % 8, 0);
+
+        // An explicit target past the limits is still an error
+        let options = CalculationOptions::new().target_size(Some(TargetSize::Absolute(3 << 40)));
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 8 << 30, &options),
+            Err(Error::Calculation(_))
+        ));
+    }
+
+    #[test]
+    fn test_calculate_small_fat_size() {
+        // 1.44 MB floppy: 2847 clusters need 4274 bytes of FAT12
+        assert_eq!(
+            calculate_small_fat_size(FatType::Fat12, 2880, 1, 2, 14, 1, 512).unwrap(),
+            9
+        );
+        // 9971 clusters need 19946 bytes of FAT16
+        assert_eq!(
+            calculate_small_fat_size(FatType::Fat16, 40_000, 1, 2, 32, 4, 512).unwrap(),
+            39
+        );
+    }
+
+    #[test]
+    fn test_fat16_growth_keeps_fat_type() {
+        let boot = create_fat16_boot_sector(40_000, 39);
+        assert_eq!(boot.fat_type(), FatType::Fat16);
+
+        let calc = calculate_new_size(&boot, 100_000).unwrap();
+        assert!(calc.fat_needs_growth);
+        assert_eq!((2 * calc.fat_growth_sectors) % 4, 0);
+        assert_eq!(
+            calc.new_data_clusters,
+            (100_000 - 1 - 2 * calc.new_fat_size - 32) / 4
+        );
+
+        // Filling a large device stops at the FAT16 cluster limit
+        let calc = calculate_new_size(&boot, 1_000_000).unwrap();
+        assert_eq!(
+            calc.size_limit,
+            Some(SizeLimit::ClusterCount(FatType::Fat16))
+        );
+        assert_eq!(calc.new_data_clusters, FatType::Fat16.max_clusters());
+
+        // Shrinking and re-clustering are FAT32 only
+        assert!(matches!(
+            calculate_shrink_size(&boot, TargetSize::Absolute(30_000 * 512)),
+            Err(Error::UnsupportedFatType(FatType::Fat16))
+        ));
+        assert!(matches!(
+            calculate_recluster_size(&boot, 8),
+            Err(Error::UnsupportedFatType(FatType::Fat16))
+        ));
+    }
+
+    #[test]
+    fn test_fat16_conversion_layout() {
+        let boot = create_fat16_boot_sector(40_000, 39);
+        let options = CalculationOptions::new().convert_to_fat32(true);
+
+        let calc = calculate_new_size_with_options(&boot, 1_000_000, &options).unwrap();
+        assert_eq!(calc.new_fat_type, FatType::Fat32);
+        assert_eq!(calc.size_limit, None);
+        assert_eq!(calc.new_total_sectors, 1_000_000);
+        assert_eq!(
+            calc.new_fat_size,
+            calculate_fat_size(1_000_000, FAT32_RESERVED_SECTORS, 2, 4, 512).unwrap()
+        );
+
+        // The data area moves forward by whole clusters, past the fixed root
+        // directory; the reserved area takes up the remainder
+        let reserved = converted_reserved_sectors(&boot, 2, calc.new_fat_size) as u32;
+        assert!((32..36).contains(&reserved));
+        let first_data_sector = reserved + 2 * calc.new_fat_size;
+        let shift = first_data_sector - boot.first_data_sector() as u32;
+        assert_eq!(shift % 4, 0);
+        assert_eq!(calc.first_affected_cluster, 2);
+        assert_eq!(calc.last_affected_cluster, 2 + shift / 4 - 1);
+        assert_eq!(calc.new_data_clusters, (1_000_000 - first_data_sector) / 4);
+
+        // Moving by whole clusters, a data area that starts inside a cluster
+        // of the alignment can never be aligned
+        let aligned = options.clone().align(Some(1 << 20));
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 1_000_000, &aligned),
+            Err(Error::CannotAlign(_))
+        ));
+
+        // FAT32 needs at least 65525 clusters
+        assert!(matches!(
+            calculate_new_size_with_options(&boot, 200_000, &options),
+            Err(Error::Calculation(_))
+        ));
+
+        // FAT32 filesystems ignore the conversion
+        let fat32 = create_test_boot_sector(1_000_000, 1000);
+        let calc = calculate_new_size_with_options(&fat32, 4_000_000, &options).unwrap();
+        assert_eq!(calc.new_fat_type, FatType::Fat32);
+        assert_eq!(
+            calc.new_fat_size,
+            calculate_new_size(&fat32, 4_000_000).unwrap().new_fat_size
+        );
+    }
+
+    #[test]
+    fn test_growth_capped_at_sector_limit() {
+        // With 32 KiB clusters the 32-bit sector count is reached first
+        let boot = create_test_boot_sector_with_clusters(1_000_000, 1000, 64);
+        let calc = calculate_new_size(&boot, 8 << 30).unwrap();
+
+        assert_eq!(calc.size_limit, Some(SizeLimit::SectorCount));
+        assert_eq!(calc.new_total_sectors, u32::MAX);
+        assert!(calc.new_data_clusters < FAT32_MAX_CLUSTERS);
+
+        // Already as large as FAT32 allows
+        let boot = create_test_boot_sector_with_clusters(u32::MAX, calc.new_fat_size, 64);
+        assert!(matches!(
+            calculate_new_size(&boot, 8 << 30),
+            Err(Error::AlreadyMaxSize)
+        ));
+
+        // Within the limits nothing is capped
+        let boot = create_test_boot_sector(1_000_000, 1000);
+        assert_eq!(
+            calculate_new_size(&boot, 4_000_000).unwrap().size_limit,
+            None
+        );
+    }
+
     #[test]
     fn test_calculate_new_size_same_size() {
         let boot = create_test_boot_sector(2_000_000, 2000);
@@ -0,0 +1,194 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::operations::FAT_IO_CHUNK_BYTES;
+use crate::fat32::{count_free_clusters, decode_fat_table, fat_entry, BootSector, FSInfo};
+use crate::resize::calculator::{converted_reserved_sectors, SizeCalculation};
+
+// ===== FAT12/16 to FAT32 Conversion =====
+//
+// Conversion runs as a shifting grow: phase 0 shifts the data area forward
+// behind the larger FAT32 metadata, phase 1 rewrites the metadata and phase 2
+// writes the FAT32 boot sector. Phase 1 overwrites the old FAT and the fixed
+// root directory, so phase 0 stages both right below the checkpoint first,
+// and phase 1 always works from the staged copies. That makes phase 1 safe to
+// repeat, and the root directory clusters it picks the same every time.
+
+/// First sector of the staged FAT and root directory, right below the checkpoint
+pub(crate) fn conversion_staging_sector(boot: &BootSector, checkpoint_sector: u64) -> u64 {
+    checkpoint_sector - boot.fat_size() as u64 - boot.root_dir_sectors()
+}
+
+/// Stage the first FAT copy and the fixed root directory (phase 0)
+pub(crate) fn stage_conversion(
+    device: &Device,
+    boot: &BootSector,
+    staging_sector: u64,
+) -> Result<()> {
+    let fat = device.read_sectors(boot.first_fat_sector(), boot.fat_size())?;
+    device.write_sectors(staging_sector, &fat)?;
+    let root = device.read_sectors(boot.first_root_dir_sector(), boot.root_dir_sectors() as u32)?;
+    device.write_sectors(staging_sector + boot.fat_size() as u64, &root)?;
+    device.sync()
+}
+
+/// Build the FAT32 FAT of a converted filesystem
+///
+/// Entries of the old clusters carry over, already widened to FAT32 values.
+/// The root directory gets the lowest `root_dir_clusters` free clusters below
+/// `limit`. Returns the FAT and the root directory chain.
+fn converted_fat(
+    old_fat: &[u32],
+    old_max_cluster: u32,
+    new_len: usize,
+    media: u8,
+    root_dir_clusters: u32,
+    limit: u32,
+) -> Result<(Vec<u32>, Vec<u32>)> {
+    let mut fat = vec![fat_entry::FREE; new_len];
+    fat[0] = 0x0FFFFF00 | media as u32;
+    fat[1] = fat_entry::END_OF_CHAIN;
+    let carried = (old_max_cluster as usize).min(old_fat.len()).min(new_len);
+    fat[2..carried].copy_from_slice(&old_fat[2..carried]);
+
+    let limit = (limit as usize).min(new_len) as u32;
+    let chain: Vec<u32> = (2..limit)
+        .filter(|&cluster| fat_entry::is_free(fat[cluster as usize]))
+        .take(root_dir_clusters as usize)
+        .collect();
+    if chain.len() < root_dir_clusters as usize {
+        return Err(Error::NoFreeCluster);
+    }
+    for pair in chain.windows(2) {
+        fat[pair[0] as usize] = pair[1];
+    }
+    if let Some(&last) = chain.last() {
+        fat[last as usize] = fat_entry::END_OF_CHAIN;
+    }
+
+    Ok((fat, chain))
+}
+
+/// Write the FAT32 metadata of a converted filesystem from the staged copies
+/// (phase 1)
+///
+/// Writes the reserved area with FSInfo and its backup, every FAT copy with
+/// 32-bit entries, and the root directory into its new cluster chain. The
+/// boot sector is left to phase 2. Returns the root directory cluster.
+pub(crate) fn write_converted_metadata(
+    device: &Device,
+    boot: &BootSector,
+    calc: &SizeCalculation,
+    staging_sector: u64,
+    operations: &mut Vec<String>,
+) -> Result<u32> {
+    let bytes_per_sector = boot.bytes_per_sector() as usize;
+    let sectors_per_cluster = boot.sectors_per_cluster() as u64;
+    let root_dir_sectors = boot.root_dir_sectors();
+    let reserved_sectors =
+        converted_reserved_sectors(boot, calc.new_num_fats, calc.new_fat_size) as u64;
+    let first_data_sector = reserved_sectors + calc.new_num_fats as u64 * calc.new_fat_size as u64;
+
+    let staged_fat = device.read_sectors(staging_sector, boot.fat_size())?;
+    let old_fat = decode_fat_table(boot, &staged_fat);
+    let root_dir = device.read_sectors(
+        staging_sector + boot.fat_size() as u64,
+        root_dir_sectors as u32,
+    )?;
+
+    // The root directory must not land on the staged copies
+    let staging_cluster = ((staging_sector - first_data_sector) / sectors_per_cluster) as u32 + 2;
+    let (fat, root_chain) = converted_fat(
+        &old_fat,
+        boot.data_clusters() + 2,
+        calc.new_fat_size as usize * bytes_per_sector / 4,
+        boot.media_type(),
+        root_dir_sectors.div_ceil(sectors_per_cluster) as u32,
+        staging_cluster.min(calc.new_data_clusters + 2),
+    )?;
+    let root_cluster = root_chain[0];
+
+    // Reserved area: FSInfo in sector 1 and its backup in sector 7, next to
+    // the backup boot sector phase 2 writes to sector 6
+    let free_clusters = count_free_clusters(&fat, calc.new_data_clusters);
+    let mut fsinfo = FSInfo::new(bytes_per_sector);
+    fsinfo.set_free_count(free_clusters);
+    let mut reserved = vec![0u8; (reserved_sectors as usize - 1) * bytes_per_sector];
+    for sector in [1, 7] {
+        let offset = (sector - 1) * bytes_per_sector;
+        reserved[offset..offset + bytes_per_sector].copy_from_slice(fsinfo.as_bytes());
+    }
+    device.write_sectors(1, &reserved)?;
+    operations.push(format!("Wrote FSInfo (free clusters: {})", free_clusters));
+
+    // FAT copies
+    let fat_bytes: Vec<u8> = fat.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+    for fat_num in 0..calc.new_num_fats as u64 {
+        let fat_start = reserved_sectors + fat_num * calc.new_fat_size as u64;
+        for (i, chunk) in fat_bytes.chunks(FAT_IO_CHUNK_BYTES).enumerate() {
+            device.write_sectors(
+                fat_start + (i * FAT_IO_CHUNK_BYTES / bytes_per_sector) as u64,
+                chunk,
+            )?;
+        }
+    }
+    operations.push(format!(
+        "Wrote {} FAT32 FAT copies of {} sectors",
+        calc.new_num_fats, calc.new_fat_size
+    ));
+
+    // Root directory, with the rest of its last cluster cleared
+    let cluster_bytes = boot.bytes_per_cluster() as usize;
+    for (i, &cluster) in root_chain.iter().enumerate() {
+        let mut data = vec![0u8; cluster_bytes];
+        let start = (i * cluster_bytes).min(root_dir.len());
+        let end = (start + cluster_bytes).min(root_dir.len());
+        data[..end - start].copy_from_slice(&root_dir[start..end]);
+        let sector = first_data_sector + (cluster as u64 - 2) * sectors_per_cluster;
+        device.write_sectors(sector, &data)?;
+    }
+    operations.push(format!(
+        "Moved root directory to cluster {} ({} clusters)",
+        root_cluster,
+        root_chain.len()
+    ));
+
+    device.sync()?;
+    Ok(root_cluster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converted_fat() {
+        let old_fat = vec![
+            0x0FFFFFF8, // Media
+            0x0FFFFFFF, // Reserved
+            0x00000003, // Cluster 2 -> 3
+            0x0FFFFFFF, // End of chain
+            0x00000000, // Free
+            0x0FFFFFF7, // Bad
+            0x00000000, // Free
+            0x00000000, // Free
+            0x0FFFFFFF, // Past the old data area
+        ];
+
+        let (fat, chain) = converted_fat(&old_fat, 8, 12, 0xF8, 3, 12).unwrap();
+        assert_eq!(chain, vec![4, 6, 7]);
+        assert_eq!(fat[0], 0x0FFFFFF8);
+        assert_eq!(fat[2], 3);
+        assert_eq!(fat[3], fat_entry::END_OF_CHAIN);
+        assert_eq!(fat[4], 6);
+        assert_eq!(fat[5], fat_entry::BAD_CLUSTER);
+        assert_eq!(fat[6], 7);
+        assert_eq!(fat[7], fat_entry::END_OF_CHAIN);
+        assert_eq!(&fat[8..], &[0, 0, 0, 0]);
+
+        // Clusters from the limit on are never used
+        assert!(matches!(
+            converted_fat(&old_fat, 8, 12, 0xF8, 3, 7),
+            Err(Error::NoFreeCluster)
+        ));
+    }
+}
@@ -0,0 +1,266 @@
+//! Discarding free clusters
+//!
+//! Flash storage (SSDs, SD cards) treats every block that was ever written
+//! as in use until it is told otherwise. After a grow, the added space and
+//! the places a data shift moved clusters away from still count as in use,
+//! and may still hold old data. Discarding the free clusters hands them back:
+//! `BLKDISCARD` on block devices, holes in image files and qcow2 images.
+//!
+//! Only free data clusters are discarded, found through the FAT (or the
+//! exFAT allocation bitmap) as it is after the resize. The reserved sectors,
+//! the FATs, a fixed root directory and every allocated cluster are never
+//! touched.
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::exfat::{
+    is_cluster_used, is_exfat_at, read_bitmap, read_bitmap_entry, read_boot_region, ExfatBootSector,
+};
+use crate::fat32::{fat_entry, read_boot_sector, BootSector, FatReader, FatType};
+use crate::resize::executor::{check_for_incomplete_resize, checkpoint_candidates, ResizeOptions};
+use crate::resize::exfat::{exfat_checkpoint_candidates, read_exfat_checkpoint};
+use crate::system::check_not_mounted_at;
+use std::ops::Range;
+
+/// Free space discarded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiscardStats {
+    /// Bytes of free clusters discarded (for a dry run: that would be)
+    pub discarded_bytes: u64,
+    /// Discard requests issued, one per run of adjacent free clusters
+    pub ranges: u64,
+    /// Whether the device accepted the discards; a device that does not
+    /// support them is left as it is
+    pub supported: bool,
+}
+
+/// Result of trimming a filesystem
+#[derive(Debug)]
+pub struct TrimResult {
+    /// Type of the trimmed filesystem
+    pub fat_type: FatType,
+    /// Free clusters found and discarded
+    pub discard: DiscardStats,
+    /// List of operations performed (for logging)
+    pub operations: Vec<String>,
+}
+
+/// Discard the runs of free clusters in `clusters`
+///
+/// `is_free` tells whether a cluster is free and `cluster_offset` where its
+/// data starts. With `dry_run`, the runs are only counted. Stops at the
+/// first discard the device refuses.
+fn discard_runs(
+    device: &Device,
+    clusters: Range<u32>,
+    bytes_per_cluster: u64,
+    mut is_free: impl FnMut(u32) -> Result<bool>,
+    cluster_offset: impl Fn(u32) -> u64,
+    dry_run: bool,
+) -> Result<DiscardStats> {
+    let mut stats = DiscardStats {
+        supported: true,
+        ..Default::default()
+    };
+    let mut run_start = None;
+    // One step past the end closes the last run
+    for cluster in clusters.start..=clusters.end {
+        let free = cluster < clusters.end && is_free(cluster)?;
+        match (free, run_start) {
+            (true, None) => run_start = Some(cluster),
+            (false, Some(start)) => {
+                run_start = None;
+                let len = (cluster - start) as u64 * bytes_per_cluster;
+                if !dry_run && !device.discard(cluster_offset(start), len)? {
+                    stats.supported = false;
+                    return Ok(stats);
+                }
+                stats.discarded_bytes += len;
+                stats.ranges += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(stats)
+}
+
+/// Discard the free clusters in `clusters` of a FAT12, FAT16 or FAT32
+/// filesystem, reading the FAT a window at a time within `memory_limit`
+pub(crate) fn discard_free_clusters(
+    device: &Device,
+    boot: &BootSector,
+    clusters: Range<u32>,
+    memory_limit: Option<u64>,
+    dry_run: bool,
+) -> Result<DiscardStats> {
+    let mut fat = FatReader::new(device, boot, 0, memory_limit);
+    let end = clusters
+        .end
+        .min(boot.data_clusters().saturating_add(2))
+        .min(fat.entry_count());
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    discard_runs(
+        device,
+        clusters.start.max(2)..end.max(2),
+        boot.bytes_per_cluster() as u64,
+        |cluster| Ok(fat_entry::is_free(fat.entry(cluster)?)),
+        |cluster| boot.cluster_to_sector(cluster) * bytes_per_sector,
+        dry_run,
+    )
+}
+
+/// Discard the free clusters in `clusters` of an exFAT filesystem, as its
+/// allocation bitmap marks them
+pub(crate) fn discard_free_exfat_clusters(
+    device: &Device,
+    boot: &ExfatBootSector,
+    clusters: Range<u32>,
+    dry_run: bool,
+) -> Result<DiscardStats> {
+    let entry = read_bitmap_entry(device, boot, |cluster| boot.cluster_sector(cluster))?;
+    let bitmap = read_bitmap(device, boot, &entry, |cluster| boot.cluster_sector(cluster))?;
+    let end = clusters.end.min(boot.cluster_count().saturating_add(2));
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    discard_runs(
+        device,
+        clusters.start.max(2)..end.max(2),
+        boot.bytes_per_cluster(),
+        |cluster| Ok(!is_cluster_used(&bitmap, cluster)),
+        |cluster| boot.cluster_sector(cluster) * bytes_per_sector,
+        dry_run,
+    )
+}
+
+/// Discard every free cluster of a FAT12, FAT16, FAT32 or exFAT filesystem
+///
+/// Refuses filesystems left behind by an interrupted resize, whose free
+/// clusters may hold the copies it resumes from; run the resize again
+/// first. Dirty exFAT volumes are refused as well, as their bitmap may
+/// miss allocated clusters.
+pub fn trim_filesystem(options: ResizeOptions) -> Result<TrimResult> {
+    let mut operations = Vec::new();
+
+    check_not_mounted_at(options.device_path(), options.get_location())?;
+    operations.push("Verified device is not mounted".to_string());
+
+    let exfat = is_exfat_at(options.device_path(), options.get_location())?;
+    let mut device = options.open_device()?;
+    operations.push(format!(
+        "Opened device: {}",
+        options.device_path().display()
+    ));
+
+    let (fat_type, discard) = if exfat {
+        let boot = read_boot_region(&mut device)?;
+        if boot.is_dirty() {
+            return Err(Error::DirtyVolume);
+        }
+        let candidates = exfat_checkpoint_candidates(&device, &boot, &options);
+        if read_exfat_checkpoint(&device, &boot, &candidates)?.is_some() {
+            return Err(Error::IncompleteResize);
+        }
+        operations.push(format!(
+            "Read exFAT boot region ({} clusters of {} bytes)",
+            boot.cluster_count(),
+            boot.bytes_per_cluster()
+        ));
+        let clusters = 2..boot.cluster_count() + 2;
+        let discard = discard_free_exfat_clusters(&device, &boot, clusters, options.is_dry_run())?;
+        (FatType::ExFat, discard)
+    } else {
+        let boot = read_boot_sector(&mut device)?;
+        let candidates = checkpoint_candidates(&device, &boot, &options);
+        if check_for_incomplete_resize(&device, &boot, &candidates)?.is_some() {
+            return Err(Error::IncompleteResize);
+        }
+        operations.push(format!(
+            "Read boot sector ({} clusters of {} bytes)",
+            boot.data_clusters(),
+            boot.bytes_per_cluster()
+        ));
+        let clusters = 2..boot.data_clusters() + 2;
+        let discard = discard_free_clusters(
+            &device,
+            &boot,
+            clusters,
+            options.get_memory_limit(),
+            options.is_dry_run(),
+        )?;
+        (boot.fat_type(), discard)
+    };
+
+    if !discard.supported {
+        operations.push("Device does not support discards; nothing was discarded".to_string());
+    } else if options.is_dry_run() {
+        operations.push(format!(
+            "Dry run: would discard {} bytes of free clusters in {} ranges",
+            discard.discarded_bytes, discard.ranges
+        ));
+    } else {
+        device.sync()?;
+        operations.push(format!(
+            "Discarded {} bytes of free clusters in {} ranges",
+            discard.discarded_bytes, discard.ranges
+        ));
+    }
+
+    Ok(TrimResult {
+        fat_type,
+        discard,
+        operations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::read_fat_table;
+    use crate::test_image::{assert_consistent, pattern, read_root_file, ImageSpec, TestImage};
+
+    #[test]
+    fn test_trim_discards_only_free_clusters() {
+        let mut image = TestImage::create(ImageSpec::default());
+        image.add_file(b"FIRST   BIN", &pattern(1, 40 * 512), 3);
+        image.skip_to(1500);
+        image.add_file(b"SECOND  BIN", &pattern(2, 700 * 512), 1);
+
+        // Free clusters hold stale data until they are discarded
+        let mut device = Device::open_readonly(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        let table = read_fat_table(&device, &boot, 0).unwrap();
+        drop(device);
+        let free: Vec<u32> = (2..boot.data_clusters() + 2)
+            .filter(|&cluster| fat_entry::is_free(table[cluster as usize]))
+            .collect();
+        for &cluster in &free {
+            image.write_cluster(cluster, &[0xEE; 512]);
+        }
+
+        let preview = trim_filesystem(ResizeOptions::new(image.path()).dry_run(true)).unwrap();
+        assert_eq!(
+            preview.discard.discarded_bytes,
+            free.len() as u64 * boot.bytes_per_cluster() as u64
+        );
+
+        let result = trim_filesystem(ResizeOptions::new(image.path())).unwrap();
+        assert_eq!(result.fat_type, FatType::Fat32);
+        assert_eq!(result.discard, preview.discard);
+
+        let device = Device::open_readonly(image.path()).unwrap();
+        for &cluster in &free {
+            let offset = boot.cluster_to_sector(cluster) * 512;
+            assert_eq!(device.read_bytes_at(offset, 512).unwrap(), vec![0u8; 512]);
+        }
+        drop(device);
+
+        assert_eq!(
+            read_root_file(image.path(), b"FIRST   BIN").unwrap(),
+            pattern(1, 40 * 512)
+        );
+        assert_eq!(
+            read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+            pattern(2, 700 * 512)
+        );
+        assert_consistent(image.path());
+    }
+}
@@ -1,13 +1,27 @@
-use crate::device::Device;
+use crate::device::{Device, IoStats, SparseStats};
 use crate::error::{Error, Result};
+use crate::fat32::operations::FAT_IO_CHUNK_BYTES;
 use crate::fat32::{
-    boot_sectors_match, read_backup_boot_sector, read_boot_sector, read_boot_sector_for_recovery,
-    read_fat_table, read_fsinfo, write_backup_boot_sector, write_boot_sector, write_fsinfo,
-    BootSector, FSInfo,
+    boot_sectors_match, fat_entry, read_backup_boot_sector, read_boot_sector,
+    read_boot_sector_for_recovery, read_fsinfo, write_backup_boot_sector, write_boot_sector,
+    write_fsinfo, BootSector, FSInfo, FatReader, FatType,
 };
-use crate::resize::calculator::{calculate_new_size, SizeCalculation};
-use crate::resize::relocator::{execute_relocation, plan_relocation, verify_relocation};
-use crate::system::check_not_mounted;
+use crate::partition::{mbr, partition_at, Partition, PartitionType, VolumeLocation};
+use crate::resize::calculator::{
+    calculate_new_size, calculate_new_size_with_options, conversion_calculation,
+    converted_reserved_sectors, max_sectors_for_fat_size, max_sectors_for_small_fat, Alignment,
+    CalculationOptions, FatReserve, SizeCalculation, SizeLimit, TargetSize, AUTO_ALIGN_MIN_BYTES,
+};
+use crate::resize::convert::{
+    conversion_staging_sector, stage_conversion, write_converted_metadata,
+};
+use crate::resize::discard::{discard_free_clusters, DiscardStats};
+use crate::resize::relocator::{
+    execute_relocation_with_progress, ReadAhead, RelocationPlan, WindowedShift, DEFAULT_QUEUE_DEPTH,
+};
+use crate::resize::renumber::{execute_renumbering, plan_renumber, RenumberPlan, RenumberStart};
+use crate::resize::shrinker::plan_shift_back;
+use crate::system::{check_not_mounted_at, get_alignment_hint, get_partition_start};
 
 // ===== Fault Injection for Testing =====
 //
@@ -15,19 +29,25 @@ use crate::system::check_not_mounted;
 //
 // Set FAT32_CRASH_AT environment variable to simulate crashes at specific points:
 //   - "after_checkpoint_start" - after writing phase 0 checkpoint, before data shift
+//   - "during_data_shift" - after the first data shift watermark is recorded
 //   - "after_data_shift" - after data shift, before phase 1 checkpoint
 //   - "after_checkpoint_data_copied" - after phase 1 checkpoint, before boot invalidation
 //   - "after_boot_invalidate" - after boot sector invalidated, before FAT operations
 //   - "after_fat_write" - after FAT written, before phase 2 checkpoint
 //   - "after_checkpoint_fat_written" - after phase 2 checkpoint, before boot restore
 //
+// Shrinking and the renumber strategy use the same points (a shrink's data
+// shift runs after the phase 2 checkpoint) and add:
+//   - "after_clusters_moved" - after moved clusters were copied, before the journal
+//   - "after_journal_replay" - after the journal was replayed, before the FAT copies
+//
 // Build: cargo build --release --features fault-injection
 // Usage: FAT32_CRASH_AT=after_boot_invalidate fat32expander resize image.img
 
 /// Check if we should crash at a specific point (for testing crash recovery)
 /// This function only does anything when compiled with the fault-injection feature
 #[cfg(feature = "fault-injection")]
-fn maybe_crash_at(point: &str) {
+pub(crate) fn maybe_crash_at(point: &str) {
     if let Ok(crash_point) = std::env::var("FAT32_CRASH_AT") {
         if crash_point == point {
             eprintln!("FAULT INJECTION: Simulating crash at '{}'", point);
@@ -39,7 +59,7 @@ fn maybe_crash_at(point: &str) {
 /// No-op version for production builds
 #[cfg(not(feature = "fault-injection"))]
 #[inline(always)]
-fn maybe_crash_at(_point: &str) {
+pub(crate) fn maybe_crash_at(_point: &str) {
     // No-op in production builds
 }
 
@@ -49,7 +69,7 @@ fn maybe_crash_at(_point: &str) {
 const CHECKPOINT_MAGIC: &[u8; 8] = b"FAT32RSZ";
 
 /// Current checkpoint version
-const CHECKPOINT_VERSION: u8 = 1;
+const CHECKPOINT_VERSION: u8 = 5;
 
 /// Resize phase values
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -74,10 +94,42 @@ impl ResizePhase {
     }
 }
 
+/// Operation a checkpoint belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResizeOperation {
+    /// Growing the filesystem (`resize_fat32`)
+    Grow = 0,
+    /// Shrinking the filesystem (`shrink_fat32`)
+    Shrink = 1,
+    /// Growing the filesystem with the renumber strategy
+    GrowRenumber = 2,
+    /// Changing the cluster size (`recluster_fat32`)
+    Recluster = 3,
+    /// Growing a FAT12/16 filesystem while converting it to FAT32
+    Convert = 4,
+    /// Growing an exFAT filesystem (`resize_exfat`)
+    ExfatGrow = 5,
+}
+
+impl ResizeOperation {
+    fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Self::Grow),
+            1 => Some(Self::Shrink),
+            2 => Some(Self::GrowRenumber),
+            3 => Some(Self::Recluster),
+            4 => Some(Self::Convert),
+            5 => Some(Self::ExfatGrow),
+            _ => None,
+        }
+    }
+}
+
 /// Checkpoint stored in new space for crash recovery
 ///
-/// This is written to sector `old_total_sectors` (first sector of new space)
-/// and allows resuming an interrupted resize operation.
+/// This is written to the last sector of the new filesystem (see
+/// `checkpoint_sector`) and allows resuming an interrupted resize operation.
 #[derive(Debug, Clone)]
 pub struct ResizeCheckpoint {
     /// Current resize phase
@@ -90,11 +142,30 @@ pub struct ResizeCheckpoint {
     pub old_fat_size: u32,
     /// New FAT size in sectors
     pub new_fat_size: u32,
+    /// Lowest cluster known to be shifted during phase 0 (0 if none)
+    ///
+    /// Every cluster at or above the watermark has been copied to its new
+    /// position, and its old position may since have been overwritten.
+    /// When shrinking, this tracks the data shift back during phase 2 instead,
+    /// where every cluster at or below the watermark has been copied.
+    pub watermark: u32,
+    /// Operation this checkpoint belongs to
+    pub operation: ResizeOperation,
+    /// Root directory cluster after renumbering or conversion (0 when shifting)
+    pub new_root_cluster: u32,
+    /// First cluster of the renumbering journal (0 when shifting)
+    pub journal_cluster: u32,
+    /// Number of FAT copies after the resize (0 if unchanged)
+    pub new_num_fats: u8,
+    /// exFAT cluster heap offset after the resize (0 for FAT)
+    pub new_heap_offset: u32,
+    /// First cluster of the grown exFAT allocation bitmap (0 for FAT)
+    pub bitmap_cluster: u32,
 }
 
 impl ResizeCheckpoint {
     /// Checkpoint size in bytes (without CRC)
-    const DATA_SIZE: usize = 8 + 1 + 1 + 2 + 4 + 4 + 4 + 4; // 28 bytes
+    const DATA_SIZE: usize = 8 + 1 + 1 + 2 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4; // 48 bytes
 
     /// Create a new checkpoint
     pub fn new(
@@ -110,9 +181,61 @@ impl ResizeCheckpoint {
             new_total_sectors,
             old_fat_size,
             new_fat_size,
+            watermark: 0,
+            operation: ResizeOperation::Grow,
+            new_root_cluster: 0,
+            journal_cluster: 0,
+            new_num_fats: 0,
+            new_heap_offset: 0,
+            bitmap_cluster: 0,
         }
     }
 
+    /// Mark this as a checkpoint of a renumbering with its root cluster and journal
+    pub fn journaled(
+        mut self,
+        operation: ResizeOperation,
+        new_root_cluster: u32,
+        journal_cluster: u32,
+    ) -> Self {
+        self.operation = operation;
+        self.new_root_cluster = new_root_cluster;
+        self.journal_cluster = journal_cluster;
+        self
+    }
+
+    /// Mark this as a checkpoint of the given operation
+    pub fn with_operation(mut self, operation: ResizeOperation) -> Self {
+        self.operation = operation;
+        self
+    }
+
+    /// Record the root directory cluster a conversion chose
+    pub fn with_root_cluster(mut self, cluster: u32) -> Self {
+        self.new_root_cluster = cluster;
+        self
+    }
+
+    /// Record the number of FAT copies the resize changes to
+    pub fn with_num_fats(mut self, num_fats: u8) -> Self {
+        self.new_num_fats = num_fats;
+        self
+    }
+
+    /// Record the cluster heap offset and bitmap cluster an exFAT grow chose
+    pub fn with_exfat_layout(mut self, heap_offset: u32, bitmap_cluster: u32) -> Self {
+        self.operation = ResizeOperation::ExfatGrow;
+        self.new_heap_offset = heap_offset;
+        self.bitmap_cluster = bitmap_cluster;
+        self
+    }
+
+    /// Record data shift progress (see `watermark`)
+    pub fn with_watermark(mut self, watermark: u32) -> Self {
+        self.watermark = watermark;
+        self
+    }
+
     /// Serialize checkpoint to bytes with specified sector size
     pub fn to_bytes(&self, sector_size: usize) -> Vec<u8> {
         let mut data = vec![0u8; sector_size];
@@ -126,9 +249,11 @@ impl ResizeCheckpoint {
         // Phase (1 byte)
         data[9] = self.phase as u8;
 
-        // Padding (2 bytes)
-        data[10] = 0;
-        data[11] = 0;
+        // Operation (1 byte)
+        data[10] = self.operation as u8;
+
+        // new_num_fats (1 byte)
+        data[11] = self.new_num_fats;
 
         // old_total_sectors (4 bytes)
         data[12..16].copy_from_slice(&self.old_total_sectors.to_le_bytes());
@@ -142,17 +267,32 @@ impl ResizeCheckpoint {
         // new_fat_size (4 bytes)
         data[24..28].copy_from_slice(&self.new_fat_size.to_le_bytes());
 
-        // CRC32 of data (4 bytes at offset 28)
+        // watermark (4 bytes)
+        data[28..32].copy_from_slice(&self.watermark.to_le_bytes());
+
+        // new_root_cluster (4 bytes)
+        data[32..36].copy_from_slice(&self.new_root_cluster.to_le_bytes());
+
+        // journal_cluster (4 bytes)
+        data[36..40].copy_from_slice(&self.journal_cluster.to_le_bytes());
+
+        // new_heap_offset (4 bytes)
+        data[40..44].copy_from_slice(&self.new_heap_offset.to_le_bytes());
+
+        // bitmap_cluster (4 bytes)
+        data[44..48].copy_from_slice(&self.bitmap_cluster.to_le_bytes());
+
+        // CRC32 of data (4 bytes at offset 48)
         let crc = crc32fast::hash(&data[0..Self::DATA_SIZE]);
-        data[28..32].copy_from_slice(&crc.to_le_bytes());
+        data[48..52].copy_from_slice(&crc.to_le_bytes());
 
         data
     }
 
-    /// Parse checkpoint from bytes (sector size independent - only first 32 bytes matter)
+    /// Parse checkpoint from bytes (sector size independent - only first 52 bytes matter)
     pub fn from_bytes(data: &[u8]) -> Result<Option<Self>> {
-        // Need at least 32 bytes for checkpoint data
-        if data.len() < 32 {
+        // Need at least 52 bytes for checkpoint data
+        if data.len() < 52 {
             return Ok(None);
         }
 
@@ -168,7 +308,7 @@ impl ResizeCheckpoint {
         }
 
         // Verify CRC
-        let stored_crc = u32::from_le_bytes([data[28], data[29], data[30], data[31]]);
+        let stored_crc = u32::from_le_bytes([data[48], data[49], data[50], data[51]]);
         let computed_crc = crc32fast::hash(&data[0..Self::DATA_SIZE]);
         if stored_crc != computed_crc {
             return Err(Error::CheckpointCorrupted);
@@ -178,12 +318,21 @@ impl ResizeCheckpoint {
         let Some(phase) = ResizePhase::from_u8(data[9]) else {
             return Err(Error::CheckpointCorrupted);
         };
+        let Some(operation) = ResizeOperation::from_u8(data[10]) else {
+            return Err(Error::CheckpointCorrupted);
+        };
 
         // Parse fields
         let old_total_sectors = u32::from_le_bytes([data[12], data[13], data[14], data[15]]);
         let new_total_sectors = u32::from_le_bytes([data[16], data[17], data[18], data[19]]);
         let old_fat_size = u32::from_le_bytes([data[20], data[21], data[22], data[23]]);
         let new_fat_size = u32::from_le_bytes([data[24], data[25], data[26], data[27]]);
+        let watermark = u32::from_le_bytes([data[28], data[29], data[30], data[31]]);
+        let new_root_cluster = u32::from_le_bytes([data[32], data[33], data[34], data[35]]);
+        let journal_cluster = u32::from_le_bytes([data[36], data[37], data[38], data[39]]);
+        let new_heap_offset = u32::from_le_bytes([data[40], data[41], data[42], data[43]]);
+        let bitmap_cluster = u32::from_le_bytes([data[44], data[45], data[46], data[47]]);
+        let new_num_fats = data[11];
 
         Ok(Some(Self {
             phase,
@@ -191,66 +340,154 @@ impl ResizeCheckpoint {
             new_total_sectors,
             old_fat_size,
             new_fat_size,
+            watermark,
+            operation,
+            new_root_cluster,
+            journal_cluster,
+            new_num_fats,
+            new_heap_offset,
+            bitmap_cluster,
         }))
     }
 }
 
-/// Write checkpoint to the last sector of the device
+/// Sector holding the checkpoint for a resize
 ///
-/// We use the last sector of the device (not the first sector of new space)
-/// because when data is shifted forward during FAT growth, some clusters
-/// will be written to sectors starting at old_total_sectors. Using the last
-/// sector ensures the checkpoint won't be overwritten by shifted cluster data.
-fn write_checkpoint(device: &Device, checkpoint: &ResizeCheckpoint) -> Result<()> {
-    let sector = device.total_sectors() - 1;
+/// We use the last sector of the new filesystem (not the first sector of new
+/// space) because when data is shifted forward during FAT growth, some clusters
+/// will be written to sectors starting at old_total_sectors. The calculator
+/// guarantees that the last sector lies beyond all shifted cluster data, and it
+/// is free space in both the old and the new filesystem. Without a target size
+/// this is the last sector of the device.
+fn checkpoint_sector(calc: &SizeCalculation) -> u64 {
+    calc.new_total_sectors as u64 - 1
+}
+
+/// Write checkpoint to its sector
+pub(crate) fn write_checkpoint(
+    device: &Device,
+    sector: u64,
+    checkpoint: &ResizeCheckpoint,
+) -> Result<()> {
     let sector_size = device.sector_size() as usize;
     device.write_sector(sector, &checkpoint.to_bytes(sector_size))?;
     device.sync()?;
     Ok(())
 }
 
-/// Read checkpoint from the last sector of the device
-fn read_checkpoint(device: &Device, boot: &BootSector) -> Result<Option<ResizeCheckpoint>> {
-    // Only check if device is larger than filesystem
-    if device.total_sectors() <= boot.total_sectors() as u64 {
-        return Ok(None);
-    }
+/// Read checkpoint from the first candidate sector that holds one
+///
+/// Returns the checkpoint together with the sector it was found in.
+/// Candidates inside the current filesystem or beyond the device are skipped.
+fn read_checkpoint(
+    device: &Device,
+    boot: &BootSector,
+    candidates: &[u64],
+) -> Result<Option<(ResizeCheckpoint, u64)>> {
+    for &sector in candidates {
+        if sector < boot.total_sectors() as u64 || sector >= device.total_sectors() {
+            continue;
+        }
 
-    let checkpoint_sector = device.total_sectors() - 1;
-    let data = device.read_sector(checkpoint_sector)?;
-    ResizeCheckpoint::from_bytes(&data)
+        let data = device.read_sector(sector)?;
+        if let Some(checkpoint) = ResizeCheckpoint::from_bytes(&data)? {
+            if matches!(
+                checkpoint.operation,
+                ResizeOperation::Grow | ResizeOperation::GrowRenumber | ResizeOperation::Convert
+            ) {
+                return Ok(Some((checkpoint, sector)));
+            }
+        }
+    }
+    Ok(None)
 }
 
-/// Clear checkpoint by zeroing the last sector of the device
-fn clear_checkpoint(device: &Device) -> Result<()> {
-    let sector = device.total_sectors() - 1;
+/// Clear checkpoint by zeroing its sector
+pub(crate) fn clear_checkpoint(device: &Device, sector: u64) -> Result<()> {
     let zeros = vec![0u8; device.sector_size() as usize];
     device.write_sector(sector, &zeros)?;
     Ok(())
 }
 
+/// Sectors where an interrupted resize may have left its checkpoint
+///
+/// The checkpoint sits in the last sector of the target size, so a resize that
+/// was started with a target size must be resumed with the same target (and
+/// alignment, which decides where the data area ends). The last sector of the
+/// device is always tried as well.
+pub(crate) fn checkpoint_candidates(
+    device: &Device,
+    boot: &BootSector,
+    options: &ResizeOptions,
+) -> Vec<u64> {
+    let mut candidates = Vec::new();
+
+    if options.get_target_size().is_some() {
+        if let Ok(calc) = options.calculate(boot, device.total_sectors()) {
+            candidates.push(checkpoint_sector(&calc));
+        }
+    }
+
+    if device.total_sectors() > 0 {
+        let last_sector = device.total_sectors() - 1;
+        if !candidates.contains(&last_sector) {
+            candidates.push(last_sector);
+        }
+    }
+
+    candidates
+}
+
 /// Check for incomplete resize operation and return checkpoint if found
-fn check_for_incomplete_resize(
+pub(crate) fn check_for_incomplete_resize(
     device: &Device,
     boot: &BootSector,
-) -> Result<Option<ResizeCheckpoint>> {
-    // First check if boot sector is invalidated
-    if !boot.is_signature_valid() {
-        // Boot sector was invalidated - we MUST find a valid checkpoint
-        // Checkpoint is at last sector of device
-        if device.total_sectors() <= boot.total_sectors() as u64 {
-            return Err(Error::InvalidatedFilesystem);
+    candidates: &[u64],
+) -> Result<Option<(ResizeCheckpoint, u64)>> {
+    let found = read_checkpoint(device, boot, candidates)?;
+
+    // Boot sector invalidated - we MUST find a valid checkpoint
+    if !boot.is_signature_valid() && found.is_none() {
+        return Err(Error::InvalidatedFilesystem);
+    }
+
+    // Boot sector valid - a checkpoint means a phase 0 crash
+    Ok(found)
+}
+
+/// How to make room when the FAT tables grow into the data area
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GrowStrategy {
+    /// Shift every cluster in use forward, keeping all cluster numbers
+    Shift,
+    /// Move only the clusters the FAT grows into and renumber the others,
+    /// which keep their place on disk
+    Renumber,
+    /// Use whichever strategy writes fewer bytes
+    #[default]
+    Auto,
+}
+
+impl std::str::FromStr for GrowStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "shift" => Ok(Self::Shift),
+            "renumber" => Ok(Self::Renumber),
+            "auto" => Ok(Self::Auto),
+            _ => Err(Error::InvalidStrategy(s.to_string())),
         }
+    }
+}
 
-        let checkpoint_sector = device.total_sectors() - 1;
-        let data = device.read_sector(checkpoint_sector)?;
-        match ResizeCheckpoint::from_bytes(&data)? {
-            Some(cp) => Ok(Some(cp)),
-            None => Err(Error::InvalidatedFilesystem),
+impl std::fmt::Display for GrowStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Shift => write!(f, "shift"),
+            Self::Renumber => write!(f, "renumber"),
+            Self::Auto => write!(f, "auto"),
         }
-    } else {
-        // Boot sector valid - check for checkpoint anyway (phase 0 crash)
-        read_checkpoint(device, boot)
     }
 }
 
@@ -258,8 +495,24 @@ fn check_for_incomplete_resize(
 #[derive(Debug, Clone)]
 pub struct ResizeOptions {
     device_path: std::path::PathBuf,
+    location: VolumeLocation,
     dry_run: bool,
     verbose: bool,
+    target_size: Option<TargetSize>,
+    strategy: GrowStrategy,
+    fat_reserve: Option<FatReserve>,
+    align: Option<Alignment>,
+    num_fats: Option<u8>,
+    cluster_size: Option<u64>,
+    convert_to_fat32: bool,
+    fix_hidden_sectors: bool,
+    queue_depth: usize,
+    direct_io: bool,
+    memory_limit: Option<u64>,
+    discard: bool,
+    /// Simulated crash after this many writes
+    #[cfg(test)]
+    crash_after_writes: Option<u64>,
 }
 
 impl ResizeOptions {
@@ -267,11 +520,32 @@ impl ResizeOptions {
     pub fn new(device_path: impl AsRef<std::path::Path>) -> Self {
         Self {
             device_path: device_path.as_ref().to_path_buf(),
+            location: VolumeLocation::Whole,
             dry_run: false,
             verbose: false,
+            target_size: None,
+            strategy: GrowStrategy::default(),
+            fat_reserve: None,
+            align: None,
+            num_fats: None,
+            cluster_size: None,
+            convert_to_fat32: false,
+            fix_hidden_sectors: false,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            direct_io: false,
+            memory_limit: None,
+            discard: false,
+            #[cfg(test)]
+            crash_after_writes: None,
         }
     }
 
+    /// Work on the filesystem in a partition or at an offset of the device
+    pub fn location(mut self, location: VolumeLocation) -> Self {
+        self.location = location;
+        self
+    }
+
     /// Enable or disable dry run mode (don't make changes)
     pub fn dry_run(mut self, enable: bool) -> Self {
         self.dry_run = enable;
@@ -284,11 +558,107 @@ impl ResizeOptions {
         self
     }
 
+    /// Grow to the given size instead of filling the device
+    pub fn target_size(mut self, target: Option<TargetSize>) -> Self {
+        self.target_size = target;
+        self
+    }
+
+    /// Choose how to make room when the FAT tables grow
+    pub fn strategy(mut self, strategy: GrowStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Make the FAT large enough for a future size, so later grows up to it
+    /// only update metadata
+    pub fn fat_reserve(mut self, reserve: Option<FatReserve>) -> Self {
+        self.fat_reserve = reserve;
+        self
+    }
+
+    /// Start the data area on an alignment boundary when the FAT grows
+    pub fn align(mut self, align: Option<Alignment>) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Change the number of FAT copies (1 or 2) while growing
+    pub fn num_fats(mut self, num_fats: Option<u8>) -> Self {
+        self.num_fats = num_fats;
+        self
+    }
+
+    /// Change to clusters of this many bytes (see `recluster_fat32`)
+    pub fn cluster_size(mut self, bytes: Option<u64>) -> Self {
+        self.cluster_size = bytes;
+        self
+    }
+
+    /// Convert a FAT12/16 filesystem to FAT32 while growing it, so it can grow
+    /// past the FAT12/16 cluster limit (FAT32 filesystems are unaffected)
+    pub fn convert_to_fat32(mut self, enable: bool) -> Self {
+        self.convert_to_fat32 = enable;
+        self
+    }
+
+    /// Set the boot sector's hidden sectors to the partition start while
+    /// growing, if they differ
+    pub fn fix_hidden_sectors(mut self, enable: bool) -> Self {
+        self.fix_hidden_sectors = enable;
+        self
+    }
+
+    /// Read up to this many runs of clusters ahead of writing them during a
+    /// data shift, on devices that cannot copy in the kernel (0 reads and
+    /// writes in turn)
+    ///
+    /// Each queued run holds up to 8 MiB.
+    pub fn queue_depth(mut self, depth: usize) -> Self {
+        self.queue_depth = depth;
+        self
+    }
+
+    /// Bypass the page cache on block devices (`O_DIRECT`); image files, and
+    /// devices that do not support it, keep buffered I/O
+    pub fn direct_io(mut self, enable: bool) -> Self {
+        self.direct_io = enable;
+        self
+    }
+
+    /// Keep the FAT, the planned data shift and its read-ahead runs within
+    /// about `bytes` of memory
+    ///
+    /// A larger FAT is read a window at a time, the data shift planned and
+    /// copied a segment of clusters at a time, and its runs shortened to fit
+    /// the queue. Shrinking, re-clustering, the renumber strategy and
+    /// dropping a FAT copy need the whole FAT in memory and fail under a
+    /// limit; `Auto` then shifts.
+    pub fn memory_limit(mut self, bytes: Option<u64>) -> Self {
+        self.memory_limit = bytes;
+        self
+    }
+
+    /// Discard free clusters after growing: those in the added space, and
+    /// all of them if data moved
+    ///
+    /// Uses `BLKDISCARD` on block devices and punches holes in image files
+    /// and qcow2 images.
+    pub fn discard(mut self, enable: bool) -> Self {
+        self.discard = enable;
+        self
+    }
+
     /// Get the device path
     pub fn device_path(&self) -> &std::path::Path {
         &self.device_path
     }
 
+    /// Get where the filesystem is on the device
+    pub fn get_location(&self) -> VolumeLocation {
+        self.location
+    }
+
     /// Check if dry run mode is enabled
     pub fn is_dry_run(&self) -> bool {
         self.dry_run
@@ -298,6 +668,142 @@ impl ResizeOptions {
     pub fn is_verbose(&self) -> bool {
         self.verbose
     }
+
+    /// Get the requested target size, if any
+    pub fn get_target_size(&self) -> Option<TargetSize> {
+        self.target_size
+    }
+
+    /// Get the strategy for FAT growth
+    pub fn get_strategy(&self) -> GrowStrategy {
+        self.strategy
+    }
+
+    /// Get the requested FAT reserve, if any
+    pub fn get_fat_reserve(&self) -> Option<FatReserve> {
+        self.fat_reserve
+    }
+
+    /// Get the requested data area alignment, if any
+    pub fn get_align(&self) -> Option<Alignment> {
+        self.align
+    }
+
+    /// Get the requested number of FAT copies, if any
+    pub fn get_num_fats(&self) -> Option<u8> {
+        self.num_fats
+    }
+
+    /// Get the requested cluster size in bytes, if any
+    pub fn get_cluster_size(&self) -> Option<u64> {
+        self.cluster_size
+    }
+
+    /// Check if conversion to FAT32 is requested
+    pub fn is_fat32_conversion(&self) -> bool {
+        self.convert_to_fat32
+    }
+
+    /// Check if hidden sectors are to be set to the partition start
+    pub fn is_hidden_sectors_fix(&self) -> bool {
+        self.fix_hidden_sectors
+    }
+
+    /// Get the number of runs read ahead during a data shift
+    pub fn get_queue_depth(&self) -> usize {
+        self.queue_depth
+    }
+
+    /// Check if direct I/O is requested
+    pub fn is_direct_io(&self) -> bool {
+        self.direct_io
+    }
+
+    /// Get the memory limit for the FAT and the data shift plan
+    pub fn get_memory_limit(&self) -> Option<u64> {
+        self.memory_limit
+    }
+
+    /// Read-ahead of a data shift: the queue depth, with runs short enough
+    /// that those in memory take at most a third of the memory limit
+    pub(crate) fn read_ahead(&self) -> ReadAhead {
+        ReadAhead::new(self.queue_depth).within(self.memory_limit.map(|limit| limit / 3))
+    }
+
+    /// Check if free clusters are discarded after growing
+    pub fn is_discard(&self) -> bool {
+        self.discard
+    }
+
+    /// Size calculation options matching these resize options
+    ///
+    /// Automatic alignment is resolved from the device here.
+    pub(crate) fn calculation_options(&self) -> CalculationOptions {
+        let align = self.align.map(|align| match align {
+            Alignment::Bytes(bytes) => bytes,
+            Alignment::Auto => get_alignment_hint(self.device_path())
+                .unwrap_or(0)
+                .max(AUTO_ALIGN_MIN_BYTES),
+        });
+        CalculationOptions::new()
+            .target_size(self.target_size)
+            .fat_reserve(self.fat_reserve)
+            .align(align)
+            .num_fats(self.num_fats)
+            .convert_to_fat32(self.convert_to_fat32)
+    }
+
+    /// Calculate the new layout for growing the filesystem
+    ///
+    /// If automatic alignment cannot be met, the data area is left unaligned.
+    pub(crate) fn calculate(
+        &self,
+        boot: &BootSector,
+        device_sectors: u64,
+    ) -> Result<SizeCalculation> {
+        let calc_options = self.calculation_options();
+        match calculate_new_size_with_options(boot, device_sectors, &calc_options) {
+            Err(Error::CannotAlign(_)) if self.align == Some(Alignment::Auto) => {
+                calculate_new_size_with_options(boot, device_sectors, &calc_options.align(None))
+            }
+            result => result,
+        }
+    }
+
+    /// Let the operation fail every write after the first `writes` ones
+    #[cfg(test)]
+    pub(crate) fn crash_after_writes(mut self, writes: u64) -> Self {
+        self.crash_after_writes = Some(writes);
+        self
+    }
+
+    /// Open the device as these options require
+    pub(crate) fn open_device(&self) -> Result<Device> {
+        let mut device = if self.is_dry_run() {
+            Device::open_readonly_at(self.device_path(), self.location)?
+        } else {
+            Device::open_at(self.device_path(), self.location)?
+        };
+        if self.direct_io && !self.dry_run {
+            match device.enable_direct_io() {
+                Ok(true) if self.verbose => eprintln!(
+                    "Using direct I/O ({}-byte blocks)",
+                    device.direct_io_block_size().unwrap_or_default()
+                ),
+                Ok(false) if self.verbose => {
+                    eprintln!("Direct I/O is not available here; using buffered I/O")
+                }
+                Ok(_) => {}
+                // Filesystems and drivers without O_DIRECT refuse the open
+                Err(e) => eprintln!("Direct I/O is not available ({}); using buffered I/O", e),
+            }
+        }
+        #[cfg(test)]
+        if let Some(writes) = self.crash_after_writes {
+            device.crash_after_writes(writes);
+        }
+        Ok(device)
+    }
 }
 
 /// Result of a resize operation
@@ -311,26 +817,76 @@ pub struct ResizeResult {
     pub fat_grew: bool,
     /// Number of clusters relocated
     pub clusters_relocated: usize,
+    /// Strategy used to make room for the larger FAT (`None` if it did not grow)
+    pub strategy: Option<GrowStrategy>,
+    /// FAT32 limit that stopped the filesystem short of filling the device
+    pub size_limit: Option<SizeLimit>,
+    /// FAT type of a filesystem that was converted to FAT32
+    pub converted_from: Option<FatType>,
+    /// Device bytes past the end of the filesystem, left unused
+    pub unused_bytes: u64,
+    /// Hidden sectors before and after being set to the partition start
+    pub hidden_sectors_change: Option<(u32, u32)>,
+    /// MBR partition type before and after being updated for the new size
+    /// or FAT type
+    pub partition_type_change: Option<(u8, u8)>,
+    /// Space kept free in a sparse image file or qcow2 image: holes skipped
+    /// instead of copied or zeroed, and holes punched where data moved away
+    pub sparse: SparseStats,
+    /// Reads and writes issued to the device, and the resulting throughput
+    pub io: IoStats,
+    /// Free clusters discarded after growing (`None` unless requested)
+    pub discard: Option<DiscardStats>,
     /// Detailed calculation results
     pub calculation: SizeCalculation,
     /// List of operations performed (for logging)
     pub operations: Vec<String>,
 }
 
+/// Partition start in filesystem sectors, which the boot sector's hidden
+/// sectors should match
+///
+/// Known for a partition or offset of a whole-disk device, and for partition
+/// block devices such as `/dev/sda1` through sysfs.
+fn partition_start_sector(
+    device: &Device,
+    location: VolumeLocation,
+    boot: &BootSector,
+) -> Option<u32> {
+    let start = match location {
+        VolumeLocation::Whole => get_partition_start(device.path())?,
+        _ => device.start_offset(),
+    };
+    u32::try_from(start / boot.bytes_per_sector() as u64).ok()
+}
+
+/// Change the type of an MBR partition on the whole device
+fn write_partition_type(
+    device_path: &std::path::Path,
+    partition: &Partition,
+    system_id: u8,
+) -> Result<()> {
+    let disk = Device::open(device_path)?;
+    mbr::write_entry_type(&disk, partition.entry_offset, system_id)?;
+    disk.sync()
+}
+
 /// Main resize function with crash-safe checkpoint support
+///
+/// exFAT filesystems are grown by [`resize_exfat`](crate::resize::resize_exfat).
 pub fn resize_fat32(options: ResizeOptions) -> Result<ResizeResult> {
+    if crate::exfat::is_exfat_at(options.device_path(), options.get_location())? {
+        return crate::resize::exfat::resize_exfat(options);
+    }
+
     let mut operations = Vec::new();
 
     // Check if mounted
-    check_not_mounted(options.device_path())?;
+    check_not_mounted_at(options.device_path(), options.get_location())?;
     operations.push("Verified device is not mounted".to_string());
 
     // Open device
-    let mut device = if options.is_dry_run() {
-        Device::open_readonly(options.device_path())?
-    } else {
-        Device::open(options.device_path())?
-    };
+    let mut device = options.open_device()?;
     operations.push(format!(
         "Opened device: {}",
         options.device_path().display()
@@ -347,12 +903,19 @@ pub fn resize_fat32(options: ResizeOptions) -> Result<ResizeResult> {
 
     // Check for incomplete resize operation
     let incomplete_resize = if !options.is_dry_run() {
-        check_for_incomplete_resize(&device, &boot)?
+        let candidates = checkpoint_candidates(&device, &boot, &options);
+        check_for_incomplete_resize(&device, &boot, &candidates)?
     } else {
         None
     };
 
-    if let Some(ref checkpoint) = incomplete_resize {
+    // Renumbering leaves the old filesystem untouched until the boot sector is
+    // invalidated, so until then an interrupted attempt simply starts over
+    let incomplete_resize = incomplete_resize.filter(|(checkpoint, _)| {
+        checkpoint.operation != ResizeOperation::GrowRenumber || !boot.is_signature_valid()
+    });
+
+    if let Some((ref checkpoint, _)) = incomplete_resize {
         eprintln!(
             "Resuming interrupted resize from phase {:?}...",
             checkpoint.phase
@@ -369,61 +932,61 @@ pub fn resize_fat32(options: ResizeOptions) -> Result<ResizeResult> {
         }
     }
 
+    // FAT12/16 have neither a backup boot sector nor an FSInfo sector
+    let fat_type = boot.fat_type();
+    let is_fat32 = fat_type == FatType::Fat32;
+    if !is_fat32 {
+        operations.push(format!("Detected {} filesystem", fat_type));
+    }
+
     // Read backup boot sector (skip match check if boot sector is invalidated)
     let backup_sector = boot.backup_boot_sector();
-    let backup_boot = read_backup_boot_sector(&device, backup_sector)?;
-    if boot.is_signature_valid() && !boot_sectors_match(&boot, &backup_boot) {
-        return Err(Error::BackupMismatch);
+    if is_fat32 {
+        let backup_boot = read_backup_boot_sector(&device, backup_sector)?;
+        if boot.is_signature_valid() && !boot_sectors_match(&boot, &backup_boot) {
+            return Err(Error::BackupMismatch);
+        }
+        operations.push(format!(
+            "Verified backup boot sector at sector {}",
+            backup_sector
+        ));
     }
-    operations.push(format!(
-        "Verified backup boot sector at sector {}",
-        backup_sector
-    ));
 
     // Read FSInfo
     let fsinfo_sector = boot.fs_info_sector();
-    let mut fsinfo = read_fsinfo(&device, fsinfo_sector)?;
-    operations.push(format!("Read FSInfo from sector {}", fsinfo_sector));
+    let mut fsinfo = if is_fat32 {
+        let fsinfo = read_fsinfo(&device, fsinfo_sector)?;
+        operations.push(format!("Read FSInfo from sector {}", fsinfo_sector));
+        Some(fsinfo)
+    } else {
+        None
+    };
 
     // Calculate new size (use checkpoint values if resuming)
     let device_sectors = device.total_sectors();
-    let calculation = if let Some(ref checkpoint) = incomplete_resize {
-        // Use checkpoint values for consistency
-        SizeCalculation {
-            old_total_sectors: checkpoint.old_total_sectors,
-            new_total_sectors: checkpoint.new_total_sectors,
-            old_fat_size: checkpoint.old_fat_size,
-            new_fat_size: checkpoint.new_fat_size,
-            new_data_clusters: calculate_data_clusters_from_params(
-                checkpoint.new_total_sectors,
-                boot.reserved_sectors(),
-                boot.num_fats(),
-                checkpoint.new_fat_size,
-                boot.sectors_per_cluster(),
-            ),
-            new_free_clusters: 0, // Will be recalculated
-            fat_needs_growth: checkpoint.new_fat_size > checkpoint.old_fat_size,
-            fat_growth_sectors: checkpoint
-                .new_fat_size
-                .saturating_sub(checkpoint.old_fat_size),
-            first_affected_cluster: 2,
-            // Note: formula matches calculator.rs: last = first + affected_clusters - 1
-            last_affected_cluster: 2
-                + (checkpoint
-                    .new_fat_size
-                    .saturating_sub(checkpoint.old_fat_size)
-                    * boot.num_fats() as u32)
-                    .div_ceil(boot.sectors_per_cluster() as u32)
-                - 1,
-        }
-    } else {
-        calculate_new_size(&boot, device_sectors)?
+    let calculation = match incomplete_resize {
+        Some((ref checkpoint, _)) => resumed_calculation(&boot, checkpoint),
+        None => options.calculate(&boot, device_sectors)?,
     };
+    let run = GrowRun::new(
+        &device,
+        &options,
+        &boot,
+        &calculation,
+        incomplete_resize.as_ref(),
+    );
 
     operations.push(format!(
         "Calculated resize: {} -> {} sectors",
         calculation.old_total_sectors, calculation.new_total_sectors
     ));
+    if let Some(limit) = calculation.size_limit {
+        operations.push(format!(
+            "Capped at the {}, leaving {} device sectors unused",
+            limit,
+            device_sectors - calculation.new_total_sectors as u64
+        ));
+    }
 
     if options.is_verbose() {
         print_verbose_resize_info(&boot, &calculation);
@@ -432,186 +995,113 @@ pub fn resize_fat32(options: ResizeOptions) -> Result<ResizeResult> {
     let old_size_bytes = calculation.old_total_sectors as u64 * boot.bytes_per_sector() as u64;
     let new_size_bytes = calculation.new_total_sectors as u64 * boot.bytes_per_sector() as u64;
 
-    // Read FAT table
-    let mut fat = read_fat_table(&device, &boot, 0)?;
-    operations.push(format!("Read FAT table ({} entries)", fat.len()));
+    let mut fat = FatReader::new(&device, &boot, 0, run.memory_limit);
+    if fat.is_windowed() {
+        operations.push(format!(
+            "Reading FAT table ({} entries) in windows of {} entries",
+            fat.entry_count(),
+            fat.window_entries()
+        ));
+    } else {
+        operations.push(format!("Read FAT table ({} entries)", fat.entry_count()));
+    }
 
-    let mut clusters_relocated = 0;
+    if run.converting {
+        operations.push(format!("Converting {} to FAT32", fat_type));
+    }
 
-    // Determine starting phase based on checkpoint
-    let starting_phase = incomplete_resize
-        .as_ref()
-        .map(|cp| cp.phase)
-        .unwrap_or(ResizePhase::Started);
+    // Hidden sectors and the MBR type are checked against the partition
+    let partition = partition_at(options.device_path(), options.get_location())?;
+    let hidden_sectors_change = partition_start_sector(&device, options.get_location(), &boot)
+        .filter(|&start| options.is_hidden_sectors_fix() && start != boot.hidden_sectors())
+        .map(|start| (boot.hidden_sectors(), start));
+    let partition_type_change = partition.as_ref().and_then(|partition| {
+        let PartitionType::Mbr(old_type) = partition.partition_type else {
+            return None;
+        };
+        mbr::fat_partition_type(partition, calculation.new_fat_type)
+            .map(|new_type| (old_type, new_type))
+    });
 
     // Handle FAT growth if needed
+    let mut grown = GrownFat::default();
     if calculation.fat_needs_growth {
         operations.push(format!(
             "FAT needs to grow by {} sectors",
             calculation.fat_growth_sectors
         ));
 
-        // Plan cluster data shift
-        let plan = plan_relocation(
-            &device,
-            &boot,
-            &fat,
-            calculation.first_affected_cluster,
-            calculation.last_affected_cluster,
-            calculation.new_data_clusters,
-        )?;
-
-        if !plan.is_empty() {
-            operations.push(format!(
-                "Planned data shift for {} clusters ({} bytes)",
-                plan.cluster_count(),
-                plan.total_bytes
-            ));
-
-            if options.is_verbose() {
-                eprintln!("\nData shift plan (cluster numbers unchanged, sectors shift forward):");
-                eprintln!("  {} clusters will be moved", plan.moves.len());
+        // An interrupted resize continues with the strategy it started with
+        let growth = match incomplete_resize {
+            Some((ref checkpoint, _)) if checkpoint.operation == ResizeOperation::GrowRenumber => {
+                FatGrowth::ResumeRenumber(checkpoint.clone())
             }
+            Some(_) => plan_fat_growth(
+                &device,
+                &boot,
+                &mut fat,
+                &calculation,
+                run.checkpoint_sector,
+                GrowStrategy::Shift,
+                run.memory_limit,
+            )?,
+            None => plan_fat_growth(
+                &device,
+                &boot,
+                &mut fat,
+                &calculation,
+                run.checkpoint_sector,
+                options.get_strategy(),
+                run.memory_limit,
+            )?,
+        };
 
-            if !options.is_dry_run() {
-                // === PHASE 0: Data shift (safe - source preserved) ===
-                if starting_phase == ResizePhase::Started {
-                    // Write initial checkpoint
-                    let checkpoint = ResizeCheckpoint::new(
-                        ResizePhase::Started,
-                        calculation.old_total_sectors,
-                        calculation.new_total_sectors,
-                        calculation.old_fat_size,
-                        calculation.new_fat_size,
-                    );
-                    write_checkpoint(&device, &checkpoint)?;
-                    operations.push("Wrote checkpoint (phase 0: started)".to_string());
-
-                    maybe_crash_at("after_checkpoint_start");
-
-                    // Execute data shift
-                    let _relocations = execute_relocation(
-                        &device,
-                        &boot,
-                        &mut fat,
-                        &plan,
-                        calculation.new_fat_size,
-                        calculation.new_data_clusters,
-                        options.is_verbose(),
-                    )?;
-                    clusters_relocated = plan.cluster_count();
-                    operations.push(format!("Shifted {} clusters forward", clusters_relocated));
-
-                    verify_relocation(
-                        &fat,
-                        calculation.first_affected_cluster,
-                        calculation.last_affected_cluster,
-                    )?;
-                    operations.push("Verified data shift".to_string());
-
-                    maybe_crash_at("after_data_shift");
-
-                    // Update checkpoint to phase 1
-                    let checkpoint = ResizeCheckpoint::new(
-                        ResizePhase::DataCopied,
-                        calculation.old_total_sectors,
-                        calculation.new_total_sectors,
-                        calculation.old_fat_size,
-                        calculation.new_fat_size,
-                    );
-                    write_checkpoint(&device, &checkpoint)?;
-                    operations.push("Updated checkpoint (phase 1: data copied)".to_string());
-
-                    maybe_crash_at("after_checkpoint_data_copied");
-                } else {
-                    operations.push("Skipping data shift (already done)".to_string());
-                    clusters_relocated = plan.cluster_count();
-                }
-
-                // === PHASE 1: FAT operations (dangerous - boot sector invalidated) ===
-                if starting_phase <= ResizePhase::DataCopied {
-                    // === DANGER ZONE START ===
-                    // Invalidate boot sector to prevent other tools from operating
-                    boot.invalidate_signature();
-                    write_boot_sector(&device, &boot)?;
-                    device.sync()?;
-                    operations.push("Invalidated boot sector (danger zone)".to_string());
-
-                    maybe_crash_at("after_boot_invalidate");
-
-                    // Initialize new FAT1 sectors
-                    init_new_fat_sectors(&device, &boot, &calculation)?;
-                    operations.push("Initialized new FAT sectors".to_string());
-
-                    // Sync FAT1 to FAT2
-                    sync_fat_copies(&device, &boot, &calculation)?;
-                    operations.push("Synced FAT copies".to_string());
-
-                    maybe_crash_at("after_fat_write");
-
-                    // Update checkpoint to phase 2
-                    let checkpoint = ResizeCheckpoint::new(
-                        ResizePhase::FatWritten,
-                        calculation.old_total_sectors,
-                        calculation.new_total_sectors,
-                        calculation.old_fat_size,
-                        calculation.new_fat_size,
-                    );
-                    write_checkpoint(&device, &checkpoint)?;
-                    operations.push("Updated checkpoint (phase 2: FAT written)".to_string());
-
-                    maybe_crash_at("after_checkpoint_fat_written");
-                    // === DANGER ZONE END ===
-                } else {
-                    operations.push("Skipping FAT operations (already done)".to_string());
-                }
-            } else {
-                operations.push("Dry run: would shift cluster data".to_string());
+        grown = match growth {
+            FatGrowth::Shift(plan) => {
+                run.grow_by_shift(&mut boot, &mut fat, &plan, &mut operations)?
             }
-        }
+            FatGrowth::ShiftBack(plan) => {
+                run.grow_by_shift_back(&mut boot, &plan, &mut operations)?
+            }
+            FatGrowth::Renumber(plan) => run.grow_by_renumber(&mut boot, &plan, &mut operations)?,
+            FatGrowth::ResumeRenumber(checkpoint) => run.renumber(
+                &mut boot,
+                RenumberStart::Resumed(checkpoint),
+                &mut operations,
+            )?,
+        };
     }
 
-    if !options.is_dry_run() {
-        // === PHASE 2: Metadata update (restore boot sector) ===
-
-        // Update boot sector with new values and restore signature
-        boot.set_total_sectors_32(calculation.new_total_sectors);
-        boot.set_fat_size_32(calculation.new_fat_size);
-        boot.restore_signature(); // Restore 0xAA55 signature
-
-        write_boot_sector(&device, &boot)?;
-        operations.push("Updated boot sector (signature restored)".to_string());
-
-        // Update backup boot sector
-        write_backup_boot_sector(&device, &boot, backup_sector)?;
-        operations.push("Updated backup boot sector".to_string());
+    // A conversion resumed after its FAT was written finds its root cluster
+    // in the checkpoint
+    if run.converting && grown.converted_root_cluster.is_none() {
+        grown.converted_root_cluster = incomplete_resize
+            .as_ref()
+            .map(|(checkpoint, _)| checkpoint.new_root_cluster);
+    }
 
-        // Update FSInfo with new free cluster count
-        let old_free = fsinfo.free_count();
-        let old_data_clusters = (calculation.old_total_sectors
-            - boot.reserved_sectors() as u32
-            - (boot.num_fats() as u32 * calculation.old_fat_size))
-            / boot.sectors_per_cluster() as u32;
-        let additional_clusters = calculation
-            .new_data_clusters
-            .saturating_sub(old_data_clusters);
-
-        let new_free = if old_free == FSInfo::UNKNOWN_FREE {
-            FSInfo::UNKNOWN_FREE
-        } else {
-            old_free.saturating_add(additional_clusters)
-        };
-        fsinfo.set_free_count(new_free);
-        write_fsinfo(&device, &fsinfo, fsinfo_sector)?;
-        operations.push(format!("Updated FSInfo (free clusters: {})", new_free));
+    let mut discard = None;
+    if !options.is_dry_run() {
+        run.finish(
+            &mut boot,
+            fsinfo.as_mut(),
+            &grown,
+            hidden_sectors_change,
+            &mut operations,
+        )?;
 
-        // Clear checkpoint
-        clear_checkpoint(&device)?;
-        operations.push("Cleared checkpoint".to_string());
+        // The partition type follows the filesystem once it is complete
+        if let (Some(partition), Some((old_type, new_type))) = (&partition, partition_type_change) {
+            write_partition_type(options.device_path(), partition, new_type)?;
+            operations.push(format!(
+                "Changed partition type from 0x{:02X} to 0x{:02X}",
+                old_type, new_type
+            ));
+        }
 
-        // Final sync
-        device.sync()?;
-        operations.push("Synced changes to disk".to_string());
+        if options.is_discard() {
+            discard = Some(run.discard_free_space(&boot, &mut operations)?);
+        }
     } else {
         operations.push("Dry run: no changes made".to_string());
     }
@@ -620,28 +1110,865 @@ pub fn resize_fat32(options: ResizeOptions) -> Result<ResizeResult> {
         old_size_bytes,
         new_size_bytes,
         fat_grew: calculation.fat_needs_growth,
-        clusters_relocated,
+        clusters_relocated: grown.clusters_relocated,
+        strategy: grown.strategy,
+        size_limit: calculation.size_limit,
+        converted_from: run.converting.then_some(fat_type),
+        unused_bytes: device_sectors.saturating_sub(calculation.new_total_sectors as u64)
+            * boot.bytes_per_sector() as u64,
+        hidden_sectors_change,
+        partition_type_change,
+        sparse: device.sparse_stats(),
+        io: device.io_stats(),
+        discard,
         calculation,
         operations,
     })
 }
 
-/// Print verbose resize information to stderr
-fn print_verbose_resize_info(boot: &BootSector, calculation: &SizeCalculation) {
-    eprintln!("Current filesystem:");
-    eprintln!("  Total sectors: {}", calculation.old_total_sectors);
-    eprintln!("  FAT size: {} sectors", calculation.old_fat_size);
-    eprintln!("  Data clusters: {}", boot.data_clusters());
-    eprintln!();
-    eprintln!("After resize:");
-    eprintln!("  Total sectors: {}", calculation.new_total_sectors);
-    eprintln!("  FAT size: {} sectors", calculation.new_fat_size);
-    eprintln!("  Data clusters: {}", calculation.new_data_clusters);
-    eprintln!("  FAT needs growth: {}", calculation.fat_needs_growth);
+/// Size calculation of an interrupted resize, from its checkpoint
+///
+/// The boot sector still has the old number of FATs (and the old FAT type)
+/// until the resize completes.
+fn resumed_calculation(boot: &BootSector, checkpoint: &ResizeCheckpoint) -> SizeCalculation {
+    let new_num_fats = match checkpoint.new_num_fats {
+        0 => boot.num_fats(),
+        num_fats => num_fats,
+    };
+    if checkpoint.operation == ResizeOperation::Convert {
+        return conversion_calculation(
+            boot,
+            checkpoint.new_total_sectors,
+            checkpoint.new_fat_size,
+            new_num_fats,
+            None,
+        );
+    }
+
+    let total_growth = (new_num_fats as u32 * checkpoint.new_fat_size)
+        .saturating_sub(boot.num_fats() as u32 * checkpoint.old_fat_size);
+    let affected_clusters = total_growth.div_ceil(boot.sectors_per_cluster() as u32);
+    SizeCalculation {
+        old_total_sectors: checkpoint.old_total_sectors,
+        new_total_sectors: checkpoint.new_total_sectors,
+        old_fat_size: checkpoint.old_fat_size,
+        new_fat_size: checkpoint.new_fat_size,
+        new_num_fats,
+        new_data_clusters: calculate_data_clusters_from_params(
+            checkpoint.new_total_sectors - boot.root_dir_sectors() as u32,
+            boot.reserved_sectors(),
+            new_num_fats,
+            checkpoint.new_fat_size,
+            boot.sectors_per_cluster(),
+        ),
+        new_free_clusters: 0, // Will be recalculated
+        fat_needs_growth: checkpoint.new_fat_size > checkpoint.old_fat_size
+            || new_num_fats != boot.num_fats(),
+        fat_growth_sectors: checkpoint
+            .new_fat_size
+            .saturating_sub(checkpoint.old_fat_size),
+        // Note: formula matches calculator.rs: last = first + affected_clusters - 1
+        first_affected_cluster: if affected_clusters > 0 { 2 } else { 0 },
+        last_affected_cluster: if affected_clusters > 0 {
+            2 + affected_clusters - 1
+        } else {
+            0
+        },
+        size_limit: None,
+        new_fat_type: boot.fat_type(),
+    }
 }
 
-/// Helper to calculate data clusters from parameters
-fn calculate_data_clusters_from_params(
+/// How the FAT tables grew
+#[derive(Debug, Default)]
+struct GrownFat {
+    strategy: Option<GrowStrategy>,
+    clusters_relocated: usize,
+    /// Last checkpoint of a renumbering, which changed every cluster number
+    renumbered: Option<ResizeCheckpoint>,
+    /// Root cluster of a FAT12/16 filesystem converted to FAT32
+    converted_root_cluster: Option<u32>,
+}
+
+/// The steps of a FAT32, FAT16 or FAT12 grow, and what they share
+///
+/// A grow runs in checkpointed phases. Phase 0 only writes where the old
+/// filesystem does not look, so it stays valid. Phase 1 invalidates the boot
+/// sector and writes the new FAT tables. Phase 2 writes the new boot sector,
+/// restoring its signature, and clears the checkpoint. An interrupted grow
+/// skips the phases its checkpoint records as done.
+struct GrowRun<'a> {
+    device: &'a Device,
+    options: &'a ResizeOptions,
+    calculation: &'a SizeCalculation,
+    /// Where the checkpoint recording the phases lives
+    checkpoint_sector: u64,
+    /// Phase an interrupted grow resumes at (`Started` for a new one)
+    starting_phase: ResizePhase,
+    /// Data shift progress of an interrupted phase 0
+    resume_watermark: Option<u32>,
+    /// Whether a FAT12/16 filesystem is converted to FAT32, which stages its
+    /// FAT and root directory below the checkpoint instead (see `convert`)
+    converting: bool,
+    /// Operation the checkpoints of a shift record
+    shift_operation: ResizeOperation,
+    /// Whether a fixed FAT12/16 root directory moves, to `new_root_dir_sector`
+    /// by way of `root_staging_sector` (right below the checkpoint, beyond
+    /// the shifted data)
+    moves_root_dir: bool,
+    new_root_dir_sector: u64,
+    root_staging_sector: u64,
+    /// Data clusters before the grow
+    old_data_clusters: u32,
+    /// Memory for FAT windows: a third of the memory limit, next to a third
+    /// for moves of the data shift and a third for the runs it reads ahead
+    /// (see `read_ahead`)
+    memory_limit: Option<u64>,
+}
+
+impl<'a> GrowRun<'a> {
+    fn new(
+        device: &'a Device,
+        options: &'a ResizeOptions,
+        boot: &BootSector,
+        calculation: &'a SizeCalculation,
+        incomplete_resize: Option<&(ResizeCheckpoint, u64)>,
+    ) -> Self {
+        let checkpoint_sector = incomplete_resize
+            .map(|(_, sector)| *sector)
+            .unwrap_or_else(|| checkpoint_sector(calculation));
+        let converting = calculation.new_fat_type != boot.fat_type();
+        let new_root_dir_sector = boot.reserved_sectors() as u64
+            + calculation.new_num_fats as u64 * calculation.new_fat_size as u64;
+        Self {
+            device,
+            options,
+            calculation,
+            checkpoint_sector,
+            starting_phase: incomplete_resize
+                .map(|(checkpoint, _)| checkpoint.phase)
+                .unwrap_or(ResizePhase::Started),
+            resume_watermark: incomplete_resize
+                .map(|(checkpoint, _)| checkpoint.watermark)
+                .filter(|&watermark| watermark != 0),
+            converting,
+            shift_operation: if converting {
+                ResizeOperation::Convert
+            } else {
+                ResizeOperation::Grow
+            },
+            moves_root_dir: !converting
+                && boot.root_dir_sectors() > 0
+                && new_root_dir_sector != boot.first_root_dir_sector(),
+            new_root_dir_sector,
+            root_staging_sector: checkpoint_sector.saturating_sub(boot.root_dir_sectors()),
+            old_data_clusters: boot.data_clusters(),
+            memory_limit: options.get_memory_limit().map(|limit| limit / 3),
+        }
+    }
+
+    /// Checkpoint of the grow at `phase`
+    fn checkpoint(&self, phase: ResizePhase) -> ResizeCheckpoint {
+        let calc = self.calculation;
+        ResizeCheckpoint::new(
+            phase,
+            calc.old_total_sectors,
+            calc.new_total_sectors,
+            calc.old_fat_size,
+            calc.new_fat_size,
+        )
+        .with_num_fats(calc.new_num_fats)
+        .with_operation(self.shift_operation)
+    }
+
+    fn record(&self, checkpoint: &ResizeCheckpoint) -> Result<()> {
+        write_checkpoint(self.device, self.checkpoint_sector, checkpoint)
+    }
+
+    /// Grow by shifting the data area forward: phase 0 copies the data,
+    /// phase 1 writes the FAT tables over the old places of its first
+    /// clusters
+    fn grow_by_shift(
+        &self,
+        boot: &mut BootSector,
+        fat: &mut FatReader,
+        plan: &WindowedShift,
+        operations: &mut Vec<String>,
+    ) -> Result<GrownFat> {
+        let mut grown = GrownFat {
+            strategy: Some(GrowStrategy::Shift),
+            ..GrownFat::default()
+        };
+        if !plan.is_empty() {
+            operations.push(format!(
+                "Planned data shift for {} clusters ({} bytes)",
+                plan.cluster_count, plan.total_bytes
+            ));
+
+            if self.options.is_verbose() {
+                eprintln!("\nData shift plan (cluster numbers unchanged, sectors shift forward):");
+                eprintln!("  {} clusters will be moved", plan.cluster_count);
+            }
+        }
+
+        if self.options.is_dry_run() {
+            operations.push("Dry run: would shift cluster data".to_string());
+            return Ok(grown);
+        }
+
+        if self.starting_phase == ResizePhase::Started {
+            self.shift_forward(boot, fat, plan, operations)?;
+        } else {
+            operations.push("Skipping data shift (already done)".to_string());
+        }
+        grown.clusters_relocated = plan.cluster_count;
+
+        if self.starting_phase <= ResizePhase::DataCopied {
+            grown.converted_root_cluster = self.write_shifted_fat(boot, fat, plan, operations)?;
+        } else {
+            operations.push("Skipping FAT operations (already done)".to_string());
+        }
+        Ok(grown)
+    }
+
+    /// Phase 0 of a shift (safe - source preserved): stage what the FAT
+    /// tables will overwrite, copy the data forward and record phase 1
+    fn shift_forward(
+        &self,
+        boot: &BootSector,
+        fat: &mut FatReader,
+        plan: &WindowedShift,
+        operations: &mut Vec<String>,
+    ) -> Result<()> {
+        let started = |watermark: u32| {
+            self.checkpoint(ResizePhase::Started)
+                .with_watermark(watermark)
+        };
+
+        // Write initial checkpoint, keeping the progress of an earlier attempt
+        self.record(&started(self.resume_watermark.unwrap_or(0)))?;
+        operations.push("Wrote checkpoint (phase 0: started)".to_string());
+
+        maybe_crash_at("after_checkpoint_start");
+
+        // A fixed root directory is overwritten by the FAT tables; keep a
+        // copy until phase 1 moves it
+        if self.converting {
+            let staging_sector = conversion_staging_sector(boot, self.checkpoint_sector);
+            stage_conversion(self.device, boot, staging_sector)?;
+            operations.push(format!(
+                "Staged FAT and root directory at sector {}",
+                staging_sector
+            ));
+        } else {
+            self.stage_root_dir(boot, operations)?;
+        }
+
+        // Execute data shift, recording progress in the checkpoint
+        let mut persist_watermark = |watermark: u32| -> Result<()> {
+            self.record(&started(watermark))?;
+            maybe_crash_at("during_data_shift");
+            Ok(())
+        };
+        let copied = plan.execute(
+            self.device,
+            boot,
+            fat,
+            self.resume_watermark,
+            &mut persist_watermark,
+            self.options.read_ahead(),
+            self.options.is_verbose(),
+        )?;
+        if let Some(watermark) = self.resume_watermark {
+            operations.push(format!(
+                "Resumed data shift below cluster {} ({} clusters copied)",
+                watermark, copied
+            ));
+        }
+        operations.push(format!("Shifted {} clusters forward", plan.cluster_count));
+
+        maybe_crash_at("after_data_shift");
+
+        self.record(&self.checkpoint(ResizePhase::DataCopied))?;
+        operations.push("Updated checkpoint (phase 1: data copied)".to_string());
+
+        maybe_crash_at("after_checkpoint_data_copied");
+        Ok(())
+    }
+
+    /// Phase 1 of a shift (dangerous - boot sector invalidated): free the
+    /// shifted sources, write the new FAT tables and record phase 2
+    ///
+    /// Returns the root cluster of a filesystem converted to FAT32.
+    fn write_shifted_fat(
+        &self,
+        boot: &mut BootSector,
+        fat: &mut FatReader,
+        plan: &WindowedShift,
+        operations: &mut Vec<String>,
+    ) -> Result<Option<u32>> {
+        // The shift is recorded, so its sources can go
+        let keep_from = if self.converting {
+            conversion_staging_sector(boot, self.checkpoint_sector)
+        } else if self.moves_root_dir {
+            self.root_staging_sector
+        } else {
+            self.checkpoint_sector
+        };
+        let punched = plan.punch_vacated(self.device, fat, keep_from)?;
+        if punched > 0 {
+            operations.push(format!(
+                "Punched holes where shifted clusters were ({} bytes)",
+                punched
+            ));
+        }
+
+        // === DANGER ZONE START ===
+        self.invalidate_boot_sector(boot, operations)?;
+
+        let converted_root_cluster = if self.converting {
+            // Rewrite all metadata but the boot sector from the staged copies
+            let staging_sector = conversion_staging_sector(boot, self.checkpoint_sector);
+            Some(write_converted_metadata(
+                self.device,
+                boot,
+                self.calculation,
+                staging_sector,
+                operations,
+            )?)
+        } else {
+            self.move_root_dir(boot, operations)?;
+
+            // Initialize new FAT1 sectors
+            init_new_fat_sectors(self.device, boot, self.calculation)?;
+            operations.push("Initialized new FAT sectors".to_string());
+
+            // Sync FAT1 to FAT2
+            sync_fat_copies(self.device, boot, self.calculation)?;
+            operations.push("Synced FAT copies".to_string());
+            None
+        };
+
+        maybe_crash_at("after_fat_write");
+
+        self.record(
+            &self
+                .checkpoint(ResizePhase::FatWritten)
+                .with_root_cluster(converted_root_cluster.unwrap_or(0)),
+        )?;
+        operations.push("Updated checkpoint (phase 2: FAT written)".to_string());
+
+        maybe_crash_at("after_checkpoint_fat_written");
+        // === DANGER ZONE END ===
+        Ok(converted_root_cluster)
+    }
+
+    /// Grow by shifting the data area back into a dropped FAT copy
+    ///
+    /// The dropped copy and the data after it are overwritten as soon as the
+    /// data moves, so phase 0 only stages the root directory, phase 1 writes
+    /// the FAT, and the data moves back after that, recording its progress in
+    /// the phase 2 checkpoint.
+    fn grow_by_shift_back(
+        &self,
+        boot: &mut BootSector,
+        plan: &RelocationPlan,
+        operations: &mut Vec<String>,
+    ) -> Result<GrownFat> {
+        operations.push(format!(
+            "Planned data shift back for {} clusters ({} bytes)",
+            plan.moves.len(),
+            plan.total_bytes
+        ));
+
+        if self.options.is_verbose() {
+            eprintln!("\nData shift plan (cluster numbers unchanged, sectors shift back):");
+            eprintln!("  {} clusters will be moved", plan.moves.len());
+        }
+
+        let grown = GrownFat {
+            strategy: Some(GrowStrategy::Shift),
+            clusters_relocated: plan.moves.len(),
+            ..GrownFat::default()
+        };
+        if self.options.is_dry_run() {
+            operations.push("Dry run: would shift cluster data back".to_string());
+            return Ok(grown);
+        }
+
+        if self.starting_phase == ResizePhase::Started {
+            self.stage_shift_back(boot, operations)?;
+        }
+        if self.starting_phase <= ResizePhase::DataCopied {
+            self.write_dropped_fat(boot, operations)?;
+        } else {
+            operations.push("Skipping FAT operations (already done)".to_string());
+        }
+        self.shift_back(boot, plan, operations)?;
+        Ok(grown)
+    }
+
+    /// Phase 0 of a shift back (safe - nothing overwritten): stage the root
+    /// directory and record phase 1
+    fn stage_shift_back(&self, boot: &BootSector, operations: &mut Vec<String>) -> Result<()> {
+        self.record(&self.checkpoint(ResizePhase::Started))?;
+        operations.push("Wrote checkpoint (phase 0: started)".to_string());
+
+        maybe_crash_at("after_checkpoint_start");
+
+        self.stage_root_dir(boot, operations)?;
+
+        self.record(&self.checkpoint(ResizePhase::DataCopied))?;
+        operations.push("Updated checkpoint (phase 1: data copied)".to_string());
+
+        maybe_crash_at("after_checkpoint_data_copied");
+        Ok(())
+    }
+
+    /// Phase 1 of a shift back (dangerous - boot sector invalidated): write
+    /// the remaining FAT copy and record phase 2
+    fn write_dropped_fat(&self, boot: &mut BootSector, operations: &mut Vec<String>) -> Result<()> {
+        self.invalidate_boot_sector(boot, operations)?;
+
+        self.move_root_dir(boot, operations)?;
+
+        init_new_fat_sectors(self.device, boot, self.calculation)?;
+        operations.push("Initialized new FAT sectors".to_string());
+
+        maybe_crash_at("after_fat_write");
+
+        self.record(&self.checkpoint(ResizePhase::FatWritten))?;
+        operations.push("Updated checkpoint (phase 2: FAT written)".to_string());
+
+        maybe_crash_at("after_checkpoint_fat_written");
+        Ok(())
+    }
+
+    /// Move the data back into the dropped FAT copy, recording progress in
+    /// the phase 2 checkpoint
+    fn shift_back(
+        &self,
+        boot: &BootSector,
+        plan: &RelocationPlan,
+        operations: &mut Vec<String>,
+    ) -> Result<()> {
+        let mut persist_watermark = |watermark: u32| -> Result<()> {
+            self.record(
+                &self
+                    .checkpoint(ResizePhase::FatWritten)
+                    .with_watermark(watermark),
+            )?;
+            maybe_crash_at("during_data_shift");
+            Ok(())
+        };
+        let copied = execute_relocation_with_progress(
+            self.device,
+            boot,
+            plan,
+            self.resume_watermark,
+            &mut persist_watermark,
+            self.options.read_ahead(),
+            self.options.is_verbose(),
+        )?;
+        operations.push(format!("Shifted {} clusters back", copied));
+
+        maybe_crash_at("after_data_shift");
+        // === DANGER ZONE END ===
+        Ok(())
+    }
+
+    /// Grow by moving the clusters the FAT grows into and renumbering the
+    /// others
+    fn grow_by_renumber(
+        &self,
+        boot: &mut BootSector,
+        plan: &RenumberPlan,
+        operations: &mut Vec<String>,
+    ) -> Result<GrownFat> {
+        operations.push(format!(
+            "Planned renumbering: {} clusters move, {} sectors of FAT and directories change ({} bytes)",
+            plan.moves.len(),
+            plan.journal.len(),
+            plan.bytes_written(boot.bytes_per_cluster())
+        ));
+
+        if self.options.is_verbose() {
+            eprintln!("\nRenumber plan (data stays in place, cluster numbers drop):");
+            eprintln!("  {} clusters will be moved", plan.moves.len());
+        }
+
+        if self.options.is_dry_run() {
+            operations.push("Dry run: would move and renumber clusters".to_string());
+            return Ok(GrownFat {
+                strategy: Some(GrowStrategy::Renumber),
+                clusters_relocated: plan.moves.len(),
+                ..GrownFat::default()
+            });
+        }
+
+        let start = RenumberStart::New {
+            plan,
+            operation: ResizeOperation::GrowRenumber,
+        };
+        let mut grown = self.renumber(boot, start, operations)?;
+        grown.clusters_relocated = plan.moves.len();
+        Ok(grown)
+    }
+
+    /// Run or finish a renumbering, through phases of its own (see
+    /// [`execute_renumbering`])
+    fn renumber(
+        &self,
+        boot: &mut BootSector,
+        start: RenumberStart,
+        operations: &mut Vec<String>,
+    ) -> Result<GrownFat> {
+        let checkpoint = execute_renumbering(
+            self.device,
+            boot,
+            self.calculation,
+            self.checkpoint_sector,
+            start,
+            self.options.is_verbose(),
+            operations,
+        )?;
+        Ok(GrownFat {
+            strategy: Some(GrowStrategy::Renumber),
+            renumbered: Some(checkpoint),
+            ..GrownFat::default()
+        })
+    }
+
+    /// Copy a fixed root directory that moves to its staging place
+    fn stage_root_dir(&self, boot: &BootSector, operations: &mut Vec<String>) -> Result<()> {
+        if self.moves_root_dir {
+            copy_root_dir(
+                self.device,
+                boot,
+                boot.first_root_dir_sector(),
+                self.root_staging_sector,
+            )?;
+            operations.push(format!(
+                "Staged root directory at sector {}",
+                self.root_staging_sector
+            ));
+        }
+        Ok(())
+    }
+
+    /// Move the staged root directory behind the new FAT tables
+    fn move_root_dir(&self, boot: &BootSector, operations: &mut Vec<String>) -> Result<()> {
+        if self.moves_root_dir {
+            copy_root_dir(
+                self.device,
+                boot,
+                self.root_staging_sector,
+                self.new_root_dir_sector,
+            )?;
+            operations.push(format!(
+                "Moved root directory to sector {}",
+                self.new_root_dir_sector
+            ));
+        }
+        Ok(())
+    }
+
+    /// Invalidate the boot sector to prevent other tools from operating on
+    /// the filesystem while its metadata changes
+    fn invalidate_boot_sector(
+        &self,
+        boot: &mut BootSector,
+        operations: &mut Vec<String>,
+    ) -> Result<()> {
+        boot.invalidate_signature();
+        write_boot_sector(self.device, boot)?;
+        self.device.sync()?;
+        operations.push("Invalidated boot sector (danger zone)".to_string());
+
+        maybe_crash_at("after_boot_invalidate");
+        Ok(())
+    }
+
+    /// Phase 2 (metadata update): write the new boot sector, restoring its
+    /// signature, and FSInfo, then clear the checkpoint
+    fn finish(
+        &self,
+        boot: &mut BootSector,
+        mut fsinfo: Option<&mut FSInfo>,
+        grown: &GrownFat,
+        hidden_sectors_change: Option<(u32, u32)>,
+        operations: &mut Vec<String>,
+    ) -> Result<()> {
+        let calculation = self.calculation;
+        let fsinfo_sector = boot.fs_info_sector();
+        // FAT12/16 have no backup boot sector, unless converted to FAT32
+        let has_backup = boot.fat_type() == FatType::Fat32 || self.converting;
+
+        // Update boot sector with new values and restore signature
+        let old_num_fats = boot.num_fats();
+        if let Some(root_cluster) = grown.converted_root_cluster {
+            *boot = boot.converted_to_fat32(
+                converted_reserved_sectors(
+                    boot,
+                    calculation.new_num_fats,
+                    calculation.new_fat_size,
+                ),
+                calculation.new_num_fats,
+                calculation.new_total_sectors,
+                calculation.new_fat_size,
+                root_cluster,
+            );
+        } else {
+            boot.set_total_sectors(calculation.new_total_sectors);
+            boot.set_fat_size(calculation.new_fat_size);
+            boot.set_num_fats(calculation.new_num_fats);
+        }
+        if let Some((_, start)) = hidden_sectors_change {
+            boot.set_hidden_sectors(start);
+            operations.push(format!("Set hidden sectors to {}", start));
+        }
+        boot.restore_signature(); // Restore 0xAA55 signature
+
+        // Renumbering changed every cluster number: recount the free clusters
+        // from the new FAT and drop the hint. Recounting can be repeated, so
+        // FSInfo is written before the boot sector makes the new size visible.
+        if let (Some(checkpoint), Some(fsinfo)) = (&grown.renumbered, fsinfo.as_deref_mut()) {
+            boot.set_root_cluster(checkpoint.new_root_cluster);
+            let new_free = FatReader::new(self.device, boot, 0, Some(FAT_IO_CHUNK_BYTES as u64))
+                .count_free(calculation.new_data_clusters)?;
+            fsinfo.set_free_count(new_free);
+            fsinfo.set_next_free(FSInfo::UNKNOWN_FREE);
+            write_fsinfo(self.device, fsinfo, fsinfo_sector)?;
+            self.device.sync()?;
+            operations.push(format!("Updated FSInfo (free clusters: {})", new_free));
+        }
+
+        // Backup first: until the primary is restored, a crash resumes from the checkpoint
+        if has_backup {
+            write_backup_boot_sector(self.device, boot, boot.backup_boot_sector())?;
+            operations.push("Updated backup boot sector".to_string());
+        }
+
+        write_boot_sector(self.device, boot)?;
+        operations.push("Updated boot sector (signature restored)".to_string());
+
+        // Update FSInfo with new free cluster count
+        if let (None, Some(fsinfo)) = (&grown.renumbered, fsinfo) {
+            let old_free = fsinfo.free_count();
+            let old_data_clusters = (calculation.old_total_sectors
+                - boot.reserved_sectors() as u32
+                - (old_num_fats as u32 * calculation.old_fat_size))
+                / boot.sectors_per_cluster() as u32;
+            let additional_clusters = calculation
+                .new_data_clusters
+                .saturating_sub(old_data_clusters);
+
+            let new_free = if old_free == FSInfo::UNKNOWN_FREE {
+                FSInfo::UNKNOWN_FREE
+            } else {
+                old_free.saturating_add(additional_clusters)
+            };
+            fsinfo.set_free_count(new_free);
+            write_fsinfo(self.device, fsinfo, fsinfo_sector)?;
+            operations.push(format!("Updated FSInfo (free clusters: {})", new_free));
+        }
+
+        // Clear checkpoint
+        clear_checkpoint(self.device, self.checkpoint_sector)?;
+        operations.push("Cleared checkpoint".to_string());
+
+        // Final sync
+        self.device.sync()?;
+        operations.push("Synced changes to disk".to_string());
+        Ok(())
+    }
+
+    /// Discard free clusters of the grown filesystem, once its checkpoint is
+    /// cleared
+    ///
+    /// A grown FAT shifted or renumbered the data, so any free cluster may
+    /// hold stale data; otherwise only the added clusters are new.
+    fn discard_free_space(
+        &self,
+        boot: &BootSector,
+        operations: &mut Vec<String>,
+    ) -> Result<DiscardStats> {
+        let calculation = self.calculation;
+        let first = if calculation.fat_needs_growth {
+            2
+        } else {
+            self.old_data_clusters + 2
+        };
+        let stats = discard_free_clusters(
+            self.device,
+            boot,
+            first..calculation.new_data_clusters + 2,
+            self.memory_limit,
+            false,
+        )?;
+        if stats.supported {
+            self.device.sync()?;
+            operations.push(format!(
+                "Discarded {} bytes of free clusters from cluster {}",
+                stats.discarded_bytes, first
+            ));
+        } else {
+            operations.push("Device does not support discards; skipped discarding".to_string());
+        }
+        Ok(stats)
+    }
+}
+
+/// Print verbose resize information to stderr
+fn print_verbose_resize_info(boot: &BootSector, calculation: &SizeCalculation) {
+    // A converted filesystem has the larger FAT32 reserved area and no fixed root directory
+    let (reserved_sectors, root_dir_sectors) = if calculation.new_fat_type != boot.fat_type() {
+        (
+            converted_reserved_sectors(boot, calculation.new_num_fats, calculation.new_fat_size),
+            0,
+        )
+    } else {
+        (boot.reserved_sectors(), boot.root_dir_sectors())
+    };
+
+    eprintln!("Current filesystem ({}):", boot.fat_type());
+    eprintln!("  Total sectors: {}", calculation.old_total_sectors);
+    eprintln!("  FAT size: {} sectors", calculation.old_fat_size);
+    eprintln!("  Number of FATs: {}", boot.num_fats());
+    eprintln!("  Data clusters: {}", boot.data_clusters());
+    eprintln!("  First data sector: {}", boot.first_data_sector());
+    eprintln!();
+    eprintln!("After resize ({}):", calculation.new_fat_type);
+    eprintln!("  Total sectors: {}", calculation.new_total_sectors);
+    eprintln!("  Reserved sectors: {}", reserved_sectors);
+    eprintln!("  FAT size: {} sectors", calculation.new_fat_size);
+    eprintln!("  Number of FATs: {}", calculation.new_num_fats);
+    eprintln!("  Data clusters: {}", calculation.new_data_clusters);
+    eprintln!(
+        "  First data sector: {}",
+        reserved_sectors as u64
+            + calculation.new_num_fats as u64 * calculation.new_fat_size as u64
+            + root_dir_sectors
+    );
+    eprintln!("  FAT needs growth: {}", calculation.fat_needs_growth);
+}
+
+/// Room made for the larger FAT tables
+enum FatGrowth {
+    /// Shift every cluster in use forward
+    Shift(WindowedShift),
+    /// Shift every cluster in use back, into the space of a dropped FAT copy
+    ShiftBack(RelocationPlan),
+    /// Move the clusters the FAT grows into and renumber the others
+    Renumber(RenumberPlan),
+    /// Finish an interrupted renumbering
+    ResumeRenumber(ResizeCheckpoint),
+}
+
+/// Plan making room for the larger FAT tables with the given strategy
+///
+/// `Auto` plans both strategies and picks the one writing fewer bytes.
+/// Renumbering needs free clusters for the displaced clusters and its journal;
+/// without them, `Auto` falls back to shifting. A change in the number of FATs
+/// always shifts, and needs no data moved at all when the data area stays.
+/// Dropping a FAT copy shifts the data back into its space. FAT12/16 always
+/// shift too: their fixed root directory has no cluster number to renumber.
+///
+/// Renumbering and the shift back need the whole FAT in memory; under a memory
+/// limit, `Auto` shifts and the others fail. The forward shift keeps about
+/// `memory_limit` bytes of moves at a time.
+fn plan_fat_growth(
+    device: &Device,
+    boot: &BootSector,
+    fat: &mut FatReader,
+    calc: &SizeCalculation,
+    checkpoint_sector: u64,
+    strategy: GrowStrategy,
+    memory_limit: Option<u64>,
+) -> Result<FatGrowth> {
+    let plan_shift = |fat: &mut FatReader| {
+        WindowedShift::plan(
+            boot,
+            fat,
+            calc.first_affected_cluster,
+            calc.last_affected_cluster,
+            memory_limit,
+        )
+    };
+
+    let fat_type = boot.fat_type();
+    if fat_type != FatType::Fat32 && strategy == GrowStrategy::Renumber {
+        return Err(Error::UnsupportedFatType(fat_type));
+    }
+
+    if calc.new_num_fats != boot.num_fats() || fat_type != FatType::Fat32 {
+        if strategy == GrowStrategy::Renumber {
+            return Err(Error::Calculation(
+                "Changing the number of FATs needs the shift strategy".to_string(),
+            ));
+        }
+        if shifts_back(boot, calc) {
+            let table = whole_fat(fat, memory_limit)?
+                .ok_or(Error::MemoryLimitUnsupported("Dropping a FAT copy"))?;
+            // Clusters past the old end are free in the new FAT
+            let mut new_fat = table.to_vec();
+            new_fat.truncate(boot.data_clusters() as usize + 2);
+            new_fat.resize(calc.new_data_clusters as usize + 2, fat_entry::FREE);
+            return Ok(FatGrowth::ShiftBack(plan_shift_back(boot, &new_fat, calc)));
+        }
+        if calc.first_affected_cluster == 0 {
+            return Ok(FatGrowth::Shift(WindowedShift::none(boot)));
+        }
+        return Ok(FatGrowth::Shift(plan_shift(fat)?));
+    }
+
+    match strategy {
+        GrowStrategy::Shift => Ok(FatGrowth::Shift(plan_shift(fat)?)),
+        GrowStrategy::Renumber => {
+            let table = whole_fat(fat, memory_limit)?
+                .ok_or(Error::MemoryLimitUnsupported("The renumber strategy"))?;
+            Ok(FatGrowth::Renumber(plan_renumber(
+                device,
+                boot,
+                table,
+                calc,
+                checkpoint_sector,
+            )?))
+        }
+        GrowStrategy::Auto => {
+            let shift = plan_shift(fat)?;
+            let Some(table) = whole_fat(fat, memory_limit)? else {
+                return Ok(FatGrowth::Shift(shift));
+            };
+            match plan_renumber(device, boot, table, calc, checkpoint_sector) {
+                Ok(renumber)
+                    if renumber.bytes_written(boot.bytes_per_cluster()) < shift.total_bytes =>
+                {
+                    Ok(FatGrowth::Renumber(renumber))
+                }
+                Ok(_) | Err(Error::NoFreeCluster) => Ok(FatGrowth::Shift(shift)),
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+/// The whole FAT, unless there is a memory limit
+fn whole_fat<'f>(fat: &'f mut FatReader, memory_limit: Option<u64>) -> Result<Option<&'f [u32]>> {
+    match memory_limit {
+        Some(_) => Ok(None),
+        None => fat.table(),
+    }
+}
+
+/// Whether the FAT tables take less space after the resize (a dropped FAT
+/// copy), so the data area moves back
+fn shifts_back(boot: &BootSector, calc: &SizeCalculation) -> bool {
+    (calc.new_num_fats as u32 * calc.new_fat_size) < (boot.num_fats() as u32 * calc.old_fat_size)
+}
+
+/// Helper to calculate data clusters from parameters
+pub(crate) fn calculate_data_clusters_from_params(
     total_sectors: u32,
     reserved_sectors: u16,
     num_fats: u8,
@@ -654,33 +1981,61 @@ fn calculate_data_clusters_from_params(
     data_sectors / sectors_per_cluster as u32
 }
 
+/// Copy the fixed FAT12/16 root directory region between two places
+fn copy_root_dir(device: &Device, boot: &BootSector, from: u64, to: u64) -> Result<()> {
+    let data = device.read_sectors(from, boot.root_dir_sectors() as u32)?;
+    device.write_sectors(to, &data)
+}
+
 /// Initialize new FAT sectors with free entries (zeros)
 /// This must be called BEFORE relocation so that reading new FAT sectors returns valid data
+///
+/// In an image file the new sectors become holes instead of being written.
 fn init_new_fat_sectors(device: &Device, boot: &BootSector, calc: &SizeCalculation) -> Result<()> {
-    let bytes_per_sector = boot.bytes_per_sector() as usize;
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
 
     if calc.new_fat_size <= calc.old_fat_size {
         return Ok(()); // No extension needed
     }
 
-    let fat1_start = boot.first_fat_sector();
-
-    // Create a sector of free entries
-    let free_sector = vec![0u8; bytes_per_sector];
-
     // Initialize new sectors of FAT1 with free entries
-    for sector_offset in calc.old_fat_size..calc.new_fat_size {
-        let sector = fat1_start + sector_offset as u64;
-        device.write_sector(sector, &free_sector)?;
-    }
+    let first_new_sector = boot.first_fat_sector() + calc.old_fat_size as u64;
+    let new_sectors = (calc.new_fat_size - calc.old_fat_size) as u64;
+    device.zero_range(
+        first_new_sector * bytes_per_sector,
+        new_sectors * bytes_per_sector,
+    )
+}
 
+/// Write sectors, leaving runs of all-zero sectors as holes in image files
+pub(crate) fn write_sectors_sparse(device: &Device, start_sector: u64, data: &[u8]) -> Result<()> {
+    let bytes_per_sector = device.sector_size() as usize;
+    let mut sectors = data.chunks(bytes_per_sector).enumerate().peekable();
+    while let Some((first, sector)) = sectors.next() {
+        let zero = sector.iter().all(|&b| b == 0);
+        let mut count = 1;
+        while sectors
+            .next_if(|(_, next)| next.iter().all(|&b| b == 0) == zero)
+            .is_some()
+        {
+            count += 1;
+        }
+        let offset = first * bytes_per_sector;
+        let run = &data[offset..offset + count * bytes_per_sector];
+        let sector = start_sector + first as u64;
+        if zero {
+            device.zero_range(sector * bytes_per_sector as u64, run.len() as u64)?;
+        } else {
+            device.write_sectors(sector, run)?;
+        }
+    }
     Ok(())
 }
 
 /// Sync FAT1 to FAT2 (and any additional FAT copies)
 /// This must be called AFTER relocation so that FAT2 gets the updated entries
 fn sync_fat_copies(device: &Device, boot: &BootSector, calc: &SizeCalculation) -> Result<()> {
-    if calc.new_fat_size <= calc.old_fat_size {
+    if calc.new_fat_size <= calc.old_fat_size && calc.new_num_fats <= boot.num_fats() {
         return Ok(()); // No extension needed
     }
 
@@ -688,47 +2043,110 @@ fn sync_fat_copies(device: &Device, boot: &BootSector, calc: &SizeCalculation) -
 
     // Copy the entire FAT1 to FAT2 (and any additional FAT copies)
     // FAT2 starts right after FAT1's NEW size
-    for fat_num in 1..boot.num_fats() {
-        let fat_dest_start = fat1_start + (fat_num as u64 * calc.new_fat_size as u64);
-
-        // Copy all sectors from FAT1 to this FAT copy
-        for sector_offset in 0..calc.new_fat_size {
-            let src_sector = fat1_start + sector_offset as u64;
-            let dest_sector = fat_dest_start + sector_offset as u64;
-
-            let data = device.read_sector(src_sector)?;
-            device.write_sector(dest_sector, &data)?;
+    device.timed(|| {
+        for fat_num in 1..calc.new_num_fats {
+            let fat_dest_start = fat1_start + (fat_num as u64 * calc.new_fat_size as u64);
+
+            // Copy all sectors from FAT1 to this FAT copy; free parts of the
+            // FAT stay holes in image files
+            let chunk_sectors = (FAT_IO_CHUNK_BYTES / device.sector_size() as usize) as u32;
+            for chunk_start in (0..calc.new_fat_size).step_by(chunk_sectors as usize) {
+                let count = chunk_sectors.min(calc.new_fat_size - chunk_start);
+                let data = device.read_sectors(fat1_start + chunk_start as u64, count)?;
+                write_sectors_sparse(device, fat_dest_start + chunk_start as u64, &data)?;
+            }
         }
-    }
-
-    Ok(())
+        Ok(())
+    })
 }
 
 /// Get information about a FAT32 filesystem without modifying it
 pub fn get_fs_info(device_path: impl AsRef<std::path::Path>) -> Result<FSInfoReport> {
+    get_fs_info_at(device_path, VolumeLocation::Whole)
+}
+
+/// Get information about the FAT filesystem in a partition or at an offset
+/// of the device
+pub fn get_fs_info_at(
+    device_path: impl AsRef<std::path::Path>,
+    location: VolumeLocation,
+) -> Result<FSInfoReport> {
     let device_path = device_path.as_ref();
-    let mut device = Device::open_readonly(device_path)?;
+    let mut device = Device::open_readonly_at(device_path, location)?;
     let boot = read_boot_sector(&mut device)?;
-
-    let backup_sector = boot.backup_boot_sector();
-    let backup_boot = read_backup_boot_sector(&device, backup_sector)?;
-    let backup_matches = boot_sectors_match(&boot, &backup_boot);
-
-    let fsinfo_sector = boot.fs_info_sector();
-    let fsinfo = read_fsinfo(&device, fsinfo_sector)?;
+    let fat_type = boot.fat_type();
+
+    // FAT12/16 have no backup boot sector, and no FSInfo to take the free
+    // cluster count from
+    let (backup_matches, free_clusters) = if fat_type == FatType::Fat32 {
+        let backup_boot = read_backup_boot_sector(&device, boot.backup_boot_sector())?;
+        let fsinfo = read_fsinfo(&device, boot.fs_info_sector())?;
+        (boot_sectors_match(&boot, &backup_boot), fsinfo.free_count())
+    } else {
+        let mut fat = FatReader::new(&device, &boot, 0, Some(FAT_IO_CHUNK_BYTES as u64));
+        (true, fat.count_free(boot.data_clusters())?)
+    };
 
     let device_sectors = device.total_sectors();
     let current_sectors = boot.total_sectors();
-    let can_grow = device_sectors > current_sectors as u64;
 
-    let max_new_size = if can_grow {
-        Some(device_sectors * boot.bytes_per_sector() as u64)
+    // Growing to fill the device stops at the FAT32 limits
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    let (max_new_size, size_limit) = if device_sectors > current_sectors as u64 {
+        match calculate_new_size(&boot, device_sectors) {
+            Ok(calc) => (
+                Some(calc.new_size_bytes(boot.bytes_per_sector())),
+                calc.size_limit,
+            ),
+            Err(Error::AlreadyMaxSize) if boot.data_clusters() >= fat_type.max_clusters() => {
+                (None, Some(SizeLimit::ClusterCount(fat_type)))
+            }
+            Err(Error::AlreadyMaxSize) => (None, Some(SizeLimit::SectorCount)),
+            Err(_) => (Some(device_sectors * bytes_per_sector), None),
+        }
     } else {
-        None
+        (None, None)
+    };
+    let can_grow = max_new_size.is_some();
+
+    // Largest size the current FAT can cover without growing
+    let fat_capacity_sectors = match fat_type {
+        FatType::Fat32 => max_sectors_for_fat_size(
+            boot.fat_size(),
+            boot.reserved_sectors(),
+            boot.num_fats(),
+            boot.sectors_per_cluster(),
+            boot.bytes_per_sector(),
+        ),
+        _ => max_sectors_for_small_fat(
+            fat_type,
+            boot.fat_size(),
+            boot.reserved_sectors(),
+            boot.num_fats(),
+            boot.root_dir_sectors() as u32,
+            boot.sectors_per_cluster(),
+            boot.bytes_per_sector(),
+        ),
+    };
+
+    let partition = partition_at(device_path, location)?;
+    let (partition_type, expected_partition_type) = match &partition {
+        Some(
+            partition @ Partition {
+                partition_type: PartitionType::Mbr(partition_type),
+                ..
+            },
+        ) => (
+            Some(*partition_type),
+            mbr::fat_partition_type(partition, fat_type),
+        ),
+        _ => (None, None),
     };
 
     Ok(FSInfoReport {
         device_path: device_path.to_path_buf(),
+        location,
+        fat_type,
         bytes_per_sector: boot.bytes_per_sector(),
         sectors_per_cluster: boot.sectors_per_cluster(),
         reserved_sectors: boot.reserved_sectors(),
@@ -736,22 +2154,33 @@ pub fn get_fs_info(device_path: impl AsRef<std::path::Path>) -> Result<FSInfoRep
         fat_size_sectors: boot.fat_size(),
         total_sectors: boot.total_sectors(),
         data_clusters: boot.data_clusters(),
+        first_data_sector: boot.first_data_sector(),
         root_cluster: boot.root_cluster(),
+        root_entry_count: boot.root_entry_count(),
         fsinfo_sector: boot.fs_info_sector(),
         backup_boot_sector: boot.backup_boot_sector(),
-        free_clusters: fsinfo.free_count(),
+        free_clusters,
         backup_matches,
         device_sectors,
         can_grow,
         current_size_bytes: current_sectors as u64 * boot.bytes_per_sector() as u64,
         max_new_size_bytes: max_new_size,
+        size_limit,
+        fat_capacity_bytes: fat_capacity_sectors * boot.bytes_per_sector() as u64,
+        hidden_sectors: boot.hidden_sectors(),
+        partition_start_sector: partition_start_sector(&device, location, &boot),
+        partition_type,
+        expected_partition_type,
     })
 }
 
-/// Report about a FAT32 filesystem
+/// Report about a FAT filesystem
 #[derive(Debug)]
 pub struct FSInfoReport {
     pub device_path: std::path::PathBuf,
+    /// Where the filesystem is on the device
+    pub location: VolumeLocation,
+    pub fat_type: FatType,
     pub bytes_per_sector: u16,
     pub sectors_per_cluster: u8,
     pub reserved_sectors: u16,
@@ -759,7 +2188,10 @@ pub struct FSInfoReport {
     pub fat_size_sectors: u32,
     pub total_sectors: u32,
     pub data_clusters: u32,
+    pub first_data_sector: u64,
     pub root_cluster: u32,
+    /// Entries in the fixed FAT12/16 root directory (0 for FAT32)
+    pub root_entry_count: u16,
     pub fsinfo_sector: u16,
     pub backup_boot_sector: u16,
     pub free_clusters: u32,
@@ -768,13 +2200,44 @@ pub struct FSInfoReport {
     pub can_grow: bool,
     pub current_size_bytes: u64,
     pub max_new_size_bytes: Option<u64>,
+    /// FAT limit that keeps the filesystem from filling the device
+    pub size_limit: Option<SizeLimit>,
+    /// Largest filesystem size the current FAT covers without growing
+    pub fat_capacity_bytes: u64,
+    /// Sectors before the filesystem, as recorded in the boot sector
+    pub hidden_sectors: u32,
+    /// Start of the partition in filesystem sectors, which `hidden_sectors`
+    /// should match (`None` if not known)
+    pub partition_start_sector: Option<u32>,
+    /// Type of the MBR partition holding the filesystem
+    pub partition_type: Option<u8>,
+    /// Type the MBR partition should be changed to for its size and FAT type
+    pub expected_partition_type: Option<u8>,
+}
+
+impl FSInfoReport {
+    /// How much the filesystem can grow before the FAT has to grow
+    pub fn fat_headroom_bytes(&self) -> u64 {
+        self.fat_capacity_bytes
+            .saturating_sub(self.current_size_bytes)
+    }
+
+    /// Check whether the hidden sectors match the partition start (true if
+    /// the start is not known)
+    pub fn hidden_sectors_match(&self) -> bool {
+        self.partition_start_sector
+            .is_none_or(|start| start == self.hidden_sectors)
+    }
 }
 
 impl std::fmt::Display for FSInfoReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "FAT32 Filesystem Information")?;
+        writeln!(f, "{} Filesystem Information", self.fat_type)?;
         writeln!(f, "============================")?;
         writeln!(f, "Device: {}", self.device_path.display())?;
+        if self.location != VolumeLocation::Whole {
+            writeln!(f, "Location: {}", self.location)?;
+        }
         writeln!(f)?;
         writeln!(f, "Geometry:")?;
         writeln!(f, "  Bytes per sector: {}", self.bytes_per_sector)?;
@@ -791,17 +2254,31 @@ impl std::fmt::Display for FSInfoReport {
         writeln!(f, "  FAT size (sectors): {}", self.fat_size_sectors)?;
         writeln!(f, "  Total sectors: {}", self.total_sectors)?;
         writeln!(f, "  Data clusters: {}", self.data_clusters)?;
-        writeln!(f)?;
-        writeln!(f, "Special sectors:")?;
-        writeln!(f, "  Root directory cluster: {}", self.root_cluster)?;
-        writeln!(f, "  FSInfo sector: {}", self.fsinfo_sector)?;
-        writeln!(f, "  Backup boot sector: {}", self.backup_boot_sector)?;
         writeln!(
             f,
-            "  Backup matches primary: {}",
-            if self.backup_matches { "Yes" } else { "NO" }
+            "  First data sector: {} (byte offset {})",
+            self.first_data_sector,
+            self.first_data_sector * self.bytes_per_sector as u64
         )?;
         writeln!(f)?;
+        writeln!(f, "Special sectors:")?;
+        if self.fat_type == FatType::Fat32 {
+            writeln!(f, "  Root directory cluster: {}", self.root_cluster)?;
+            writeln!(f, "  FSInfo sector: {}", self.fsinfo_sector)?;
+            writeln!(f, "  Backup boot sector: {}", self.backup_boot_sector)?;
+            writeln!(
+                f,
+                "  Backup matches primary: {}",
+                if self.backup_matches { "Yes" } else { "NO" }
+            )?;
+        } else {
+            writeln!(
+                f,
+                "  Root directory: fixed region of {} entries",
+                self.root_entry_count
+            )?;
+        }
+        writeln!(f)?;
         writeln!(f, "Usage:")?;
         if self.free_clusters == FSInfo::UNKNOWN_FREE {
             writeln!(f, "  Free clusters: Unknown")?;
@@ -837,6 +2314,60 @@ impl std::fmt::Display for FSInfoReport {
                 max_size as f64 / (1024.0 * 1024.0)
             )?;
         }
+        if let Some(limit) = self.size_limit {
+            let fs_bytes = self.max_new_size_bytes.unwrap_or(self.current_size_bytes);
+            let unused =
+                (self.device_sectors * self.bytes_per_sector as u64).saturating_sub(fs_bytes);
+            writeln!(
+                f,
+                "  Limited by: {} ({} bytes of the device stay unused)",
+                limit, unused
+            )?;
+        }
+        writeln!(
+            f,
+            "  FAT covers up to: {} bytes ({:.2} MB)",
+            self.fat_capacity_bytes,
+            self.fat_capacity_bytes as f64 / (1024.0 * 1024.0)
+        )?;
+        writeln!(
+            f,
+            "  FAT headroom: {} bytes ({:.2} MB)",
+            self.fat_headroom_bytes(),
+            self.fat_headroom_bytes() as f64 / (1024.0 * 1024.0)
+        )?;
+        if self.partition_start_sector.is_some() || self.partition_type.is_some() {
+            writeln!(f)?;
+            writeln!(f, "Partition:")?;
+        }
+        if let Some(start) = self.partition_start_sector {
+            writeln!(f, "  Start sector: {}", start)?;
+            writeln!(
+                f,
+                "  Hidden sectors: {}{}",
+                self.hidden_sectors,
+                if self.hidden_sectors_match() {
+                    ""
+                } else {
+                    " (does NOT match the partition start)"
+                }
+            )?;
+        }
+        if let Some(partition_type) = self.partition_type {
+            let name = |id: u8| mbr::type_name(id).unwrap_or("unknown");
+            write!(
+                f,
+                "  Partition type: 0x{:02X} ({})",
+                partition_type,
+                name(partition_type)
+            )?;
+            match self.expected_partition_type {
+                Some(expected) => {
+                    writeln!(f, ", should be 0x{:02X} ({})", expected, name(expected))?
+                }
+                None => writeln!(f)?,
+            }
+        }
         Ok(())
     }
 }
@@ -844,6 +2375,13 @@ impl std::fmt::Display for FSInfoReport {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fat32::{fat_entry, read_fat_table, validate_fsinfo};
+    use crate::resize::calculator::FAT32_RESERVED_SECTORS;
+    use crate::resize::relocator::{execute_relocation_with_progress, plan_relocation};
+    use crate::test_image::{
+        assert_consistent, assert_resumes_after_crashes, pattern, read_file, read_root_file,
+        ImageSpec, TestImage,
+    };
 
     #[test]
     fn test_resize_options() {
@@ -852,6 +2390,24 @@ mod tests {
         assert_eq!(opts.device_path(), std::path::Path::new("/dev/sda1"));
         assert!(opts.is_dry_run());
         assert!(!opts.is_verbose());
+        assert_eq!(opts.get_strategy(), GrowStrategy::Auto);
+    }
+
+    #[test]
+    fn test_grow_strategy_parsing() {
+        assert_eq!(
+            "shift".parse::<GrowStrategy>().unwrap(),
+            GrowStrategy::Shift
+        );
+        assert_eq!(
+            "Renumber".parse::<GrowStrategy>().unwrap(),
+            GrowStrategy::Renumber
+        );
+        assert_eq!(GrowStrategy::Auto.to_string(), "auto");
+        assert!(matches!(
+            "fastest".parse::<GrowStrategy>(),
+            Err(Error::InvalidStrategy(_))
+        ));
     }
 
     #[test]
@@ -863,10 +2419,13 @@ mod tests {
             new_fat_size: 2000,
             new_data_clusters: 200000,
             new_free_clusters: 100000,
+            new_num_fats: 2,
             fat_needs_growth: true,
             fat_growth_sectors: 1000,
             first_affected_cluster: 2,
             last_affected_cluster: 10,
+            size_limit: None,
+            new_fat_type: FatType::Fat32,
         };
 
         let result = ResizeResult {
@@ -874,6 +2433,15 @@ mod tests {
             new_size_bytes: 1024000000,
             fat_grew: true,
             clusters_relocated: 5,
+            strategy: Some(GrowStrategy::Shift),
+            size_limit: None,
+            converted_from: None,
+            unused_bytes: 0,
+            hidden_sectors_change: None,
+            partition_type_change: None,
+            sparse: SparseStats::default(),
+            io: IoStats::default(),
+            discard: None,
             calculation: calc,
             operations: vec!["test".to_string()],
         };
@@ -882,4 +2450,769 @@ mod tests {
         assert!(result.fat_grew);
         assert_eq!(result.clusters_relocated, 5);
     }
+
+    #[test]
+    fn test_checkpoint_roundtrip() {
+        let checkpoint = ResizeCheckpoint::new(ResizePhase::Started, 1000, 2000, 10, 20)
+            .with_num_fats(1)
+            .with_watermark(1234);
+        let parsed = ResizeCheckpoint::from_bytes(&checkpoint.to_bytes(512))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(parsed.phase, ResizePhase::Started);
+        assert_eq!(parsed.old_total_sectors, 1000);
+        assert_eq!(parsed.new_total_sectors, 2000);
+        assert_eq!(parsed.old_fat_size, 10);
+        assert_eq!(parsed.new_fat_size, 20);
+        assert_eq!(parsed.watermark, 1234);
+        assert_eq!(parsed.new_num_fats, 1);
+    }
+
+    #[test]
+    fn test_checkpoint_roundtrip_journaled() {
+        let checkpoint = ResizeCheckpoint::new(ResizePhase::FatWritten, 2000, 1000, 20, 10)
+            .journaled(ResizeOperation::Shrink, 7, 900)
+            .with_watermark(55);
+        let parsed = ResizeCheckpoint::from_bytes(&checkpoint.to_bytes(4096))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(parsed.operation, ResizeOperation::Shrink);
+        assert_eq!(parsed.new_root_cluster, 7);
+        assert_eq!(parsed.journal_cluster, 900);
+        assert_eq!(parsed.watermark, 55);
+
+        let grow = ResizeCheckpoint::new(ResizePhase::Started, 1000, 2000, 10, 20);
+        let parsed = ResizeCheckpoint::from_bytes(&grow.to_bytes(512))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.operation, ResizeOperation::Grow);
+
+        let renumber = grow.journaled(ResizeOperation::GrowRenumber, 3, 1500);
+        let parsed = ResizeCheckpoint::from_bytes(&renumber.to_bytes(512))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.operation, ResizeOperation::GrowRenumber);
+        assert_eq!(parsed.journal_cluster, 1500);
+
+        let exfat = ResizeCheckpoint::new(ResizePhase::DataCopied, 1000, 2000, 8, 16)
+            .with_exfat_layout(64, 9);
+        let parsed = ResizeCheckpoint::from_bytes(&exfat.to_bytes(512))
+            .unwrap()
+            .unwrap();
+        assert_eq!(parsed.operation, ResizeOperation::ExfatGrow);
+        assert_eq!(parsed.new_heap_offset, 64);
+        assert_eq!(parsed.bitmap_cluster, 9);
+    }
+
+    #[test]
+    fn test_checkpoint_corrupted_watermark() {
+        let mut data = ResizeCheckpoint::new(ResizePhase::Started, 1000, 2000, 10, 20)
+            .with_watermark(1234)
+            .to_bytes(512);
+        data[29] ^= 0xFF;
+
+        assert!(matches!(
+            ResizeCheckpoint::from_bytes(&data),
+            Err(Error::CheckpointCorrupted)
+        ));
+    }
+
+    #[test]
+    fn test_resume_interrupted_data_shift() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let first = pattern(1, 40 * 512);
+        let second = pattern(2, 40 * 512);
+        image.add_file(b"FIRST   BIN", &first, 1);
+        image.add_file(b"SECOND  BIN", &second, 3);
+        image.extend_to_sectors(71_000);
+
+        // Run phase 0 as resize_fat32 would, and crash halfway through the shift
+        {
+            let mut device = Device::open(image.path()).unwrap();
+            let boot = read_boot_sector(&mut device).unwrap();
+            let fat = read_fat_table(&device, &boot, 0).unwrap();
+            let calc = calculate_new_size(&boot, device.total_sectors()).unwrap();
+            let plan = plan_relocation(
+                &device,
+                &boot,
+                &fat,
+                calc.first_affected_cluster,
+                calc.last_affected_cluster,
+                calc.new_data_clusters,
+            )
+            .unwrap();
+            let sector = checkpoint_sector(&calc);
+            let checkpoint = |watermark: u32| {
+                ResizeCheckpoint::new(
+                    ResizePhase::Started,
+                    calc.old_total_sectors,
+                    calc.new_total_sectors,
+                    calc.old_fat_size,
+                    calc.new_fat_size,
+                )
+                .with_watermark(watermark)
+            };
+            write_checkpoint(&device, sector, &checkpoint(0)).unwrap();
+
+            device.crash_after_writes(plan.cluster_count() as u64 / 2);
+            let result = execute_relocation_with_progress(
+                &device,
+                &boot,
+                &plan,
+                None,
+                &mut |watermark| write_checkpoint(&device, sector, &checkpoint(watermark)),
+                ReadAhead::default(),
+                false,
+            );
+            assert!(result.is_err());
+
+            let (saved, _) = read_checkpoint(&device, &boot, &[sector]).unwrap().unwrap();
+            assert_ne!(saved.watermark, 0);
+        }
+
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert!(result
+            .operations
+            .iter()
+            .any(|op| op.starts_with("Resumed data shift")));
+
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+        assert_eq!(
+            read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+            second
+        );
+        assert!(get_fs_info(image.path()).unwrap().backup_matches);
+    }
+
+    #[test]
+    fn test_grow_phases_in_order() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let first = pattern(1, 40 * 512);
+        image.add_file(b"FIRST   BIN", &first, 1);
+        image.extend_to_sectors(71_000);
+
+        let options = ResizeOptions::new(image.path());
+        let mut device = Device::open(image.path()).unwrap();
+        let mut boot = read_boot_sector(&mut device).unwrap();
+        let mut fsinfo = read_fsinfo(&device, boot.fs_info_sector()).unwrap();
+        let calc = options.calculate(&boot, device.total_sectors()).unwrap();
+        let run = GrowRun::new(&device, &options, &boot, &calc, None);
+        let mut fat = FatReader::new(&device, &boot, 0, None);
+        let growth = plan_fat_growth(
+            &device,
+            &boot,
+            &mut fat,
+            &calc,
+            run.checkpoint_sector,
+            GrowStrategy::Shift,
+            None,
+        )
+        .unwrap();
+        let FatGrowth::Shift(plan) = growth else {
+            panic!("expected a shift");
+        };
+
+        let phase = || {
+            let data = device.read_sector(run.checkpoint_sector).unwrap();
+            ResizeCheckpoint::from_bytes(&data)
+                .unwrap()
+                .map(|checkpoint| checkpoint.phase)
+        };
+        let signed = || device.read_sector(0).unwrap()[510..512] == [0x55, 0xAA];
+        let mut operations = Vec::new();
+
+        // The data moves while the old filesystem stays valid
+        run.shift_forward(&boot, &mut fat, &plan, &mut operations)
+            .unwrap();
+        assert_eq!(phase(), Some(ResizePhase::DataCopied));
+        assert!(signed());
+
+        // The FAT is written behind an invalidated boot sector
+        run.write_shifted_fat(&mut boot, &mut fat, &plan, &mut operations)
+            .unwrap();
+        assert_eq!(phase(), Some(ResizePhase::FatWritten));
+        assert!(!signed());
+
+        // The new boot sector is valid before the checkpoint goes
+        let grown = GrownFat::default();
+        run.finish(&mut boot, Some(&mut fsinfo), &grown, None, &mut operations)
+            .unwrap();
+        assert_eq!(phase(), None);
+        assert!(signed());
+
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+    }
+
+    #[test]
+    fn test_shift_keeps_image_sparse() {
+        let allocated = |path: &std::path::Path| {
+            use std::os::unix::fs::MetadataExt;
+            std::fs::metadata(path).unwrap().blocks() * 512
+        };
+        let mut image = TestImage::create(ImageSpec::default());
+        let data = pattern(1, 300 * 512);
+        image.add_file(b"DATA    BIN", &data, 1);
+        // A file whose clusters were never written, all in a hole
+        let clusters = image.add_file(b"EMPTY   BIN", &[0u8; 64 * 512], 1);
+        let device = Device::open(image.path()).unwrap();
+        device
+            .punch_hole(image.cluster_offset(clusters[0]), 64 * 512)
+            .unwrap();
+        drop(device);
+        image.extend_to_sectors(1_000_000);
+        let before = allocated(image.path());
+
+        let options = ResizeOptions::new(image.path()).strategy(GrowStrategy::Shift);
+        let result = resize_fat32(options).unwrap();
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        assert!(result.sparse.skipped_bytes > 0);
+        assert!(result.sparse.punched_bytes > 0);
+
+        // The data moved instead of being copied into newly allocated space,
+        // and the grown FAT takes only the sectors that are in use
+        assert!(allocated(image.path()) < before + 64 * 1024);
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"DATA    BIN").unwrap(), data);
+        assert_eq!(
+            read_root_file(image.path(), b"EMPTY   BIN").unwrap(),
+            vec![0u8; 64 * 512]
+        );
+    }
+
+    #[test]
+    fn test_shift_coalesces_io() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let data = pattern(3, 2000 * 512);
+        image.add_file(b"BIG     BIN", &data, 1);
+        image.extend_to_sectors(1_000_000);
+
+        let options = ResizeOptions::new(image.path()).strategy(GrowStrategy::Shift);
+        let result = resize_fat32(options).unwrap();
+        assert!(result.clusters_relocated >= 2000);
+        // Adjacent clusters move in runs, and the FAT in large chunks
+        assert!(result.io.write_ops < result.clusters_relocated as u64 / 4);
+        assert!(result.io.bytes_written >= data.len() as u64);
+        assert!(result.io.throughput() > 0.0);
+        assert_eq!(read_root_file(image.path(), b"BIG     BIN").unwrap(), data);
+    }
+
+    /// An image whose FAT (70,000 entries) does not fit into 64 KiB, with
+    /// files in several shift segments
+    fn windowed_image() -> (TestImage, Vec<u8>, Vec<u8>) {
+        let mut image = TestImage::create(ImageSpec::default());
+        let first = pattern(1, 200 * 512);
+        let second = pattern(2, 200 * 512);
+        image.add_file(b"FIRST   BIN", &first, 1);
+        image.skip_to(5000);
+        image.add_file(b"SECOND  BIN", &second, 3);
+        image.extend_to_sectors(1_000_000);
+        (image, first, second)
+    }
+
+    #[test]
+    fn test_resize_within_memory_limit() {
+        let (image, first, second) = windowed_image();
+        let limited = ResizeOptions::new(image.path()).memory_limit(Some(64 * 1024));
+
+        let renumber = limited.clone().strategy(GrowStrategy::Renumber);
+        assert!(matches!(
+            resize_fat32(renumber),
+            Err(Error::MemoryLimitUnsupported(_))
+        ));
+
+        let result = resize_fat32(limited).unwrap();
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        assert!(result
+            .operations
+            .iter()
+            .any(|op| op.contains("in windows of")));
+        // Both files and the root directory
+        assert_eq!(result.clusters_relocated, 401);
+        assert!(result.sparse.punched_bytes > 0);
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+        assert_eq!(
+            read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_resize_discards_free_clusters() {
+        let (image, first, second) = windowed_image();
+        // Stale data in free clusters, which the shift moves the FAT over
+        for cluster in 1000..1100 {
+            image.write_cluster(cluster, &[0xEE; 512]);
+        }
+
+        let options = ResizeOptions::new(image.path())
+            .memory_limit(Some(64 * 1024))
+            .discard(true);
+        let result = resize_fat32(options).unwrap();
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        let discard = result.discard.unwrap();
+        assert!(discard.supported);
+
+        let mut device = Device::open_readonly(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        let table = read_fat_table(&device, &boot, 0).unwrap();
+        let bytes_per_cluster = boot.bytes_per_cluster() as u64;
+        let mut free = 0;
+        for cluster in 2..boot.data_clusters() + 2 {
+            if fat_entry::is_free(table[cluster as usize]) {
+                let offset = boot.cluster_to_sector(cluster) * boot.bytes_per_sector() as u64;
+                let data = device
+                    .read_bytes_at(offset, bytes_per_cluster as usize)
+                    .unwrap();
+                assert!(data.iter().all(|&byte| byte == 0), "cluster {}", cluster);
+                free += 1;
+            }
+        }
+        assert_eq!(discard.discarded_bytes, free * bytes_per_cluster);
+        drop(device);
+
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+        assert_eq!(
+            read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_interrupted_windowed_shift_resumes() {
+        let writes = assert_resumes_after_crashes(
+            5,
+            windowed_image,
+            |(image, _, _)| ResizeOptions::new(image.path()).memory_limit(Some(64 * 1024)),
+            resize_fat32,
+            |(image, _, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device)
+                    .is_ok_and(|boot| boot.total_sectors() as u64 == device.total_sectors())
+            },
+            |(image, first, second)| {
+                assert_consistent(image.path());
+                assert_eq!(
+                    read_root_file(image.path(), b"FIRST   BIN").unwrap(),
+                    *first
+                );
+                assert_eq!(
+                    read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+                    *second
+                );
+            },
+        );
+        assert!(writes > 100);
+    }
+
+    #[test]
+    fn test_resize_to_target_size() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let contents = pattern(1, 20_000);
+        image.add_file(b"DATA    BIN", &contents, 3);
+        image.extend_to_sectors(400_000);
+
+        let options =
+            ResizeOptions::new(image.path()).target_size(Some(TargetSize::Absolute(200_000 * 512)));
+        let result = resize_fat32(options).unwrap();
+
+        assert!(result.fat_grew);
+        let info = get_fs_info(image.path()).unwrap();
+        assert!(info.total_sectors <= 200_000);
+        assert!(info.total_sectors > 200_000 - 8);
+        assert_eq!(info.device_sectors, 400_000);
+        assert_eq!(
+            read_root_file(image.path(), b"DATA    BIN").unwrap(),
+            contents
+        );
+
+        // The space past the target is left untouched
+        let device = Device::open_readonly(image.path()).unwrap();
+        assert_eq!(device.read_sector(399_999).unwrap(), vec![0u8; 512]);
+    }
+
+    #[test]
+    fn test_resize_with_fat_reserve() {
+        let mut image = TestImage::create(ImageSpec::default());
+        let contents = pattern(2, 20_000);
+        image.add_file(b"DATA    BIN", &contents, 3);
+        image.extend_to_sectors(100_000);
+
+        // The default image's FAT covers the image and little more
+        let before = get_fs_info(image.path()).unwrap();
+        assert!(before.fat_headroom_bytes() < 1 << 20);
+
+        let options =
+            ResizeOptions::new(image.path()).fat_reserve(Some(FatReserve::UpTo(400_000 * 512)));
+        assert!(resize_fat32(options).unwrap().fat_grew);
+
+        let info = get_fs_info(image.path()).unwrap();
+        assert_eq!(info.total_sectors, 100_000);
+        assert!(info.fat_capacity_bytes >= 400_000 * 512);
+        assert!(info.fat_headroom_bytes() >= 300_000 * 512);
+
+        // Growing within the reserve only updates the metadata
+        image.extend_to_sectors(400_000);
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert!(!result.fat_grew);
+        assert_eq!(
+            get_fs_info(image.path()).unwrap().fat_size_sectors,
+            info.fat_size_sectors
+        );
+        assert_eq!(
+            read_root_file(image.path(), b"DATA    BIN").unwrap(),
+            contents
+        );
+    }
+
+    #[test]
+    fn test_resize_aligned() {
+        for strategy in [GrowStrategy::Shift, GrowStrategy::Renumber] {
+            let mut image = TestImage::create(ImageSpec::default());
+            let contents = pattern(3, 30_000);
+            image.add_file(b"DATA    BIN", &contents, 3);
+            image.extend_to_sectors(300_000);
+
+            // An image file has no alignment hints, so auto means 1 MiB
+            let options = ResizeOptions::new(image.path())
+                .strategy(strategy)
+                .align(Some(Alignment::Auto));
+            assert!(resize_fat32(options).unwrap().fat_grew);
+
+            let info = get_fs_info(image.path()).unwrap();
+            assert_eq!((info.first_data_sector * 512) % AUTO_ALIGN_MIN_BYTES, 0);
+            assert_consistent(image.path());
+            assert_eq!(
+                read_root_file(image.path(), b"DATA    BIN").unwrap(),
+                contents
+            );
+        }
+    }
+
+    /// Image with `num_fats` FATs and files on both sides of the FAT growth
+    fn fat_count_image(num_fats: u8) -> (TestImage, Vec<u8>, Vec<u8>) {
+        let mut image = TestImage::create(ImageSpec {
+            num_fats,
+            ..Default::default()
+        });
+        let first = pattern(1, 30 * 512);
+        let second = pattern(2, 20 * 512);
+        image.add_file(b"FIRST   BIN", &first, 1);
+        image.skip_to(3_000);
+        image.add_file(b"SECOND  BIN", &second, 3);
+        image.extend_to_sectors(100_000);
+        (image, first, second)
+    }
+
+    fn assert_fat_count_image(image: &TestImage, num_fats: u8, first: &[u8], second: &[u8]) {
+        assert_eq!(get_fs_info(image.path()).unwrap().num_fats, num_fats);
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+        assert_eq!(
+            read_root_file(image.path(), b"SECOND  BIN").unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_resize_adds_fat() {
+        let (image, first, second) = fat_count_image(1);
+
+        let result = resize_fat32(ResizeOptions::new(image.path()).num_fats(Some(2))).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        assert!(result.clusters_relocated > 0);
+        assert_fat_count_image(&image, 2, &first, &second);
+    }
+
+    #[test]
+    fn test_resize_drops_fat() {
+        let (image, first, second) = fat_count_image(2);
+        let before = get_fs_info(image.path()).unwrap();
+
+        let result = resize_fat32(ResizeOptions::new(image.path()).num_fats(Some(1))).unwrap();
+        assert!(result.fat_grew);
+        assert!(result.clusters_relocated > 0);
+        assert_fat_count_image(&image, 1, &first, &second);
+
+        // The dropped copy's sectors go to the data area, which moves back
+        let after = get_fs_info(image.path()).unwrap();
+        assert!(after.first_data_sector < before.first_data_sector);
+        let kept_in_place = (after.total_sectors as u64 - before.first_data_sector)
+            / after.sectors_per_cluster as u64;
+        assert!(after.data_clusters as u64 > kept_in_place);
+    }
+
+    #[test]
+    fn test_resize_fat_count_needs_shift() {
+        let (image, _, _) = fat_count_image(1);
+
+        let options = ResizeOptions::new(image.path())
+            .num_fats(Some(2))
+            .strategy(GrowStrategy::Renumber);
+        assert!(matches!(resize_fat32(options), Err(Error::Calculation(_))));
+    }
+
+    #[test]
+    fn test_interrupted_fat_count_change_resumes() {
+        for (from, to) in [(1, 2), (2, 1)] {
+            // FAT copies are written in chunks, so every write is tried
+            let writes = assert_resumes_after_crashes(
+                1,
+                || fat_count_image(from),
+                |(image, _, _)| ResizeOptions::new(image.path()).num_fats(Some(to)),
+                resize_fat32,
+                |(image, _, _)| {
+                    let mut device = Device::open(image.path()).unwrap();
+                    read_boot_sector(&mut device).is_ok_and(|b| b.num_fats() == to)
+                },
+                |(image, first, second)| assert_fat_count_image(image, to, first, second),
+            );
+            assert!(writes > 5);
+        }
+    }
+
+    /// A FAT12 floppy or a small FAT16 volume with a file in the fixed root
+    /// directory and a fragmented one in a subdirectory
+    fn small_fat_image(fat_type: FatType) -> (TestImage, Vec<u8>, Vec<u8>) {
+        let spec = match fat_type {
+            FatType::Fat12 => ImageSpec {
+                fat_type,
+                total_sectors: 2_880,
+                reserved_sectors: 1,
+                root_entries: 224,
+                ..Default::default()
+            },
+            _ => ImageSpec {
+                fat_type,
+                total_sectors: 40_000,
+                sectors_per_cluster: 4,
+                reserved_sectors: 1,
+                root_entries: 512,
+                ..Default::default()
+            },
+        };
+        let mut image = TestImage::create(spec);
+        let first = pattern(1, 30 * 512);
+        let second = pattern(2, 50 * 512);
+        image.add_file(b"FIRST   BIN", &first, 1);
+        let dir = image.add_dir(image.root(), b"SUBDIR     ");
+        image.add_file_in(dir, b"SECOND  BIN", &second, 3);
+        (image, first, second)
+    }
+
+    fn assert_small_fat_image(image: &TestImage, fat_type: FatType, first: &[u8], second: &[u8]) {
+        assert_eq!(get_fs_info(image.path()).unwrap().fat_type, fat_type);
+        assert_consistent(image.path());
+        assert_eq!(read_root_file(image.path(), b"FIRST   BIN").unwrap(), first);
+        assert_eq!(
+            read_file(image.path(), &[b"SUBDIR     ", b"SECOND  BIN"]).unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_resize_fat16() {
+        let (image, first, second) = small_fat_image(FatType::Fat16);
+        let before = get_fs_info(image.path()).unwrap();
+        assert_eq!(before.fat_type, FatType::Fat16);
+        image.extend_to_sectors(100_000);
+
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        assert!(result.clusters_relocated > 0);
+        assert_small_fat_image(&image, FatType::Fat16, &first, &second);
+
+        // Past 65535 sectors the size moves to the 32-bit field
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert_eq!(boot.total_sectors_16(), 0);
+        assert_eq!(boot.total_sectors(), 100_000);
+        assert!(boot.fat_size_16() as u32 > before.fat_size_sectors);
+    }
+
+    #[test]
+    fn test_resize_fat12() {
+        let (image, first, second) = small_fat_image(FatType::Fat12);
+        image.extend_to_sectors(4_000);
+
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert!(result.fat_grew);
+        assert_small_fat_image(&image, FatType::Fat12, &first, &second);
+
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert_eq!(boot.total_sectors_16(), 4_000);
+        assert_eq!(boot.total_sectors_32(), 0);
+    }
+
+    #[test]
+    fn test_resize_fat16_capped_at_cluster_limit() {
+        let (image, first, second) = small_fat_image(FatType::Fat16);
+        image.extend_to_sectors(600_000);
+
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert_eq!(
+            result.size_limit,
+            Some(SizeLimit::ClusterCount(FatType::Fat16))
+        );
+        assert_eq!(
+            result.calculation.new_data_clusters,
+            FatType::Fat16.max_clusters()
+        );
+        assert_small_fat_image(&image, FatType::Fat16, &first, &second);
+    }
+
+    #[test]
+    fn test_resize_fat16_needs_shift() {
+        let (image, _, _) = small_fat_image(FatType::Fat16);
+        image.extend_to_sectors(100_000);
+
+        let options = ResizeOptions::new(image.path()).strategy(GrowStrategy::Renumber);
+        assert!(matches!(
+            resize_fat32(options),
+            Err(Error::UnsupportedFatType(FatType::Fat16))
+        ));
+    }
+
+    #[test]
+    fn test_interrupted_fat16_fat_drop_resumes() {
+        // The fixed root directory moves back along with the data
+        let writes = assert_resumes_after_crashes(
+            1,
+            || {
+                let (image, first, second) = small_fat_image(FatType::Fat16);
+                image.extend_to_sectors(100_000);
+                (image, first, second)
+            },
+            |(image, _, _)| ResizeOptions::new(image.path()).num_fats(Some(1)),
+            resize_fat32,
+            |(image, _, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device).is_ok_and(|b| b.num_fats() == 1)
+            },
+            |(image, first, second)| {
+                assert_small_fat_image(image, FatType::Fat16, first, second);
+                assert_eq!(get_fs_info(image.path()).unwrap().num_fats, 1);
+            },
+        );
+        assert!(writes > 20);
+    }
+
+    #[test]
+    fn test_interrupted_fat16_resize_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            || {
+                let (image, first, second) = small_fat_image(FatType::Fat16);
+                image.extend_to_sectors(100_000);
+                (image, first, second)
+            },
+            |(image, _, _)| ResizeOptions::new(image.path()),
+            resize_fat32,
+            |(image, _, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device).is_ok_and(|b| b.total_sectors() == 100_000)
+            },
+            |(image, first, second)| assert_small_fat_image(image, FatType::Fat16, first, second),
+        );
+        assert!(writes > 20);
+    }
+
+    /// Check an image converted to FAT32 from a `small_fat_image`
+    fn assert_converted_image(image: &TestImage, first: &[u8], second: &[u8]) {
+        assert_small_fat_image(image, FatType::Fat32, first, second);
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert!(boot.reserved_sectors() >= FAT32_RESERVED_SECTORS);
+        assert_eq!(boot.root_entry_count(), 0);
+        assert_eq!(boot.fs_type(), b"FAT32   ");
+        let fsinfo = read_fsinfo(&device, boot.fs_info_sector()).unwrap();
+        validate_fsinfo(&fsinfo).unwrap();
+    }
+
+    #[test]
+    fn test_convert_fat16_to_fat32() {
+        let (image, first, second) = small_fat_image(FatType::Fat16);
+        image.extend_to_sectors(300_000);
+
+        // Without conversion, growth stops at the FAT16 cluster limit
+        let options = ResizeOptions::new(image.path()).dry_run(true);
+        let result = resize_fat32(options.clone()).unwrap();
+        assert_eq!(
+            result.size_limit,
+            Some(SizeLimit::ClusterCount(FatType::Fat16))
+        );
+
+        let result = resize_fat32(ResizeOptions::new(image.path()).convert_to_fat32(true)).unwrap();
+        assert_eq!(result.converted_from, Some(FatType::Fat16));
+        assert_eq!(result.size_limit, None);
+        assert!(result.clusters_relocated > 0);
+        assert_converted_image(&image, &first, &second);
+        assert_eq!(get_fs_info(image.path()).unwrap().total_sectors, 300_000);
+
+        // The result grows like any FAT32 filesystem
+        image.extend_to_sectors(400_000);
+        resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert_converted_image(&image, &first, &second);
+    }
+
+    #[test]
+    fn test_convert_fat12_to_fat32() {
+        let (image, first, second) = small_fat_image(FatType::Fat12);
+        image.extend_to_sectors(70_000);
+
+        let options = ResizeOptions::new(image.path()).convert_to_fat32(true);
+        let result = resize_fat32(options).unwrap();
+        assert_eq!(result.converted_from, Some(FatType::Fat12));
+        assert_converted_image(&image, &first, &second);
+    }
+
+    #[test]
+    fn test_convert_needs_fat32_cluster_count() {
+        let (image, first, second) = small_fat_image(FatType::Fat16);
+        image.extend_to_sectors(100_000);
+
+        let options = ResizeOptions::new(image.path()).convert_to_fat32(true);
+        assert!(matches!(resize_fat32(options), Err(Error::Calculation(_))));
+        assert_small_fat_image(&image, FatType::Fat16, &first, &second);
+    }
+
+    #[test]
+    fn test_interrupted_conversion_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            || {
+                let (image, first, second) = small_fat_image(FatType::Fat16);
+                image.extend_to_sectors(300_000);
+                (image, first, second)
+            },
+            |(image, _, _)| ResizeOptions::new(image.path()).convert_to_fat32(true),
+            resize_fat32,
+            |(image, _, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device).is_ok_and(|b| b.total_sectors() == 300_000)
+            },
+            |(image, first, second)| assert_converted_image(image, first, second),
+        );
+        assert!(writes > 30);
+    }
+
+    #[test]
+    fn test_resize_target_exceeding_device_fails() {
+        let image = TestImage::create(ImageSpec::default());
+        image.extend_to_sectors(100_000);
+
+        let options =
+            ResizeOptions::new(image.path()).target_size(Some(TargetSize::Absolute(100_001 * 512)));
+        assert!(matches!(
+            resize_fat32(options),
+            Err(Error::TargetTooLarge { .. })
+        ));
+    }
 }
@@ -0,0 +1,1302 @@
+//! Growing exFAT filesystems
+//!
+//! exFAT keeps cluster allocation in a bitmap stored in the cluster heap, so
+//! growing the filesystem grows the bitmap as well as the FAT. The FAT only
+//! has to cover the new cluster count; when it outgrows the gap before the
+//! cluster heap, the heap moves forward and every allocated cluster is
+//! shifted with it, cluster numbers unchanged (as the FAT32 shift strategy
+//! does). A bitmap that no longer fits its clusters moves to the lowest free
+//! run of clusters that holds it.
+//!
+//! The resize runs in the same three phases as a FAT32 grow, with the
+//! checkpoint in the last sector of the new filesystem:
+//!
+//! 0. Shift allocated clusters to the new heap (the old boot region stays valid)
+//! 1. Invalidate the boot sector, write the grown bitmap, FAT and bitmap entry
+//! 2. Write the backup and then the main boot region with new checksums
+
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::exfat::{
+    boot_regions_match, count_free_clusters, fat_entry, is_cluster_used, read_bitmap,
+    read_bitmap_entry, read_boot_region, read_boot_region_for_recovery, set_cluster_used,
+    write_bitmap_entry, write_boot_region, write_boot_sector, write_fat_entries, BitmapEntry,
+    ExfatBootSector, BACKUP_BOOT_REGION, MAX_CLUSTERS,
+};
+use crate::fat32::FatType;
+use crate::partition::VolumeLocation;
+use crate::resize::calculator::{
+    alignment_sectors, CalculationOptions, SizeCalculation, SizeLimit,
+};
+use crate::resize::discard::discard_free_exfat_clusters;
+use crate::resize::executor::{
+    clear_checkpoint, maybe_crash_at, write_checkpoint, GrowStrategy, ResizeCheckpoint,
+    ResizeOperation, ResizeOptions, ResizePhase, ResizeResult,
+};
+use crate::resize::relocator::{shift_clusters, ClusterMove, RelocationPlan};
+use crate::system::check_not_mounted_at;
+
+/// Layout of an exFAT filesystem before and after growing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExfatLayout {
+    /// Volume length before the resize, in sectors
+    pub old_total_sectors: u32,
+    /// Volume length after the resize, in sectors
+    pub new_total_sectors: u32,
+    /// FAT length before the resize, in sectors
+    pub old_fat_length: u32,
+    /// FAT length after the resize, in sectors
+    pub new_fat_length: u32,
+    /// First sector of the cluster heap before the resize
+    pub old_heap_offset: u32,
+    /// First sector of the cluster heap after the resize
+    pub new_heap_offset: u32,
+    /// Clusters in the heap before the resize
+    pub old_cluster_count: u32,
+    /// Clusters in the heap after the resize
+    pub new_cluster_count: u32,
+    /// Limit that stopped the filesystem short of filling the device
+    pub size_limit: Option<SizeLimit>,
+}
+
+impl ExfatLayout {
+    /// Rebuild the layout an interrupted resize recorded in its checkpoint
+    fn from_checkpoint(boot: &ExfatBootSector, checkpoint: &ResizeCheckpoint) -> Self {
+        let heap_sectors = checkpoint
+            .new_total_sectors
+            .saturating_sub(checkpoint.new_heap_offset);
+        Self {
+            old_total_sectors: checkpoint.old_total_sectors,
+            new_total_sectors: checkpoint.new_total_sectors,
+            old_fat_length: checkpoint.old_fat_size,
+            new_fat_length: checkpoint.new_fat_size,
+            old_heap_offset: boot.cluster_heap_offset(),
+            new_heap_offset: checkpoint.new_heap_offset,
+            old_cluster_count: boot.cluster_count(),
+            new_cluster_count: (heap_sectors / boot.sectors_per_cluster()).min(MAX_CLUSTERS),
+            size_limit: None,
+        }
+    }
+
+    /// Whether the cluster heap moves, so allocated clusters must be shifted
+    pub fn heap_moves(&self) -> bool {
+        self.new_heap_offset != self.old_heap_offset
+    }
+
+    /// Sector holding the resize checkpoint (the last sector of the new volume)
+    fn checkpoint_sector(&self) -> u64 {
+        self.new_total_sectors as u64 - 1
+    }
+
+    /// Cluster of the new heap that contains the checkpoint sector, if any
+    fn checkpoint_cluster(&self, sectors_per_cluster: u32) -> Option<u32> {
+        let sector = self.checkpoint_sector();
+        let offset = sector.checked_sub(self.new_heap_offset as u64)?;
+        let cluster = 2 + offset / sectors_per_cluster as u64;
+        (cluster < self.new_cluster_count as u64 + 2).then_some(cluster as u32)
+    }
+
+    /// The layout in the terms the FAT resize reports
+    pub fn size_calculation(&self, boot: &ExfatBootSector) -> SizeCalculation {
+        let shift = self.new_heap_offset - self.old_heap_offset;
+        let affected_clusters = shift.div_ceil(boot.sectors_per_cluster());
+        SizeCalculation {
+            old_total_sectors: self.old_total_sectors,
+            new_total_sectors: self.new_total_sectors,
+            old_fat_size: self.old_fat_length,
+            new_fat_size: self.new_fat_length,
+            new_num_fats: boot.number_of_fats(),
+            new_data_clusters: self.new_cluster_count,
+            new_free_clusters: self.new_cluster_count - self.old_cluster_count,
+            fat_needs_growth: self.new_fat_length > self.old_fat_length,
+            fat_growth_sectors: self.new_fat_length - self.old_fat_length,
+            first_affected_cluster: if affected_clusters > 0 { 2 } else { 0 },
+            last_affected_cluster: if affected_clusters > 0 {
+                1 + affected_clusters
+            } else {
+                0
+            },
+            size_limit: self.size_limit,
+            new_fat_type: FatType::ExFat,
+        }
+    }
+}
+
+/// FAT length in sectors for a cluster heap of `clusters` clusters
+fn fat_length_for(clusters: u32, bytes_per_sector: u32) -> u32 {
+    ((clusters as u64 + 2) * 4).div_ceil(bytes_per_sector as u64) as u32
+}
+
+/// Calculate the layout for growing an exFAT filesystem
+///
+/// The FAT grows into the gap before the cluster heap first; only when that
+/// is too small does the heap move, to the next multiple of the requested
+/// alignment (or of the cluster size) after the FAT. Sector counts stay
+/// within 32 bits, which the resize checkpoint records. exFAT has no FAT copy
+/// to add or drop and no other FAT type to convert to.
+pub fn calculate_exfat_size(
+    boot: &ExfatBootSector,
+    device_sectors: u64,
+    options: &CalculationOptions,
+) -> Result<ExfatLayout> {
+    if options.is_fat32_conversion()
+        || options
+            .get_num_fats()
+            .is_some_and(|num_fats| num_fats != boot.number_of_fats())
+    {
+        return Err(Error::UnsupportedFatType(FatType::ExFat));
+    }
+
+    let bytes_per_sector = boot.bytes_per_sector();
+    let sectors_per_cluster = boot.sectors_per_cluster();
+    let num_fats = boot.number_of_fats() as u64;
+    let old_total_sectors = u32::try_from(boot.volume_length()).map_err(|_| {
+        Error::Calculation(format!(
+            "Volume of {} sectors exceeds the supported maximum of {} sectors",
+            boot.volume_length(),
+            u32::MAX
+        ))
+    })?;
+
+    let requested_sectors = match options.get_target_size() {
+        Some(target) => {
+            let target_sectors =
+                target.to_sectors(old_total_sectors as u64, bytes_per_sector as u16);
+            if target_sectors > device_sectors {
+                return Err(Error::TargetTooLarge {
+                    target: target_sectors,
+                    available: device_sectors,
+                });
+            }
+            target_sectors
+        }
+        None => device_sectors,
+    };
+    let (mut new_total_sectors, mut size_limit) = if requested_sectors > u32::MAX as u64 {
+        if options.get_target_size().is_some() {
+            return Err(Error::Calculation(format!(
+                "Size {} sectors exceeds the supported maximum of {} sectors",
+                requested_sectors,
+                u32::MAX
+            )));
+        }
+        (u32::MAX, Some(SizeLimit::SectorCount))
+    } else {
+        (requested_sectors as u32, None)
+    };
+    if new_total_sectors < old_total_sectors {
+        return Err(Error::ShrinkNotSupported);
+    }
+
+    // The heap stays where it is while the FAT fits in front of it
+    let align_sectors = match options.get_align() {
+        Some(bytes) => alignment_sectors(bytes, bytes_per_sector as u16)?,
+        None => sectors_per_cluster as u64,
+    };
+    let heap_offset_for = |fat_length: u32| {
+        let fat_end = boot.fat_offset() as u64 + num_fats * fat_length as u64;
+        if fat_end <= boot.cluster_heap_offset() as u64 {
+            boot.cluster_heap_offset() as u64
+        } else {
+            fat_end.next_multiple_of(align_sectors)
+        }
+    };
+
+    // Size the FAT for a future size, but never beyond the exFAT cluster limit
+    let reserve_length = match options.get_fat_reserve() {
+        Some(reserve) => {
+            let future_sectors =
+                reserve.to_sectors(new_total_sectors as u64, bytes_per_sector as u16);
+            let clusters = (future_sectors / sectors_per_cluster as u64).min(MAX_CLUSTERS as u64);
+            fat_length_for(clusters as u32, bytes_per_sector)
+        }
+        None => 0,
+    };
+
+    // A larger FAT may push the heap forward and leave fewer clusters, which
+    // never needs a larger FAT, so this settles after a few rounds
+    let mut new_fat_length = boot.fat_length().max(reserve_length);
+    let (new_heap_offset, clusters) = loop {
+        let heap_offset = heap_offset_for(new_fat_length);
+        if heap_offset >= new_total_sectors as u64 {
+            return Err(Error::Calculation(format!(
+                "Size {} sectors is too small for the FAT",
+                new_total_sectors
+            )));
+        }
+        let clusters = (new_total_sectors as u64 - heap_offset) / sectors_per_cluster as u64;
+        let needed = fat_length_for(clusters.min(MAX_CLUSTERS as u64) as u32, bytes_per_sector);
+        if needed <= new_fat_length {
+            break (heap_offset as u32, clusters);
+        }
+        new_fat_length = needed;
+    };
+
+    let new_cluster_count = clusters.min(MAX_CLUSTERS as u64) as u32;
+    if clusters > MAX_CLUSTERS as u64 {
+        if options.get_target_size().is_some() {
+            return Err(Error::Calculation(format!(
+                "New cluster count {} exceeds {} maximum of {}",
+                clusters,
+                FatType::ExFat,
+                MAX_CLUSTERS
+            )));
+        }
+        new_total_sectors = new_heap_offset + new_cluster_count * sectors_per_cluster;
+        size_limit = Some(SizeLimit::ClusterCount(FatType::ExFat));
+    }
+
+    if new_total_sectors <= old_total_sectors {
+        return Err(Error::AlreadyMaxSize);
+    }
+
+    // An explicit target ends on a cluster boundary so no partial cluster is left over
+    let old_cluster_count = boot.cluster_count();
+    if options.get_target_size().is_some() {
+        let aligned = new_heap_offset + new_cluster_count * sectors_per_cluster;
+        if aligned <= old_total_sectors || new_cluster_count <= old_cluster_count {
+            return Err(Error::Calculation(format!(
+                "Target size {} sectors does not add a whole cluster to the filesystem",
+                new_total_sectors
+            )));
+        }
+        new_total_sectors = aligned;
+    }
+
+    // The checkpoint in the last sector must lie beyond the shifted clusters
+    let shifted_end =
+        new_heap_offset as u64 + old_cluster_count as u64 * sectors_per_cluster as u64;
+    if (new_total_sectors as u64) <= shifted_end {
+        return Err(Error::Calculation(
+            "FAT growth would leave no room for additional clusters".to_string(),
+        ));
+    }
+
+    Ok(ExfatLayout {
+        old_total_sectors,
+        new_total_sectors,
+        old_fat_length: boot.fat_length(),
+        new_fat_length,
+        old_heap_offset: boot.cluster_heap_offset(),
+        new_heap_offset,
+        old_cluster_count,
+        new_cluster_count,
+        size_limit,
+    })
+}
+
+/// Choose the first cluster of the grown allocation bitmap
+///
+/// The bitmap stays where it is while its clusters hold the new size.
+/// Otherwise it moves to the lowest run of clusters that is free in the old
+/// bitmap (or new) and avoids the checkpoint; the old bitmap's clusters are
+/// kept intact until the new bitmap is in place. Only the old bits decide,
+/// so an interrupted resize chooses the same run again.
+pub fn plan_bitmap_cluster(
+    old_bitmap: &[u8],
+    entry: &BitmapEntry,
+    layout: &ExfatLayout,
+    bytes_per_cluster: u64,
+    checkpoint_cluster: Option<u32>,
+) -> Result<u32> {
+    let needed = (layout.new_cluster_count as u64)
+        .div_ceil(8)
+        .div_ceil(bytes_per_cluster) as u32;
+    let old_clusters = entry.first_cluster..entry.first_cluster + entry.clusters(bytes_per_cluster);
+    if needed <= old_clusters.len() as u32 {
+        return Ok(entry.first_cluster);
+    }
+
+    let old_end = layout.old_cluster_count + 2;
+    let is_free = |cluster: u32| {
+        !old_clusters.contains(&cluster)
+            && Some(cluster) != checkpoint_cluster
+            && (cluster >= old_end || !is_cluster_used(old_bitmap, cluster))
+    };
+
+    let mut run_start = 2;
+    let mut run = 0;
+    for cluster in 2..layout.new_cluster_count + 2 {
+        if !is_free(cluster) {
+            run = 0;
+            continue;
+        }
+        if run == 0 {
+            run_start = cluster;
+        }
+        run += 1;
+        if run == needed {
+            return Ok(run_start);
+        }
+    }
+    Err(Error::NoFreeCluster)
+}
+
+/// Build the allocation bitmap for the new cluster count
+///
+/// Keeps the old allocation, frees the old bitmap's clusters when the bitmap
+/// moves and marks the clusters of the bitmap at `bitmap_cluster` as used.
+pub fn grown_bitmap(
+    old_bitmap: &[u8],
+    entry: &BitmapEntry,
+    layout: &ExfatLayout,
+    bitmap_cluster: u32,
+    bytes_per_cluster: u64,
+) -> Vec<u8> {
+    let new_length = (layout.new_cluster_count as u64).div_ceil(8) as usize;
+    let mut bitmap = vec![0u8; new_length];
+
+    let full_bytes = (layout.old_cluster_count / 8) as usize;
+    bitmap[..full_bytes].copy_from_slice(&old_bitmap[..full_bytes]);
+    let rest = layout.old_cluster_count % 8;
+    if rest > 0 {
+        bitmap[full_bytes] = old_bitmap[full_bytes] & ((1 << rest) - 1);
+    }
+
+    if entry.first_cluster != bitmap_cluster {
+        for cluster in entry.first_cluster..entry.first_cluster + entry.clusters(bytes_per_cluster)
+        {
+            set_cluster_used(&mut bitmap, cluster, false);
+        }
+    }
+    let clusters = (new_length as u64).div_ceil(bytes_per_cluster) as u32;
+    for cluster in bitmap_cluster..bitmap_cluster + clusters {
+        set_cluster_used(&mut bitmap, cluster, true);
+    }
+    bitmap
+}
+
+/// Write the grown bitmap, FAT and bitmap entry
+///
+/// `view` is the boot sector with the new cluster heap offset. Each step is
+/// synced before the next, and the bitmap entry comes last: until it points
+/// at the new bitmap, rerunning this from the old entry gives the same result.
+fn write_grown_metadata(
+    device: &Device,
+    view: &ExfatBootSector,
+    layout: &ExfatLayout,
+    entry: &BitmapEntry,
+    bitmap_cluster: u32,
+    bitmap: &[u8],
+) -> Result<()> {
+    let bytes_per_cluster = view.bytes_per_cluster();
+    let bytes_per_sector = view.bytes_per_sector() as u64;
+
+    // The bitmap, padded to whole clusters, which are contiguous
+    let clusters = (bitmap.len() as u64).div_ceil(bytes_per_cluster) as u32;
+    let mut data = bitmap.to_vec();
+    data.resize((clusters as u64 * bytes_per_cluster) as usize, 0);
+    device.write_sectors(view.cluster_sector(bitmap_cluster), &data)?;
+    device.sync()?;
+
+    // Free FAT entries for the new clusters, including the sectors the FAT grows by
+    let fat_offset = view.fat_offset() as u64;
+    let first_byte = (layout.old_cluster_count as u64 + 2) * 4;
+    let first_sector = first_byte / bytes_per_sector;
+    if first_sector < layout.new_fat_length as u64 {
+        let mut sector = device.read_sector(fat_offset + first_sector)?;
+        sector[(first_byte % bytes_per_sector) as usize..].fill(0);
+        device.write_sector(fat_offset + first_sector, &sector)?;
+
+        let next = first_sector + 1;
+        if next < layout.new_fat_length as u64 {
+            device.zero_range(
+                (fat_offset + next) * bytes_per_sector,
+                (layout.new_fat_length as u64 - next) * bytes_per_sector,
+            )?;
+        }
+    }
+
+    // Chain the new bitmap, and free the old one once it moved
+    let mut entries = Vec::new();
+    if entry.first_cluster != bitmap_cluster {
+        for cluster in entry.first_cluster..entry.first_cluster + entry.clusters(bytes_per_cluster)
+        {
+            entries.push((cluster, fat_entry::FREE));
+        }
+    }
+    for cluster in bitmap_cluster..bitmap_cluster + clusters {
+        let next = if cluster + 1 == bitmap_cluster + clusters {
+            fat_entry::END_OF_CHAIN
+        } else {
+            cluster + 1
+        };
+        entries.push((cluster, next));
+    }
+    write_fat_entries(device, view, &entries)?;
+    device.sync()?;
+
+    let new_entry = BitmapEntry {
+        first_cluster: bitmap_cluster,
+        data_length: bitmap.len() as u64,
+        ..*entry
+    };
+    write_bitmap_entry(device, &new_entry)?;
+    device.sync()
+}
+
+/// Plan shifting every allocated cluster to the moved cluster heap
+fn plan_heap_shift(boot: &ExfatBootSector, bitmap: &[u8], layout: &ExfatLayout) -> RelocationPlan {
+    let sectors_per_cluster = boot.sectors_per_cluster() as u64;
+    let old_first = layout.old_heap_offset as u64;
+    let new_first = layout.new_heap_offset as u64;
+
+    // Highest cluster first, so no source is overwritten before it is copied
+    let moves: Vec<ClusterMove> = if layout.heap_moves() {
+        (2..layout.old_cluster_count + 2)
+            .rev()
+            .filter(|&cluster| is_cluster_used(bitmap, cluster))
+            .map(|cluster| {
+                let offset = (cluster - 2) as u64 * sectors_per_cluster;
+                ClusterMove {
+                    from_cluster: cluster,
+                    to_cluster: cluster,
+                    from_sector: old_first + offset,
+                    to_sector: new_first + offset,
+                }
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    RelocationPlan {
+        total_bytes: moves.len() as u64 * boot.bytes_per_cluster(),
+        moves,
+        old_first_data_sector: old_first,
+        new_first_data_sector: new_first,
+    }
+}
+
+/// Read the checkpoint of an interrupted exFAT grow from the first candidate holding one
+pub(crate) fn read_exfat_checkpoint(
+    device: &Device,
+    boot: &ExfatBootSector,
+    candidates: &[u64],
+) -> Result<Option<(ResizeCheckpoint, u64)>> {
+    for &sector in candidates {
+        if sector < boot.volume_length() || sector >= device.total_sectors() {
+            continue;
+        }
+
+        let data = device.read_sector(sector)?;
+        if let Some(checkpoint) = ResizeCheckpoint::from_bytes(&data)? {
+            if checkpoint.operation == ResizeOperation::ExfatGrow
+                && checkpoint.old_total_sectors as u64 == boot.volume_length()
+            {
+                return Ok(Some((checkpoint, sector)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Sectors where an interrupted exFAT grow may have left its checkpoint
+///
+/// As for FAT, a grow started with a target size must be resumed with it.
+pub(crate) fn exfat_checkpoint_candidates(
+    device: &Device,
+    boot: &ExfatBootSector,
+    options: &ResizeOptions,
+) -> Vec<u64> {
+    let mut candidates = Vec::new();
+
+    if options.get_target_size().is_some() {
+        if let Ok(layout) =
+            calculate_exfat_size(boot, device.total_sectors(), &options.calculation_options())
+        {
+            candidates.push(layout.checkpoint_sector());
+        }
+    }
+
+    if device.total_sectors() > 0 {
+        let last_sector = device.total_sectors() - 1;
+        if !candidates.contains(&last_sector) {
+            candidates.push(last_sector);
+        }
+    }
+
+    candidates
+}
+
+/// Grow an exFAT filesystem, with the same crash-safe checkpoints as
+/// [`resize_fat32`](crate::resize::resize_fat32)
+///
+/// Only the shift strategy applies, and volumes with two FATs (TexFAT) and
+/// volumes marked dirty are refused.
+pub fn resize_exfat(options: ResizeOptions) -> Result<ResizeResult> {
+    let mut operations = Vec::new();
+
+    check_not_mounted_at(options.device_path(), options.get_location())?;
+    operations.push("Verified device is not mounted".to_string());
+
+    let mut device = options.open_device()?;
+    operations.push(format!(
+        "Opened device: {}",
+        options.device_path().display()
+    ));
+
+    // Allows the invalidated signature of an interrupted resize
+    let mut boot = read_boot_region_for_recovery(&mut device)?;
+    operations.push(format!(
+        "Read exFAT boot region ({}-byte sectors)",
+        boot.bytes_per_sector()
+    ));
+
+    if boot.number_of_fats() != 1 {
+        return Err(Error::Calculation(
+            "exFAT volumes with two FATs (TexFAT) are not supported".to_string(),
+        ));
+    }
+    if options.get_strategy() == GrowStrategy::Renumber {
+        return Err(Error::UnsupportedFatType(FatType::ExFat));
+    }
+
+    let incomplete_resize = if !options.is_dry_run() {
+        let candidates = exfat_checkpoint_candidates(&device, &boot, &options);
+        read_exfat_checkpoint(&device, &boot, &candidates)?
+    } else {
+        None
+    };
+    if !boot.is_signature_valid() && incomplete_resize.is_none() {
+        return Err(Error::InvalidatedFilesystem);
+    }
+
+    if let Some((ref checkpoint, _)) = incomplete_resize {
+        eprintln!(
+            "Resuming interrupted resize from phase {:?}...",
+            checkpoint.phase
+        );
+        operations.push(format!(
+            "Detected incomplete resize at phase {:?}",
+            checkpoint.phase
+        ));
+        if device.total_sectors() < checkpoint.new_total_sectors as u64 {
+            return Err(Error::ResizeSizeMismatch(checkpoint.phase as u8));
+        }
+    } else {
+        // A dirty volume needs a check first; growing it would hide the damage
+        if boot.is_dirty() {
+            return Err(Error::DirtyVolume);
+        }
+        if !boot_regions_match(&device)? {
+            return Err(Error::BackupMismatch);
+        }
+        operations.push(format!(
+            "Verified backup boot region at sector {}",
+            BACKUP_BOOT_REGION
+        ));
+    }
+
+    // Calculate new layout (use checkpoint values if resuming)
+    let device_sectors = device.total_sectors();
+    let layout = match incomplete_resize {
+        Some((ref checkpoint, _)) => ExfatLayout::from_checkpoint(&boot, checkpoint),
+        None => calculate_exfat_size(&boot, device_sectors, &options.calculation_options())?,
+    };
+    let checkpoint_sector = incomplete_resize
+        .as_ref()
+        .map(|(_, sector)| *sector)
+        .unwrap_or_else(|| layout.checkpoint_sector());
+
+    operations.push(format!(
+        "Calculated resize: {} -> {} sectors",
+        layout.old_total_sectors, layout.new_total_sectors
+    ));
+    if let Some(limit) = layout.size_limit {
+        operations.push(format!(
+            "Capped at the {}, leaving {} device sectors unused",
+            limit,
+            device_sectors - layout.new_total_sectors as u64
+        ));
+    }
+
+    if options.is_verbose() {
+        print_verbose_exfat_info(&layout);
+    }
+
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    let bytes_per_cluster = boot.bytes_per_cluster();
+    let starting_phase = incomplete_resize
+        .as_ref()
+        .map(|(cp, _)| cp.phase)
+        .unwrap_or(ResizePhase::Started);
+    let resume_watermark = incomplete_resize
+        .as_ref()
+        .map(|(cp, _)| cp.watermark)
+        .filter(|&watermark| watermark != 0);
+
+    // The boot sector with the new heap offset, for clusters that were shifted
+    let mut view = boot.clone();
+    view.set_cluster_heap_offset(layout.new_heap_offset);
+
+    let mut clusters_relocated = 0;
+    let mut discard = None;
+    let mut bitmap_cluster = incomplete_resize
+        .as_ref()
+        .map(|(cp, _)| cp.bitmap_cluster)
+        .unwrap_or(0);
+    // Where a resumed grow had its bitmap before is no longer known
+    let mut bitmap_moved = incomplete_resize.is_some();
+
+    // === PHASE 0: Data shift (safe - source preserved) ===
+    if starting_phase == ResizePhase::Started {
+        // Clusters at or above the watermark of an interrupted shift were copied
+        let cluster_sector = |cluster: u32| {
+            if resume_watermark.is_some_and(|watermark| cluster >= watermark) {
+                view.cluster_sector(cluster)
+            } else {
+                boot.cluster_sector(cluster)
+            }
+        };
+        let entry = read_bitmap_entry(&device, &boot, cluster_sector)?;
+        let old_bitmap = read_bitmap(&device, &boot, &entry, cluster_sector)?;
+        operations.push(format!(
+            "Read allocation bitmap ({} bytes at cluster {})",
+            entry.data_length, entry.first_cluster
+        ));
+
+        if bitmap_cluster == 0 {
+            bitmap_cluster = plan_bitmap_cluster(
+                &old_bitmap,
+                &entry,
+                &layout,
+                bytes_per_cluster,
+                layout.checkpoint_cluster(boot.sectors_per_cluster()),
+            )?;
+        }
+        bitmap_moved = bitmap_cluster != entry.first_cluster;
+        if bitmap_moved {
+            operations.push(format!(
+                "Allocation bitmap moves from cluster {} to cluster {}",
+                entry.first_cluster, bitmap_cluster
+            ));
+        }
+
+        let plan = plan_heap_shift(&boot, &old_bitmap, &layout);
+        if layout.heap_moves() {
+            operations.push(format!(
+                "Cluster heap moves from sector {} to sector {}",
+                layout.old_heap_offset, layout.new_heap_offset
+            ));
+            operations.push(format!(
+                "Planned data shift for {} clusters ({} bytes)",
+                plan.cluster_count(),
+                plan.total_bytes
+            ));
+        }
+
+        if !options.is_dry_run() {
+            let started_checkpoint = |watermark: u32| {
+                ResizeCheckpoint::new(
+                    ResizePhase::Started,
+                    layout.old_total_sectors,
+                    layout.new_total_sectors,
+                    layout.old_fat_length,
+                    layout.new_fat_length,
+                )
+                .with_exfat_layout(layout.new_heap_offset, bitmap_cluster)
+                .with_watermark(watermark)
+            };
+
+            // Write initial checkpoint, keeping the progress of an earlier attempt
+            let checkpoint = started_checkpoint(resume_watermark.unwrap_or(0));
+            write_checkpoint(&device, checkpoint_sector, &checkpoint)?;
+            operations.push("Wrote checkpoint (phase 0: started)".to_string());
+
+            maybe_crash_at("after_checkpoint_start");
+
+            if !plan.is_empty() {
+                let mut persist_watermark = |watermark: u32| -> Result<()> {
+                    write_checkpoint(&device, checkpoint_sector, &started_checkpoint(watermark))?;
+                    maybe_crash_at("during_data_shift");
+                    Ok(())
+                };
+                let copied = shift_clusters(
+                    &device,
+                    boot.sectors_per_cluster(),
+                    &plan,
+                    resume_watermark,
+                    &mut persist_watermark,
+                    options.read_ahead(),
+                    options.is_verbose(),
+                )?;
+                clusters_relocated = plan.cluster_count();
+                if let Some(watermark) = resume_watermark {
+                    operations.push(format!(
+                        "Resumed data shift below cluster {} ({} clusters copied)",
+                        watermark, copied
+                    ));
+                }
+                operations.push(format!("Shifted {} clusters forward", clusters_relocated));
+            }
+
+            maybe_crash_at("after_data_shift");
+
+            let checkpoint = ResizeCheckpoint::new(
+                ResizePhase::DataCopied,
+                layout.old_total_sectors,
+                layout.new_total_sectors,
+                layout.old_fat_length,
+                layout.new_fat_length,
+            )
+            .with_exfat_layout(layout.new_heap_offset, bitmap_cluster);
+            write_checkpoint(&device, checkpoint_sector, &checkpoint)?;
+            operations.push("Updated checkpoint (phase 1: data copied)".to_string());
+
+            maybe_crash_at("after_checkpoint_data_copied");
+        } else if layout.heap_moves() {
+            operations.push("Dry run: would shift cluster data".to_string());
+        }
+    } else {
+        operations.push("Skipping data shift (already done)".to_string());
+    }
+
+    if !options.is_dry_run() {
+        // === PHASE 1: Bitmap and FAT (dangerous - boot sector invalidated) ===
+        if starting_phase <= ResizePhase::DataCopied {
+            boot.invalidate_signature();
+            write_boot_sector(&device, &boot)?;
+            device.sync()?;
+            operations.push("Invalidated boot sector (danger zone)".to_string());
+
+            maybe_crash_at("after_boot_invalidate");
+
+            let entry = read_bitmap_entry(&device, &view, |cluster| view.cluster_sector(cluster))?;
+            let bitmap = read_bitmap(&device, &view, &entry, |cluster| {
+                view.cluster_sector(cluster)
+            })?;
+            let grown = grown_bitmap(&bitmap, &entry, &layout, bitmap_cluster, bytes_per_cluster);
+            write_grown_metadata(&device, &view, &layout, &entry, bitmap_cluster, &grown)?;
+            operations.push(format!(
+                "Wrote allocation bitmap ({} bytes at cluster {})",
+                grown.len(),
+                bitmap_cluster
+            ));
+            operations.push("Initialized new FAT entries".to_string());
+
+            maybe_crash_at("after_fat_write");
+
+            let checkpoint = ResizeCheckpoint::new(
+                ResizePhase::FatWritten,
+                layout.old_total_sectors,
+                layout.new_total_sectors,
+                layout.old_fat_length,
+                layout.new_fat_length,
+            )
+            .with_exfat_layout(layout.new_heap_offset, bitmap_cluster);
+            write_checkpoint(&device, checkpoint_sector, &checkpoint)?;
+            operations.push("Updated checkpoint (phase 2: FAT written)".to_string());
+
+            maybe_crash_at("after_checkpoint_fat_written");
+        } else {
+            operations.push("Skipping bitmap and FAT update (already done)".to_string());
+        }
+    }
+
+    // Free clusters from the grown bitmap, or estimated for a dry run
+    let new_free_clusters = if !options.is_dry_run() {
+        let entry = read_bitmap_entry(&device, &view, |cluster| view.cluster_sector(cluster))?;
+        let bitmap = read_bitmap(&device, &view, &entry, |cluster| {
+            view.cluster_sector(cluster)
+        })?;
+        count_free_clusters(&bitmap, layout.new_cluster_count)
+    } else {
+        layout.new_cluster_count - layout.old_cluster_count
+    };
+
+    if !options.is_dry_run() {
+        // === PHASE 2: Boot regions (restore boot sector) ===
+        boot.set_volume_length(layout.new_total_sectors as u64);
+        boot.set_fat_length(layout.new_fat_length);
+        boot.set_cluster_heap_offset(layout.new_heap_offset);
+        boot.set_cluster_count(layout.new_cluster_count);
+        if boot.percent_in_use() != 0xFF {
+            let used = (layout.new_cluster_count - new_free_clusters) as u64;
+            boot.set_percent_in_use((used * 100 / layout.new_cluster_count as u64) as u8);
+        }
+        boot.restore_signature();
+
+        // Backup first: until the main region is restored, a crash resumes from the checkpoint
+        write_boot_region(&device, &boot, BACKUP_BOOT_REGION)?;
+        operations.push("Updated backup boot region".to_string());
+
+        write_boot_region(&device, &boot, 0)?;
+        operations.push("Updated boot region (signature restored)".to_string());
+
+        clear_checkpoint(&device, checkpoint_sector)?;
+        operations.push("Cleared checkpoint".to_string());
+
+        device.sync()?;
+        operations.push("Synced changes to disk".to_string());
+
+        // Shifted clusters and a moved bitmap leave stale data in clusters
+        // that are now free; otherwise only the added clusters are new
+        if options.is_discard() {
+            let first = if layout.heap_moves() || bitmap_moved {
+                2
+            } else {
+                layout.old_cluster_count + 2
+            };
+            let stats = discard_free_exfat_clusters(
+                &device,
+                &boot,
+                first..layout.new_cluster_count + 2,
+                false,
+            )?;
+            if stats.supported {
+                device.sync()?;
+                operations.push(format!(
+                    "Discarded {} bytes of free clusters from cluster {}",
+                    stats.discarded_bytes, first
+                ));
+            } else {
+                operations.push("Device does not support discards; skipped discarding".to_string());
+            }
+            discard = Some(stats);
+        }
+    } else {
+        operations.push("Dry run: no changes made".to_string());
+    }
+
+    let mut calculation = layout.size_calculation(&boot);
+    calculation.new_free_clusters = new_free_clusters;
+
+    Ok(ResizeResult {
+        old_size_bytes: layout.old_total_sectors as u64 * bytes_per_sector,
+        new_size_bytes: layout.new_total_sectors as u64 * bytes_per_sector,
+        fat_grew: calculation.fat_needs_growth,
+        clusters_relocated,
+        strategy: layout.heap_moves().then_some(GrowStrategy::Shift),
+        size_limit: layout.size_limit,
+        converted_from: None,
+        unused_bytes: device_sectors.saturating_sub(layout.new_total_sectors as u64)
+            * bytes_per_sector,
+        hidden_sectors_change: None,
+        partition_type_change: None,
+        sparse: device.sparse_stats(),
+        io: device.io_stats(),
+        discard,
+        calculation,
+        operations,
+    })
+}
+
+/// Print verbose resize information to stderr
+fn print_verbose_exfat_info(layout: &ExfatLayout) {
+    eprintln!("Current filesystem (exFAT):");
+    eprintln!("  Volume length: {} sectors", layout.old_total_sectors);
+    eprintln!("  FAT length: {} sectors", layout.old_fat_length);
+    eprintln!("  Cluster heap offset: {}", layout.old_heap_offset);
+    eprintln!("  Cluster count: {}", layout.old_cluster_count);
+    eprintln!();
+    eprintln!("After resize (exFAT):");
+    eprintln!("  Volume length: {} sectors", layout.new_total_sectors);
+    eprintln!("  FAT length: {} sectors", layout.new_fat_length);
+    eprintln!("  Cluster heap offset: {}", layout.new_heap_offset);
+    eprintln!("  Cluster count: {}", layout.new_cluster_count);
+    eprintln!("  Cluster heap moves: {}", layout.heap_moves());
+}
+
+/// Get information about an exFAT filesystem without modifying it
+pub fn get_exfat_info(device_path: impl AsRef<std::path::Path>) -> Result<ExfatInfoReport> {
+    get_exfat_info_at(device_path, VolumeLocation::Whole)
+}
+
+/// Get information about the exFAT filesystem in a partition or at an
+/// offset of the device
+pub fn get_exfat_info_at(
+    device_path: impl AsRef<std::path::Path>,
+    location: VolumeLocation,
+) -> Result<ExfatInfoReport> {
+    let device_path = device_path.as_ref();
+    let mut device = Device::open_readonly_at(device_path, location)?;
+    let boot = read_boot_region(&mut device)?;
+    let backup_matches = boot_regions_match(&device)?;
+
+    let cluster_sector = |cluster: u32| boot.cluster_sector(cluster);
+    let entry = read_bitmap_entry(&device, &boot, cluster_sector)?;
+    let bitmap = read_bitmap(&device, &boot, &entry, cluster_sector)?;
+    let free_clusters = count_free_clusters(&bitmap, boot.cluster_count());
+
+    let device_sectors = device.total_sectors();
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+
+    // Growing to fill the device stops at the exFAT limits
+    let (max_new_size, size_limit) = if device_sectors > boot.volume_length() {
+        match calculate_exfat_size(&boot, device_sectors, &CalculationOptions::new()) {
+            Ok(layout) => (
+                Some(layout.new_total_sectors as u64 * bytes_per_sector),
+                layout.size_limit,
+            ),
+            Err(Error::AlreadyMaxSize) if boot.cluster_count() >= MAX_CLUSTERS => {
+                (None, Some(SizeLimit::ClusterCount(FatType::ExFat)))
+            }
+            Err(Error::AlreadyMaxSize) => (None, Some(SizeLimit::SectorCount)),
+            Err(_) => (None, None),
+        }
+    } else {
+        (None, None)
+    };
+
+    // Largest size reachable with the FAT filling the gap before the heap
+    let max_fat_length =
+        (boot.cluster_heap_offset() - boot.fat_offset()) / boot.number_of_fats() as u32;
+    let fat_clusters = (max_fat_length as u64 * bytes_per_sector / 4 - 2).min(MAX_CLUSTERS as u64);
+    let fat_capacity_sectors =
+        boot.cluster_heap_offset() as u64 + fat_clusters * boot.sectors_per_cluster() as u64;
+
+    Ok(ExfatInfoReport {
+        device_path: device_path.to_path_buf(),
+        location,
+        bytes_per_sector: boot.bytes_per_sector(),
+        sectors_per_cluster: boot.sectors_per_cluster(),
+        fat_offset: boot.fat_offset(),
+        fat_length: boot.fat_length(),
+        num_fats: boot.number_of_fats(),
+        cluster_heap_offset: boot.cluster_heap_offset(),
+        cluster_count: boot.cluster_count(),
+        volume_length: boot.volume_length(),
+        root_cluster: boot.root_cluster(),
+        bitmap_cluster: entry.first_cluster,
+        volume_serial: boot.volume_serial(),
+        dirty: boot.is_dirty(),
+        free_clusters,
+        backup_matches,
+        device_sectors,
+        can_grow: max_new_size.is_some(),
+        current_size_bytes: boot.volume_length() * bytes_per_sector,
+        max_new_size_bytes: max_new_size,
+        size_limit,
+        fat_capacity_bytes: fat_capacity_sectors * bytes_per_sector,
+    })
+}
+
+/// Report about an exFAT filesystem
+#[derive(Debug)]
+pub struct ExfatInfoReport {
+    pub device_path: std::path::PathBuf,
+    /// Where the filesystem is on the device
+    pub location: VolumeLocation,
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub num_fats: u8,
+    pub cluster_heap_offset: u32,
+    pub cluster_count: u32,
+    pub volume_length: u64,
+    pub root_cluster: u32,
+    /// First cluster of the allocation bitmap
+    pub bitmap_cluster: u32,
+    pub volume_serial: u32,
+    /// Whether the volume is marked dirty (not cleanly unmounted)
+    pub dirty: bool,
+    pub free_clusters: u32,
+    pub backup_matches: bool,
+    pub device_sectors: u64,
+    pub can_grow: bool,
+    pub current_size_bytes: u64,
+    pub max_new_size_bytes: Option<u64>,
+    /// exFAT limit that keeps the filesystem from filling the device
+    pub size_limit: Option<SizeLimit>,
+    /// Largest filesystem size reachable without moving the cluster heap
+    pub fat_capacity_bytes: u64,
+}
+
+impl ExfatInfoReport {
+    /// How much the filesystem can grow before the cluster heap has to move
+    pub fn fat_headroom_bytes(&self) -> u64 {
+        self.fat_capacity_bytes
+            .saturating_sub(self.current_size_bytes)
+    }
+}
+
+impl std::fmt::Display for ExfatInfoReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "exFAT Filesystem Information")?;
+        writeln!(f, "============================")?;
+        writeln!(f, "Device: {}", self.device_path.display())?;
+        if self.location != VolumeLocation::Whole {
+            writeln!(f, "Location: {}", self.location)?;
+        }
+        writeln!(f, "Volume serial: {:08X}", self.volume_serial)?;
+        writeln!(f)?;
+        writeln!(f, "Geometry:")?;
+        writeln!(f, "  Bytes per sector: {}", self.bytes_per_sector)?;
+        writeln!(f, "  Sectors per cluster: {}", self.sectors_per_cluster)?;
+        writeln!(
+            f,
+            "  Bytes per cluster: {}",
+            self.bytes_per_sector as u64 * self.sectors_per_cluster as u64
+        )?;
+        writeln!(f)?;
+        writeln!(f, "Layout:")?;
+        writeln!(f, "  FAT offset: {}", self.fat_offset)?;
+        writeln!(f, "  FAT length (sectors): {}", self.fat_length)?;
+        writeln!(f, "  Number of FATs: {}", self.num_fats)?;
+        writeln!(f, "  Volume length (sectors): {}", self.volume_length)?;
+        writeln!(f, "  Cluster count: {}", self.cluster_count)?;
+        writeln!(
+            f,
+            "  Cluster heap offset: {} (byte offset {})",
+            self.cluster_heap_offset,
+            self.cluster_heap_offset as u64 * self.bytes_per_sector as u64
+        )?;
+        writeln!(f)?;
+        writeln!(f, "Special clusters:")?;
+        writeln!(f, "  Root directory cluster: {}", self.root_cluster)?;
+        writeln!(f, "  Allocation bitmap cluster: {}", self.bitmap_cluster)?;
+        writeln!(
+            f,
+            "  Backup boot region matches primary: {}",
+            if self.backup_matches { "Yes" } else { "NO" }
+        )?;
+        writeln!(
+            f,
+            "  Volume dirty: {}",
+            if self.dirty { "YES" } else { "No" }
+        )?;
+        writeln!(f)?;
+        writeln!(f, "Usage:")?;
+        writeln!(
+            f,
+            "  Free clusters: {} ({} bytes)",
+            self.free_clusters,
+            self.free_clusters as u64
+                * self.bytes_per_sector as u64
+                * self.sectors_per_cluster as u64
+        )?;
+        writeln!(f)?;
+        writeln!(f, "Size:")?;
+        writeln!(
+            f,
+            "  Current size: {} bytes ({:.2} MB)",
+            self.current_size_bytes,
+            self.current_size_bytes as f64 / (1024.0 * 1024.0)
+        )?;
+        writeln!(f, "  Device sectors: {}", self.device_sectors)?;
+        writeln!(
+            f,
+            "  Can grow: {}",
+            if self.can_grow { "Yes" } else { "No" }
+        )?;
+        if let Some(max_size) = self.max_new_size_bytes {
+            writeln!(
+                f,
+                "  Max new size: {} bytes ({:.2} MB)",
+                max_size,
+                max_size as f64 / (1024.0 * 1024.0)
+            )?;
+        }
+        if let Some(limit) = self.size_limit {
+            let fs_bytes = self.max_new_size_bytes.unwrap_or(self.current_size_bytes);
+            let unused =
+                (self.device_sectors * self.bytes_per_sector as u64).saturating_sub(fs_bytes);
+            writeln!(
+                f,
+                "  Limited by: {} ({} bytes of the device stay unused)",
+                limit, unused
+            )?;
+        }
+        writeln!(
+            f,
+            "  FAT covers up to: {} bytes ({:.2} MB)",
+            self.fat_capacity_bytes,
+            self.fat_capacity_bytes as f64 / (1024.0 * 1024.0)
+        )?;
+        writeln!(
+            f,
+            "  FAT headroom: {} bytes ({:.2} MB)",
+            self.fat_headroom_bytes(),
+            self.fat_headroom_bytes() as f64 / (1024.0 * 1024.0)
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resize::{resize_fat32, trim_filesystem, TargetSize};
+    use crate::test_image::{
+        assert_exfat_consistent, assert_resumes_after_crashes, pattern, read_exfat_file, ImageSpec,
+        TestImage,
+    };
+
+    type Files = Vec<(&'static str, Vec<u8>)>;
+
+    /// A contiguous file without a FAT chain and a fragmented one
+    fn exfat_image(spec: ImageSpec) -> (TestImage, Files) {
+        let mut image = TestImage::create(spec);
+        let files: Files = vec![
+            ("first.bin", pattern(1, 20 * 4096)),
+            ("second.bin", pattern(2, 9 * 4096 + 100)),
+        ];
+        image.add_exfat_file(files[0].0, &files[0].1, 1);
+        image.add_exfat_file(files[1].0, &files[1].1, 3);
+        (image, files)
+    }
+
+    fn assert_files_intact(image: &TestImage, files: &Files) {
+        for (name, contents) in files {
+            let data = read_exfat_file(image.path(), name);
+            assert!(data.as_ref() == Some(contents), "{} differs", name);
+        }
+    }
+
+    fn read_boot(image: &TestImage) -> ExfatBootSector {
+        let mut device = Device::open_readonly(image.path()).unwrap();
+        read_boot_region(&mut device).unwrap()
+    }
+
+    #[test]
+    fn test_grow_discards_free_clusters() {
+        let (image, files) = exfat_image(ImageSpec::exfat());
+        image.extend_to_sectors(65_536);
+
+        // The heap moves, so every free cluster is discarded
+        let options = ResizeOptions::new(image.path()).discard(true);
+        let result = resize_fat32(options).unwrap();
+        let discard = result.discard.unwrap();
+        assert!(discard.supported);
+        assert_eq!(
+            discard.discarded_bytes,
+            result.calculation.new_free_clusters as u64 * 512
+        );
+        assert_files_intact(&image, &files);
+        assert_exfat_consistent(image.path());
+
+        let trim = trim_filesystem(ResizeOptions::new(image.path())).unwrap();
+        assert_eq!(trim.fat_type, FatType::ExFat);
+        assert_eq!(trim.discard, discard);
+        assert_files_intact(&image, &files);
+    }
+
+    #[test]
+    fn test_grow_moves_heap_and_bitmap() {
+        let (image, files) = exfat_image(ImageSpec::exfat());
+        image.extend_to_sectors(65_536);
+
+        // FAT resizes hand exFAT volumes over
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(result.strategy, Some(GrowStrategy::Shift));
+        assert!(result.clusters_relocated > 0);
+        assert_eq!(result.calculation.new_fat_type, FatType::ExFat);
+
+        let boot = read_boot(&image);
+        assert_eq!(boot.volume_length(), 65_536);
+        assert!(boot.cluster_heap_offset() > image.heap_offset());
+        assert_eq!(boot.cluster_count(), result.calculation.new_data_clusters);
+
+        let info = get_exfat_info(image.path()).unwrap();
+        assert_ne!(info.bitmap_cluster, image.bitmap_cluster());
+        assert_eq!(info.free_clusters, result.calculation.new_free_clusters);
+        assert!(info.backup_matches);
+        assert!(!info.can_grow);
+
+        assert_files_intact(&image, &files);
+        assert_exfat_consistent(image.path());
+    }
+
+    #[test]
+    fn test_grow_within_fat_gap_keeps_heap_and_bitmap() {
+        // 4 KiB clusters: one bitmap cluster covers 32768 clusters
+        let (image, files) = exfat_image(ImageSpec {
+            sectors_per_cluster: 8,
+            heap_gap: 64,
+            ..ImageSpec::exfat()
+        });
+        image.extend_to_sectors(65_536);
+
+        let result = resize_exfat(ResizeOptions::new(image.path())).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(result.strategy, None);
+        assert_eq!(result.clusters_relocated, 0);
+
+        let boot = read_boot(&image);
+        assert_eq!(boot.cluster_heap_offset(), image.heap_offset());
+        let info = get_exfat_info(image.path()).unwrap();
+        assert_eq!(info.bitmap_cluster, image.bitmap_cluster());
+
+        assert_files_intact(&image, &files);
+        assert_exfat_consistent(image.path());
+    }
+
+    #[test]
+    fn test_grow_to_target_size_and_alignment() {
+        let (image, files) = exfat_image(ImageSpec::exfat());
+        image.extend_to_sectors(65_536);
+
+        let options = ResizeOptions::new(image.path())
+            .target_size(Some(TargetSize::Absolute(40_000 * 512 + 100)))
+            .align(Some(crate::resize::Alignment::Bytes(64 * 1024)));
+        let result = resize_exfat(options).unwrap();
+        assert_eq!(result.unused_bytes, (65_536 - 40_000) * 512);
+
+        let boot = read_boot(&image);
+        assert!(boot.cluster_heap_offset().is_multiple_of(128));
+        assert_eq!(
+            boot.volume_length(),
+            boot.cluster_sector(boot.cluster_count() + 2)
+        );
+        assert!(boot.volume_length() <= 40_000);
+
+        assert_files_intact(&image, &files);
+        assert_exfat_consistent(image.path());
+    }
+
+    #[test]
+    fn test_rejects_what_exfat_cannot_do() {
+        let (image, _) = exfat_image(ImageSpec::exfat());
+        image.extend_to_sectors(65_536);
+        let before = std::fs::read(image.path()).unwrap();
+
+        let unsupported = [
+            ResizeOptions::new(image.path()).num_fats(Some(2)),
+            ResizeOptions::new(image.path()).convert_to_fat32(true),
+            ResizeOptions::new(image.path()).strategy(GrowStrategy::Renumber),
+        ];
+        for options in unsupported {
+            assert!(matches!(
+                resize_exfat(options),
+                Err(Error::UnsupportedFatType(FatType::ExFat))
+            ));
+        }
+
+        // A dry run only reports
+        let result = resize_exfat(ResizeOptions::new(image.path()).dry_run(true)).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(std::fs::read(image.path()).unwrap(), before);
+
+        image.set_dirty();
+        assert!(matches!(
+            resize_exfat(ResizeOptions::new(image.path())),
+            Err(Error::DirtyVolume)
+        ));
+    }
+
+    #[test]
+    fn test_interrupted_exfat_grow_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            || {
+                let (image, files) = exfat_image(ImageSpec::exfat());
+                image.extend_to_sectors(65_536);
+                (image, files)
+            },
+            |(image, _)| ResizeOptions::new(image.path()),
+            resize_exfat,
+            |(image, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_region(&mut device).is_ok_and(|b| b.volume_length() == 65_536)
+            },
+            |(image, files)| {
+                assert_files_intact(image, files);
+                assert_exfat_consistent(image.path());
+            },
+        );
+        assert!(writes > 40);
+    }
+}
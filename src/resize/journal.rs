@@ -0,0 +1,252 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::{read_cluster, write_cluster, BootSector};
+
+/// Magic bytes for journal identification
+const JOURNAL_MAGIC: &[u8; 8] = b"FAT32JNL";
+
+/// Current journal version
+const JOURNAL_VERSION: u8 = 1;
+
+/// Journal header size in bytes
+const HEADER_SIZE: usize = 8 + 1 + 3 + 4 + 4 + 4; // 24 bytes
+
+/// Bytes at the end of each journal cluster holding the next cluster number
+const LINK_SIZE: usize = 4;
+
+/// Sector writes prepared ahead of a metadata switch
+///
+/// Shrinking and renumbering rewrite FAT sectors and directory entries that
+/// the old filesystem still depends on. Those writes are collected here and stored in
+/// free clusters before the switch. Once the journal is complete, replaying it
+/// is idempotent, so an interrupted switch is finished by replaying it again.
+///
+/// The journal is a byte stream spread over a list of clusters. The last four
+/// bytes of each cluster hold the number of the next one (0 for the last), so
+/// the journal can be read back from its first cluster without the FAT.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    sector_size: usize,
+    records: Vec<(u64, Vec<u8>)>,
+}
+
+impl Journal {
+    /// Create an empty journal for sectors of the given size
+    pub fn new(sector_size: usize) -> Self {
+        Self {
+            sector_size,
+            records: Vec::new(),
+        }
+    }
+
+    /// Record that `data` (one sector) must be written to `sector`
+    pub fn add(&mut self, sector: u64, data: Vec<u8>) {
+        debug_assert_eq!(data.len(), self.sector_size);
+        self.records.push((sector, data));
+    }
+
+    /// Number of recorded sector writes
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Recorded sector writes, in order
+    pub fn records(&self) -> &[(u64, Vec<u8>)] {
+        &self.records
+    }
+
+    /// Size of the stored journal in bytes
+    pub fn size_bytes(&self) -> usize {
+        HEADER_SIZE + self.records.len() * (8 + self.sector_size)
+    }
+
+    /// Number of clusters needed to store the journal
+    pub fn clusters_needed(&self, bytes_per_cluster: u32) -> usize {
+        self.size_bytes()
+            .div_ceil(bytes_per_cluster as usize - LINK_SIZE)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.size_bytes() - HEADER_SIZE);
+        for (sector, data) in &self.records {
+            body.extend_from_slice(&sector.to_le_bytes());
+            body.extend_from_slice(data);
+        }
+
+        let mut stream = Vec::with_capacity(self.size_bytes());
+        stream.extend_from_slice(JOURNAL_MAGIC);
+        stream.push(JOURNAL_VERSION);
+        stream.extend_from_slice(&[0u8; 3]);
+        stream.extend_from_slice(&(self.records.len() as u32).to_le_bytes());
+        stream.extend_from_slice(&(self.sector_size as u32).to_le_bytes());
+        stream.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+        stream.extend_from_slice(&body);
+        stream
+    }
+
+    /// Parse the header, returning record count and sector size
+    fn parse_header(data: &[u8]) -> Result<(usize, usize)> {
+        if data.len() < HEADER_SIZE || &data[0..8] != JOURNAL_MAGIC || data[8] != JOURNAL_VERSION {
+            return Err(Error::JournalCorrupted);
+        }
+        let count = u32::from_le_bytes([data[12], data[13], data[14], data[15]]) as usize;
+        let sector_size = u32::from_le_bytes([data[16], data[17], data[18], data[19]]) as usize;
+        Ok((count, sector_size))
+    }
+
+    fn from_bytes(stream: &[u8]) -> Result<Self> {
+        let (count, sector_size) = Self::parse_header(stream)?;
+        let body = &stream[HEADER_SIZE..];
+        let stored_crc = u32::from_le_bytes([stream[20], stream[21], stream[22], stream[23]]);
+        if body.len() != count * (8 + sector_size) || crc32fast::hash(body) != stored_crc {
+            return Err(Error::JournalCorrupted);
+        }
+
+        let records = body
+            .chunks_exact(8 + sector_size)
+            .map(|record| {
+                let mut sector = [0u8; 8];
+                sector.copy_from_slice(&record[..8]);
+                (u64::from_le_bytes(sector), record[8..].to_vec())
+            })
+            .collect();
+
+        Ok(Self {
+            sector_size,
+            records,
+        })
+    }
+
+    /// Store the journal in the given clusters and sync it
+    ///
+    /// `clusters` must hold at least `clusters_needed()` free clusters.
+    pub fn write(&self, device: &Device, boot: &BootSector, clusters: &[u32]) -> Result<()> {
+        let bytes_per_cluster = boot.bytes_per_cluster() as usize;
+        let payload = bytes_per_cluster - LINK_SIZE;
+        let stream = self.to_bytes();
+        let needed = self.clusters_needed(boot.bytes_per_cluster());
+
+        if clusters.len() < needed {
+            return Err(Error::NoFreeCluster);
+        }
+
+        for (i, chunk) in stream.chunks(payload).enumerate() {
+            let next = if i + 1 < needed { clusters[i + 1] } else { 0 };
+            let mut data = vec![0u8; bytes_per_cluster];
+            data[..chunk.len()].copy_from_slice(chunk);
+            data[payload..].copy_from_slice(&next.to_le_bytes());
+            write_cluster(device, boot, clusters[i], &data)?;
+        }
+
+        device.sync()
+    }
+
+    /// Read a journal back, starting at its first cluster
+    pub fn read(device: &Device, boot: &BootSector, first_cluster: u32) -> Result<Self> {
+        let payload = boot.bytes_per_cluster() as usize - LINK_SIZE;
+        let max_cluster = boot.data_clusters() + 2;
+
+        let mut stream = Vec::new();
+        let mut cluster = first_cluster;
+        let mut expected_len = None;
+
+        loop {
+            if !(2..max_cluster).contains(&cluster) {
+                return Err(Error::JournalCorrupted);
+            }
+            let data = read_cluster(device, boot, cluster)?;
+            stream.extend_from_slice(&data[..payload]);
+
+            if expected_len.is_none() {
+                let (count, sector_size) = Self::parse_header(&stream)?;
+                if sector_size != boot.bytes_per_sector() as usize {
+                    return Err(Error::JournalCorrupted);
+                }
+                expected_len = Some(HEADER_SIZE + count * (8 + sector_size));
+            }
+
+            let expected = expected_len.unwrap_or_default();
+            if stream.len() >= expected {
+                stream.truncate(expected);
+                return Self::from_bytes(&stream);
+            }
+
+            cluster = u32::from_le_bytes([
+                data[payload],
+                data[payload + 1],
+                data[payload + 2],
+                data[payload + 3],
+            ]);
+        }
+    }
+
+    /// Perform all recorded sector writes and sync
+    pub fn replay(&self, device: &Device) -> Result<()> {
+        for (sector, data) in &self.records {
+            device.write_sector(*sector, data)?;
+        }
+        device.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::read_boot_sector;
+    use crate::test_image::{ImageSpec, TestImage};
+
+    #[test]
+    fn test_journal_roundtrip() {
+        let image = TestImage::create(ImageSpec::default());
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+
+        let mut journal = Journal::new(512);
+        for i in 0..5u8 {
+            journal.add(100 + i as u64, vec![i; 512]);
+        }
+
+        // Spread over non-contiguous clusters (one 512-byte sector each)
+        let clusters: Vec<u32> = (0..journal.clusters_needed(512) as u32)
+            .map(|i| 1000 + i * 7)
+            .collect();
+        assert_eq!(clusters.len(), 6);
+        journal.write(&device, &boot, &clusters).unwrap();
+
+        let read = Journal::read(&device, &boot, clusters[0]).unwrap();
+        assert_eq!(read.records(), journal.records());
+
+        read.replay(&device).unwrap();
+        assert_eq!(device.read_sector(103).unwrap(), vec![3u8; 512]);
+    }
+
+    #[test]
+    fn test_journal_corrupted() {
+        let image = TestImage::create(ImageSpec::default());
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+
+        let mut journal = Journal::new(512);
+        journal.add(100, vec![0xAB; 512]);
+        journal.write(&device, &boot, &[1000, 1001, 1002]).unwrap();
+
+        // Damage the recorded sector data
+        let sector = boot.cluster_to_sector(1000);
+        let mut data = device.read_sector(sector).unwrap();
+        data[100] ^= 0xFF;
+        device.write_sector(sector, &data).unwrap();
+
+        assert!(matches!(
+            Journal::read(&device, &boot, 1000),
+            Err(Error::JournalCorrupted)
+        ));
+        assert!(matches!(
+            Journal::read(&device, &boot, 2000),
+            Err(Error::JournalCorrupted)
+        ));
+    }
+}
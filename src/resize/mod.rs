@@ -1,17 +1,49 @@
 pub mod calculator;
+pub mod convert;
+pub mod discard;
 pub mod executor;
+pub mod exfat;
+pub mod journal;
+pub mod recluster;
 pub mod relocator;
+pub mod renumber;
+pub mod shrinker;
 
 // Re-export calculator types and functions
-pub use calculator::{calculate_fat_size, calculate_new_size, SizeCalculation};
+pub use calculator::{
+    calculate_fat_size, calculate_new_size, calculate_new_size_with_options,
+    calculate_recluster_size, calculate_shrink_size, calculate_small_fat_size,
+    max_sectors_for_fat_size, max_sectors_for_small_fat, parse_size, Alignment, CalculationOptions,
+    FatReserve, SizeCalculation, SizeLimit, TargetSize, AUTO_ALIGN_MIN_BYTES,
+    FAT32_RESERVED_SECTORS,
+};
+
+// Re-export discard types and functions
+pub use discard::{trim_filesystem, DiscardStats, TrimResult};
 
 // Re-export executor types and functions
 pub use executor::{
-    get_fs_info, resize_fat32, FSInfoReport, ResizeCheckpoint, ResizeOptions, ResizePhase,
-    ResizeResult,
+    get_fs_info, get_fs_info_at, resize_fat32, FSInfoReport, GrowStrategy, ResizeCheckpoint,
+    ResizeOperation, ResizeOptions, ResizePhase, ResizeResult,
+};
+
+// Re-export exFAT types and functions
+pub use exfat::{
+    calculate_exfat_size, get_exfat_info, get_exfat_info_at, resize_exfat, ExfatInfoReport,
+    ExfatLayout,
 };
 
 // Re-export relocator types and functions
 pub use relocator::{
-    execute_relocation, plan_relocation, verify_relocation, ClusterMove, RelocationPlan,
+    execute_relocation, execute_relocation_with_progress, plan_relocation, verify_relocation,
+    ClusterMove, ReadAhead, RelocationPlan, WindowedShift, DEFAULT_QUEUE_DEPTH,
 };
+
+// Re-export re-clustering types and functions
+pub use recluster::{plan_recluster, recluster_fat32, ReclusterPlan};
+
+// Re-export renumbering types and functions
+pub use renumber::{plan_renumber, RenumberPlan};
+
+// Re-export shrinker types and functions
+pub use shrinker::{plan_shrink, shrink_fat32, ShrinkPlan};
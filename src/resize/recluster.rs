@@ -0,0 +1,632 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::{
+    cluster_chain, count_free_clusters, entry_first_cluster, entry_kind, fat_entry,
+    read_boot_sector_for_recovery, read_cluster, read_fat_table, BootSector, EntryKind, ENTRY_SIZE,
+};
+use crate::resize::calculator::{calculate_recluster_size, SizeCalculation};
+use crate::resize::executor::{ResizeCheckpoint, ResizeOperation, ResizeOptions, ResizeResult};
+use crate::resize::journal::Journal;
+use crate::resize::relocator::ClusterMove;
+use crate::resize::renumber::{
+    execute_renumbering, holds_data, journal_fat, patch_directory, RenumberPlan, RenumberStart,
+};
+use crate::resize::shrinker::finish_shift_back;
+use crate::system::check_not_mounted_at;
+use std::collections::{HashMap, HashSet};
+
+/// Everything needed to change the cluster size, worked out before any write
+#[derive(Debug)]
+pub struct ReclusterPlan {
+    /// Old clusters copied into free clusters of the new size, and the FAT
+    /// and directory updates for the new cluster numbers
+    pub renumber: RenumberPlan,
+    /// Free cluster of the new size (in the staging layout) that holds the checkpoint
+    pub checkpoint_cluster: u32,
+}
+
+/// Boot sector for the staging layout: the new cluster size on the old data
+/// area start
+///
+/// Data is packed into clusters of the new size here first; the data area
+/// moves back to follow the smaller FAT only after the switch.
+fn staging_boot(boot: &BootSector, sectors_per_cluster: u8) -> BootSector {
+    let mut staging = boot.clone();
+    staging.set_sectors_per_cluster(sectors_per_cluster);
+    staging
+}
+
+/// Cluster chains reachable from the root directory
+struct Chains {
+    /// Directory chains, the root directory first
+    directories: Vec<Vec<u32>>,
+    files: Vec<Vec<u32>>,
+    /// Every cluster in one of the chains
+    claimed: HashSet<u32>,
+}
+
+/// Runs of one chain, each with its new cluster if it stays in place
+type Runs<'a> = Vec<(&'a [u32], Option<u32>)>;
+
+/// Collect the directory and file chains reachable from the root directory
+///
+/// Fails with `CorruptedFAT` if a cluster belongs to two chains.
+fn collect_chains(device: &Device, boot: &BootSector, fat: &[u32]) -> Result<Chains> {
+    let mut directories = Vec::new();
+    let mut files = Vec::new();
+    let mut claimed = HashSet::new();
+    let mut claim = |chain: &[u32]| -> Result<()> {
+        match chain.iter().find(|&&cluster| !claimed.insert(cluster)) {
+            Some(&cluster) => Err(Error::CorruptedFAT(cluster)),
+            None => Ok(()),
+        }
+    };
+
+    let mut pending = vec![boot.root_cluster()];
+    while let Some(first) = pending.pop() {
+        let chain = cluster_chain(fat, first)?;
+        claim(&chain)?;
+
+        'clusters: for &cluster in &chain {
+            let data = read_cluster(device, boot, cluster)?;
+            for entry in data.chunks_exact(ENTRY_SIZE) {
+                let first = entry_first_cluster(entry);
+                match entry_kind(entry) {
+                    EntryKind::End => break 'clusters,
+                    EntryKind::Directory if first >= 2 => pending.push(first),
+                    EntryKind::File if first >= 2 => {
+                        let file = cluster_chain(fat, first)?;
+                        claim(&file)?;
+                        files.push(file);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        directories.push(chain);
+    }
+
+    Ok(Chains {
+        directories,
+        files,
+        claimed,
+    })
+}
+
+/// Plan a change of the cluster size
+///
+/// Every chain is cut into runs of old clusters that fill one new cluster.
+/// A run of a file whose old clusters already line up with a new cluster, in
+/// order, stays where it is. All other runs, and every directory, are copied
+/// into new clusters that are entirely free, so the current filesystem stays
+/// intact until the switch. The checkpoint goes into a free cluster that the
+/// data shift after the switch does not reach.
+pub fn plan_recluster(
+    device: &Device,
+    boot: &BootSector,
+    fat: &[u32],
+    calc: &SizeCalculation,
+    sectors_per_cluster: u8,
+) -> Result<ReclusterPlan> {
+    let factor = (sectors_per_cluster / boot.sectors_per_cluster()) as u32;
+    let staging = staging_boot(boot, sectors_per_cluster);
+    let staged_max = staging.data_clusters() + 2;
+    let old_max = (boot.data_clusters() + 2).min(fat.len() as u32);
+    let old_sectors_per_cluster = boot.sectors_per_cluster() as u64;
+    let slots = |cluster: u32| {
+        let first = 2 + (cluster - 2) * factor;
+        first..first + factor
+    };
+
+    let Chains {
+        directories,
+        files,
+        claimed,
+    } = collect_chains(device, boot, fat)?;
+    if let Some(lost) = (2..old_max).find(|&c| holds_data(fat[c as usize]) && !claimed.contains(&c))
+    {
+        return Err(Error::CorruptedFAT(lost));
+    }
+
+    // New cluster already holding this run of a file, in order
+    let in_place = |run: &[u32]| {
+        let first = run[0];
+        if !(first - 2).is_multiple_of(factor) {
+            return None;
+        }
+        let cluster = 2 + (first - 2) / factor;
+        let contiguous = run.iter().zip(first..).all(|(&c, expected)| c == expected);
+        (cluster < staged_max
+            && contiguous
+            && slots(cluster).all(|c| !fat_entry::is_bad(fat[c as usize])))
+        .then_some(cluster)
+    };
+
+    // Runs of every chain, flagged if the chain is a directory
+    let mut chains: Vec<(bool, Runs)> = Vec::new();
+    for chain in &directories {
+        let runs = chain.chunks(factor as usize).map(|run| (run, None));
+        chains.push((true, runs.collect()));
+    }
+    for chain in &files {
+        let runs = chain
+            .chunks(factor as usize)
+            .map(|run| (run, in_place(run)));
+        chains.push((false, runs.collect()));
+    }
+    let kept: HashSet<u32> = chains
+        .iter()
+        .flat_map(|(_, runs)| runs.iter().filter_map(|&(_, cluster)| cluster))
+        .collect();
+
+    // New clusters whose old clusters are all free can take copies
+    let mut free: Vec<u32> = (2..staged_max)
+        .filter(|&cluster| slots(cluster).all(|c| fat_entry::is_free(fat[c as usize])))
+        .collect();
+
+    // After the switch, cluster `n` moves onto cluster `n - shift`, so the
+    // checkpoint needs a free cluster whose counterpart `shift` higher stays free
+    let shift = (boot.num_fats() as u32 * (calc.old_fat_size - calc.new_fat_size))
+        / sectors_per_cluster as u32;
+    let checkpoint_cluster = free
+        .iter()
+        .rev()
+        .copied()
+        .find(|&cluster| !kept.contains(&(cluster + shift)))
+        .ok_or(Error::NoFreeCluster)?;
+    free.retain(|&cluster| cluster != checkpoint_cluster && cluster != checkpoint_cluster + shift);
+
+    let needed = chains
+        .iter()
+        .flat_map(|(_, runs)| runs.iter())
+        .filter(|(_, cluster)| cluster.is_none())
+        .count();
+    if needed > free.len() {
+        return Err(Error::NotEnoughFreeSpace {
+            needed: needed as u32,
+            free: free.len() as u32,
+        });
+    }
+
+    // Give every run a new cluster and build the new FAT
+    let entries_per_sector = boot.bytes_per_sector() as usize / 4;
+    let mut new_fat = vec![fat_entry::FREE; calc.new_fat_size as usize * entries_per_sector];
+    new_fat[0] = fat[0];
+    new_fat[1] = fat[1];
+    for cluster in 2..staged_max {
+        if slots(cluster).any(|c| fat_entry::is_bad(fat[c as usize])) {
+            new_fat[cluster as usize] = fat_entry::BAD_CLUSTER;
+        }
+    }
+
+    let mut available = free.into_iter();
+    let mut mapping = HashMap::new();
+    let mut placed = Vec::with_capacity(chains.len());
+    for (is_directory, runs) in chains {
+        let mut new_chain = Vec::with_capacity(runs.len());
+        for (run, cluster) in runs {
+            let cluster = match cluster {
+                Some(cluster) => cluster,
+                None => available.next().ok_or(Error::NoFreeCluster)?,
+            };
+            new_chain.push((run, cluster));
+        }
+        for pair in new_chain.windows(2) {
+            new_fat[pair[0].1 as usize] = pair[1].1;
+        }
+        if let (Some(&(run, first)), Some(&(_, last))) = (new_chain.first(), new_chain.last()) {
+            new_fat[last as usize] = fat_entry::END_OF_CHAIN;
+            mapping.insert(run[0], first);
+        }
+        placed.push((is_directory, new_chain));
+    }
+    let map = |cluster: u32| mapping.get(&cluster).copied();
+
+    // Copy the runs that move; directories get their entries updated, and the
+    // rest of their last cluster cleared so no stale entries follow
+    let old_cluster_bytes = boot.bytes_per_cluster() as usize;
+    let mut moves = Vec::new();
+    let mut directory_data = HashMap::new();
+    for (is_directory, new_chain) in &placed {
+        let mut ended = false;
+        for &(run, cluster) in new_chain {
+            if !is_directory && in_place(run) == Some(cluster) {
+                continue;
+            }
+            let target = staging.cluster_to_sector(cluster);
+            for (i, &old) in run.iter().enumerate() {
+                moves.push(ClusterMove {
+                    from_cluster: old,
+                    to_cluster: cluster,
+                    from_sector: boot.cluster_to_sector(old),
+                    to_sector: target + i as u64 * old_sectors_per_cluster,
+                });
+                if *is_directory {
+                    let mut data = read_cluster(device, boot, old)?;
+                    if !ended {
+                        ended = patch_directory(&mut data, &map)?;
+                    }
+                    if i + 1 == run.len() {
+                        data.resize((factor as usize - i) * old_cluster_bytes, 0);
+                    }
+                    directory_data.insert(old, data);
+                }
+            }
+        }
+    }
+
+    let mut journal = Journal::new(boot.bytes_per_sector() as usize);
+    journal_fat(&mut journal, boot, fat, &new_fat);
+
+    // The journal is read back before the switch, in old clusters
+    let journal_needed = journal.clusters_needed(boot.bytes_per_cluster());
+    let journal_clusters: Vec<u32> = available.flat_map(slots).take(journal_needed).collect();
+    if journal_clusters.len() < journal_needed {
+        return Err(Error::NoFreeCluster);
+    }
+
+    let root = boot.root_cluster();
+    Ok(ReclusterPlan {
+        renumber: RenumberPlan {
+            moves,
+            new_root_cluster: map(root).ok_or(Error::CorruptedFAT(root))?,
+            journal,
+            journal_clusters,
+            new_free_clusters: count_free_clusters(&new_fat, calc.new_data_clusters),
+            directory_data,
+        },
+        checkpoint_cluster,
+    })
+}
+
+/// Find the checkpoint of an interrupted change to the same cluster size
+///
+/// Only needed once the boot sector has been invalidated. The checkpoint sits
+/// in a free cluster of the staging layout, usually one of the last ones, so
+/// the search runs from the end.
+fn find_recluster_checkpoint(
+    device: &Device,
+    staging: &BootSector,
+    calc: &SizeCalculation,
+) -> Result<Option<(ResizeCheckpoint, u64)>> {
+    for cluster in (2..staging.data_clusters() + 2).rev() {
+        let sector = staging.cluster_to_sector(cluster);
+        if sector >= device.total_sectors() {
+            continue;
+        }
+        let data = device.read_sector(sector)?;
+        let Ok(Some(checkpoint)) = ResizeCheckpoint::from_bytes(&data) else {
+            continue;
+        };
+        if checkpoint.operation == ResizeOperation::Recluster
+            && checkpoint.old_total_sectors == calc.old_total_sectors
+            && checkpoint.new_total_sectors == calc.new_total_sectors
+            && checkpoint.old_fat_size == calc.old_fat_size
+            && checkpoint.new_fat_size == calc.new_fat_size
+        {
+            return Ok(Some((checkpoint, sector)));
+        }
+    }
+    Ok(None)
+}
+
+/// Change the cluster size of a FAT32 filesystem, with crash-safe checkpoints
+///
+/// The data is packed into clusters of the new size: runs of old clusters
+/// that already line up stay in place, everything else is copied into free
+/// space. The FAT, the directory entries and the root cluster then switch to
+/// the new cluster numbers through a journal, and finally the data area moves
+/// back to follow the smaller FAT. The filesystem keeps its size, so it needs
+/// enough free space for the clusters that move; growing it first helps.
+pub fn recluster_fat32(options: ResizeOptions) -> Result<ResizeResult> {
+    let mut operations = Vec::new();
+
+    let cluster_bytes = options
+        .get_cluster_size()
+        .ok_or_else(|| Error::Calculation("Re-clustering requires a cluster size".to_string()))?;
+    if options.get_memory_limit().is_some() {
+        return Err(Error::MemoryLimitUnsupported("Re-clustering"));
+    }
+
+    check_not_mounted_at(options.device_path(), options.get_location())?;
+    operations.push("Verified device is not mounted".to_string());
+
+    let mut device = options.open_device()?;
+    operations.push(format!(
+        "Opened device: {}",
+        options.device_path().display()
+    ));
+
+    // Allow an invalidated boot sector from an interrupted re-clustering
+    let mut boot = read_boot_sector_for_recovery(&mut device)?;
+    operations.push(format!(
+        "Read boot sector ({}-byte sectors)",
+        boot.bytes_per_sector()
+    ));
+
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    if !cluster_bytes.is_multiple_of(bytes_per_sector) || cluster_bytes / bytes_per_sector > 128 {
+        return Err(Error::InvalidClusterSize(cluster_bytes));
+    }
+    let sectors_per_cluster = (cluster_bytes / bytes_per_sector) as u8;
+
+    let mut calculation = calculate_recluster_size(&boot, sectors_per_cluster)?;
+    let staging = staging_boot(&boot, sectors_per_cluster);
+    operations.push(format!(
+        "Calculated re-clustering: {} -> {} bytes per cluster, {} -> {} clusters",
+        boot.bytes_per_cluster(),
+        cluster_bytes,
+        boot.data_clusters(),
+        calculation.new_data_clusters
+    ));
+
+    // While the boot sector is valid nothing the old filesystem uses has been
+    // touched, so an interrupted re-clustering simply starts over. Once it is
+    // invalidated the FAT can no longer be trusted and the checkpoint decides.
+    let (old_fat, incomplete) = if boot.is_signature_valid() {
+        (Some(read_fat_table(&device, &boot, 0)?), None)
+    } else {
+        let found = find_recluster_checkpoint(&device, &staging, &calculation)?
+            .ok_or(Error::InvalidatedFilesystem)?;
+        (None, Some(found))
+    };
+    if let Some((ref checkpoint, _)) = incomplete {
+        eprintln!(
+            "Resuming interrupted re-clustering from phase {:?}...",
+            checkpoint.phase
+        );
+        operations.push(format!(
+            "Detected incomplete re-clustering at phase {:?}",
+            checkpoint.phase
+        ));
+    }
+
+    if options.is_verbose() {
+        eprintln!("Current filesystem:");
+        eprintln!("  Bytes per cluster: {}", boot.bytes_per_cluster());
+        eprintln!("  FAT size: {} sectors", calculation.old_fat_size);
+        eprintln!("  Data clusters: {}", boot.data_clusters());
+        eprintln!();
+        eprintln!("After re-clustering:");
+        eprintln!("  Bytes per cluster: {}", cluster_bytes);
+        eprintln!("  FAT size: {} sectors", calculation.new_fat_size);
+        eprintln!("  Data clusters: {}", calculation.new_data_clusters);
+    }
+
+    let mut clusters_relocated = 0;
+    let plan = match old_fat {
+        Some(ref fat) => {
+            let plan = plan_recluster(&device, &boot, fat, &calculation, sectors_per_cluster)?;
+            clusters_relocated = plan.renumber.moves.len();
+            calculation.new_free_clusters = plan.renumber.new_free_clusters;
+            operations.push(format!(
+                "Planned move of {} clusters into larger clusters ({} journal entries)",
+                plan.renumber.moves.len(),
+                plan.renumber.journal.len()
+            ));
+
+            if options.is_dry_run() {
+                operations.push("Dry run: no changes made".to_string());
+                return Ok(recluster_result(
+                    &device,
+                    &boot,
+                    calculation,
+                    clusters_relocated,
+                    operations,
+                ));
+            }
+            Some(plan)
+        }
+        None => None,
+    };
+
+    // === PHASES 0 and 1: Pack clusters, then switch to the new cluster numbers ===
+    let (start, checkpoint_sector) = match (&plan, incomplete) {
+        (Some(plan), _) => (
+            RenumberStart::New {
+                plan: &plan.renumber,
+                operation: ResizeOperation::Recluster,
+            },
+            staging.cluster_to_sector(plan.checkpoint_cluster),
+        ),
+        (None, Some((checkpoint, sector))) => (RenumberStart::Resumed(checkpoint), sector),
+        (None, None) => return Err(Error::InvalidatedFilesystem),
+    };
+    let checkpoint = execute_renumbering(
+        &device,
+        &mut boot,
+        &calculation,
+        checkpoint_sector,
+        start,
+        options.is_verbose(),
+        &mut operations,
+    )?;
+
+    // === PHASE 2: Move the data area back and restore the boot sector ===
+    finish_shift_back(
+        &device,
+        &staging,
+        &mut calculation,
+        &checkpoint,
+        checkpoint_sector,
+        &options,
+        &mut operations,
+    )?;
+
+    Ok(recluster_result(
+        &device,
+        &boot,
+        calculation,
+        clusters_relocated,
+        operations,
+    ))
+}
+
+fn recluster_result(
+    device: &Device,
+    boot: &BootSector,
+    calculation: SizeCalculation,
+    clusters_relocated: usize,
+    operations: Vec<String>,
+) -> ResizeResult {
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    ResizeResult {
+        old_size_bytes: calculation.old_total_sectors as u64 * bytes_per_sector,
+        new_size_bytes: calculation.new_total_sectors as u64 * bytes_per_sector,
+        fat_grew: false,
+        clusters_relocated,
+        strategy: None,
+        size_limit: None,
+        converted_from: None,
+        unused_bytes: device
+            .total_sectors()
+            .saturating_sub(calculation.new_total_sectors as u64)
+            * bytes_per_sector,
+        hidden_sectors_change: None,
+        partition_type_change: None,
+        sparse: device.sparse_stats(),
+        io: device.io_stats(),
+        discard: None,
+        calculation,
+        operations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::read_boot_sector;
+    use crate::test_image::{
+        assert_consistent, assert_resumes_after_crashes, pattern, read_file, ImageSpec, TestImage,
+    };
+
+    type Files = Vec<(Vec<&'static [u8; 11]>, Vec<u8>)>;
+
+    /// 512-byte clusters with aligned, unaligned and fragmented files
+    fn reclustering_image() -> (TestImage, Files) {
+        let mut image = TestImage::create(ImageSpec {
+            total_sectors: 140_000,
+            ..Default::default()
+        });
+        let mut files: Files = Vec::new();
+
+        // Starts at an odd cluster, so none of its runs line up
+        let unaligned = pattern(1, 20 * 512);
+        image.add_file(b"UNALIGNDBIN", &unaligned, 1);
+        files.push((vec![b"UNALIGNDBIN"], unaligned));
+
+        // Lines up with 1 KiB clusters and can stay where it is
+        image.skip_to(100);
+        let aligned = pattern(2, 21 * 512 - 3);
+        image.add_file(b"ALIGNED BIN", &aligned, 1);
+        files.push((vec![b"ALIGNED BIN"], aligned));
+
+        image.skip_to(5_001);
+        let sub = image.add_dir(2, b"SUBDIR     ");
+        let nested = image.add_dir(sub, b"NESTED     ");
+        let fragmented = pattern(3, 30 * 512 + 100);
+        image.add_file_in(sub, b"FRAG    BIN", &fragmented, 3);
+        files.push((vec![b"SUBDIR     ", b"FRAG    BIN"], fragmented));
+        let deep = pattern(4, 5 * 512);
+        image.add_file_in(nested, b"DEEP    BIN", &deep, 1);
+        files.push((vec![b"SUBDIR     ", b"NESTED     ", b"DEEP    BIN"], deep));
+
+        // Near the end of the data area, past where it ends after the switch
+        image.skip_to(137_001);
+        let tail = pattern(5, 40 * 512);
+        image.add_file(b"TAIL    BIN", &tail, 1);
+        files.push((vec![b"TAIL    BIN"], tail));
+
+        image.set_fat(9_000, fat_entry::BAD_CLUSTER);
+
+        (image, files)
+    }
+
+    fn assert_files_intact(image: &TestImage, files: &Files) {
+        for (path, contents) in files {
+            assert_eq!(
+                read_file(image.path(), path).as_ref(),
+                Some(contents),
+                "{:?} corrupted",
+                path.last().map(|name| String::from_utf8_lossy(&name[..]))
+            );
+        }
+    }
+
+    fn options(image: &TestImage) -> ResizeOptions {
+        ResizeOptions::new(image.path()).cluster_size(Some(1024))
+    }
+
+    #[test]
+    fn test_recluster_keeps_files() {
+        let (image, files) = reclustering_image();
+
+        let result = recluster_fat32(options(&image)).unwrap();
+        assert!(result.calculation.new_fat_size < result.calculation.old_fat_size);
+        assert_eq!(result.old_size_bytes, result.new_size_bytes);
+        // Everything except the aligned files moves
+        let clusters = 1 + 20 + 2 + 31 + 40;
+        assert_eq!(result.clusters_relocated, clusters);
+
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert_eq!(boot.bytes_per_cluster(), 1024);
+        assert_eq!(boot.data_clusters(), result.calculation.new_data_clusters);
+        let fat = read_fat_table(&device, &boot, 0).unwrap();
+        assert!(fat_entry::is_bad(fat[2 + (9_000 - 2) / 2]));
+
+        assert_files_intact(&image, &files);
+        assert_consistent(image.path());
+    }
+
+    #[test]
+    fn test_recluster_dry_run() {
+        let (image, _) = reclustering_image();
+        let before = std::fs::read(image.path()).unwrap();
+
+        let result = recluster_fat32(options(&image).dry_run(true)).unwrap();
+        assert!(result.clusters_relocated > 0);
+        assert_eq!(std::fs::read(image.path()).unwrap(), before);
+
+        // Re-clustering reads the whole FAT, so a memory limit is refused
+        assert!(matches!(
+            recluster_fat32(options(&image).memory_limit(Some(1 << 20))),
+            Err(Error::MemoryLimitUnsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_recluster_rejects_smaller_clusters() {
+        let (image, _) = reclustering_image();
+
+        for bytes in [512, 768, 128 * 1024] {
+            let result =
+                recluster_fat32(ResizeOptions::new(image.path()).cluster_size(Some(bytes)));
+            assert!(
+                matches!(result, Err(Error::InvalidClusterSize(b)) if b == bytes),
+                "{} bytes accepted",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn test_interrupted_recluster_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            reclustering_image,
+            |(image, _)| options(image),
+            recluster_fat32,
+            |(image, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device).is_ok_and(|b| b.bytes_per_cluster() == 1024)
+            },
+            |(image, files)| {
+                assert_files_intact(image, files);
+                assert_consistent(image.path());
+            },
+        );
+        assert!(writes > 100);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::device::Device;
-use crate::error::Result;
-use crate::fat32::{fat_entry, BootSector};
+use crate::error::{Error, Result};
+use crate::fat32::{fat_entry, BootSector, FatReader};
+use std::ops::Range;
 
 /// A planned cluster relocation representing physical sector movement
 ///
@@ -102,7 +103,7 @@ pub fn plan_relocation(
     }
 
     // Sort by cluster number descending (for safe copying from end to start)
-    moves.sort_by(|a, b| b.from_cluster.cmp(&a.from_cluster));
+    moves.sort_by_key(|mv| std::cmp::Reverse(mv.from_cluster));
 
     let total_bytes = moves.len() as u64 * boot.bytes_per_cluster() as u64;
 
@@ -114,6 +115,309 @@ pub fn plan_relocation(
     })
 }
 
+/// Memory a planned move takes while it is copied: the move, and the
+/// shift's reference to it and share of a run
+const MOVE_MEMORY_BYTES: u64 = 2 * std::mem::size_of::<ClusterMove>() as u64;
+
+/// A forward data shift planned from the FAT one segment of clusters at a
+/// time
+///
+/// Holds only the extent of the shift, as [`plan_relocation`] would plan it.
+/// The moves of each segment are planned from a [`FatReader`] again as they
+/// are copied, so neither the FAT nor the list of moves has to fit into
+/// memory at once.
+#[derive(Debug, Clone)]
+pub struct WindowedShift {
+    first_affected: u32,
+    /// One past the last cluster that may move
+    end_cluster: u32,
+    /// Clusters planned at a time
+    segment_clusters: u32,
+    sectors_per_cluster: u64,
+    bytes_per_cluster: u64,
+    /// Number of clusters in use that move
+    pub cluster_count: usize,
+    /// Total bytes to be relocated
+    pub total_bytes: u64,
+    /// Old first data sector
+    pub old_first_data_sector: u64,
+    /// New first data sector
+    pub new_first_data_sector: u64,
+}
+
+impl WindowedShift {
+    /// Plan shifting every cluster in use from `first_affected` on forward by
+    /// the clusters up to `last_affected`, keeping about `memory_limit` bytes
+    /// of moves at a time (`None` for no limit)
+    pub fn plan(
+        boot: &BootSector,
+        fat: &mut FatReader,
+        first_affected: u32,
+        last_affected: u32,
+        memory_limit: Option<u64>,
+    ) -> Result<Self> {
+        let sectors_per_cluster = boot.sectors_per_cluster() as u64;
+        let old_first_data_sector = boot.first_data_sector();
+        let shift_sectors = (last_affected - first_affected + 1) as u64 * sectors_per_cluster;
+        let end_cluster = boot.data_clusters() + 2;
+        let cluster_count = fat.count_used(first_affected..end_cluster)? as usize;
+        Ok(Self {
+            first_affected,
+            end_cluster,
+            segment_clusters: memory_limit.map_or(u32::MAX, |limit| {
+                (limit / MOVE_MEMORY_BYTES).clamp(1, u32::MAX as u64) as u32
+            }),
+            sectors_per_cluster,
+            bytes_per_cluster: boot.bytes_per_cluster() as u64,
+            cluster_count,
+            total_bytes: cluster_count as u64 * boot.bytes_per_cluster() as u64,
+            old_first_data_sector,
+            new_first_data_sector: old_first_data_sector + shift_sectors,
+        })
+    }
+
+    /// A shift that moves nothing
+    pub fn none(boot: &BootSector) -> Self {
+        Self {
+            first_affected: 0,
+            end_cluster: 0,
+            segment_clusters: u32::MAX,
+            sectors_per_cluster: boot.sectors_per_cluster() as u64,
+            bytes_per_cluster: boot.bytes_per_cluster() as u64,
+            cluster_count: 0,
+            total_bytes: 0,
+            old_first_data_sector: boot.first_data_sector(),
+            new_first_data_sector: boot.first_data_sector(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cluster_count == 0
+    }
+
+    /// Clusters moved onto the old position of a cluster
+    fn distance(&self) -> u32 {
+        ((self.new_first_data_sector - self.old_first_data_sector) / self.sectors_per_cluster)
+            as u32
+    }
+
+    /// Old first sector of a cluster
+    fn old_sector(&self, cluster: u32) -> u64 {
+        self.old_first_data_sector + (cluster - 2) as u64 * self.sectors_per_cluster
+    }
+
+    /// Segments of clusters in copy order (highest first)
+    fn segments(&self) -> impl Iterator<Item = Range<u32>> + '_ {
+        let mut end = self.end_cluster;
+        std::iter::from_fn(move || {
+            (end > self.first_affected).then(|| {
+                let start = end
+                    .saturating_sub(self.segment_clusters)
+                    .max(self.first_affected);
+                let segment = start..end;
+                end = start;
+                segment
+            })
+        })
+    }
+
+    /// Plan the moves of one segment, highest cluster first
+    fn plan_segment(&self, fat: &mut FatReader, clusters: Range<u32>) -> Result<RelocationPlan> {
+        let shift_sectors = self.new_first_data_sector - self.old_first_data_sector;
+        let mut moves = Vec::new();
+        for cluster in clusters.rev() {
+            if !fat_entry::is_free(fat.entry(cluster)?) {
+                let from_sector = self.old_sector(cluster);
+                moves.push(ClusterMove {
+                    from_cluster: cluster,
+                    to_cluster: cluster,
+                    from_sector,
+                    to_sector: from_sector + shift_sectors,
+                });
+            }
+        }
+        Ok(RelocationPlan {
+            total_bytes: moves.len() as u64 * self.bytes_per_cluster,
+            moves,
+            old_first_data_sector: self.old_first_data_sector,
+            new_first_data_sector: self.new_first_data_sector,
+        })
+    }
+
+    /// Copy the clusters segment by segment, as
+    /// [`execute_relocation_with_progress`] does for a whole plan
+    ///
+    /// The watermark is recorded after every segment but the last, so the
+    /// next one starts from a durable watermark. Returns the number of
+    /// clusters copied by this call.
+    #[allow(clippy::too_many_arguments)]
+    pub fn execute(
+        &self,
+        device: &Device,
+        boot: &BootSector,
+        fat: &mut FatReader,
+        watermark: Option<u32>,
+        persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+        read_ahead: ReadAhead,
+        verbose: bool,
+    ) -> Result<usize> {
+        let mut watermark = watermark;
+        let mut copied = 0;
+        for clusters in self.segments() {
+            // Segments at or above the watermark were copied before
+            if watermark.is_some_and(|watermark| watermark <= clusters.start) {
+                continue;
+            }
+            let last_segment = clusters.start == self.first_affected;
+            let plan = self.plan_segment(fat, clusters)?;
+            let segment_copied = execute_relocation_with_progress(
+                device,
+                boot,
+                &plan,
+                watermark,
+                persist_watermark,
+                read_ahead,
+                verbose,
+            )?;
+            copied += segment_copied;
+            // The shift synced the data it copied
+            if let (false, true, Some(last)) = (last_segment, segment_copied > 0, plan.moves.last())
+            {
+                persist_watermark(last.from_cluster)?;
+                watermark = Some(last.from_cluster);
+            }
+        }
+        Ok(copied)
+    }
+
+    /// Punch holes where shifted clusters used to be, so an image file gets
+    /// the space of the shift back
+    ///
+    /// Only old positions that no cluster was copied onto are punched: the
+    /// old position of a cluster is overwritten exactly when the cluster
+    /// `distance()` below it moved too. Punching starts at the new first
+    /// data sector (below it, the FAT is written next) and stops at
+    /// `keep_from`, where staged data and the checkpoint are kept, and at the
+    /// first hole the device refuses. Must only be called once the shift is
+    /// complete and recorded, since the punched ranges are the sources a
+    /// resumed shift would copy from.
+    ///
+    /// Returns the number of bytes punched; nothing is punched on block
+    /// devices.
+    pub fn punch_vacated(
+        &self,
+        device: &Device,
+        fat: &mut FatReader,
+        keep_from: u64,
+    ) -> Result<u64> {
+        let spc = self.sectors_per_cluster;
+        let sector_size = device.sector_size() as u64;
+        let distance = self.distance();
+
+        let punch = |run: Range<u64>, punched: &mut u64| -> Result<bool> {
+            let len = (run.end - run.start) * sector_size;
+            let done = device.punch_hole(run.start * sector_size, len)?;
+            if done {
+                *punched += len;
+            }
+            Ok(done)
+        };
+
+        // Vacated positions are collected into runs from the top down
+        let mut run: Option<Range<u64>> = None;
+        let mut punched = 0;
+        for cluster in (self.first_affected..self.end_cluster).rev() {
+            let start = self.old_sector(cluster);
+            if start < self.new_first_data_sector {
+                break;
+            }
+            let moved = |fat: &mut FatReader, cluster: u32| -> Result<bool> {
+                Ok(cluster >= self.first_affected && !fat_entry::is_free(fat.entry(cluster)?))
+            };
+            let vacated = start + spc <= keep_from
+                && moved(fat, cluster)?
+                && !(cluster >= distance && moved(fat, cluster - distance)?);
+            match run.take() {
+                // Punch adjacent positions together, so whole filesystem
+                // blocks are freed
+                Some(adjacent) if vacated && adjacent.start == start + spc => {
+                    run = Some(start..adjacent.end);
+                }
+                previous => {
+                    if let Some(previous) = previous {
+                        if !punch(previous, &mut punched)? {
+                            break;
+                        }
+                    }
+                    run = vacated.then_some(start..start + spc);
+                }
+            }
+        }
+        if let Some(last) = run {
+            punch(last, &mut punched)?;
+        }
+        if punched > 0 {
+            device.sync()?;
+        }
+        Ok(punched)
+    }
+}
+
+/// Bytes of cluster data copied between routine watermark updates
+const WATERMARK_INTERVAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Largest run of adjacent clusters moved in one go
+pub(crate) const MAX_RUN_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Runs read ahead of the writer by default (see
+/// [`execute_relocation_with_progress`])
+pub const DEFAULT_QUEUE_DEPTH: usize = 4;
+
+/// How a data shift buffers the runs of clusters it moves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadAhead {
+    /// Runs read ahead of the writes (0: every run is read, then written)
+    pub queue_depth: usize,
+    /// Largest run of adjacent clusters moved in one go (at least one
+    /// cluster is)
+    pub max_run_bytes: u64,
+}
+
+impl ReadAhead {
+    /// Read `queue_depth` runs of up to 8 MiB ahead
+    pub fn new(queue_depth: usize) -> Self {
+        Self {
+            queue_depth,
+            max_run_bytes: MAX_RUN_BYTES,
+        }
+    }
+
+    /// Shorten the runs so that all of them in memory at once fit into
+    /// about `memory_limit` bytes (`None` for no limit)
+    ///
+    /// Besides the queued runs, the reader fills one and the writer empties
+    /// another; without a queue, one run is read and written at a time.
+    pub fn within(self, memory_limit: Option<u64>) -> Self {
+        let Some(limit) = memory_limit else {
+            return self;
+        };
+        let runs = match self.queue_depth {
+            0 => 1,
+            depth => depth as u64 + 2,
+        };
+        Self {
+            max_run_bytes: self.max_run_bytes.min(limit / runs),
+            ..self
+        }
+    }
+}
+
+impl Default for ReadAhead {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUEUE_DEPTH)
+    }
+}
+
 /// Execute a relocation plan by shifting all data forward
 ///
 /// This copies cluster data from old positions to new positions.
@@ -131,43 +435,419 @@ pub fn execute_relocation(
     _new_data_clusters: u32,
     verbose: bool,
 ) -> Result<Vec<(u32, u32)>> {
-    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    execute_relocation_with_progress(
+        device,
+        boot,
+        plan,
+        None,
+        &mut |_| Ok(()),
+        ReadAhead::default(),
+        verbose,
+    )?;
+
+    // No cluster number changes, so return empty vector
+    // The root cluster stays at cluster 2 (just at a different physical location)
+    Ok(Vec::new())
+}
+
+/// Execute a relocation plan, keeping a resumable progress watermark
+///
+/// The plan's moves are copied in order: from the highest cluster down when
+/// the data area moves forward, from the lowest cluster up when it moves back.
+/// Once the shift is under way, the old position of a copied cluster may be
+/// overwritten by a later one, so its source can no longer be trusted.
+///
+/// The watermark is the last cluster copied such that every cluster before it
+/// in the plan is copied too. `watermark` resumes an interrupted shift: clusters
+/// up to and including it are skipped. `persist_watermark` is called with a new
+/// watermark before any write that would overwrite the source of a cluster
+/// copied since the last recorded watermark, and every
+/// `WATERMARK_INTERVAL_BYTES` of copied data. The copied data is synced before
+/// each call, and the callback must make the watermark durable before it
+/// returns.
+///
+/// Devices that cannot copy in the kernel (block devices, qcow2 images) are
+/// read by a separate thread up to `read_ahead.queue_depth` runs of adjacent
+/// clusters ahead of the writes, so reading and writing overlap; with a depth
+/// of 0, every run is read and then written in turn.
+///
+/// Returns the number of clusters copied by this call.
+pub fn execute_relocation_with_progress(
+    device: &Device,
+    boot: &BootSector,
+    plan: &RelocationPlan,
+    watermark: Option<u32>,
+    persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    read_ahead: ReadAhead,
+    verbose: bool,
+) -> Result<usize> {
+    shift_clusters(
+        device,
+        boot.sectors_per_cluster() as u32,
+        plan,
+        watermark,
+        persist_watermark,
+        read_ahead,
+        verbose,
+    )
+}
+
+/// Execute a relocation plan of clusters with `sectors_per_cluster` sectors
+///
+/// Works like [`execute_relocation_with_progress`] without a FAT boot sector,
+/// so exFAT clusters, which may span more than 255 sectors, can shift too.
+pub(crate) fn shift_clusters(
+    device: &Device,
+    sectors_per_cluster: u32,
+    plan: &RelocationPlan,
+    watermark: Option<u32>,
+    persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    read_ahead: ReadAhead,
+    verbose: bool,
+) -> Result<usize> {
+    device.timed(|| {
+        shift_runs(
+            device,
+            sectors_per_cluster,
+            plan,
+            watermark,
+            persist_watermark,
+            read_ahead,
+            verbose,
+        )
+    })
+}
+
+fn shift_runs(
+    device: &Device,
+    sectors_per_cluster: u32,
+    plan: &RelocationPlan,
+    watermark: Option<u32>,
+    persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    read_ahead: ReadAhead,
+    verbose: bool,
+) -> Result<usize> {
+    let spc = sectors_per_cluster as u64;
+    let cluster_bytes = spc * device.sector_size() as u64;
+    let forward = plan.new_first_data_sector >= plan.old_first_data_sector;
+    let mut progress = ShiftProgress::new(plan, spc, watermark);
+
+    let pending: Vec<&ClusterMove> = plan
+        .moves
+        .iter()
+        .filter(|mv| progress.rank(mv.from_cluster) < progress.recorded)
+        .collect();
 
     if verbose {
         eprintln!(
-            "Shifting data forward: {} clusters need movement",
+            "Shifting data {}: {} clusters need movement",
+            if forward { "forward" } else { "back" },
             plan.moves.len()
         );
         eprintln!("  Old first data sector: {}", plan.old_first_data_sector);
         eprintln!("  New first data sector: {}", plan.new_first_data_sector);
-    }
-
-    // Copy data from highest cluster to lowest (already sorted in plan_relocation)
-    for (i, mv) in plan.moves.iter().enumerate() {
-        if verbose && (i < 10 || i % 100 == 0 || i == plan.moves.len() - 1) {
+        if let Some(watermark) = watermark {
             eprintln!(
-                "Moving cluster {} from sector {} to sector {} ({}/{})",
-                mv.from_cluster,
-                mv.from_sector,
-                mv.to_sector,
-                i + 1,
-                plan.moves.len()
+                "  Resuming after cluster {} ({} clusters already copied)",
+                watermark,
+                plan.moves.len() - pending.len()
             );
         }
+    }
+
+    // Copy data in plan order (highest first when shifting forward), in runs
+    // of adjacent clusters: image files move them inside the kernel, other
+    // devices in one read and one write per run
+    let shift = plan
+        .new_first_data_sector
+        .abs_diff(plan.old_first_data_sector);
+    let max_run = (shift / spc)
+        .min(read_ahead.max_run_bytes / cluster_bytes)
+        .max(1) as usize;
+    let queue_depth = read_ahead.queue_depth;
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        let run = run_length(&pending[i..], spc, max_run);
+        runs.push(&pending[i..i + run]);
+        i += run;
+    }
+
+    // Runs go one at a time until it is known whether the device copies in
+    // the kernel; if it cannot, the rest are read ahead by a reader thread
+    let mut offload = None;
+    let mut done = 0;
+    let mut next = 0;
+    while next < runs.len() && (queue_depth == 0 || offload != Some(false)) {
+        let moves = runs[next];
+        log_run(verbose, moves, done, pending.len());
+        progress.before_run(device, moves, persist_watermark)?;
+        for part in run_parts(device, moves, cluster_bytes)? {
+            write_part(device, part, &mut offload)?;
+        }
+        progress.after_run(device, moves, cluster_bytes, persist_watermark)?;
+        done += moves.len();
+        next += 1;
+    }
 
-        // Read from old position
-        let data = device.read_sectors(mv.from_sector, sectors_per_cluster)?;
+    if next < runs.len() {
+        if verbose {
+            eprintln!("  Reading up to {} runs ahead of writing", queue_depth);
+        }
+        // The reader follows plan order, so every source a run's destination
+        // overlaps (one of an earlier run, in a shift) is read before the
+        // writer gets to the run. Sources of later runs lie beyond the
+        // destinations written so far, so reading ahead sees original data.
+        let runs = &runs[next..];
+        std::thread::scope(|scope| -> Result<()> {
+            let (sender, receiver) = std::sync::mpsc::sync_channel(queue_depth);
+            scope.spawn(move || {
+                for moves in runs {
+                    let parts = read_run(device, moves, cluster_bytes);
+                    let failed = parts.is_err();
+                    // The writer hung up after an error of its own
+                    if sender.send(parts).is_err() || failed {
+                        break;
+                    }
+                }
+            });
 
-        // Write to new position
-        device.write_sectors(mv.to_sector, &data)?;
+            for moves in runs {
+                let parts = receiver
+                    .recv()
+                    .map_err(|_| Error::Io(std::io::Error::other("data shift reader stopped")))??;
+                log_run(verbose, moves, done, pending.len());
+                progress.before_run(device, moves, persist_watermark)?;
+                for part in parts {
+                    write_part(device, part, &mut offload)?;
+                }
+                progress.after_run(device, moves, cluster_bytes, persist_watermark)?;
+                done += moves.len();
+            }
+            Ok(())
+        })?;
     }
 
     // Sync after data movement
     device.sync()?;
 
-    // No cluster number changes, so return empty vector
-    // The root cluster stays at cluster 2 (just at a different physical location)
-    Ok(Vec::new())
+    if verbose && device.offloaded_bytes() > 0 {
+        eprintln!(
+            "  {:.2} MB moved by the host filesystem (copy_file_range or reflink)",
+            device.offloaded_bytes() as f64 / (1024.0 * 1024.0)
+        );
+    }
+
+    Ok(pending.len())
+}
+
+/// Watermark bookkeeping of a data shift
+struct ShiftProgress<'a> {
+    plan: &'a RelocationPlan,
+    spc: u64,
+    forward: bool,
+    /// Clusters ranked at or above `recorded` are durably known to be copied
+    recorded: u32,
+    /// Rank of the last cluster copied (u32::MAX until the first copy)
+    copied: u32,
+    bytes_since_update: u64,
+}
+
+impl<'a> ShiftProgress<'a> {
+    fn new(plan: &'a RelocationPlan, spc: u64, watermark: Option<u32>) -> Self {
+        let mut progress = Self {
+            plan,
+            spc,
+            forward: plan.new_first_data_sector >= plan.old_first_data_sector,
+            recorded: u32::MAX,
+            copied: u32::MAX,
+            bytes_since_update: 0,
+        };
+        progress.recorded = watermark.map_or(u32::MAX, |w| progress.rank(w));
+        progress.copied = progress.recorded;
+        progress
+    }
+
+    /// Position of a cluster in copy order (ascending); its own inverse
+    fn rank(&self, cluster: u32) -> u32 {
+        if self.forward {
+            cluster
+        } else {
+            u32::MAX - cluster
+        }
+    }
+
+    /// Sync and record the last cluster copied
+    fn record(
+        &mut self,
+        device: &Device,
+        persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    ) -> Result<()> {
+        device.sync()?;
+        persist_watermark(self.rank(self.copied))?;
+        self.recorded = self.copied;
+        self.bytes_since_update = 0;
+        Ok(())
+    }
+
+    /// Record progress before a run destroys a source we may still need
+    fn before_run(
+        &mut self,
+        device: &Device,
+        moves: &[&ClusterMove],
+        persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    ) -> Result<()> {
+        let (spc, old_first) = (self.spc, self.plan.old_first_data_sector);
+
+        // Lowest sector the run is written to, and the sector after it
+        let to_start = moves[0].to_sector.min(moves[moves.len() - 1].to_sector);
+        let to_end = moves[0].to_sector.max(moves[moves.len() - 1].to_sector) + spc;
+
+        // Clusters whose old positions this run overlaps
+        if self.copied < self.recorded && to_end > old_first {
+            let first_overlapped = 2 + (to_start.saturating_sub(old_first) / spc) as u32;
+            let last_overlapped = 2 + ((to_end - 1 - old_first) / spc) as u32;
+            let (low, high) = if self.forward {
+                (first_overlapped, last_overlapped)
+            } else {
+                (self.rank(last_overlapped), self.rank(first_overlapped))
+            };
+            if high >= self.copied && low < self.recorded {
+                self.record(device, persist_watermark)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count a copied run, recording progress every `WATERMARK_INTERVAL_BYTES`
+    fn after_run(
+        &mut self,
+        device: &Device,
+        moves: &[&ClusterMove],
+        cluster_bytes: u64,
+        persist_watermark: &mut dyn FnMut(u32) -> Result<()>,
+    ) -> Result<()> {
+        self.copied = self.rank(moves[moves.len() - 1].from_cluster);
+        self.bytes_since_update += moves.len() as u64 * cluster_bytes;
+        if self.bytes_since_update >= WATERMARK_INTERVAL_BYTES {
+            self.record(device, persist_watermark)?;
+        }
+        Ok(())
+    }
+}
+
+/// Part of a run whose old position is all holes or all data
+struct RunPart {
+    hole: bool,
+    from_sector: u64,
+    to_sector: u64,
+    len: u64,
+    /// Data read ahead by the pipeline's reader
+    data: Option<Vec<u8>>,
+}
+
+/// Split a run into parts that are holes or data at their old position
+///
+/// The run's sources and destinations do not overlap, so its parts can be
+/// written in any order.
+fn run_parts(device: &Device, moves: &[&ClusterMove], cluster_bytes: u64) -> Result<Vec<RunPart>> {
+    let sector_size = device.sector_size() as u64;
+    let holes = moves
+        .iter()
+        .map(|mv| device.is_hole(mv.from_sector * sector_size, cluster_bytes))
+        .collect::<Result<Vec<bool>>>()?;
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while start < moves.len() {
+        let hole = holes[start];
+        let end = start + holes[start..].iter().take_while(|&&h| h == hole).count();
+        let (first, last) = (moves[start], moves[end - 1]);
+        parts.push(RunPart {
+            hole,
+            from_sector: first.from_sector.min(last.from_sector),
+            to_sector: first.to_sector.min(last.to_sector),
+            len: (end - start) as u64 * cluster_bytes,
+            data: None,
+        });
+        start = end;
+    }
+    Ok(parts)
+}
+
+/// Split a run into parts and read the data parts (pipeline reader)
+fn read_run(device: &Device, moves: &[&ClusterMove], cluster_bytes: u64) -> Result<Vec<RunPart>> {
+    let sector_size = device.sector_size() as u64;
+    let mut parts = run_parts(device, moves, cluster_bytes)?;
+    for part in parts.iter_mut().filter(|part| !part.hole) {
+        part.data = Some(device.read_sectors(part.from_sector, (part.len / sector_size) as u32)?);
+    }
+    Ok(parts)
+}
+
+/// Write a part of a run at its new position
+///
+/// Holes stay holes. Data not read ahead is copied in the kernel unless
+/// `offload` says the device cannot, and read here otherwise; `offload` is
+/// set by the first attempt.
+fn write_part(device: &Device, part: RunPart, offload: &mut Option<bool>) -> Result<()> {
+    let sector_size = device.sector_size() as u64;
+    if part.hole {
+        // Nothing to copy out of a hole; the new position becomes one too
+        return device.zero_range(part.to_sector * sector_size, part.len);
+    }
+    let data = match part.data {
+        Some(data) => data,
+        None => {
+            if *offload != Some(false) {
+                let copied = device.copy_range(
+                    part.from_sector * sector_size,
+                    part.to_sector * sector_size,
+                    part.len,
+                )?;
+                *offload = Some(copied);
+                if copied {
+                    return Ok(());
+                }
+            }
+            // One large transfer instead of one per cluster
+            device.read_sectors(part.from_sector, (part.len / sector_size) as u32)?
+        }
+    };
+    device.write_sectors(part.to_sector, &data)
+}
+
+/// Report a run in verbose mode (the first few, then every 100 clusters)
+fn log_run(verbose: bool, moves: &[&ClusterMove], done: usize, total: usize) {
+    let run = moves.len();
+    if verbose && (done < 10 || done / 100 != (done + run - 1) / 100 || done + run == total) {
+        eprintln!(
+            "Moving clusters {}-{} from sector {} to sector {} ({}/{})",
+            moves[0].from_cluster,
+            moves[run - 1].from_cluster,
+            moves[0].from_sector,
+            moves[0].to_sector,
+            done + run,
+            total
+        );
+    }
+}
+
+/// Number of moves at the start of `moves` that form a run: adjacent
+/// clusters, in copy order, of at most `max_run` clusters
+///
+/// `max_run` must not exceed the shift, so that a run's destination never
+/// overlaps its own source.
+fn run_length(moves: &[&ClusterMove], spc: u64, max_run: usize) -> usize {
+    let adjacent = |pair: &[&ClusterMove]| {
+        pair[0].from_sector.abs_diff(pair[1].from_sector) == spc
+            && pair[1].from_sector.wrapping_sub(pair[0].from_sector)
+                == pair[1].to_sector.wrapping_sub(pair[0].to_sector)
+    };
+    1 + moves
+        .windows(2)
+        .take(max_run - 1)
+        .take_while(|pair| adjacent(pair))
+        .count()
 }
 
 /// Verify that all clusters in the affected range are free after relocation
@@ -187,6 +867,38 @@ pub fn verify_relocation(_fat: &[u32], _first_affected: u32, _last_affected: u32
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fat32::{read_boot_sector, read_fat_table};
+    use crate::resize::calculate_new_size;
+    use crate::test_image::{pattern, ImageSpec, Qcow2Disk, TestImage};
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Image with fragmented files whose growth shifts the data by a few clusters
+    fn shifting_image() -> TestImage {
+        let mut image = TestImage::create(ImageSpec::default());
+        image.add_file(b"FIRST   BIN", &pattern(1, 30 * 512), 1);
+        image.add_file(b"SECOND  BIN", &pattern(2, 30 * 512), 2);
+        image.extend_to_sectors(71_000);
+        image
+    }
+
+    /// Open the image and plan its data shift
+    fn open_and_plan(image: &TestImage) -> (Device, BootSector, RelocationPlan) {
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        let fat = read_fat_table(&device, &boot, 0).unwrap();
+        let calc = calculate_new_size(&boot, device.total_sectors()).unwrap();
+        let plan = plan_relocation(
+            &device,
+            &boot,
+            &fat,
+            calc.first_affected_cluster,
+            calc.last_affected_cluster,
+            calc.new_data_clusters,
+        )
+        .unwrap();
+        (device, boot, plan)
+    }
 
     #[test]
     fn test_cluster_move() {
@@ -253,4 +965,293 @@ mod tests {
         // With new approach, verification always succeeds
         assert!(verify_relocation(&fat, 2, 4).is_ok());
     }
+
+    /// Crash a shift of the image at `path` after `crash_after` writes, resume
+    /// it and check every cluster
+    ///
+    /// Returns `false` if the shift needed no more than `crash_after` writes
+    /// and completed without crashing.
+    fn assert_shift_survives_crash(
+        path: &Path,
+        plan: &RelocationPlan,
+        crash_after: u64,
+        read_ahead: ReadAhead,
+    ) -> bool {
+        let device = Device::open(path).unwrap();
+        let boot = read_boot_sector(&mut Device::open(path).unwrap()).unwrap();
+        let spc = boot.sectors_per_cluster() as u32;
+        let original: HashMap<u32, Vec<u8>> = plan
+            .moves
+            .iter()
+            .map(|mv| {
+                (
+                    mv.from_cluster,
+                    device.read_sectors(mv.from_sector, spc).unwrap(),
+                )
+            })
+            .collect();
+
+        // Watermarks only ever advance through the plan
+        let position = |cluster| plan.moves.iter().position(|mv| mv.from_cluster == cluster);
+        let mut watermark = None;
+        device.crash_after_writes(crash_after);
+        let result = execute_relocation_with_progress(
+            &device,
+            &boot,
+            plan,
+            None,
+            &mut |w| {
+                assert!(watermark.is_none_or(|previous| position(w) > position(previous)));
+                watermark = Some(w);
+                Ok(())
+            },
+            read_ahead,
+            false,
+        );
+        if result.is_ok() {
+            return false;
+        }
+
+        // Resume with a fresh handle, as after a restart
+        let device = Device::open(path).unwrap();
+        let copied = execute_relocation_with_progress(
+            &device,
+            &boot,
+            plan,
+            watermark,
+            &mut |_| Ok(()),
+            read_ahead,
+            false,
+        )
+        .unwrap();
+        assert!(copied <= plan.cluster_count());
+
+        for mv in &plan.moves {
+            assert_eq!(
+                device.read_sectors(mv.to_sector, spc).unwrap(),
+                original[&mv.from_cluster],
+                "cluster {} corrupted after crash at write {}",
+                mv.from_cluster,
+                crash_after
+            );
+        }
+        true
+    }
+
+    #[test]
+    fn test_interrupted_shift_resumes_from_watermark() {
+        // Runs of adjacent clusters take one write each, clusters without a
+        // neighbour one write of their own
+        let mut crash_after = 0;
+        loop {
+            let image = shifting_image();
+            let (_, _, plan) = open_and_plan(&image);
+            if !assert_shift_survives_crash(image.path(), &plan, crash_after, ReadAhead::default())
+            {
+                break;
+            }
+            crash_after += 1;
+        }
+        assert!(crash_after > 10, "only {crash_after} writes");
+    }
+
+    #[test]
+    fn test_interrupted_backward_shift_resumes_from_watermark() {
+        let mut crash_after = 0;
+        loop {
+            // Shift forward, then move everything back as a shrink would
+            let image = shifting_image();
+            let (device, boot, plan) = open_and_plan(&image);
+            execute_relocation_with_progress(
+                &device,
+                &boot,
+                &plan,
+                None,
+                &mut |_| Ok(()),
+                ReadAhead::default(),
+                false,
+            )
+            .unwrap();
+
+            let back = RelocationPlan {
+                moves: plan
+                    .moves
+                    .iter()
+                    .rev()
+                    .map(|mv| ClusterMove {
+                        from_cluster: mv.to_cluster,
+                        to_cluster: mv.from_cluster,
+                        from_sector: mv.to_sector,
+                        to_sector: mv.from_sector,
+                    })
+                    .collect(),
+                total_bytes: plan.total_bytes,
+                old_first_data_sector: plan.new_first_data_sector,
+                new_first_data_sector: plan.old_first_data_sector,
+            };
+            if !assert_shift_survives_crash(image.path(), &back, crash_after, ReadAhead::default())
+            {
+                break;
+            }
+            crash_after += 1;
+        }
+        assert!(crash_after > 10, "only {crash_after} writes");
+    }
+
+    #[test]
+    fn test_interrupted_pipelined_shift_resumes() {
+        // qcow2 images cannot copy in the kernel, so runs go through the
+        // reader thread unless the queue depth is 0
+        for queue_depth in [0, 1, 4] {
+            let mut crash_after = 0;
+            loop {
+                let image = shifting_image();
+                let (_, _, plan) = open_and_plan(&image);
+                let disk = Qcow2Disk::from_raw(image.path(), 16);
+                let read_ahead = ReadAhead::new(queue_depth);
+                if !assert_shift_survives_crash(disk.path(), &plan, crash_after, read_ahead) {
+                    break;
+                }
+                disk.assert_consistent();
+                crash_after += 1;
+            }
+            assert!(crash_after > 10, "only {crash_after} writes");
+        }
+    }
+
+    #[test]
+    fn test_shift_without_copy_offload() {
+        // A raw image on a filesystem without copy_file_range is copied
+        // through buffers, read ahead unless the queue depth is 0
+        for queue_depth in [0, DEFAULT_QUEUE_DEPTH] {
+            let image = shifting_image();
+            let (device, boot, plan) = open_and_plan(&image);
+            let spc = boot.sectors_per_cluster() as u32;
+            let original: Vec<Vec<u8>> = plan
+                .moves
+                .iter()
+                .map(|mv| device.read_sectors(mv.from_sector, spc).unwrap())
+                .collect();
+
+            device.refuse_copy_range();
+            let copied = execute_relocation_with_progress(
+                &device,
+                &boot,
+                &plan,
+                None,
+                &mut |_| Ok(()),
+                ReadAhead::new(queue_depth),
+                false,
+            )
+            .unwrap();
+            assert_eq!(copied, plan.cluster_count());
+            assert_eq!(device.offloaded_bytes(), 0);
+            for (mv, data) in plan.moves.iter().zip(&original) {
+                assert_eq!(&device.read_sectors(mv.to_sector, spc).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipelined_read_error_reaches_writer() {
+        let image = shifting_image();
+        let (_, _, plan) = open_and_plan(&image);
+        let disk = Qcow2Disk::from_raw(image.path(), 16);
+        let boot = read_boot_sector(&mut Device::open(disk.path()).unwrap()).unwrap();
+        let device = Device::open(disk.path()).unwrap();
+
+        // The first run is copied before the reader thread starts, so the
+        // second read fails in the reader
+        device.fail_reads_after(1);
+        let error = execute_relocation_with_progress(
+            &device,
+            &boot,
+            &plan,
+            None,
+            &mut |_| Ok(()),
+            ReadAhead::default(),
+            false,
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("simulated read error"),
+            "{}",
+            error
+        );
+        assert!(device.io_stats().write_ops > 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_direct_shift_reads_while_writing() {
+        let image = shifting_image();
+        let (mut device, boot, plan) = open_and_plan(&image);
+        // 512-byte blocks, so every cluster transfer goes direct
+        if !device.attach_direct_io(512) {
+            return; // The temporary directory does not support O_DIRECT
+        }
+        let spc = boot.sectors_per_cluster() as u32;
+        let original: Vec<Vec<u8>> = plan
+            .moves
+            .iter()
+            .map(|mv| device.read_sectors(mv.from_sector, spc).unwrap())
+            .collect();
+
+        // One cluster per run, so the reader thread reads the next runs
+        // while the writer writes
+        device.refuse_copy_range();
+        let read_ahead = ReadAhead {
+            queue_depth: 2,
+            max_run_bytes: boot.bytes_per_cluster() as u64,
+        };
+        execute_relocation_with_progress(
+            &device,
+            &boot,
+            &plan,
+            None,
+            &mut |_| Ok(()),
+            read_ahead,
+            false,
+        )
+        .unwrap();
+        assert!(device.peak_direct_transfers() >= 2);
+        for (mv, data) in plan.moves.iter().zip(&original) {
+            assert_eq!(&device.read_sectors(mv.to_sector, spc).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_read_ahead_within_memory_limit() {
+        let read_ahead = ReadAhead::default();
+        assert_eq!(read_ahead.within(None), read_ahead);
+        // Four queued runs, one being read and one being written
+        assert_eq!(read_ahead.within(Some(6 << 20)).max_run_bytes, 1 << 20);
+        assert_eq!(
+            read_ahead.within(Some(1 << 30)).max_run_bytes,
+            MAX_RUN_BYTES
+        );
+        assert_eq!(
+            ReadAhead::new(0).within(Some(64 * 1024)).max_run_bytes,
+            64 * 1024
+        );
+    }
+
+    #[test]
+    fn test_watermark_skips_copied_clusters() {
+        let image = shifting_image();
+        let (device, boot, plan) = open_and_plan(&image);
+        let watermark = plan.moves[10].from_cluster;
+
+        let copied = execute_relocation_with_progress(
+            &device,
+            &boot,
+            &plan,
+            Some(watermark),
+            &mut |_| Ok(()),
+            ReadAhead::default(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(copied, plan.cluster_count() - 11);
+    }
 }
@@ -0,0 +1,608 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::operations::FAT_IO_CHUNK_BYTES;
+use crate::fat32::{
+    count_free_clusters, directory_chains, entry_first_cluster, entry_kind, fat_entry,
+    read_cluster, set_entry_first_cluster, write_boot_sector, BootSector, EntryKind, ENTRY_SIZE,
+};
+use crate::resize::calculator::SizeCalculation;
+use crate::resize::executor::{
+    maybe_crash_at, write_checkpoint, write_sectors_sparse, ResizeCheckpoint, ResizeOperation,
+    ResizePhase,
+};
+use crate::resize::journal::Journal;
+use crate::resize::relocator::ClusterMove;
+use std::collections::{HashMap, HashSet};
+
+/// Cluster moves and metadata updates that give clusters new numbers
+///
+/// Used when shrinking, and when growing by moving only the clusters the
+/// larger FAT needs. Moved clusters are copied to clusters that are free in the
+/// current filesystem, so that step is harmless; FAT and directory sectors that
+/// the current filesystem still depends on go into the journal.
+#[derive(Debug)]
+pub struct RenumberPlan {
+    /// Clusters whose data moves (`to_cluster` is the new number)
+    pub moves: Vec<ClusterMove>,
+    /// Root directory cluster after renumbering
+    pub new_root_cluster: u32,
+    /// FAT sectors and directory sectors to rewrite
+    pub journal: Journal,
+    /// Clusters that hold the journal (in the layout of the current filesystem)
+    pub journal_clusters: Vec<u32>,
+    /// Free clusters after renumbering
+    pub new_free_clusters: u32,
+    /// Contents of moved directory clusters with their entries updated; may
+    /// run past one cluster to clear the rest of a larger target cluster
+    pub(crate) directory_data: HashMap<u32, Vec<u8>>,
+}
+
+impl RenumberPlan {
+    /// Bytes written to carry out the plan: moved clusters plus the journal,
+    /// which is written once and replayed once
+    pub fn bytes_written(&self, bytes_per_cluster: u32) -> u64 {
+        self.moves.len() as u64 * bytes_per_cluster as u64 + 2 * self.journal.size_bytes() as u64
+    }
+}
+
+/// Whether a FAT entry marks a cluster holding data
+pub(crate) fn holds_data(entry: u32) -> bool {
+    !fat_entry::is_free(entry) && !fat_entry::is_bad(entry)
+}
+
+/// Build the FAT for new cluster numbers
+///
+/// `map` gives the new number of each old cluster, or `None` for clusters that
+/// disappear, which must not hold data. Bad cluster marks move with their
+/// cluster; everything else not mapped to is free.
+fn renumber_fat(
+    fat: &[u32],
+    old_max_cluster: u32,
+    new_len: usize,
+    map: &impl Fn(u32) -> Option<u32>,
+) -> Result<Vec<u32>> {
+    let mut new_fat = vec![fat_entry::FREE; new_len];
+    new_fat[0] = fat[0];
+    new_fat[1] = fat[1];
+
+    for cluster in 2..old_max_cluster.min(fat.len() as u32) {
+        let entry = fat[cluster as usize];
+        if fat_entry::is_free(entry) {
+            continue;
+        }
+        let Some(new_cluster) = map(cluster) else {
+            if fat_entry::is_bad(entry) {
+                continue;
+            }
+            return Err(Error::CorruptedFAT(cluster));
+        };
+        let new_entry = match fat_entry::next_cluster(entry) {
+            Some(next) if !fat_entry::is_bad(entry) => {
+                let new_next = map(next).ok_or(Error::CorruptedFAT(next))?;
+                (entry & !fat_entry::CLUSTER_MASK) | new_next
+            }
+            _ => entry,
+        };
+        *new_fat
+            .get_mut(new_cluster as usize)
+            .ok_or(Error::CorruptedFAT(cluster))? = new_entry;
+    }
+
+    Ok(new_fat)
+}
+
+/// Journal every sector of FAT1 that differs between the old and new FAT
+pub(crate) fn journal_fat(journal: &mut Journal, boot: &BootSector, fat: &[u32], new_fat: &[u32]) {
+    let entries_per_sector = boot.bytes_per_sector() as usize / 4;
+    for (index, entries) in new_fat.chunks(entries_per_sector).enumerate() {
+        let start = index * entries_per_sector;
+        if fat.get(start..start + entries_per_sector) != Some(entries) {
+            let data = entries.iter().flat_map(|e| e.to_le_bytes()).collect();
+            journal.add(boot.first_fat_sector() + index as u64, data);
+        }
+    }
+}
+
+/// Point directory entries at their new clusters
+///
+/// Returns true once the end-of-directory marker is reached, so callers can
+/// skip the remaining clusters of the directory.
+pub(crate) fn patch_directory(data: &mut [u8], map: &impl Fn(u32) -> Option<u32>) -> Result<bool> {
+    for entry in data.chunks_exact_mut(ENTRY_SIZE) {
+        match entry_kind(entry) {
+            EntryKind::End => return Ok(true),
+            EntryKind::File | EntryKind::Directory | EntryKind::DotEntry => {
+                let cluster = entry_first_cluster(entry);
+                if cluster >= 2 {
+                    let new_cluster = map(cluster).ok_or(Error::CorruptedFAT(cluster))?;
+                    set_entry_first_cluster(entry, new_cluster);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(false)
+}
+
+/// Update every directory entry for new cluster numbers
+///
+/// Directory clusters that move are returned with their patched contents, to
+/// be written at their new position. Changed sectors of directories that stay
+/// in place are added to the journal.
+fn renumber_directories(
+    device: &Device,
+    boot: &BootSector,
+    fat: &[u32],
+    map: &impl Fn(u32) -> Option<u32>,
+    moved: &HashSet<u32>,
+    journal: &mut Journal,
+) -> Result<HashMap<u32, Vec<u8>>> {
+    let bytes_per_sector = boot.bytes_per_sector() as usize;
+    let mut directory_data = HashMap::new();
+
+    for chain in directory_chains(device, boot, fat)? {
+        for cluster in chain {
+            let mut data = read_cluster(device, boot, cluster)?;
+            let original = data.clone();
+            let ended = patch_directory(&mut data, map)?;
+
+            if moved.contains(&cluster) {
+                directory_data.insert(cluster, data);
+            } else if data != original {
+                let first_sector = boot.cluster_to_sector(cluster);
+                for (i, (new, old)) in data
+                    .chunks(bytes_per_sector)
+                    .zip(original.chunks(bytes_per_sector))
+                    .enumerate()
+                {
+                    if new != old {
+                        journal.add(first_sector + i as u64, new.to_vec());
+                    }
+                }
+            }
+
+            if ended {
+                break;
+            }
+        }
+    }
+
+    Ok(directory_data)
+}
+
+/// Work out the FAT and directory updates for a renumbering
+///
+/// `moved` maps each cluster whose data moves to its new number, `map` gives
+/// the new number of every cluster, and `to_sector` the position of a new
+/// cluster number on disk. The journal clusters are left to the caller.
+pub(crate) fn plan_renumbering(
+    device: &Device,
+    boot: &BootSector,
+    fat: &[u32],
+    calc: &SizeCalculation,
+    moved: Vec<(u32, u32)>,
+    map: &impl Fn(u32) -> Option<u32>,
+    to_sector: &impl Fn(u32) -> u64,
+) -> Result<RenumberPlan> {
+    let entries_per_sector = boot.bytes_per_sector() as usize / 4;
+    let new_fat = renumber_fat(
+        fat,
+        boot.data_clusters() + 2,
+        calc.new_fat_size as usize * entries_per_sector,
+        map,
+    )?;
+
+    let mut journal = Journal::new(boot.bytes_per_sector() as usize);
+    journal_fat(&mut journal, boot, fat, &new_fat);
+
+    let sources: HashSet<u32> = moved.iter().map(|&(from, _)| from).collect();
+    let directory_data = renumber_directories(device, boot, fat, map, &sources, &mut journal)?;
+
+    let root = boot.root_cluster();
+    Ok(RenumberPlan {
+        moves: moved
+            .into_iter()
+            .map(|(from, to)| ClusterMove {
+                from_cluster: from,
+                to_cluster: to,
+                from_sector: boot.cluster_to_sector(from),
+                to_sector: to_sector(to),
+            })
+            .collect(),
+        new_root_cluster: map(root).ok_or(Error::CorruptedFAT(root))?,
+        new_free_clusters: count_free_clusters(&new_fat, calc.new_data_clusters),
+        journal,
+        journal_clusters: Vec::new(),
+        directory_data,
+    })
+}
+
+/// Copy moved clusters to their new positions
+///
+/// The targets are free in the current filesystem, so this is safe to repeat.
+fn copy_clusters(
+    device: &Device,
+    boot: &BootSector,
+    plan: &RenumberPlan,
+    verbose: bool,
+) -> Result<()> {
+    let sectors_per_cluster = boot.sectors_per_cluster() as u32;
+    device.timed(|| {
+        for (i, mv) in plan.moves.iter().enumerate() {
+            if verbose && (i < 10 || i % 100 == 0 || i == plan.moves.len() - 1) {
+                eprintln!(
+                    "Moving cluster {} to cluster {} ({}/{})",
+                    mv.from_cluster,
+                    mv.to_cluster,
+                    i + 1,
+                    plan.moves.len()
+                );
+            }
+            let data = match plan.directory_data.get(&mv.from_cluster) {
+                Some(data) => data.clone(),
+                None => device.read_sectors(mv.from_sector, sectors_per_cluster)?,
+            };
+            device.write_sectors(mv.to_sector, &data)?;
+        }
+        device.sync()
+    })
+}
+
+/// Copy FAT1 into the other FAT copies at their new positions
+fn write_fat_copies(device: &Device, boot: &BootSector, fat_size: u32) -> Result<()> {
+    let fat1_start = boot.first_fat_sector();
+    let chunk_sectors = (FAT_IO_CHUNK_BYTES / boot.bytes_per_sector() as usize) as u32;
+    device.timed(|| {
+        for fat_num in 1..boot.num_fats() as u64 {
+            for chunk_start in (0..fat_size).step_by(chunk_sectors as usize) {
+                let count = chunk_sectors.min(fat_size - chunk_start);
+                let data = device.read_sectors(fat1_start + chunk_start as u64, count)?;
+                write_sectors_sparse(
+                    device,
+                    fat1_start + fat_num * fat_size as u64 + chunk_start as u64,
+                    &data,
+                )?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Boot sector addressing journal clusters: the current layout, extended over
+/// whichever of the old and new sizes is larger
+fn journal_boot(boot: &BootSector, calc: &SizeCalculation) -> BootSector {
+    let mut journal_boot = boot.clone();
+    journal_boot.set_total_sectors_32(calc.old_total_sectors.max(calc.new_total_sectors));
+    journal_boot
+}
+
+/// How far a renumbering got before this run
+pub(crate) enum RenumberStart<'a> {
+    /// Nothing written yet
+    New {
+        plan: &'a RenumberPlan,
+        operation: ResizeOperation,
+    },
+    /// Interrupted after the boot sector was invalidated
+    Resumed(ResizeCheckpoint),
+}
+
+/// Run the journaled phases of a renumbering
+///
+/// Phase 0 copies the moved clusters and writes the journal while the boot
+/// sector is still valid. Phase 1 invalidates it, replays the journal and
+/// writes the FAT copies at their new offsets. Returns the phase 2 checkpoint;
+/// restoring the boot sector is left to the caller.
+pub(crate) fn execute_renumbering(
+    device: &Device,
+    boot: &mut BootSector,
+    calc: &SizeCalculation,
+    checkpoint_sector: u64,
+    start: RenumberStart,
+    verbose: bool,
+    operations: &mut Vec<String>,
+) -> Result<ResizeCheckpoint> {
+    let journal_boot = journal_boot(boot, calc);
+
+    // === PHASE 0: Move clusters and write the journal (boot sector valid) ===
+    let checkpoint = match start {
+        RenumberStart::New { plan, operation } => {
+            let started = ResizeCheckpoint::new(
+                ResizePhase::Started,
+                calc.old_total_sectors,
+                calc.new_total_sectors,
+                calc.old_fat_size,
+                calc.new_fat_size,
+            )
+            .journaled(operation, plan.new_root_cluster, plan.journal_clusters[0]);
+            write_checkpoint(device, checkpoint_sector, &started)?;
+            operations.push("Wrote checkpoint (phase 0: started)".to_string());
+
+            maybe_crash_at("after_checkpoint_start");
+
+            copy_clusters(device, boot, plan, verbose)?;
+            operations.push(format!("Moved {} clusters", plan.moves.len()));
+
+            maybe_crash_at("after_clusters_moved");
+
+            plan.journal
+                .write(device, &journal_boot, &plan.journal_clusters)?;
+            operations.push(format!("Wrote journal ({} sectors)", plan.journal.len()));
+
+            let data_copied = ResizeCheckpoint {
+                phase: ResizePhase::DataCopied,
+                ..started
+            };
+            write_checkpoint(device, checkpoint_sector, &data_copied)?;
+            operations.push("Updated checkpoint (phase 1: data copied)".to_string());
+
+            maybe_crash_at("after_checkpoint_data_copied");
+            data_copied
+        }
+        RenumberStart::Resumed(checkpoint) => {
+            operations.push("Skipping cluster moves (already done)".to_string());
+            checkpoint
+        }
+    };
+
+    if checkpoint.phase == ResizePhase::FatWritten {
+        operations.push("Skipping journal replay (already done)".to_string());
+        return Ok(checkpoint);
+    }
+
+    // === PHASE 1: Switch to the new cluster numbers (boot sector invalidated) ===
+    boot.invalidate_signature();
+    write_boot_sector(device, boot)?;
+    device.sync()?;
+    operations.push("Invalidated boot sector (danger zone)".to_string());
+
+    maybe_crash_at("after_boot_invalidate");
+
+    let journal = Journal::read(device, &journal_boot, checkpoint.journal_cluster)?;
+    journal.replay(device)?;
+    operations.push(format!("Replayed journal ({} sectors)", journal.len()));
+
+    maybe_crash_at("after_journal_replay");
+
+    write_fat_copies(device, boot, calc.new_fat_size)?;
+    operations.push("Synced FAT copies".to_string());
+
+    maybe_crash_at("after_fat_write");
+
+    let fat_written = ResizeCheckpoint {
+        phase: ResizePhase::FatWritten,
+        ..checkpoint
+    };
+    write_checkpoint(device, checkpoint_sector, &fat_written)?;
+    operations.push("Updated checkpoint (phase 2: FAT written)".to_string());
+
+    maybe_crash_at("after_checkpoint_fat_written");
+    Ok(fat_written)
+}
+
+/// Plan growing the FAT by moving only the clusters it grows into
+///
+/// The data area starts `k` clusters later, where `k` is the number of
+/// clusters the larger FAT tables take. Every cluster past those keeps its
+/// position on disk and its number drops by `k`. The clusters the FAT grows
+/// into move to free clusters, preferably in the added space, and the journal
+/// goes into the next free ones. The cluster holding `reserved_sector` (the
+/// checkpoint) is never used.
+pub fn plan_renumber(
+    device: &Device,
+    boot: &BootSector,
+    fat: &[u32],
+    calc: &SizeCalculation,
+    reserved_sector: u64,
+) -> Result<RenumberPlan> {
+    let shift = calc.last_affected_cluster - calc.first_affected_cluster + 1;
+    let old_max_cluster = boot.data_clusters() + 2;
+    let new_max_cluster = calc.new_data_clusters + 2;
+    let sectors_per_cluster = boot.sectors_per_cluster() as u64;
+
+    // Position of new cluster `n` on disk: old cluster `n + k`, past the old end
+    // for the added space
+    let to_sector = |cluster: u32| boot.cluster_to_sector(cluster + shift);
+    let usable = |&cluster: &u32| {
+        let sector = to_sector(cluster);
+        !(sector..sector + sectors_per_cluster).contains(&reserved_sector)
+    };
+    let added = (old_max_cluster - shift)..new_max_cluster;
+    let free_inside = (2..old_max_cluster - shift)
+        .filter(|&cluster| fat_entry::is_free(fat[(cluster + shift) as usize]));
+    let mut available = added.chain(free_inside).filter(usable);
+
+    let mut moved = Vec::new();
+    for cluster in calc.first_affected_cluster..=calc.last_affected_cluster {
+        if holds_data(fat[cluster as usize]) {
+            moved.push((cluster, available.next().ok_or(Error::NoFreeCluster)?));
+        }
+    }
+
+    let targets: HashMap<u32, u32> = moved.iter().copied().collect();
+    let map = |cluster: u32| {
+        if cluster <= calc.last_affected_cluster {
+            targets.get(&cluster).copied()
+        } else {
+            Some(cluster - shift)
+        }
+    };
+
+    let mut plan = plan_renumbering(device, boot, fat, calc, moved, &map, &to_sector)?;
+
+    // The journal is read back before the switch, so its clusters are
+    // numbered in the old layout
+    let needed = plan.journal.clusters_needed(boot.bytes_per_cluster());
+    plan.journal_clusters = available
+        .take(needed)
+        .map(|cluster| cluster + shift)
+        .collect();
+    if plan.journal_clusters.len() < needed {
+        return Err(Error::NoFreeCluster);
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::read_boot_sector;
+    use crate::resize::{resize_fat32, GrowStrategy, ResizeOptions};
+    use crate::test_image::{
+        assert_consistent, assert_resumes_after_crashes, pattern, read_file, ImageSpec, TestImage,
+    };
+
+    type Files = Vec<(Vec<&'static [u8; 11]>, Vec<u8>)>;
+
+    /// Cluster far from the start, whose data must stay where it is
+    const FAR: u32 = 60_000;
+
+    /// Image whose root directory, files and directories sit where the FAT
+    /// grows into when extended to 71 000 sectors
+    fn growing_image() -> (TestImage, Files) {
+        let mut image = TestImage::create(ImageSpec::default());
+        let mut files: Files = Vec::new();
+
+        // Reaches past the clusters the FAT grows into
+        let straddle = pattern(1, 20 * 512);
+        image.add_file(b"STRADDLEBIN", &straddle, 1);
+        files.push((vec![b"STRADDLEBIN"], straddle));
+
+        let sub = image.add_dir(2, b"SUBDIR     ");
+        let nested = image.add_dir(sub, b"NESTED     ");
+        let fragmented = pattern(2, 10 * 512 + 100);
+        image.add_file_in(sub, b"FRAG    BIN", &fragmented, 3);
+        files.push((vec![b"SUBDIR     ", b"FRAG    BIN"], fragmented));
+        let deep = pattern(3, 3 * 512);
+        image.add_file_in(nested, b"DEEP    BIN", &deep, 1);
+        files.push((vec![b"SUBDIR     ", b"NESTED     ", b"DEEP    BIN"], deep));
+
+        image.skip_to(FAR);
+        let far = pattern(4, 8 * 512);
+        image.add_file(b"FAR     BIN", &far, 1);
+        files.push((vec![b"FAR     BIN"], far));
+
+        image.extend_to_sectors(71_000);
+        (image, files)
+    }
+
+    fn assert_files_intact(image: &TestImage, files: &Files) {
+        for (path, contents) in files {
+            assert_eq!(
+                read_file(image.path(), path).as_ref(),
+                Some(contents),
+                "{:?} corrupted",
+                path.last().map(|name| String::from_utf8_lossy(&name[..]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_renumber_fat() {
+        let fat = vec![
+            0x0FFFFFF8, 0x0FFFFFFF, // reserved
+            0x00000004, // 2 -> 4
+            0x00000000, // 3: free
+            0x0FFFFFFF, // 4: end of chain
+            0x0FFFFFF7, // 5: bad
+            0x10000002, // 6 -> 2, with reserved high bits
+        ];
+        // Move cluster 6 into the free cluster 3 and drop cluster 5
+        let map = |cluster: u32| match cluster {
+            5 => None,
+            6 => Some(3),
+            cluster => Some(cluster),
+        };
+
+        let new_fat = renumber_fat(&fat, 7, 6, &map).unwrap();
+        assert_eq!(
+            new_fat,
+            vec![0x0FFFFFF8, 0x0FFFFFFF, 0x00000004, 0x10000002, 0x0FFFFFFF, 0]
+        );
+
+        // Clusters holding data must have a new number
+        let lost = |cluster: u32| (cluster != 4).then_some(cluster);
+        assert!(matches!(
+            renumber_fat(&fat, 7, 7, &lost),
+            Err(Error::CorruptedFAT(4))
+        ));
+    }
+
+    #[test]
+    fn test_grow_by_renumbering() {
+        let (image, files) = growing_image();
+        let far_sector = {
+            let mut device = Device::open(image.path()).unwrap();
+            read_boot_sector(&mut device)
+                .unwrap()
+                .cluster_to_sector(FAR)
+        };
+        let far_before = Device::open(image.path())
+            .unwrap()
+            .read_sector(far_sector)
+            .unwrap();
+
+        let options = ResizeOptions::new(image.path()).strategy(GrowStrategy::Renumber);
+        let result = resize_fat32(options).unwrap();
+        assert!(result.fat_grew);
+        assert_eq!(result.strategy, Some(GrowStrategy::Renumber));
+        let shift = result.calculation.last_affected_cluster - 1;
+        // Every displaced cluster is in use: the root, both directories and files
+        assert_eq!(result.clusters_relocated, shift as usize);
+
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert!(boot.root_cluster() > 2);
+        // Data past the displaced clusters stays in place under a lower number
+        assert_eq!(boot.cluster_to_sector(FAR - shift), far_sector);
+        assert_eq!(device.read_sector(far_sector).unwrap(), far_before);
+
+        assert_files_intact(&image, &files);
+        assert_consistent(image.path());
+    }
+
+    #[test]
+    fn test_auto_strategy_moves_fewer_bytes() {
+        // Almost empty: shifting a few clusters beats any FAT rewrite
+        let mut image = TestImage::create(ImageSpec::default());
+        image.add_file(b"SMALL   BIN", &pattern(1, 1000), 1);
+        image.extend_to_sectors(71_000);
+        let options = ResizeOptions::new(image.path()).dry_run(true);
+        assert_eq!(
+            resize_fat32(options).unwrap().strategy,
+            Some(GrowStrategy::Shift)
+        );
+
+        // Mostly full: renumbering leaves the bulk of the data alone
+        let (mut image, mut files) = growing_image();
+        let bulk = pattern(5, 40_000 * 512);
+        image.skip_to(10_000);
+        image.add_file(b"BULK    BIN", &bulk, 1);
+        files.push((vec![b"BULK    BIN"], bulk));
+
+        let result = resize_fat32(ResizeOptions::new(image.path())).unwrap();
+        assert_eq!(result.strategy, Some(GrowStrategy::Renumber));
+        assert_files_intact(&image, &files);
+        assert_consistent(image.path());
+    }
+
+    #[test]
+    fn test_interrupted_renumbering_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            growing_image,
+            |(image, _)| ResizeOptions::new(image.path()).strategy(GrowStrategy::Renumber),
+            resize_fat32,
+            |(image, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                read_boot_sector(&mut device).is_ok_and(|b| b.total_sectors() > 70_000)
+            },
+            |(image, files)| {
+                assert_files_intact(image, files);
+                assert_consistent(image.path());
+            },
+        );
+        assert!(writes > 40);
+    }
+}
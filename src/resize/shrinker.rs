@@ -0,0 +1,571 @@
+use crate::device::Device;
+use crate::error::{Error, Result};
+use crate::fat32::{
+    count_free_clusters, fat_entry, find_free_cluster, read_boot_sector_for_recovery,
+    read_fat_table, read_fsinfo, write_backup_boot_sector, write_boot_sector, write_fsinfo,
+    BootSector, FSInfo,
+};
+use crate::resize::calculator::{calculate_shrink_size, SizeCalculation};
+use crate::resize::executor::{
+    clear_checkpoint, maybe_crash_at, write_checkpoint, ResizeCheckpoint, ResizeOperation,
+    ResizeOptions, ResizePhase, ResizeResult,
+};
+use crate::resize::relocator::{execute_relocation_with_progress, ClusterMove, RelocationPlan};
+use crate::resize::renumber::{
+    execute_renumbering, holds_data, plan_renumbering, RenumberPlan, RenumberStart,
+};
+use crate::system::check_not_mounted_at;
+use std::collections::HashMap;
+
+/// Everything needed to shrink a filesystem, worked out before any write
+#[derive(Debug)]
+pub struct ShrinkPlan {
+    /// Clusters past the new end moving to free clusters inside it, and the
+    /// FAT and directory updates for their new numbers
+    pub renumber: RenumberPlan,
+    /// Free cluster past the new end that holds the checkpoint, recorded in
+    /// the boot sector while it is invalidated
+    pub checkpoint_cluster: u32,
+}
+
+/// Plan a shrink: pick new homes for clusters past the new end and prepare
+/// the FAT and directory updates
+pub fn plan_shrink(
+    device: &Device,
+    boot: &BootSector,
+    fat: &[u32],
+    calc: &SizeCalculation,
+) -> Result<ShrinkPlan> {
+    let limit = calc.first_affected_cluster;
+    let old_max_cluster = (boot.data_clusters() + 2).min(fat.len() as u32);
+
+    // Clusters past the new end that hold data
+    let outside: Vec<u32> = (limit..old_max_cluster)
+        .filter(|&cluster| holds_data(fat[cluster as usize]))
+        .collect();
+    let free_inside = count_free_clusters(&fat[..limit as usize], limit - 2);
+    if outside.len() as u32 > free_inside {
+        return Err(Error::NotEnoughFreeSpace {
+            needed: outside.len() as u32,
+            free: free_inside,
+        });
+    }
+
+    // Give each one the lowest free cluster inside the new size
+    let mut inside = fat[..limit as usize].to_vec();
+    let mut moved = Vec::with_capacity(outside.len());
+    let mut hint = 2;
+    for &cluster in &outside {
+        let target = find_free_cluster(device, boot, &inside, hint).ok_or(Error::NoFreeCluster)?;
+        inside[target as usize] = fat_entry::END_OF_CHAIN;
+        moved.push((cluster, target));
+        hint = target + 1;
+    }
+
+    let mapping: HashMap<u32, u32> = moved.iter().copied().collect();
+    let map = |cluster: u32| {
+        if cluster < limit {
+            Some(cluster)
+        } else {
+            mapping.get(&cluster).copied()
+        }
+    };
+    let to_sector = |cluster: u32| boot.cluster_to_sector(cluster);
+    let mut renumber = plan_renumbering(device, boot, fat, calc, moved, &map, &to_sector)?;
+
+    // Checkpoint and journal go into free clusters past the new end, which
+    // nothing else writes to and which the shrunk filesystem no longer needs
+    let needed = renumber.journal.clusters_needed(boot.bytes_per_cluster());
+    let mut spare = (limit..old_max_cluster).filter(|&c| fat_entry::is_free(fat[c as usize]));
+    let checkpoint_cluster = spare.next().ok_or(Error::NoFreeCluster)?;
+    renumber.journal_clusters = spare.take(needed).collect();
+    if renumber.journal_clusters.len() < needed {
+        return Err(Error::NoFreeCluster);
+    }
+
+    Ok(ShrinkPlan {
+        renumber,
+        checkpoint_cluster,
+    })
+}
+
+/// Plan moving every cluster in use back to the start of the data area, after
+/// a smaller FAT or a dropped FAT copy
+pub(crate) fn plan_shift_back(
+    boot: &BootSector,
+    new_fat: &[u32],
+    calc: &SizeCalculation,
+) -> RelocationPlan {
+    let sectors_per_cluster = boot.sectors_per_cluster() as u64;
+    let old_first_data_sector = boot.first_data_sector();
+    let new_first_data_sector = boot.reserved_sectors() as u64
+        + calc.new_num_fats as u64 * calc.new_fat_size as u64
+        + boot.root_dir_sectors();
+
+    let moves: Vec<ClusterMove> = (2..calc.new_data_clusters + 2)
+        .filter(|&cluster| holds_data(new_fat[cluster as usize]))
+        .map(|cluster| ClusterMove {
+            from_cluster: cluster,
+            to_cluster: cluster,
+            from_sector: old_first_data_sector + (cluster - 2) as u64 * sectors_per_cluster,
+            to_sector: new_first_data_sector + (cluster - 2) as u64 * sectors_per_cluster,
+        })
+        .collect();
+
+    let total_bytes = moves.len() as u64 * boot.bytes_per_cluster() as u64;
+    RelocationPlan {
+        moves,
+        total_bytes,
+        old_first_data_sector,
+        new_first_data_sector,
+    }
+}
+
+/// Move the data area back to follow the smaller FAT and restore the boot
+/// sector: the last phase of a shrink or a cluster size change
+///
+/// `boot` is the layout the data sits in after the switch to the new cluster
+/// numbers. Every step can be repeated: the shift resumes from the watermark
+/// in the checkpoint and FSInfo is recounted from the new FAT. The backup boot
+/// sector is written first, so until the primary is restored a crash resumes
+/// from the checkpoint, which is cleared last.
+pub(crate) fn finish_shift_back(
+    device: &Device,
+    boot: &BootSector,
+    calc: &mut SizeCalculation,
+    checkpoint: &ResizeCheckpoint,
+    checkpoint_sector: u64,
+    options: &ResizeOptions,
+    operations: &mut Vec<String>,
+) -> Result<()> {
+    let mut new_boot = boot.clone();
+    new_boot.set_fat_size_32(calc.new_fat_size);
+    let new_fat = read_fat_table(device, &new_boot, 0)?;
+
+    let shift = plan_shift_back(boot, &new_fat, calc);
+    if shift.old_first_data_sector != shift.new_first_data_sector {
+        let resume = (checkpoint.watermark != 0).then_some(checkpoint.watermark);
+        let mut persist_watermark = |watermark: u32| -> Result<()> {
+            let progress = ResizeCheckpoint {
+                phase: ResizePhase::FatWritten,
+                ..checkpoint.clone()
+            }
+            .with_watermark(watermark);
+            write_checkpoint(device, checkpoint_sector, &progress)?;
+            maybe_crash_at("during_data_shift");
+            Ok(())
+        };
+        let copied = execute_relocation_with_progress(
+            device,
+            boot,
+            &shift,
+            resume,
+            &mut persist_watermark,
+            options.read_ahead(),
+            options.is_verbose(),
+        )?;
+        operations.push(format!("Shifted {} clusters back", copied));
+
+        maybe_crash_at("after_data_shift");
+    }
+
+    // The cluster numbers changed, so the next-free hint is dropped
+    let fsinfo_sector = boot.fs_info_sector();
+    let mut fsinfo = read_fsinfo(device, fsinfo_sector)?;
+    let new_free = count_free_clusters(&new_fat, calc.new_data_clusters);
+    calc.new_free_clusters = new_free;
+    fsinfo.set_free_count(new_free);
+    fsinfo.set_next_free(FSInfo::UNKNOWN_FREE);
+    write_fsinfo(device, &fsinfo, fsinfo_sector)?;
+    operations.push(format!("Updated FSInfo (free clusters: {})", new_free));
+
+    new_boot.set_total_sectors_32(calc.new_total_sectors);
+    new_boot.set_root_cluster(checkpoint.new_root_cluster);
+    new_boot.set_checkpoint_cluster(0);
+    new_boot.restore_signature();
+
+    write_backup_boot_sector(device, &new_boot, new_boot.backup_boot_sector())?;
+    device.sync()?;
+    operations.push("Updated backup boot sector".to_string());
+    write_boot_sector(device, &new_boot)?;
+    operations.push("Updated boot sector (signature restored)".to_string());
+
+    clear_checkpoint(device, checkpoint_sector)?;
+    operations.push("Cleared checkpoint".to_string());
+
+    device.sync()?;
+    operations.push("Synced changes to disk".to_string());
+    Ok(())
+}
+
+/// Find the checkpoint of an interrupted shrink to the same size
+///
+/// Only needed once the boot sector has been invalidated. The invalidated boot
+/// sector records the cluster the plan put the checkpoint in, so only that
+/// sector is read.
+fn find_shrink_checkpoint(
+    device: &Device,
+    boot: &BootSector,
+    calc: &SizeCalculation,
+) -> Result<Option<(ResizeCheckpoint, u64)>> {
+    let cluster = boot.checkpoint_cluster();
+    if !(calc.first_affected_cluster..boot.data_clusters() + 2).contains(&cluster) {
+        return Ok(None);
+    }
+    let sector = boot.cluster_to_sector(cluster);
+    if sector >= device.total_sectors() {
+        return Ok(None);
+    }
+    let data = device.read_sector(sector)?;
+    let Ok(Some(checkpoint)) = ResizeCheckpoint::from_bytes(&data) else {
+        return Ok(None);
+    };
+    let matches = checkpoint.operation == ResizeOperation::Shrink
+        && checkpoint.old_total_sectors == calc.old_total_sectors
+        && checkpoint.new_total_sectors == calc.new_total_sectors
+        && checkpoint.old_fat_size == calc.old_fat_size
+        && checkpoint.new_fat_size == calc.new_fat_size;
+    Ok(matches.then_some((checkpoint, sector)))
+}
+
+/// Shrink a FAT32 filesystem to the target size, with crash-safe checkpoints
+///
+/// Clusters past the new end are copied into free clusters inside it, then the
+/// FAT chains, directory entries and root cluster are switched to the new
+/// cluster numbers through a journal, and finally the data area moves back to
+/// follow the smaller FAT. The device itself is not truncated; shrink the
+/// partition or image afterwards.
+pub fn shrink_fat32(options: ResizeOptions) -> Result<ResizeResult> {
+    let mut operations = Vec::new();
+
+    let target = options
+        .get_target_size()
+        .ok_or_else(|| Error::Calculation("Shrinking requires a target size".to_string()))?;
+    if options.get_memory_limit().is_some() {
+        return Err(Error::MemoryLimitUnsupported("Shrinking"));
+    }
+
+    check_not_mounted_at(options.device_path(), options.get_location())?;
+    operations.push("Verified device is not mounted".to_string());
+
+    let mut device = options.open_device()?;
+    operations.push(format!(
+        "Opened device: {}",
+        options.device_path().display()
+    ));
+
+    // Allow an invalidated boot sector from an interrupted shrink
+    let mut boot = read_boot_sector_for_recovery(&mut device)?;
+    operations.push(format!(
+        "Read boot sector ({}-byte sectors)",
+        boot.bytes_per_sector()
+    ));
+
+    let mut calculation = calculate_shrink_size(&boot, target)?;
+    operations.push(format!(
+        "Calculated shrink: {} -> {} sectors",
+        calculation.old_total_sectors, calculation.new_total_sectors
+    ));
+
+    // While the boot sector is valid nothing the old filesystem uses has been
+    // touched, so an interrupted shrink simply starts over. Once it is
+    // invalidated the FAT can no longer be trusted and the checkpoint decides.
+    let (old_fat, incomplete) = if boot.is_signature_valid() {
+        (Some(read_fat_table(&device, &boot, 0)?), None)
+    } else {
+        let found = find_shrink_checkpoint(&device, &boot, &calculation)?
+            .ok_or(Error::InvalidatedFilesystem)?;
+        (None, Some(found))
+    };
+    if let Some((ref checkpoint, _)) = incomplete {
+        eprintln!(
+            "Resuming interrupted shrink from phase {:?}...",
+            checkpoint.phase
+        );
+        operations.push(format!(
+            "Detected incomplete shrink at phase {:?}",
+            checkpoint.phase
+        ));
+    }
+
+    if options.is_verbose() {
+        eprintln!("Current filesystem:");
+        eprintln!("  Total sectors: {}", calculation.old_total_sectors);
+        eprintln!("  FAT size: {} sectors", calculation.old_fat_size);
+        eprintln!("  Data clusters: {}", boot.data_clusters());
+        eprintln!();
+        eprintln!("After shrink:");
+        eprintln!("  Total sectors: {}", calculation.new_total_sectors);
+        eprintln!("  FAT size: {} sectors", calculation.new_fat_size);
+        eprintln!("  Data clusters: {}", calculation.new_data_clusters);
+    }
+
+    let mut clusters_relocated = 0;
+    let plan = match old_fat {
+        Some(ref fat) => {
+            let plan = plan_shrink(&device, &boot, fat, &calculation)?;
+            clusters_relocated = plan.renumber.moves.len();
+            calculation.new_free_clusters = plan.renumber.new_free_clusters;
+            operations.push(format!(
+                "Planned move of {} clusters past the new end ({} journal entries)",
+                plan.renumber.moves.len(),
+                plan.renumber.journal.len()
+            ));
+
+            if options.is_dry_run() {
+                operations.push("Dry run: no changes made".to_string());
+                return Ok(shrink_result(
+                    &device,
+                    &boot,
+                    calculation,
+                    clusters_relocated,
+                    operations,
+                ));
+            }
+            Some(plan)
+        }
+        None => None,
+    };
+
+    // === PHASES 0 and 1: Move clusters, then switch to the new cluster numbers ===
+    let (start, checkpoint_sector) = match (&plan, incomplete) {
+        (Some(plan), _) => {
+            // Written along with the invalidated signature, so a resumed
+            // shrink reads the checkpoint straight from there
+            boot.set_checkpoint_cluster(plan.checkpoint_cluster);
+            (
+                RenumberStart::New {
+                    plan: &plan.renumber,
+                    operation: ResizeOperation::Shrink,
+                },
+                boot.cluster_to_sector(plan.checkpoint_cluster),
+            )
+        }
+        (None, Some((checkpoint, sector))) => (RenumberStart::Resumed(checkpoint), sector),
+        (None, None) => return Err(Error::InvalidatedFilesystem),
+    };
+    let checkpoint = execute_renumbering(
+        &device,
+        &mut boot,
+        &calculation,
+        checkpoint_sector,
+        start,
+        options.is_verbose(),
+        &mut operations,
+    )?;
+
+    // === PHASE 2: Move the data area back and restore the boot sector ===
+    finish_shift_back(
+        &device,
+        &boot,
+        &mut calculation,
+        &checkpoint,
+        checkpoint_sector,
+        &options,
+        &mut operations,
+    )?;
+
+    Ok(shrink_result(
+        &device,
+        &boot,
+        calculation,
+        clusters_relocated,
+        operations,
+    ))
+}
+
+fn shrink_result(
+    device: &Device,
+    boot: &BootSector,
+    calculation: SizeCalculation,
+    clusters_relocated: usize,
+    operations: Vec<String>,
+) -> ResizeResult {
+    let bytes_per_sector = boot.bytes_per_sector() as u64;
+    ResizeResult {
+        old_size_bytes: calculation.old_total_sectors as u64 * bytes_per_sector,
+        new_size_bytes: calculation.new_total_sectors as u64 * bytes_per_sector,
+        fat_grew: false,
+        clusters_relocated,
+        strategy: None,
+        size_limit: None,
+        converted_from: None,
+        unused_bytes: device
+            .total_sectors()
+            .saturating_sub(calculation.new_total_sectors as u64)
+            * bytes_per_sector,
+        hidden_sectors_change: None,
+        partition_type_change: None,
+        sparse: device.sparse_stats(),
+        io: device.io_stats(),
+        discard: None,
+        calculation,
+        operations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fat32::{
+        read_boot_sector, read_cluster, write_backup_boot_sector, write_boot_sector, write_cluster,
+    };
+    use crate::resize::TargetSize;
+    use crate::test_image::{
+        assert_consistent, assert_resumes_after_crashes, pattern, read_file, ImageSpec, TestImage,
+    };
+
+    const TARGET: TargetSize = TargetSize::Absolute(67_000 * 512);
+
+    type Files = Vec<(Vec<&'static [u8; 11]>, Vec<u8>)>;
+
+    /// Image with files, directories and the root directory past the new end
+    fn shrinking_image() -> (TestImage, Files) {
+        let mut image = TestImage::create(ImageSpec {
+            total_sectors: 72_000,
+            ..Default::default()
+        });
+        let mut files: Files = Vec::new();
+
+        let inside = pattern(1, 20 * 512);
+        image.add_file(b"INSIDE  BIN", &inside, 1);
+        files.push((vec![b"INSIDE  BIN"], inside));
+
+        // Straddles the new end of the filesystem
+        image.skip_to(65_900);
+        let straddle = pattern(2, 40 * 512 + 7);
+        image.add_file(b"STRADDLEBIN", &straddle, 1);
+        files.push((vec![b"STRADDLEBIN"], straddle));
+
+        image.skip_to(69_000);
+        let sub = image.add_dir(2, b"SUBDIR     ");
+        let nested = image.add_dir(sub, b"NESTED     ");
+        let fragmented = pattern(3, 30 * 512 + 100);
+        image.add_file_in(sub, b"FRAG    BIN", &fragmented, 3);
+        files.push((vec![b"SUBDIR     ", b"FRAG    BIN"], fragmented));
+        let deep = pattern(4, 5 * 512);
+        image.add_file_in(nested, b"DEEP    BIN", &deep, 1);
+        files.push((vec![b"SUBDIR     ", b"NESTED     ", b"DEEP    BIN"], deep));
+        let outside = pattern(5, 40 * 512);
+        image.add_file(b"OUTSIDE BIN", &outside, 1);
+        files.push((vec![b"OUTSIDE BIN"], outside));
+
+        // Move the root directory past the new end as well
+        let mut device = Device::open(image.path()).unwrap();
+        let mut boot = read_boot_sector(&mut device).unwrap();
+        let root = read_cluster(&device, &boot, 2).unwrap();
+        write_cluster(&device, &boot, 70_500, &root).unwrap();
+        image.set_fat(70_500, fat_entry::END_OF_CHAIN);
+        image.set_fat(2, fat_entry::FREE);
+        boot.set_root_cluster(70_500);
+        write_boot_sector(&device, &boot).unwrap();
+        write_backup_boot_sector(&device, &boot, boot.backup_boot_sector()).unwrap();
+        device.sync().unwrap();
+
+        (image, files)
+    }
+
+    fn assert_files_intact(image: &TestImage, files: &Files) {
+        for (path, contents) in files {
+            assert_eq!(
+                read_file(image.path(), path).as_ref(),
+                Some(contents),
+                "{:?} corrupted",
+                path.last().map(|name| String::from_utf8_lossy(&name[..]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_shrink_moves_clusters_and_directories() {
+        let (image, files) = shrinking_image();
+
+        let result =
+            shrink_fat32(ResizeOptions::new(image.path()).target_size(Some(TARGET))).unwrap();
+        let limit = result.calculation.first_affected_cluster;
+        assert!((65_900..65_941).contains(&limit));
+        assert!(result.calculation.new_fat_size < result.calculation.old_fat_size);
+        assert!(result.new_size_bytes <= 67_000 * 512);
+        // Tail of the straddling file, both directories, three files and the root
+        let straddle_tail = 65_941 - limit as usize;
+        assert_eq!(
+            result.clusters_relocated,
+            straddle_tail + 2 + 31 + 5 + 40 + 1
+        );
+
+        let mut device = Device::open(image.path()).unwrap();
+        let boot = read_boot_sector(&mut device).unwrap();
+        assert_eq!(boot.total_sectors() as u64 * 512, result.new_size_bytes);
+        assert!(boot.root_cluster() < limit);
+
+        assert_files_intact(&image, &files);
+        assert_consistent(image.path());
+    }
+
+    #[test]
+    fn test_shrink_dry_run() {
+        let (image, _) = shrinking_image();
+        let before = std::fs::read(image.path()).unwrap();
+
+        let result = shrink_fat32(
+            ResizeOptions::new(image.path())
+                .target_size(Some(TARGET))
+                .dry_run(true),
+        )
+        .unwrap();
+        assert!(result.clusters_relocated > 0);
+        assert_eq!(std::fs::read(image.path()).unwrap(), before);
+
+        // Shrinking reads the whole FAT, so a memory limit is refused
+        let limited = ResizeOptions::new(image.path())
+            .target_size(Some(TARGET))
+            .memory_limit(Some(1 << 20));
+        assert!(matches!(
+            shrink_fat32(limited),
+            Err(Error::MemoryLimitUnsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_shrink_not_enough_free_space() {
+        let (mut image, _) = shrinking_image();
+        // Leave no free cluster inside the new size
+        image.set_fat(2, fat_entry::BAD_CLUSTER);
+        image.set_fat_range(23..65_900, fat_entry::BAD_CLUSTER);
+
+        let result = shrink_fat32(ResizeOptions::new(image.path()).target_size(Some(TARGET)));
+        assert!(matches!(
+            result,
+            Err(Error::NotEnoughFreeSpace { free: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_interrupted_shrink_resumes() {
+        let writes = assert_resumes_after_crashes(
+            1,
+            shrinking_image,
+            |(image, _)| ResizeOptions::new(image.path()).target_size(Some(TARGET)),
+            shrink_fat32,
+            |(image, _)| {
+                let mut device = Device::open(image.path()).unwrap();
+                let boot = read_boot_sector_for_recovery(&mut device).unwrap();
+                // An invalidated boot sector points at the checkpoint
+                if !boot.is_signature_valid() {
+                    assert_ne!(boot.checkpoint_cluster(), 0);
+                }
+                boot.is_signature_valid() && boot.total_sectors() < 72_000
+            },
+            |(image, files)| {
+                assert_files_intact(image, files);
+                assert_consistent(image.path());
+                let mut device = Device::open(image.path()).unwrap();
+                assert_eq!(
+                    read_boot_sector(&mut device).unwrap().checkpoint_cluster(),
+                    0
+                );
+            },
+        );
+        assert!(writes > 100);
+    }
+}
@@ -1,11 +1,22 @@
 use crate::error::{Error, Result};
+use crate::partition::VolumeLocation;
 use std::fs;
 use std::path::Path;
 
 /// Check if a device is currently mounted
 ///
-/// On Linux, this parses /proc/mounts to check if the device is mounted.
+/// On Linux, this parses /proc/mounts to check if the device, or one of its
+/// partitions, is mounted.
 pub fn check_not_mounted(device_path: impl AsRef<Path>) -> Result<()> {
+    check_not_mounted_at(device_path, VolumeLocation::Whole)
+}
+
+/// Check if the filesystem at `location` of a device is currently mounted
+///
+/// Like [`check_not_mounted`], but for a partition only the device itself
+/// and that partition's node count, so a volume on a disk whose other
+/// partitions are mounted (such as the root filesystem) can be resized.
+pub fn check_not_mounted_at(device_path: impl AsRef<Path>, location: VolumeLocation) -> Result<()> {
     let device_path = resolve_device_path(device_path.as_ref())?;
 
     // Read /proc/mounts
@@ -25,7 +36,7 @@ pub fn check_not_mounted(device_path: impl AsRef<Path>) -> Result<()> {
 
             // Check if this mount entry matches our device
             if let Ok(resolved_mount) = resolve_device_path(Path::new(mount_device)) {
-                if resolved_mount == device_path {
+                if holds_volume(&resolved_mount, &device_path, location) {
                     return Err(Error::DeviceMounted(device_path, mount_point.to_string()));
                 }
             }
@@ -35,6 +46,44 @@ pub fn check_not_mounted(device_path: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// Check whether a mounted device node is the device, or holds the
+/// filesystem at `location` of it
+///
+/// The partition an offset lies in is not known, so for an offset, as for
+/// the whole device, every partition counts.
+fn holds_volume(mounted: &str, device: &str, location: VolumeLocation) -> bool {
+    mounted == device
+        || match location {
+            VolumeLocation::Partition(number) => mounted == partition_node(device, number),
+            VolumeLocation::Whole | VolumeLocation::Offset(_) => is_partition_of(mounted, device),
+        }
+}
+
+/// Device node of a disk's partition, named as Linux names them: with a `p`
+/// before the number when the disk's name ends in a digit (`/dev/sda1`,
+/// `/dev/nvme0n1p1`)
+fn partition_node(disk: &str, number: u32) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", disk, number)
+    } else {
+        format!("{}{}", disk, number)
+    }
+}
+
+/// Check whether a device node is a partition of a disk, named as
+/// [`partition_node`] names them
+fn is_partition_of(partition: &str, disk: &str) -> bool {
+    let Some(suffix) = partition.strip_prefix(disk) else {
+        return false;
+    };
+    let number = if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        suffix.strip_prefix('p')
+    } else {
+        Some(suffix)
+    };
+    number.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
 /// Resolve a device path to its canonical form
 ///
 /// This handles symlinks (e.g., /dev/disk/by-uuid/... -> /dev/sda1)
@@ -97,6 +146,359 @@ pub fn get_block_device_size(path: impl AsRef<Path>) -> Result<u64> {
     Ok(size)
 }
 
+/// Logical block size of an open block device: the smallest unit it can be
+/// addressed in, which direct I/O must be aligned to
+#[cfg(target_os = "linux")]
+pub fn get_logical_block_size(file: &fs::File) -> Option<u64> {
+    use std::os::unix::io::AsRawFd;
+
+    // BLKSSZGET = 0x1268
+    const BLKSSZGET: libc::Ioctl = 0x1268;
+    let mut size: libc::c_int = 0;
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET, &mut size) };
+    (result == 0 && size > 0).then_some(size as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_logical_block_size(_file: &fs::File) -> Option<u64> {
+    None
+}
+
+/// Alignment hints the kernel reports for a block device, in bytes
+///
+/// Returns the largest of the physical block size, the minimum and optimal
+/// I/O sizes and the preferred erase size (reported by SD/MMC cards), or
+/// `None` for image files and when sysfs has no answer.
+#[cfg(target_os = "linux")]
+pub fn get_alignment_hint(path: impl AsRef<Path>) -> Option<u64> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+    let rdev = metadata.rdev();
+    let (major, minor) = (libc::major(rdev), libc::minor(rdev));
+
+    // Partitions keep the queue limits and device attributes on their disk
+    let mut dir = Path::new("/sys/dev/block")
+        .join(format!("{}:{}", major, minor))
+        .canonicalize()
+        .ok()?;
+    if dir.join("partition").exists() {
+        dir.pop();
+    }
+
+    let read = |name: &str| -> Option<u64> {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+    };
+    [
+        "queue/physical_block_size",
+        "queue/minimum_io_size",
+        "queue/optimal_io_size",
+        "device/preferred_erase_size",
+    ]
+    .into_iter()
+    .filter_map(read)
+    .max()
+    .filter(|&bytes| bytes > 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_alignment_hint(_path: impl AsRef<Path>) -> Option<u64> {
+    None
+}
+
+/// Byte offset at which a partition block device (such as `/dev/sda1`)
+/// starts on its disk
+///
+/// Returns `None` for image files, whole disks and when sysfs has no answer.
+#[cfg(target_os = "linux")]
+pub fn get_partition_start(path: impl AsRef<Path>) -> Option<u64> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = fs::metadata(path).ok()?;
+    if !metadata.file_type().is_block_device() {
+        return None;
+    }
+    let rdev = metadata.rdev();
+    let dir =
+        Path::new("/sys/dev/block").join(format!("{}:{}", libc::major(rdev), libc::minor(rdev)));
+    if !dir.join("partition").exists() {
+        return None;
+    }
+    // sysfs counts in 512-byte sectors whatever the logical sector size
+    let start: u64 = fs::read_to_string(dir.join("start"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(start * 512)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn get_partition_start(_path: impl AsRef<Path>) -> Option<u64> {
+    None
+}
+
+/// Tell the kernel that a partition of a block device changed size
+///
+/// Uses `BLKPG_RESIZE_PARTITION`, which works while other partitions of the
+/// disk are in use, and falls back to re-reading the whole partition table
+/// (`BLKRRPART`). Returns `false` for image files, which have no kernel
+/// partition table.
+#[cfg(target_os = "linux")]
+pub fn notify_partition_resize(
+    device_path: impl AsRef<Path>,
+    number: u32,
+    start_bytes: u64,
+    size_bytes: u64,
+) -> Result<bool> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::io::AsRawFd;
+
+    let path = device_path.as_ref();
+    if !fs::metadata(path)?.file_type().is_block_device() {
+        return Ok(false);
+    }
+    let file = fs::File::open(path)?;
+    let fd = file.as_raw_fd();
+
+    // From <linux/blkpg.h>
+    #[repr(C)]
+    struct BlkpgPartition {
+        start: libc::c_longlong,
+        length: libc::c_longlong,
+        pno: libc::c_int,
+        devname: [libc::c_char; 64],
+        volname: [libc::c_char; 64],
+    }
+    #[repr(C)]
+    struct BlkpgIoctlArg {
+        op: libc::c_int,
+        flags: libc::c_int,
+        datalen: libc::c_int,
+        data: *mut libc::c_void,
+    }
+    const BLKPG: libc::Ioctl = 0x1269;
+    const BLKRRPART: libc::Ioctl = 0x125F;
+    const BLKPG_RESIZE_PARTITION: libc::c_int = 3;
+
+    let mut partition = BlkpgPartition {
+        start: start_bytes as libc::c_longlong,
+        length: size_bytes as libc::c_longlong,
+        pno: number as libc::c_int,
+        devname: [0; 64],
+        volname: [0; 64],
+    };
+    let mut arg = BlkpgIoctlArg {
+        op: BLKPG_RESIZE_PARTITION,
+        flags: 0,
+        datalen: std::mem::size_of::<BlkpgPartition>() as libc::c_int,
+        data: &mut partition as *mut BlkpgPartition as *mut libc::c_void,
+    };
+    if unsafe { libc::ioctl(fd, BLKPG, &mut arg) } == 0 {
+        return Ok(true);
+    }
+    if unsafe { libc::ioctl(fd, BLKRRPART) } == 0 {
+        return Ok(true);
+    }
+    Err(Error::Io(std::io::Error::last_os_error()))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn notify_partition_resize(
+    _device_path: impl AsRef<Path>,
+    _number: u32,
+    _start_bytes: u64,
+    _size_bytes: u64,
+) -> Result<bool> {
+    Ok(false)
+}
+
+/// Check whether a byte range of a regular file lies entirely in a hole
+///
+/// Uses `SEEK_DATA`, so a range is only reported as a hole when the
+/// filesystem knows it has no data blocks there. Returns `false` if the
+/// filesystem cannot tell.
+#[cfg(target_os = "linux")]
+pub fn is_file_hole(file: &fs::File, offset: u64, len: u64) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: lseek only moves the file position, which positional I/O ignores
+    let data = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        // ENXIO: no data at or after `offset`
+        return std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO);
+    }
+    data as u64 >= offset + len
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_file_hole(_file: &fs::File, _offset: u64, _len: u64) -> bool {
+    false
+}
+
+/// Deallocate a byte range of a regular file, which then reads as zeros
+///
+/// Uses `fallocate(FALLOC_FL_PUNCH_HOLE)`; the file size does not change.
+/// Returns `false` if the filesystem does not support punching holes.
+#[cfg(target_os = "linux")]
+pub fn punch_file_hole(file: &fs::File, offset: u64, len: u64) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: fallocate on an open file descriptor, with no pointers
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(error.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn punch_file_hole(_file: &fs::File, _offset: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+/// Tell a block device that a byte range no longer holds data
+/// (`BLKDISCARD`), so flash storage can erase it ahead of the next write
+///
+/// What the range reads afterwards depends on the device. Returns `false`
+/// if the device does not support discards.
+#[cfg(target_os = "linux")]
+pub fn discard_block_range(file: &fs::File, offset: u64, len: u64) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // BLKDISCARD = 0x1277
+    const BLKDISCARD: libc::Ioctl = 0x1277;
+    let range: [u64; 2] = [offset, len];
+    // SAFETY: BLKDISCARD reads two u64s (start and length) from the pointer
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD, &range) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) | Some(libc::EINVAL) => Ok(false),
+        _ => Err(error.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn discard_block_range(_file: &fs::File, _offset: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+/// Copy a byte range of a regular file to another, non-overlapping place in
+/// the same file without passing the data through userspace
+///
+/// Ranges aligned to `block_size` are first cloned with `FICLONERANGE`,
+/// which shares the blocks on filesystems with reflinks (XFS, Btrfs).
+/// Otherwise, or if cloning is not supported, `copy_file_range` copies in
+/// the kernel, which can also use reflinks or server-side copies.
+///
+/// Returns `false`, copying nothing, if neither is supported.
+#[cfg(target_os = "linux")]
+pub fn copy_file_range_within(
+    file: &fs::File,
+    from: u64,
+    to: u64,
+    len: u64,
+    block_size: u64,
+) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let unsupported = |error: &std::io::Error| {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::EOPNOTSUPP | libc::ENOSYS | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+        )
+    };
+
+    if [from, to, len].iter().all(|v| v % block_size == 0) {
+        // From <linux/fs.h>
+        #[repr(C)]
+        struct FileCloneRange {
+            src_fd: i64,
+            src_offset: u64,
+            src_length: u64,
+            dest_offset: u64,
+        }
+        const FICLONERANGE: libc::Ioctl = 0x4020_940D;
+
+        let range = FileCloneRange {
+            src_fd: fd as i64,
+            src_offset: from,
+            src_length: len,
+            dest_offset: to,
+        };
+        // SAFETY: FICLONERANGE reads a struct file_clone_range
+        if unsafe { libc::ioctl(fd, FICLONERANGE, &range) } == 0 {
+            return Ok(true);
+        }
+        let error = std::io::Error::last_os_error();
+        if !unsupported(&error) {
+            return Err(error.into());
+        }
+    }
+
+    let mut done = 0;
+    while done < len {
+        let mut off_in = (from + done) as libc::off64_t;
+        let mut off_out = (to + done) as libc::off64_t;
+        // SAFETY: the offsets are valid for the call, and the file is open
+        let copied = unsafe {
+            libc::copy_file_range(
+                fd,
+                &mut off_in,
+                fd,
+                &mut off_out,
+                (len - done) as libc::size_t,
+                0,
+            )
+        };
+        if copied < 0 {
+            let error = std::io::Error::last_os_error();
+            if done == 0 && unsupported(&error) {
+                return Ok(false);
+            }
+            return Err(error.into());
+        }
+        if copied == 0 {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("copy_file_range stopped at offset {}", from + done),
+            )));
+        }
+        done += copied as u64;
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn copy_file_range_within(
+    _file: &fs::File,
+    _from: u64,
+    _to: u64,
+    _len: u64,
+    _block_size: u64,
+) -> Result<bool> {
+    Ok(false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,6 +524,55 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_is_partition_of() {
+        assert!(is_partition_of("/dev/sda1", "/dev/sda"));
+        assert!(is_partition_of("/dev/mmcblk0p2", "/dev/mmcblk0"));
+        assert!(!is_partition_of("/dev/sda", "/dev/sda"));
+        assert!(!is_partition_of("/dev/sdab", "/dev/sda"));
+        assert!(!is_partition_of("/dev/sdb1", "/dev/sda"));
+        assert!(is_partition_of("/dev/nvme0n1p1", "/dev/nvme0n1"));
+        assert!(!is_partition_of("/dev/nvme0n10", "/dev/nvme0n1"));
+        assert!(!is_partition_of("/dev/mmcblk01", "/dev/mmcblk0"));
+    }
+
+    #[test]
+    fn test_holds_volume() {
+        assert_eq!(partition_node("/dev/sda", 2), "/dev/sda2");
+        assert_eq!(partition_node("/dev/nvme0n1", 2), "/dev/nvme0n1p2");
+
+        // Another partition of the disk only counts without --partition
+        let part = VolumeLocation::Partition(2);
+        assert!(holds_volume("/dev/sda", "/dev/sda", part));
+        assert!(holds_volume("/dev/sda2", "/dev/sda", part));
+        assert!(!holds_volume("/dev/sda1", "/dev/sda", part));
+        assert!(holds_volume("/dev/nvme0n1p2", "/dev/nvme0n1", part));
+        assert!(!holds_volume("/dev/nvme0n1p1", "/dev/nvme0n1", part));
+        assert!(holds_volume("/dev/sda1", "/dev/sda", VolumeLocation::Whole));
+        assert!(holds_volume(
+            "/dev/sda1",
+            "/dev/sda",
+            VolumeLocation::Offset(1 << 20)
+        ));
+        assert!(!holds_volume(
+            "/dev/sdb1",
+            "/dev/sda",
+            VolumeLocation::Whole
+        ));
+    }
+
+    #[test]
+    fn test_notify_partition_resize_file() {
+        let file = NamedTempFile::new().unwrap();
+        assert!(!notify_partition_resize(file.path(), 1, 0, 512).unwrap());
+    }
+
+    #[test]
+    fn test_get_alignment_hint_file() {
+        let file = NamedTempFile::new().unwrap();
+        assert_eq!(get_alignment_hint(file.path()), None);
+    }
+
     #[test]
     fn test_get_block_device_size_file() {
         let file = NamedTempFile::new().unwrap();
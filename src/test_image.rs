@@ -0,0 +1,1536 @@
+//! Minimal FAT image builder for unit tests
+//!
+//! Builds small sparse FAT32 (or FAT12/16) images in temporary files without
+//! needing `mkfs.fat`, and reads files back so tests can check that a resize
+//! kept every byte in place. exFAT images are built the same way, without
+//! `mkfs.exfat`.
+
+use crate::error::Result;
+use crate::fat32::{fat_entry, FatType};
+use crate::resize::ResizeOptions;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use tempfile::NamedTempFile;
+
+/// Layout parameters for a test image
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSpec {
+    /// Filesystem to format; FAT12 and FAT16 also need `root_entries`
+    pub fat_type: FatType,
+    pub total_sectors: u32,
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    /// Sectors before the first FAT (the FAT offset on exFAT)
+    pub reserved_sectors: u16,
+    pub num_fats: u8,
+    /// Entries in the fixed FAT12/16 root directory
+    pub root_entries: u16,
+    /// Unused sectors between the FAT and the exFAT cluster heap
+    pub heap_gap: u32,
+}
+
+impl Default for ImageSpec {
+    fn default() -> Self {
+        // Just above the FAT32 minimum of 65525 clusters
+        Self {
+            fat_type: FatType::Fat32,
+            total_sectors: 70_000,
+            bytes_per_sector: 512,
+            sectors_per_cluster: 1,
+            reserved_sectors: 32,
+            num_fats: 2,
+            root_entries: 0,
+            heap_gap: 0,
+        }
+    }
+}
+
+impl ImageSpec {
+    /// 8 MiB of 512-byte exFAT clusters: a 4-cluster bitmap and a FAT that
+    /// fills the space before the heap, so growing moves both
+    pub fn exfat() -> Self {
+        Self {
+            fat_type: FatType::ExFat,
+            total_sectors: 16_384,
+            reserved_sectors: 24,
+            num_fats: 1,
+            ..Default::default()
+        }
+    }
+}
+
+/// A FAT12, FAT16, FAT32 or exFAT image in a temporary file
+///
+/// An exFAT image also holds an allocation bitmap, an up-case table and a
+/// one-cluster root directory, each chained in the FAT like `mkfs.exfat`
+/// does.
+pub struct TestImage {
+    file: NamedTempFile,
+    spec: ImageSpec,
+    fat_size: u32,
+    /// First sector of the data area (the cluster heap)
+    heap_offset: u32,
+    cluster_count: u32,
+    /// 0 for the fixed FAT12/16 root directory
+    root_cluster: u32,
+    /// exFAT allocation bitmap
+    bitmap_cluster: u32,
+    next_free: u32,
+}
+
+impl TestImage {
+    /// Format a new image; the backing file is exactly `total_sectors` long
+    pub fn create(spec: ImageSpec) -> Self {
+        let file = NamedTempFile::new().unwrap();
+        file.as_file()
+            .set_len(spec.total_sectors as u64 * spec.bytes_per_sector as u64)
+            .unwrap();
+
+        let fat_size = Self::fat_size(&spec);
+        let heap_offset = spec.reserved_sectors as u32
+            + spec.num_fats as u32 * fat_size
+            + Self::root_dir_sectors(&spec)
+            + spec.heap_gap;
+        let cluster_count = (spec.total_sectors - heap_offset) / spec.sectors_per_cluster as u32;
+        if spec.fat_type != FatType::ExFat {
+            assert_eq!(
+                FatType::from_cluster_count(cluster_count),
+                spec.fat_type,
+                "cluster count {}",
+                cluster_count
+            );
+        }
+
+        let mut image = Self {
+            file,
+            spec,
+            fat_size,
+            heap_offset,
+            cluster_count,
+            root_cluster: 0,
+            bitmap_cluster: 0,
+            next_free: 2,
+        };
+        image.set_fat(0, image.end_of_chain() & !0x7);
+        image.set_fat(1, image.end_of_chain());
+        match spec.fat_type {
+            FatType::ExFat => image.format_exfat(),
+            FatType::Fat32 => {
+                image.root_cluster = image.allocate(1, 1, true)[0];
+                image.write_boot_sectors();
+            }
+            _ => image.write_boot_sectors(),
+        }
+        image
+    }
+
+    /// FAT size in sectors, with the same formulas as the calculator so the
+    /// FAT fits exactly
+    fn fat_size(spec: &ImageSpec) -> u32 {
+        match spec.fat_type {
+            FatType::Fat32 => crate::resize::calculate_fat_size(
+                spec.total_sectors,
+                spec.reserved_sectors,
+                spec.num_fats,
+                spec.sectors_per_cluster,
+                spec.bytes_per_sector,
+            )
+            .unwrap(),
+            FatType::ExFat => {
+                let mut fat_size = 1;
+                loop {
+                    let heap_offset = spec.reserved_sectors as u32 + fat_size + spec.heap_gap;
+                    let clusters =
+                        (spec.total_sectors - heap_offset) / spec.sectors_per_cluster as u32;
+                    let needed = ((clusters + 2) * 4).div_ceil(spec.bytes_per_sector as u32);
+                    if needed <= fat_size {
+                        return fat_size;
+                    }
+                    fat_size = needed;
+                }
+            }
+            fat_type => crate::resize::calculate_small_fat_size(
+                fat_type,
+                spec.total_sectors,
+                spec.reserved_sectors,
+                spec.num_fats,
+                Self::root_dir_sectors(spec),
+                spec.sectors_per_cluster,
+                spec.bytes_per_sector,
+            )
+            .unwrap(),
+        }
+    }
+
+    fn root_dir_sectors(spec: &ImageSpec) -> u32 {
+        (spec.root_entries as u32 * 32).div_ceil(spec.bytes_per_sector as u32)
+    }
+
+    /// Cluster number that stands for the root directory: its first cluster,
+    /// or 0 for the fixed FAT12/16 root directory
+    pub fn root(&self) -> u32 {
+        self.root_cluster
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    fn handle(&self) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.file.path())
+            .unwrap()
+    }
+
+    fn is_exfat(&self) -> bool {
+        self.spec.fat_type == FatType::ExFat
+    }
+
+    /// End-of-chain marker, in FAT32 terms on FAT
+    fn end_of_chain(&self) -> u32 {
+        if self.is_exfat() {
+            0xFFFFFFFF
+        } else {
+            fat_entry::END_OF_CHAIN
+        }
+    }
+
+    fn write_boot_sectors(&self) {
+        let spec = self.spec;
+        let bps = spec.bytes_per_sector as usize;
+        let mut boot = vec![0u8; bps];
+        boot[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        boot[3..11].copy_from_slice(b"TESTIMG ");
+        boot[11..13].copy_from_slice(&spec.bytes_per_sector.to_le_bytes());
+        boot[13] = spec.sectors_per_cluster;
+        boot[14..16].copy_from_slice(&spec.reserved_sectors.to_le_bytes());
+        boot[16] = spec.num_fats;
+        boot[21] = 0xF8;
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        let file = self.handle();
+        if spec.fat_type != FatType::Fat32 {
+            boot[17..19].copy_from_slice(&spec.root_entries.to_le_bytes());
+            if spec.total_sectors <= u16::MAX as u32 {
+                boot[19..21].copy_from_slice(&(spec.total_sectors as u16).to_le_bytes());
+            } else {
+                boot[32..36].copy_from_slice(&spec.total_sectors.to_le_bytes());
+            }
+            boot[22..24].copy_from_slice(&(self.fat_size as u16).to_le_bytes());
+            boot[38] = 0x29;
+            boot[43..54].copy_from_slice(b"TEST       ");
+            boot[54..62].copy_from_slice(format!("{:<8}", spec.fat_type.to_string()).as_bytes());
+            file.write_all_at(&boot, 0).unwrap();
+            return;
+        }
+
+        boot[32..36].copy_from_slice(&spec.total_sectors.to_le_bytes());
+        boot[36..40].copy_from_slice(&self.fat_size.to_le_bytes());
+        boot[44..48].copy_from_slice(&self.root_cluster.to_le_bytes());
+        boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+        boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+        boot[66] = 0x29;
+        boot[71..82].copy_from_slice(b"TEST       ");
+        boot[82..90].copy_from_slice(b"FAT32   ");
+
+        let mut fsinfo = vec![0u8; bps];
+        fsinfo[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+        fsinfo[492..496].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+
+        file.write_all_at(&boot, 0).unwrap();
+        file.write_all_at(&fsinfo, bps as u64).unwrap();
+        file.write_all_at(&boot, 6 * bps as u64).unwrap();
+        file.write_all_at(&fsinfo, 7 * bps as u64).unwrap();
+    }
+
+    /// Lay out the exFAT bitmap, up-case table and root directory, then
+    /// write both boot regions
+    fn format_exfat(&mut self) {
+        let bitmap_clusters = (self.cluster_count as u64)
+            .div_ceil(8)
+            .div_ceil(self.cluster_bytes() as u64) as u32;
+        // Clusters are marked in the bitmap as they are allocated, starting
+        // with its own
+        self.bitmap_cluster = self.next_free;
+        let bitmap = self.allocate(bitmap_clusters, 1, true);
+        let upcase_table = pattern(0x82, 128);
+        let upcase = self.allocate(1, 1, true);
+        self.write_clusters(&upcase, &upcase_table);
+        self.root_cluster = self.allocate(1, 1, true)[0];
+
+        let mut bitmap_entry = [0u8; 32];
+        bitmap_entry[0] = 0x81;
+        bitmap_entry[20..24].copy_from_slice(&bitmap[0].to_le_bytes());
+        bitmap_entry[24..32]
+            .copy_from_slice(&(self.cluster_count as u64).div_ceil(8).to_le_bytes());
+        let mut upcase_entry = [0u8; 32];
+        upcase_entry[0] = 0x82;
+        upcase_entry[20..24].copy_from_slice(&upcase[0].to_le_bytes());
+        upcase_entry[24..32].copy_from_slice(&(upcase_table.len() as u64).to_le_bytes());
+        self.add_entries(self.root_cluster, &[bitmap_entry, upcase_entry]);
+
+        self.write_boot_regions(0);
+    }
+
+    /// Write both exFAT boot regions with their checksums
+    fn write_boot_regions(&self, volume_flags: u16) {
+        let spec = self.spec;
+        let bps = spec.bytes_per_sector as usize;
+        let mut region = vec![0u8; 12 * bps];
+        let boot = &mut region[..bps];
+        boot[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+        boot[3..11].copy_from_slice(b"EXFAT   ");
+        boot[72..80].copy_from_slice(&(spec.total_sectors as u64).to_le_bytes());
+        boot[80..84].copy_from_slice(&(spec.reserved_sectors as u32).to_le_bytes());
+        boot[84..88].copy_from_slice(&self.fat_size.to_le_bytes());
+        boot[88..92].copy_from_slice(&self.heap_offset.to_le_bytes());
+        boot[92..96].copy_from_slice(&self.cluster_count.to_le_bytes());
+        boot[96..100].copy_from_slice(&self.root_cluster.to_le_bytes());
+        boot[100..104].copy_from_slice(&0x1234ABCDu32.to_le_bytes());
+        boot[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+        boot[106..108].copy_from_slice(&volume_flags.to_le_bytes());
+        boot[108] = spec.bytes_per_sector.trailing_zeros() as u8;
+        boot[109] = spec.sectors_per_cluster.trailing_zeros() as u8;
+        boot[110] = 1;
+        boot[111] = 0x80;
+        boot[112] = (self.used_clusters() as u64 * 100 / self.cluster_count as u64) as u8;
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+        for sector in 1..9 {
+            region[(sector + 1) * bps - 4..(sector + 1) * bps]
+                .copy_from_slice(&0xAA550000u32.to_le_bytes());
+        }
+
+        let checksum = crate::exfat::boot_checksum(&region[..11 * bps]);
+        for word in region[11 * bps..].chunks_exact_mut(4) {
+            word.copy_from_slice(&checksum.to_le_bytes());
+        }
+        let file = self.handle();
+        file.write_all_at(&region, 0).unwrap();
+        file.write_all_at(&region, 12 * bps as u64).unwrap();
+    }
+
+    /// Mark an exFAT volume dirty, as a system that crashed while it was mounted
+    pub fn set_dirty(&self) {
+        self.write_boot_regions(0x0002);
+    }
+
+    /// First cluster of the exFAT allocation bitmap
+    pub fn bitmap_cluster(&self) -> u32 {
+        self.bitmap_cluster
+    }
+
+    /// First sector of the data area (the exFAT cluster heap)
+    pub fn heap_offset(&self) -> u32 {
+        self.heap_offset
+    }
+
+    fn used_clusters(&self) -> u32 {
+        let mut bitmap = vec![0u8; (self.cluster_count as usize).div_ceil(8)];
+        self.handle()
+            .read_exact_at(&mut bitmap, self.cluster_offset(self.bitmap_cluster))
+            .unwrap();
+        (0..self.cluster_count)
+            .filter(|&i| bitmap[i as usize / 8] & (1 << (i % 8)) != 0)
+            .count() as u32
+    }
+
+    /// Mark a cluster used in the exFAT allocation bitmap
+    fn set_used(&self, cluster: u32) {
+        let index = (cluster - 2) as u64;
+        let offset = self.cluster_offset(self.bitmap_cluster) + index / 8;
+        let file = self.handle();
+        let mut byte = [0u8];
+        file.read_exact_at(&mut byte, offset).unwrap();
+        byte[0] |= 1 << (index % 8);
+        file.write_all_at(&byte, offset).unwrap();
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        self.spec.bytes_per_sector as usize * self.spec.sectors_per_cluster as usize
+    }
+
+    fn root_dir_offset(&self) -> u64 {
+        (self.spec.reserved_sectors as u64 + self.spec.num_fats as u64 * self.fat_size as u64)
+            * self.spec.bytes_per_sector as u64
+    }
+
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.heap_offset as u64 + (cluster as u64 - 2) * self.spec.sectors_per_cluster as u64)
+            * self.spec.bytes_per_sector as u64
+    }
+
+    /// Set a FAT entry in every FAT copy
+    ///
+    /// Values are given in FAT32 terms; FAT12/16 keep the low bits, which
+    /// turns the FAT32 end-of-chain and bad-cluster markers into theirs.
+    pub fn set_fat(&mut self, cluster: u32, value: u32) {
+        let file = self.handle();
+        let bps = self.spec.bytes_per_sector as u64;
+        for fat in 0..self.spec.num_fats as u64 {
+            let base = (self.spec.reserved_sectors as u64 + fat * self.fat_size as u64) * bps;
+            match self.spec.fat_type {
+                FatType::Fat32 | FatType::ExFat => file
+                    .write_all_at(&value.to_le_bytes(), base + cluster as u64 * 4)
+                    .unwrap(),
+                FatType::Fat16 => file
+                    .write_all_at(&(value as u16).to_le_bytes(), base + cluster as u64 * 2)
+                    .unwrap(),
+                FatType::Fat12 => {
+                    let offset = base + cluster as u64 * 3 / 2;
+                    let mut word = [0u8; 2];
+                    file.read_exact_at(&mut word, offset).unwrap();
+                    let word = u16::from_le_bytes(word);
+                    let value = value as u16 & 0x0FFF;
+                    let word = if cluster & 1 == 1 {
+                        (word & 0x000F) | (value << 4)
+                    } else {
+                        (word & 0xF000) | value
+                    };
+                    file.write_all_at(&word.to_le_bytes(), offset).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Set the FAT entries of a range of clusters to the same value
+    pub fn set_fat_range(&mut self, clusters: std::ops::Range<u32>, value: u32) {
+        if !matches!(self.spec.fat_type, FatType::Fat32 | FatType::ExFat) {
+            for cluster in clusters {
+                self.set_fat(cluster, value);
+            }
+            return;
+        }
+
+        let entries: Vec<u8> = clusters.clone().flat_map(|_| value.to_le_bytes()).collect();
+        let file = self.handle();
+        let bps = self.spec.bytes_per_sector as u64;
+        for fat in 0..self.spec.num_fats as u64 {
+            let offset = (self.spec.reserved_sectors as u64 + fat * self.fat_size as u64) * bps
+                + clusters.start as u64 * 4;
+            file.write_all_at(&entries, offset).unwrap();
+        }
+    }
+
+    /// Write one cluster of data (padded with zeros)
+    pub fn write_cluster(&self, cluster: u32, data: &[u8]) {
+        let mut buf = vec![0u8; self.cluster_bytes()];
+        buf[..data.len()].copy_from_slice(data);
+        self.handle()
+            .write_all_at(&buf, self.cluster_offset(cluster))
+            .unwrap();
+    }
+
+    /// Write `contents` across the clusters of `chain`
+    fn write_clusters(&self, chain: &[u32], contents: &[u8]) {
+        for (i, &cluster) in chain.iter().enumerate() {
+            let start = (i * self.cluster_bytes()).min(contents.len());
+            let end = (start + self.cluster_bytes()).min(contents.len());
+            self.write_cluster(cluster, &contents[start..end]);
+        }
+    }
+
+    /// Allocate the next files and directories from `cluster` onwards
+    pub fn skip_to(&mut self, cluster: u32) {
+        self.next_free = cluster;
+    }
+
+    /// Allocate `count` clusters `stride` apart, chained in the FAT or not
+    /// (exFAT files without a FAT chain), and marked in the exFAT bitmap
+    fn allocate(&mut self, count: u32, stride: u32, fat_chain: bool) -> Vec<u32> {
+        let chain: Vec<u32> = (0..count).map(|i| self.next_free + i * stride).collect();
+        self.next_free = chain.last().unwrap() + 1;
+        for (i, &cluster) in chain.iter().enumerate() {
+            if self.is_exfat() {
+                self.set_used(cluster);
+            }
+            if fat_chain {
+                let next = chain.get(i + 1).copied().unwrap_or(self.end_of_chain());
+                self.set_fat(cluster, next);
+            }
+        }
+        chain
+    }
+
+    /// Add a file to the root directory, spreading its clusters `stride` apart
+    ///
+    /// A stride of 1 gives a contiguous file; larger strides fragment it.
+    /// Returns the cluster chain used.
+    pub fn add_file(&mut self, name: &[u8; 11], contents: &[u8], stride: u32) -> Vec<u32> {
+        self.add_file_in(self.root(), name, contents, stride)
+    }
+
+    /// Add a file to the directory starting at cluster `dir`
+    pub fn add_file_in(
+        &mut self,
+        dir: u32,
+        name: &[u8; 11],
+        contents: &[u8],
+        stride: u32,
+    ) -> Vec<u32> {
+        let count = contents.len().div_ceil(self.cluster_bytes()).max(1) as u32;
+        let chain = self.allocate(count, stride, true);
+        self.write_clusters(&chain, contents);
+        self.add_entry(dir, name, 0x20, chain[0], contents.len() as u32);
+        chain
+    }
+
+    /// Add a file with a long name to the root directory of an exFAT image
+    ///
+    /// Contiguous files (stride 1) get no FAT chain (NoFatChain), as
+    /// `mkfs.exfat` and most drivers write them.
+    pub fn add_exfat_file(&mut self, name: &str, contents: &[u8], stride: u32) -> Vec<u32> {
+        let count = contents.len().div_ceil(self.cluster_bytes()).max(1) as u32;
+        let contiguous = stride == 1;
+        let chain = self.allocate(count, stride, !contiguous);
+        self.write_clusters(&chain, contents);
+
+        let name: Vec<u16> = name.encode_utf16().collect();
+        assert!(name.len() <= 15, "name too long");
+        let mut file_entry = [0u8; 32];
+        file_entry[0] = 0x85;
+        file_entry[1] = 2;
+        file_entry[4] = 0x20;
+        let mut stream = [0u8; 32];
+        stream[0] = 0xC0;
+        stream[1] = if contiguous { 0x03 } else { 0x01 };
+        stream[3] = name.len() as u8;
+        stream[8..16].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+        stream[20..24].copy_from_slice(&chain[0].to_le_bytes());
+        stream[24..32].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+        let mut file_name = [0u8; 32];
+        file_name[0] = 0xC1;
+        for (i, unit) in name.iter().enumerate() {
+            file_name[2 + i * 2..4 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+
+        let mut checksum = 0u16;
+        for (i, byte) in [file_entry, stream, file_name].iter().flatten().enumerate() {
+            if i != 2 && i != 3 {
+                checksum = checksum.rotate_right(1).wrapping_add(*byte as u16);
+            }
+        }
+        file_entry[2..4].copy_from_slice(&checksum.to_le_bytes());
+        self.add_entries(self.root_cluster, &[file_entry, stream, file_name]);
+
+        // The percentage in use changed
+        self.write_boot_regions(0);
+        chain
+    }
+
+    /// Add an empty one-cluster subdirectory to the directory at `parent`
+    ///
+    /// Returns the cluster of the new directory.
+    pub fn add_dir(&mut self, parent: u32, name: &[u8; 11]) -> u32 {
+        let cluster = self.allocate(1, 1, true)[0];
+        self.write_cluster(cluster, &[]);
+
+        // ".." points at cluster 0 when the parent is the root directory
+        let parent_ref = if parent == self.root() { 0 } else { parent };
+        self.add_entry(cluster, b".          ", 0x10, cluster, 0);
+        self.add_entry(cluster, b"..         ", 0x10, parent_ref, 0);
+        self.add_entry(parent, name, 0x10, cluster, 0);
+        cluster
+    }
+
+    /// Append a short-name entry to a one-cluster directory, or the fixed
+    /// root directory
+    fn add_entry(&self, dir: u32, name: &[u8; 11], attributes: u8, first_cluster: u32, size: u32) {
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(name);
+        entry[11] = attributes;
+        entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+        entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        self.add_entries(dir, &[entry]);
+    }
+
+    /// Append entries to a one-cluster directory, or the fixed root directory
+    fn add_entries(&self, dir: u32, entries: &[[u8; 32]]) {
+        let file = self.handle();
+        let (base, capacity) = if dir == 0 {
+            (self.root_dir_offset(), self.spec.root_entries as usize * 32)
+        } else {
+            (self.cluster_offset(dir), self.cluster_bytes())
+        };
+        let mut entry = [0u8; 32];
+        let mut slot = 0u64;
+        while ((slot + 1) * 32) as usize <= capacity {
+            file.read_exact_at(&mut entry, base + slot * 32).unwrap();
+            if entry[0] == 0 {
+                break;
+            }
+            slot += 1;
+        }
+        assert!(
+            (slot as usize + entries.len()) * 32 <= capacity,
+            "directory full"
+        );
+        for (i, entry) in entries.iter().enumerate() {
+            file.write_all_at(entry, base + (slot + i as u64) * 32)
+                .unwrap();
+        }
+    }
+
+    /// Grow the backing file, as `truncate -s` would
+    pub fn extend_to_sectors(&self, total_sectors: u64) {
+        self.file
+            .as_file()
+            .set_len(total_sectors * self.spec.bytes_per_sector as u64)
+            .unwrap();
+    }
+}
+
+/// Raw view of a FAT image, independent of the code under test
+struct RawImage {
+    file: File,
+    bps: u64,
+    spc: u64,
+    reserved: u64,
+    num_fats: u64,
+    fat_size: u64,
+    root_dir_sectors: u64,
+    total_sectors: u64,
+    /// 0 for the fixed FAT12/16 root directory
+    root_cluster: u32,
+    boot: Vec<u8>,
+    /// Entries widened to FAT32 values
+    fat: Vec<u32>,
+}
+
+impl RawImage {
+    fn open(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let mut boot = vec![0u8; 512];
+        file.read_exact_at(&mut boot, 0).ok()?;
+        let u16_at = |i: usize| u16::from_le_bytes([boot[i], boot[i + 1]]) as u64;
+        let u32_at =
+            |i: usize| u32::from_le_bytes([boot[i], boot[i + 1], boot[i + 2], boot[i + 3]]);
+        let bps = u16_at(11);
+        let spc = boot[13] as u64;
+        let reserved = u16_at(14);
+        let num_fats = boot[16] as u64;
+        let root_dir_sectors = (u16_at(17) * 32).div_ceil(bps);
+        let fat_size = match u16_at(22) {
+            0 => u32_at(36) as u64,
+            size => size,
+        };
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32) as u64,
+            total => total,
+        };
+        let clusters = (total_sectors - reserved - num_fats * fat_size - root_dir_sectors) / spc;
+
+        let mut raw_fat = vec![0u8; (fat_size * bps) as usize];
+        file.read_exact_at(&mut raw_fat, reserved * bps).ok()?;
+        let (fat, root_cluster) = match FatType::from_cluster_count(clusters as u32) {
+            FatType::Fat32 => (
+                raw_fat
+                    .chunks_exact(4)
+                    .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]) & fat_entry::CLUSTER_MASK)
+                    .collect(),
+                u32_at(44),
+            ),
+            narrow => {
+                let bits = narrow.entry_bits() as usize;
+                let mask = (1u32 << bits) - 1;
+                let fat = (0..raw_fat.len() * 8 / bits)
+                    .map(|i| {
+                        let offset = i * bits / 8;
+                        let word = u16::from_le_bytes([raw_fat[offset], raw_fat[offset + 1]]);
+                        let raw = if bits == 12 && i % 2 == 1 {
+                            word as u32 >> 4
+                        } else {
+                            word as u32 & mask
+                        };
+                        // Widen end-of-chain and bad-cluster markers
+                        if raw >= mask - 8 {
+                            raw | (fat_entry::CLUSTER_MASK & !mask)
+                        } else {
+                            raw
+                        }
+                    })
+                    .collect();
+                (fat, 0)
+            }
+        };
+
+        Some(Self {
+            fat,
+            bps,
+            spc,
+            reserved,
+            num_fats,
+            fat_size,
+            root_dir_sectors,
+            total_sectors,
+            root_cluster,
+            boot,
+            file,
+        })
+    }
+
+    fn first_data_sector(&self) -> u64 {
+        self.reserved + self.num_fats * self.fat_size + self.root_dir_sectors
+    }
+
+    /// Contents of the root directory, wherever it lives
+    fn root_dir(&self) -> Vec<u8> {
+        if self.root_cluster == 0 {
+            let start = self.first_data_sector() - self.root_dir_sectors;
+            self.read(
+                start * self.bps,
+                (self.root_dir_sectors * self.bps) as usize,
+            )
+        } else {
+            self.read_chain(self.root_cluster)
+        }
+    }
+
+    fn cluster_bytes(&self) -> usize {
+        (self.bps * self.spc) as usize
+    }
+
+    /// First cluster number past the end of the data area
+    fn max_cluster(&self) -> u32 {
+        ((self.total_sectors - self.first_data_sector()) / self.spc) as u32 + 2
+    }
+
+    fn read(&self, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    fn fat_entry(&self, cluster: u32) -> u32 {
+        self.fat[cluster as usize]
+    }
+
+    fn chain(&self, first: u32) -> Vec<u32> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while (2..fat_entry::BAD_CLUSTER).contains(&cluster) {
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster);
+        }
+        chain
+    }
+
+    fn read_chain(&self, first: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for cluster in self.chain(first) {
+            let offset = (self.first_data_sector() + (cluster as u64 - 2) * self.spc) * self.bps;
+            data.extend(self.read(offset, self.cluster_bytes()));
+        }
+        data
+    }
+}
+
+/// Entries of a directory that are in use, up to the end marker
+fn directory_entries(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.chunks(32)
+        .take_while(|entry| entry[0] != 0)
+        .filter(|entry| entry[0] != 0xE5 && entry[11] & 0x3F != 0x0F && entry[11] & 0x08 == 0)
+}
+
+fn entry_cluster(entry: &[u8]) -> u32 {
+    let hi = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let lo = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+    (hi << 16) | lo
+}
+
+fn entry_size(entry: &[u8]) -> usize {
+    u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]) as usize
+}
+
+/// Read a file from the root directory of any FAT32 image, following the FAT
+pub fn read_root_file(path: &Path, name: &[u8; 11]) -> Option<Vec<u8>> {
+    read_file(path, &[name])
+}
+
+/// Read a file by its path of short names, e.g. `&[b"SUBDIR     ", b"FILE    TXT"]`
+pub fn read_file(path: &Path, names: &[&[u8; 11]]) -> Option<Vec<u8>> {
+    let image = RawImage::open(path)?;
+    let (file_name, dirs) = names.split_last()?;
+
+    let mut dir = image.root_dir();
+    for name in dirs {
+        let entry = directory_entries(&dir).find(|e| &e[0..11] == *name)?;
+        dir = image.read_chain(entry_cluster(entry));
+    }
+
+    let entry = directory_entries(&dir).find(|e| &e[0..11] == *file_name)?;
+    let mut data = image.read_chain(entry_cluster(entry));
+    data.truncate(entry_size(entry));
+    Some(data)
+}
+
+/// Check an image the way `fsck.fat` would, panicking on the first problem
+///
+/// Verifies the boot sector and its backup, that all FAT copies match, that
+/// every chain reachable from the directory tree stays inside the filesystem
+/// without cross-links, that no cluster is lost, and that FSInfo's free count
+/// is right. FAT12/16 images have no backup boot sector or FSInfo to check.
+pub fn assert_consistent(path: &Path) {
+    let image = RawImage::open(path).expect("image readable");
+    let is_fat32 = image.root_cluster != 0;
+    assert_eq!(&image.boot[510..512], &[0x55, 0xAA], "boot signature");
+    if is_fat32 {
+        assert_eq!(
+            image.read(6 * image.bps, 512),
+            image.boot,
+            "backup boot sector differs"
+        );
+    }
+    assert!(image.total_sectors * image.bps <= image.file.metadata().unwrap().len());
+
+    let fat_bytes = (image.fat_size * image.bps) as usize;
+    let fat1 = image.read(image.reserved * image.bps, fat_bytes);
+    for fat in 1..image.num_fats {
+        let copy = image.read(
+            (image.reserved + fat * image.fat_size) * image.bps,
+            fat_bytes,
+        );
+        assert!(copy == fat1, "FAT copy {} differs from FAT1", fat);
+    }
+
+    let max_cluster = image.max_cluster();
+    let mut used = std::collections::HashSet::new();
+    let mut claim = |chain: &[u32], what: &str| {
+        for &cluster in chain {
+            assert!(
+                cluster < max_cluster,
+                "{} uses cluster {} past the end",
+                what,
+                cluster
+            );
+            assert!(
+                used.insert(cluster),
+                "{} cross-linked at cluster {}",
+                what,
+                cluster
+            );
+        }
+    };
+
+    let root_chain = image.chain(image.root_cluster);
+    claim(&root_chain, "root directory");
+    let mut pending = vec![image.root_dir()];
+    while let Some(dir) = pending.pop() {
+        for entry in directory_entries(&dir).filter(|e| e[0] != b'.') {
+            let name = String::from_utf8_lossy(&entry[0..11]).to_string();
+            let first = entry_cluster(entry);
+            let chain = if first == 0 {
+                Vec::new()
+            } else {
+                image.chain(first)
+            };
+            assert!(
+                first == 0 || fat_entry::is_end_of_chain(image.fat_entry(*chain.last().unwrap())),
+                "{} has a broken chain",
+                name
+            );
+            claim(&chain, &name);
+            if entry[11] & 0x10 != 0 {
+                let data = image.read_chain(first);
+                assert_eq!(entry_cluster(&data[0..32]), first, "{} '.' entry", name);
+                pending.push(data);
+            } else if entry_size(entry) > 0 {
+                assert_eq!(
+                    chain.len(),
+                    entry_size(entry).div_ceil(image.cluster_bytes()),
+                    "{} chain length",
+                    name
+                );
+            }
+        }
+    }
+
+    let mut free = 0;
+    for cluster in 2..max_cluster {
+        let entry = image.fat_entry(cluster);
+        if fat_entry::is_free(entry) {
+            free += 1;
+        } else if !fat_entry::is_bad(entry) {
+            assert!(used.contains(&cluster), "cluster {} is lost", cluster);
+        }
+    }
+
+    if !is_fat32 {
+        return;
+    }
+    let fsinfo = image.read(image.bps, 512);
+    let free_count = u32::from_le_bytes([fsinfo[488], fsinfo[489], fsinfo[490], fsinfo[491]]);
+    if free_count != 0xFFFFFFFF {
+        assert_eq!(free_count, free, "FSInfo free count");
+    }
+}
+
+/// Raw view of an exFAT image, independent of the code under test
+struct RawExfat {
+    file: File,
+    bps: u64,
+    spc: u64,
+    boot: Vec<u8>,
+    fat_offset: u64,
+    heap_offset: u64,
+    cluster_count: u32,
+}
+
+/// A file or directory in an exFAT root directory
+struct ExfatFile {
+    name: String,
+    first_cluster: u32,
+    length: u64,
+    no_fat_chain: bool,
+}
+
+impl RawExfat {
+    fn open(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let mut boot = vec![0u8; 512];
+        file.read_exact_at(&mut boot, 0).ok()?;
+        let u32_at = |i: usize| u32::from_le_bytes(boot[i..i + 4].try_into().unwrap());
+        Some(Self {
+            bps: 1 << boot[108],
+            spc: 1 << boot[109],
+            fat_offset: u32_at(80) as u64,
+            heap_offset: u32_at(88) as u64,
+            cluster_count: u32_at(92),
+            file,
+            boot,
+        })
+    }
+
+    fn u64_at(&self, i: usize) -> u64 {
+        u64::from_le_bytes(self.boot[i..i + 8].try_into().unwrap())
+    }
+
+    fn read(&self, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.file.read_exact_at(&mut buf, offset).unwrap();
+        buf
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.bps * self.spc
+    }
+
+    fn fat_entry(&self, cluster: u32) -> u32 {
+        let data = self.read(self.fat_offset * self.bps + cluster as u64 * 4, 4);
+        u32::from_le_bytes(data.try_into().unwrap())
+    }
+
+    /// Clusters of a file, following the FAT unless it has no FAT chain
+    fn clusters(&self, first: u32, length: u64, no_fat_chain: bool) -> Vec<u32> {
+        if first == 0 {
+            return Vec::new();
+        }
+        if no_fat_chain {
+            let count = length.div_ceil(self.cluster_bytes()) as u32;
+            return (first..first + count).collect();
+        }
+        let mut chain = vec![first];
+        loop {
+            let next = self.fat_entry(*chain.last().unwrap());
+            if next >= 0xFFFFFFF8 {
+                return chain;
+            }
+            assert!(
+                chain.len() <= self.cluster_count as usize
+                    && (2..self.cluster_count + 2).contains(&next),
+                "broken chain at cluster {}",
+                chain.last().unwrap()
+            );
+            chain.push(next);
+        }
+    }
+
+    fn read_clusters(&self, clusters: &[u32]) -> Vec<u8> {
+        clusters
+            .iter()
+            .flat_map(|&cluster| {
+                let offset = (self.heap_offset + (cluster as u64 - 2) * self.spc) * self.bps;
+                self.read(offset, self.cluster_bytes() as usize)
+            })
+            .collect()
+    }
+
+    fn root_entries(&self) -> Vec<Vec<u8>> {
+        let root_cluster = u32::from_le_bytes(self.boot[96..100].try_into().unwrap());
+        let data = self.read_clusters(&self.clusters(root_cluster, 0, false));
+        data.chunks(32)
+            .take_while(|entry| entry[0] != 0)
+            .map(|entry| entry.to_vec())
+            .collect()
+    }
+
+    fn root_files(&self) -> Vec<ExfatFile> {
+        let entries = self.root_entries();
+        let mut files = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if entry[0] != 0x85 {
+                continue;
+            }
+            let stream = &entries[i + 1];
+            let name_length = stream[3] as usize;
+            let units: Vec<u16> = entries[i + 2..=i + entry[1] as usize]
+                .iter()
+                .flat_map(|name| name[2..32].chunks(2))
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take(name_length)
+                .collect();
+            files.push(ExfatFile {
+                name: String::from_utf16(&units).unwrap(),
+                first_cluster: u32::from_le_bytes(stream[20..24].try_into().unwrap()),
+                length: u64::from_le_bytes(stream[24..32].try_into().unwrap()),
+                no_fat_chain: stream[1] & 0x02 != 0,
+            });
+        }
+        files
+    }
+}
+
+/// Read a file from the root directory of an exFAT image
+pub fn read_exfat_file(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let image = RawExfat::open(path)?;
+    let file = image
+        .root_files()
+        .into_iter()
+        .find(|file| file.name == name)?;
+    let mut data =
+        image.read_clusters(&image.clusters(file.first_cluster, file.length, file.no_fat_chain));
+    data.truncate(file.length as usize);
+    Some(data)
+}
+
+/// Check an exFAT image the way `fsck.exfat` would, panicking on the first problem
+///
+/// Verifies both boot regions and their checksums, that the layout fits the
+/// volume, and that the allocation bitmap marks exactly the clusters of the
+/// bitmap, the up-case table, the root directory and its files.
+pub fn assert_exfat_consistent(path: &Path) {
+    let image = RawExfat::open(path).expect("image readable");
+    assert_eq!(&image.boot[510..512], &[0x55, 0xAA], "boot signature");
+
+    let bps = image.bps as usize;
+    let main = image.read(0, 12 * bps);
+    let backup = image.read(12 * image.bps, 12 * bps);
+    for (name, region) in [("main", &main), ("backup", &backup)] {
+        let checksum = crate::exfat::boot_checksum(&region[..11 * bps]);
+        assert!(
+            region[11 * bps..]
+                .chunks_exact(4)
+                .all(|word| word == checksum.to_le_bytes()),
+            "{} boot region checksum",
+            name
+        );
+    }
+    for (i, (a, b)) in main.iter().zip(&backup).enumerate() {
+        assert!(
+            a == b || matches!(i, 106 | 107 | 112),
+            "backup boot region differs at byte {}",
+            i
+        );
+    }
+
+    let volume_length = image.u64_at(72);
+    let fat_length = u32::from_le_bytes(image.boot[84..88].try_into().unwrap()) as u64;
+    assert!(volume_length * image.bps <= image.file.metadata().unwrap().len());
+    assert!(
+        image.fat_offset + fat_length <= image.heap_offset,
+        "FAT overlaps the heap"
+    );
+    assert!(
+        (image.cluster_count as u64 + 2) * 4 <= fat_length * image.bps,
+        "FAT too small"
+    );
+    assert!(
+        image.heap_offset + image.cluster_count as u64 * image.spc <= volume_length,
+        "cluster heap past the end of the volume"
+    );
+
+    let entries = image.root_entries();
+    let find = |entry_type: u8| {
+        let entry = entries
+            .iter()
+            .find(|entry| entry[0] == entry_type)
+            .expect("root directory entry");
+        (
+            u32::from_le_bytes(entry[20..24].try_into().unwrap()),
+            u64::from_le_bytes(entry[24..32].try_into().unwrap()),
+        )
+    };
+    let (bitmap_cluster, bitmap_length) = find(0x81);
+    let (upcase_cluster, upcase_length) = find(0x82);
+    assert!(
+        bitmap_length >= (image.cluster_count as u64).div_ceil(8),
+        "bitmap too short"
+    );
+
+    let mut used = std::collections::HashSet::new();
+    let mut claim = |clusters: &[u32], what: &str| {
+        for &cluster in clusters {
+            assert!(
+                (2..image.cluster_count + 2).contains(&cluster),
+                "{} uses cluster {} outside the heap",
+                what,
+                cluster
+            );
+            assert!(
+                used.insert(cluster),
+                "{} cross-linked at cluster {}",
+                what,
+                cluster
+            );
+        }
+    };
+    let bitmap_clusters = image.clusters(bitmap_cluster, bitmap_length, false);
+    assert_eq!(
+        bitmap_clusters.len() as u64,
+        bitmap_length.div_ceil(image.cluster_bytes()),
+        "bitmap chain length"
+    );
+    claim(&bitmap_clusters, "allocation bitmap");
+    claim(
+        &image.clusters(upcase_cluster, upcase_length, false),
+        "up-case table",
+    );
+    let root_cluster = u32::from_le_bytes(image.boot[96..100].try_into().unwrap());
+    claim(&image.clusters(root_cluster, 0, false), "root directory");
+    for file in image.root_files() {
+        let clusters = image.clusters(file.first_cluster, file.length, file.no_fat_chain);
+        assert_eq!(
+            clusters.len() as u64,
+            file.length.div_ceil(image.cluster_bytes()),
+            "{} chain length",
+            file.name
+        );
+        claim(&clusters, &file.name);
+    }
+
+    let mut bitmap = image.read_clusters(&bitmap_clusters);
+    bitmap.truncate(bitmap_length as usize);
+    let mut in_use = 0u64;
+    for index in 0..bitmap_length * 8 {
+        let set = bitmap[index as usize / 8] & (1 << (index % 8)) != 0;
+        let cluster = index as u32 + 2;
+        if index >= image.cluster_count as u64 {
+            assert!(!set, "bitmap marks cluster {} past the heap", cluster);
+            continue;
+        }
+        assert_eq!(
+            set,
+            used.contains(&cluster),
+            "bitmap bit of cluster {}",
+            cluster
+        );
+        in_use += set as u64;
+    }
+
+    if image.boot[112] != 0xFF {
+        assert_eq!(
+            image.boot[112] as u64,
+            in_use * 100 / image.cluster_count as u64,
+            "percent in use"
+        );
+    }
+}
+
+// ===== Partitioned disk images =====
+
+/// Partitions start on 1 MiB boundaries, as partitioning tools place them
+const PARTITION_ALIGN: u64 = 2048;
+
+/// A whole-disk image with an MBR or GPT partition table, each partition
+/// holding a copy of a filesystem image
+pub struct DiskImage {
+    file: NamedTempFile,
+    /// Number, first sector and length in sectors of each partition
+    partitions: Vec<(u32, u64, u64)>,
+}
+
+impl DiskImage {
+    /// Lay out partitions of the given lengths, copying each image into its
+    /// partition; the disk has 1 MiB of space after the last partition
+    fn create(sizes: &[(&Path, u64)], first_start: u64) -> (Self, Vec<u64>) {
+        let mut starts = Vec::new();
+        let mut next = first_start;
+        for &(_, sectors) in sizes {
+            starts.push(next);
+            next = (next + sectors).div_ceil(PARTITION_ALIGN) * PARTITION_ALIGN;
+        }
+        let file = NamedTempFile::new().unwrap();
+        file.as_file()
+            .set_len((next + PARTITION_ALIGN) * 512)
+            .unwrap();
+        for (&(image, sectors), &start) in sizes.iter().zip(&starts) {
+            let mut data = std::fs::read(image).unwrap();
+            data.truncate((sectors * 512) as usize);
+            file.as_file().write_all_at(&data, start * 512).unwrap();
+        }
+        let image = Self {
+            file,
+            partitions: Vec::new(),
+        };
+        (image, starts)
+    }
+
+    /// Disk image with up to four primary MBR partitions of the given
+    /// system IDs and lengths in sectors
+    pub fn mbr(partitions: &[(u8, &Path, u64)]) -> Self {
+        let sizes: Vec<_> = partitions
+            .iter()
+            .map(|&(_, path, len)| (path, len))
+            .collect();
+        let (mut disk, starts) = Self::create(&sizes, PARTITION_ALIGN);
+        let mut mbr = vec![0u8; 512];
+        for (i, (&(system_id, _, sectors), &start)) in partitions.iter().zip(&starts).enumerate() {
+            Self::write_mbr_entry(&mut mbr, i, system_id, start, sectors);
+            disk.partitions.push((i as u32 + 1, start, sectors));
+        }
+        disk.write_sector(0, &mbr);
+        disk
+    }
+
+    /// Disk image with one extended partition (1) holding one logical
+    /// partition (5)
+    pub fn mbr_logical(system_id: u8, image: &Path, sectors: u64) -> Self {
+        let ebr_start = PARTITION_ALIGN;
+        let (mut disk, starts) = Self::create(&[(image, sectors)], ebr_start + PARTITION_ALIGN);
+        // The extended partition reaches up to the free space at the end
+        let extended_end = disk.file.as_file().metadata().unwrap().len() / 512 - PARTITION_ALIGN;
+        let mut mbr = vec![0u8; 512];
+        Self::write_mbr_entry(&mut mbr, 0, 0x0F, ebr_start, extended_end - ebr_start);
+        disk.write_sector(0, &mbr);
+        let mut ebr = vec![0u8; 512];
+        Self::write_mbr_entry(&mut ebr, 0, system_id, starts[0] - ebr_start, sectors);
+        disk.write_sector(ebr_start, &ebr);
+        disk.partitions.push((5, starts[0], sectors));
+        disk
+    }
+
+    /// Disk image with a GPT of the given partition types and lengths, the
+    /// partitions named `part1`, `part2`, ...
+    pub fn gpt(partitions: &[([u8; 16], &Path, u64)]) -> Self {
+        let sizes: Vec<_> = partitions
+            .iter()
+            .map(|&(_, path, len)| (path, len))
+            .collect();
+        let (mut disk, starts) = Self::create(&sizes, PARTITION_ALIGN);
+        let total = disk.file.as_file().metadata().unwrap().len() / 512;
+
+        let mut entries = vec![0u8; 128 * 128];
+        for (i, (&(type_guid, _, sectors), &start)) in partitions.iter().zip(&starts).enumerate() {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[0..16].copy_from_slice(&type_guid);
+            entry[16..32].fill(i as u8 + 1);
+            entry[32..40].copy_from_slice(&start.to_le_bytes());
+            entry[40..48].copy_from_slice(&(start + sectors - 1).to_le_bytes());
+            for (j, unit) in format!("part{}", i + 1).encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            disk.partitions.push((i as u32 + 1, start, sectors));
+        }
+
+        let mut mbr = vec![0u8; 512];
+        Self::write_mbr_entry(&mut mbr, 0, 0xEE, 1, (total - 1).min(u32::MAX as u64));
+        disk.write_sector(0, &mbr);
+
+        // Primary header and entries at the start, backup ones at the end
+        let entries_crc = crc32fast::hash(&entries);
+        for (current, backup, entries_lba) in [(1, total - 1, 2), (total - 1, 1, total - 33)] {
+            let mut header = vec![0u8; 512];
+            header[0..8].copy_from_slice(b"EFI PART");
+            header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&current.to_le_bytes());
+            header[32..40].copy_from_slice(&backup.to_le_bytes());
+            header[40..48].copy_from_slice(&34u64.to_le_bytes());
+            header[48..56].copy_from_slice(&(total - 34).to_le_bytes());
+            header[56..72].fill(0x42);
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&128u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let crc = crc32fast::hash(&header[..92]);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+            disk.write_sector(current, &header);
+            disk.file
+                .as_file()
+                .write_all_at(&entries, entries_lba * 512)
+                .unwrap();
+        }
+        disk
+    }
+
+    fn write_mbr_entry(sector: &mut [u8], slot: usize, system_id: u8, start: u64, sectors: u64) {
+        let entry = &mut sector[446 + slot * 16..462 + slot * 16];
+        entry[4] = system_id;
+        entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+        entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+    }
+
+    fn write_sector(&mut self, sector: u64, data: &[u8]) {
+        self.file
+            .as_file()
+            .write_all_at(data, sector * 512)
+            .unwrap();
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Extend the disk, as growing an image or cloning to a larger card would
+    pub fn extend_to_sectors(&self, total_sectors: u64) {
+        self.file.as_file().set_len(total_sectors * 512).unwrap();
+    }
+
+    /// Flip the bits of one byte of the disk
+    pub fn corrupt(&self, offset: u64) {
+        let mut byte = [0u8];
+        self.file
+            .as_file()
+            .read_exact_at(&mut byte, offset)
+            .unwrap();
+        self.file
+            .as_file()
+            .write_all_at(&[!byte[0]], offset)
+            .unwrap();
+    }
+
+    /// Contents of a partition
+    pub fn read_partition(&self, number: u32) -> Vec<u8> {
+        let &(_, start, sectors) = self
+            .partitions
+            .iter()
+            .find(|&&(n, _, _)| n == number)
+            .unwrap();
+        let mut data = vec![0u8; (sectors * 512) as usize];
+        self.file
+            .as_file()
+            .read_exact_at(&mut data, start * 512)
+            .unwrap();
+        data
+    }
+
+    /// Copy a partition to its own image, for the raw image readers
+    pub fn extract_partition(&self, number: u32) -> NamedTempFile {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), self.read_partition(number)).unwrap();
+        file
+    }
+}
+
+// ===== qcow2 images =====
+
+/// A qcow2 image in a temporary file, built without `qemu-img`
+pub struct Qcow2Disk {
+    file: NamedTempFile,
+}
+
+impl Qcow2Disk {
+    /// Empty version 3 image of `size` bytes with clusters of
+    /// `1 << cluster_bits` bytes and 16-bit refcounts
+    ///
+    /// Laid out as `qemu-img create` does: header, refcount table, one
+    /// refcount block, then the L1 table.
+    pub fn create(size: u64, cluster_bits: u32) -> Self {
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_entries = size.div_ceil(cluster_size * cluster_size / 8);
+        let l1_clusters = (l1_entries * 8).div_ceil(cluster_size).max(1);
+        let used_clusters = 3 + l1_clusters;
+        assert!(
+            used_clusters <= cluster_size / 2,
+            "refcount block too small"
+        );
+
+        let file = NamedTempFile::new().unwrap();
+        let mut header = vec![0u8; 104];
+        header[0..4].copy_from_slice(b"QFI\xfb");
+        header[4..8].copy_from_slice(&3u32.to_be_bytes());
+        header[20..24].copy_from_slice(&cluster_bits.to_be_bytes());
+        header[24..32].copy_from_slice(&size.to_be_bytes());
+        header[36..40].copy_from_slice(&(l1_entries as u32).to_be_bytes());
+        header[40..48].copy_from_slice(&(3 * cluster_size).to_be_bytes());
+        header[48..56].copy_from_slice(&cluster_size.to_be_bytes());
+        header[56..60].copy_from_slice(&1u32.to_be_bytes());
+        header[96..100].copy_from_slice(&4u32.to_be_bytes());
+        header[100..104].copy_from_slice(&104u32.to_be_bytes());
+
+        let mut data = vec![0u8; (used_clusters * cluster_size) as usize];
+        data[..104].copy_from_slice(&header);
+        let table = cluster_size as usize;
+        data[table..table + 8].copy_from_slice(&(2 * cluster_size).to_be_bytes());
+        let block = 2 * cluster_size as usize;
+        for cluster in 0..used_clusters as usize {
+            data[block + cluster * 2..block + cluster * 2 + 2].copy_from_slice(&1u16.to_be_bytes());
+        }
+        std::fs::write(file.path(), data).unwrap();
+        Self { file }
+    }
+
+    /// Image holding a copy of a raw image, written through [`Device`](crate::device::Device)
+    pub fn from_raw(raw: &Path, cluster_bits: u32) -> Self {
+        let data = std::fs::read(raw).unwrap();
+        let disk = Self::create(data.len() as u64, cluster_bits);
+        let device = crate::device::Device::open(disk.path()).unwrap();
+        for (i, chunk) in data.chunks(1 << 16).enumerate() {
+            device.write_bytes_at(i as u64 * (1 << 16), chunk).unwrap();
+        }
+        disk
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Size of the image file, not of its virtual disk
+    pub fn file_size(&self) -> u64 {
+        self.file.as_file().metadata().unwrap().len()
+    }
+
+    /// Copy the virtual disk to a raw image, for the raw image readers
+    pub fn to_raw(&self) -> NamedTempFile {
+        let device = crate::device::Device::open_readonly(self.path()).unwrap();
+        let size = device.size_bytes().unwrap();
+        let file = NamedTempFile::new().unwrap();
+        file.as_file().set_len(size).unwrap();
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(1 << 20);
+            let data = device.read_bytes_at(offset, len as usize).unwrap();
+            file.as_file().write_all_at(&data, offset).unwrap();
+            offset += len;
+        }
+        file
+    }
+
+    fn read_be(&self, offset: u64, len: usize) -> u64 {
+        let mut buf = vec![0u8; len];
+        self.file.as_file().read_exact_at(&mut buf, offset).unwrap();
+        buf.iter().fold(0, |value, &b| value << 8 | b as u64)
+    }
+
+    /// Check the image the way `qemu-img check` would, panicking on the
+    /// first problem
+    ///
+    /// Every cluster of the file must have the refcount its uses add up to
+    /// (no leaks, no missing counts), and every L1 and L2 entry must have
+    /// the COPIED flag.
+    pub fn assert_consistent(&self) {
+        let cluster_bits = self.read_be(20, 4);
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_size = self.read_be(36, 4);
+        let l1_offset = self.read_be(40, 8);
+        let table_offset = self.read_be(48, 8);
+        let table_clusters = self.read_be(56, 4);
+        let file_clusters = self.file_size().div_ceil(cluster_size);
+        let mut expected = vec![0u64; file_clusters as usize];
+        let mut add = |offset: u64, clusters: u64| {
+            assert_eq!(offset % cluster_size, 0, "unaligned cluster {:#x}", offset);
+            for i in 0..clusters {
+                let cluster = (offset / cluster_size + i) as usize;
+                assert!(
+                    cluster < expected.len(),
+                    "cluster {:#x} past the end",
+                    offset
+                );
+                expected[cluster] += 1;
+            }
+        };
+
+        add(0, 1);
+        add(table_offset, table_clusters);
+        add(l1_offset, (l1_size * 8).div_ceil(cluster_size));
+        let mask = 0x00ff_ffff_ffff_fe00;
+        for i in 0..l1_size {
+            let l1_entry = self.read_be(l1_offset + i * 8, 8);
+            if l1_entry == 0 {
+                continue;
+            }
+            assert_ne!(l1_entry & 1 << 63, 0, "L1 entry {} not COPIED", i);
+            let l2_offset = l1_entry & mask;
+            add(l2_offset, 1);
+            for j in 0..cluster_size / 8 {
+                let entry = self.read_be(l2_offset + j * 8, 8);
+                if entry & mask != 0 {
+                    assert_ne!(entry & 1 << 63, 0, "L2 entry {} not COPIED", j);
+                    add(entry & mask, 1);
+                }
+            }
+        }
+
+        let block_entries = cluster_size * 8 / 16;
+        let mut blocks = Vec::new();
+        for i in 0..table_clusters * cluster_size / 8 {
+            let block = self.read_be(table_offset + i * 8, 8);
+            if block != 0 {
+                add(block, 1);
+                blocks.push((i, block));
+            }
+        }
+        for cluster in 0..file_clusters {
+            let stored = blocks
+                .iter()
+                .find(|&&(i, _)| i == cluster / block_entries)
+                .map_or(0, |&(_, block)| {
+                    self.read_be(block + cluster % block_entries * 2, 2)
+                });
+            assert_eq!(
+                stored, expected[cluster as usize],
+                "refcount of cluster {}",
+                cluster
+            );
+        }
+    }
+}
+
+/// Deterministic test pattern that differs per file and per offset
+pub fn pattern(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u32).wrapping_mul(31).wrapping_add(seed as u32 * 97) as u8)
+        .collect()
+}
+
+/// Interrupt an operation after every `step`th write in turn, and check that
+/// running it again finishes the job
+///
+/// `setup` builds a fresh image for every attempt and `options` the options
+/// `run` gets for it. Once the boot sector is written only the checkpoint is
+/// left to clear, so a run that `finished` recognizes is not repeated. `check`
+/// verifies the image after every attempt. Returns the number of writes the
+/// uninterrupted operation took, rounded up to `step`.
+pub fn assert_resumes_after_crashes<T, R>(
+    step: u64,
+    setup: impl Fn() -> T,
+    options: impl Fn(&T) -> ResizeOptions,
+    run: impl Fn(ResizeOptions) -> Result<R>,
+    finished: impl Fn(&T) -> bool,
+    check: impl Fn(&T),
+) -> u64 {
+    let mut crash_at = 0;
+    loop {
+        let image = setup();
+        if run(options(&image).crash_after_writes(crash_at)).is_ok() {
+            return crash_at;
+        }
+        if !finished(&image) {
+            if let Err(e) = run(options(&image)) {
+                panic!("resume after crash at write {}: {}", crash_at, e);
+            }
+        }
+        check(&image);
+        crash_at += step;
+    }
+}
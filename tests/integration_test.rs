@@ -1,9 +1,19 @@
-use fat32expander::{get_fs_info, resize_fat32, ResizeOptions};
+use fat32expander::{
+    check_gpt, get_exfat_info, get_fs_info, get_fs_info_at, grow_image, grow_partition,
+    list_partitions, repair_gpt, resize_fat32, shrink_fat32, FatType, GrowStrategy, ResizeOptions,
+    TargetSize, VolumeLocation,
+};
+use std::io::Write;
 use std::process::Command;
 use tempfile::NamedTempFile;
 
 /// Create a FAT32 test image of the specified size in MB
 fn create_fat32_image(size_mb: u32) -> NamedTempFile {
+    create_fat_image(size_mb, 32)
+}
+
+/// Create a FAT12, FAT16 or FAT32 test image of the specified size in MB
+fn create_fat_image(size_mb: u32, fat_bits: u8) -> NamedTempFile {
     let file = NamedTempFile::new().expect("Failed to create temp file");
     let path = file.path();
 
@@ -15,10 +25,10 @@ fn create_fat32_image(size_mb: u32) -> NamedTempFile {
         .status()
         .expect("Failed to truncate file");
 
-    // Format as FAT32
+    // Format with the requested FAT type
     let status = Command::new("mkfs.fat")
         .arg("-F")
-        .arg("32")
+        .arg(fat_bits.to_string())
         .arg(path)
         .status()
         .expect("Failed to run mkfs.fat");
@@ -28,6 +38,28 @@ fn create_fat32_image(size_mb: u32) -> NamedTempFile {
     file
 }
 
+/// Create an exFAT test image of the specified size in MB
+fn create_exfat_image(size_mb: u32) -> NamedTempFile {
+    let file = NamedTempFile::new().expect("Failed to create temp file");
+    let path = file.path();
+
+    Command::new("truncate")
+        .arg("-s")
+        .arg(format!("{}M", size_mb))
+        .arg(path)
+        .status()
+        .expect("Failed to truncate file");
+
+    let status = Command::new("mkfs.exfat")
+        .arg(path)
+        .status()
+        .expect("Failed to run mkfs.exfat");
+
+    assert!(status.success(), "mkfs.exfat failed");
+
+    file
+}
+
 /// Extend an image file to a larger size
 fn extend_image(path: &std::path::Path, new_size_mb: u32) {
     Command::new("truncate")
@@ -38,6 +70,30 @@ fn extend_image(path: &std::path::Path, new_size_mb: u32) {
         .expect("Failed to extend file");
 }
 
+/// Partition an image with an sfdisk script and put a 64 MiB FAT32
+/// filesystem at 1 MiB
+fn partition_image(path: &std::path::Path, script: &str) {
+    let mut sfdisk = Command::new("sfdisk")
+        .arg(path)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to run sfdisk");
+    sfdisk
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    assert!(sfdisk.wait().unwrap().success(), "sfdisk failed");
+    let status = Command::new("mkfs.fat")
+        .args(["-F", "32", "--offset", "2048"])
+        .arg(path)
+        .arg("65536")
+        .status()
+        .expect("Failed to run mkfs.fat");
+    assert!(status.success(), "mkfs.fat failed");
+}
+
 /// Run dosfsck on an image and check for errors
 fn check_filesystem(path: &std::path::Path) -> bool {
     let output = Command::new("dosfsck")
@@ -187,3 +243,255 @@ fn test_resize_with_data() {
     // Verify filesystem integrity
     assert!(check_filesystem(image.path()), "Filesystem check failed");
 }
+
+#[test]
+#[ignore] // Requires mkfs.fat and dosfsck
+fn test_resize_to_target_size() {
+    // Create a 128MB FAT32 image
+    let image = create_fat32_image(128);
+
+    // Extend to 512MB but only grow the filesystem to 256MB
+    extend_image(image.path(), 512);
+
+    let options = ResizeOptions::new(image.path())
+        .dry_run(false)
+        .verbose(false)
+        .target_size(Some(TargetSize::Absolute(256 * 1024 * 1024)));
+    let result = resize_fat32(options).expect("Resize failed");
+
+    assert!(result.new_size_bytes <= 256 * 1024 * 1024);
+    assert!(result.new_size_bytes > 255 * 1024 * 1024);
+
+    // The rest of the device is still available
+    let info_after = get_fs_info(image.path()).expect("Failed to get fs info");
+    assert!(info_after.can_grow, "Should still be able to grow further");
+
+    // Verify filesystem integrity
+    assert!(check_filesystem(image.path()), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.fat and dosfsck
+fn test_shrink() {
+    // Create a 1GB FAT32 image and shrink it to 512MB
+    let image = create_fat32_image(1024);
+
+    let options = ResizeOptions::new(image.path())
+        .dry_run(false)
+        .verbose(false)
+        .target_size(Some(TargetSize::Absolute(512 * 1024 * 1024)));
+    let result = shrink_fat32(options).expect("Shrink failed");
+
+    assert!(result.new_size_bytes <= 512 * 1024 * 1024);
+    assert!(result.new_size_bytes > 511 * 1024 * 1024);
+
+    // Cut the image down to the new size and check it
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(image.path())
+        .expect("Failed to open image");
+    file.set_len(result.new_size_bytes)
+        .expect("Failed to truncate image");
+    assert!(check_filesystem(image.path()), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.fat and dosfsck
+fn test_resize_with_renumber_strategy() {
+    // Create a 128MB FAT32 image and grow it to 256MB without shifting data
+    let image = create_fat32_image(128);
+    extend_image(image.path(), 256);
+
+    let options = ResizeOptions::new(image.path()).strategy(GrowStrategy::Renumber);
+    let result = resize_fat32(options).expect("Resize failed");
+
+    assert!(result.fat_grew);
+    assert_eq!(result.strategy, Some(GrowStrategy::Renumber));
+    assert!(check_filesystem(image.path()), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.fat and dosfsck
+fn test_resize_fat16() {
+    let image = create_fat_image(16, 16);
+    extend_image(image.path(), 64);
+
+    let info_before = get_fs_info(image.path()).expect("Failed to get fs info");
+    assert_eq!(info_before.fat_type, FatType::Fat16);
+    assert!(info_before.can_grow);
+
+    let result = resize_fat32(ResizeOptions::new(image.path())).expect("Resize failed");
+    assert!(result.fat_grew);
+
+    let info_after = get_fs_info(image.path()).expect("Failed to get fs info");
+    assert_eq!(info_after.fat_type, FatType::Fat16);
+    assert!(info_after.data_clusters > info_before.data_clusters);
+    assert!(check_filesystem(image.path()), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.fat and dosfsck
+fn test_convert_fat16_to_fat32() {
+    let image = create_fat_image(16, 16);
+    extend_image(image.path(), 512);
+
+    let options = ResizeOptions::new(image.path()).convert_to_fat32(true);
+    let result = resize_fat32(options).expect("Resize failed");
+    assert_eq!(result.converted_from, Some(FatType::Fat16));
+
+    let info_after = get_fs_info(image.path()).expect("Failed to get fs info");
+    assert_eq!(info_after.fat_type, FatType::Fat32);
+    assert!(info_after.backup_matches);
+    assert!(check_filesystem(image.path()), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.exfat and fsck.exfat
+fn test_resize_exfat() {
+    let image = create_exfat_image(64);
+    extend_image(image.path(), 1024);
+
+    let info_before = get_exfat_info(image.path()).expect("Failed to get fs info");
+    assert!(info_before.can_grow);
+
+    let result = resize_fat32(ResizeOptions::new(image.path())).expect("Resize failed");
+    assert_eq!(result.calculation.new_fat_type, FatType::ExFat);
+    assert!(result.new_size_bytes > result.old_size_bytes);
+
+    let info_after = get_exfat_info(image.path()).expect("Failed to get fs info");
+    assert!(!info_after.can_grow, "Should be at max size now");
+    assert!(info_after.backup_matches);
+    assert!(info_after.cluster_count > info_before.cluster_count);
+
+    let status = Command::new("fsck.exfat")
+        .arg("-n")
+        .arg(image.path())
+        .status()
+        .expect("Failed to run fsck.exfat");
+    assert!(status.success(), "Filesystem check failed");
+}
+
+#[test]
+#[ignore] // Requires sfdisk and mkfs.fat
+fn test_resize_in_partition() {
+    let disk = NamedTempFile::new().expect("Failed to create temp file");
+    extend_image(disk.path(), 512);
+
+    // One 256 MiB partition at 1 MiB holding a 64 MiB filesystem
+    partition_image(disk.path(), "label: dos\nstart=2048, size=524288, type=c\n");
+
+    let table = list_partitions(disk.path())
+        .expect("Failed to read partition table")
+        .expect("No partition table found");
+    assert_eq!(table.partition(1).unwrap().filesystem, Some(FatType::Fat32));
+
+    let location = VolumeLocation::Partition(1);
+    let result =
+        resize_fat32(ResizeOptions::new(disk.path()).location(location)).expect("Resize failed");
+    assert_eq!(result.new_size_bytes, 256 * 1024 * 1024);
+
+    let info = get_fs_info_at(disk.path(), location).expect("Failed to get fs info");
+    assert!(!info.can_grow, "Should fill the partition now");
+    assert!(info.backup_matches);
+}
+
+#[test]
+#[ignore] // Requires sfdisk and mkfs.fat
+fn test_grow_gpt_partition() {
+    let disk = NamedTempFile::new().expect("Failed to create temp file");
+    extend_image(disk.path(), 128);
+    partition_image(disk.path(), "label: gpt\nstart=2048, size=131072\n");
+
+    // Enlarge the image, then let the partition and filesystem follow
+    extend_image(disk.path(), 512);
+    let growth = grow_partition(disk.path(), 1, false).expect("Failed to grow partition");
+    assert!(growth.grew());
+
+    // sfdisk accepts the moved backup header without complaint
+    let output = Command::new("sfdisk")
+        .arg("--verify")
+        .arg(disk.path())
+        .output()
+        .expect("Failed to run sfdisk");
+    assert!(output.status.success(), "sfdisk --verify failed");
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("GPT"),
+        "sfdisk reported GPT problems"
+    );
+
+    let location = VolumeLocation::Partition(1);
+    let result =
+        resize_fat32(ResizeOptions::new(disk.path()).location(location)).expect("Resize failed");
+    assert_eq!(result.new_size_bytes, growth.new_size_bytes);
+    let info = get_fs_info_at(disk.path(), location).expect("Failed to get fs info");
+    assert!(!info.can_grow, "Should fill the partition now");
+}
+
+#[test]
+#[ignore] // Requires sfdisk and mkfs.fat
+fn test_repair_gpt_after_extending() {
+    let disk = NamedTempFile::new().expect("Failed to create temp file");
+    extend_image(disk.path(), 128);
+    partition_image(disk.path(), "label: gpt\nstart=2048, size=131072\n");
+    extend_image(disk.path(), 512);
+    assert!(!check_gpt(disk.path())
+        .expect("Failed to check GPT")
+        .is_empty());
+
+    let repair = repair_gpt(disk.path(), false).expect("Failed to repair GPT");
+    assert_eq!(repair.backup_lba, 512 * 2048 - 1);
+    assert!(check_gpt(disk.path())
+        .expect("Failed to check GPT")
+        .is_empty());
+
+    let output = Command::new("sfdisk")
+        .arg("--verify")
+        .arg(disk.path())
+        .output()
+        .expect("Failed to run sfdisk");
+    assert!(output.status.success(), "sfdisk --verify failed");
+    assert!(
+        !String::from_utf8_lossy(&output.stderr).contains("GPT"),
+        "sfdisk reported GPT problems"
+    );
+}
+
+/// Convert an image between raw and qcow2 with `qemu-img convert`
+fn qemu_img_convert(from: &std::path::Path, to: &std::path::Path, format: &str) {
+    let output = Command::new("qemu-img")
+        .args(["convert", "-O", format])
+        .arg(from)
+        .arg(to)
+        .output()
+        .expect("Failed to run qemu-img");
+    assert!(output.status.success(), "qemu-img convert failed");
+}
+
+#[test]
+#[ignore] // Requires mkfs.fat, qemu-img and dosfsck
+fn test_resize_qcow2() {
+    let raw = create_fat32_image(64);
+    let qcow2 = NamedTempFile::new().expect("Failed to create temp file");
+    qemu_img_convert(raw.path(), qcow2.path(), "qcow2");
+
+    grow_image(
+        qcow2.path(),
+        TargetSize::Absolute(256 * 1024 * 1024),
+        VolumeLocation::Whole,
+        false,
+    )
+    .expect("Failed to grow image");
+    let result = resize_fat32(ResizeOptions::new(qcow2.path())).expect("Resize failed");
+    assert_eq!(result.new_size_bytes, 256 * 1024 * 1024);
+
+    let output = Command::new("qemu-img")
+        .arg("check")
+        .arg(qcow2.path())
+        .output()
+        .expect("Failed to run qemu-img");
+    assert!(output.status.success(), "qemu-img check failed");
+
+    let back = NamedTempFile::new().expect("Failed to create temp file");
+    qemu_img_convert(qcow2.path(), back.path(), "raw");
+    assert!(check_filesystem(back.path()), "Filesystem check failed");
+}